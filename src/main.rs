@@ -1,42 +1,86 @@
 mod network;
 mod nodes;
 mod simulation_controller;
+#[cfg(test)]
+mod test_support;
 use std::collections::HashMap;
 use std::error::Error;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use crossbeam_channel::{Receiver, Sender, unbounded};
+use std::time::Duration;
+use crossbeam_channel::{Sender, unbounded};
 use wg_2024::controller::{DroneCommand, DroneEvent};
 use wg_2024::drone::Drone;
 use wg_2024::network::NodeId;
 use wg_2024::packet::Packet;
 use simulation_controller::app::NetworkApp;
-use simulation_controller::SC_backend::SimulationController;
+use simulation_controller::SC_backend::{SimulationController, SHUTDOWN_GRACE};
+use simulation_controller::headless::{HeadlessRunner, EXIT_FAILED, EXIT_OK};
 use crate::network::TOML_parser;
 use crate::network::initializer::{DroneImplementation, MyDrone, NetworkInitializer, ParsedConfig};
 use crate::simulation_controller::gui_input_queue::{ new_gui_input_queue, SharedGuiInput};
+use crate::simulation_controller::gui_event_queue::{new_gui_event_queue, SharedGuiEvents};
+use crate::simulation_controller::sim_log::{new_sim_log, SharedSimLog};
+use crate::simulation_controller::replay::{new_recorder, node_seed, ReplayHeader, SharedRecorder};
 
 fn main() -> Result<(), Box<dyn Error>> {
     println!("🚀 Starting main()");
 
     env_logger::init();
 
-    let config_path = std::env::args()
-        .nth(1)
+    // `--headless [--script file]`, `--trace file`, `--seed n`, `--record file`, `--replay file`
+    // and `--duration secs` can go anywhere, the other arguments keep their positions
+    let mut args: Vec<String> = std::env::args().collect();
+    let mut headless = take_flag(&mut args, "--headless");
+    let mut script = take_option(&mut args, "--script");
+    let trace_path = take_option(&mut args, "--trace");
+    let record_path = take_option(&mut args, "--record");
+    let replay_path = take_option(&mut args, "--replay");
+    // a seed that does not parse would silently give another run, so it stops here (exit code 1)
+    let seed_arg = take_option(&mut args, "--seed")
+        .map(|s| s.parse::<u64>().map_err(|_| format!("--seed expects a number, got '{}'", s)))
+        .transpose()?;
+    let duration_arg = take_option(&mut args, "--duration");
+
+    // a replay is a headless run of the recorded script, with its seed and topology
+    let replay = match &replay_path {
+        Some(path) => {
+            headless = true;
+            script = Some(path.clone());
+            ReplayHeader::read(Path::new(path))?
+        }
+        None => ReplayHeader::default(),
+    };
+
+    let config_path = args
+        .get(1)
+        .cloned()
+        .or_else(|| replay.topology.clone())
         .unwrap_or_else(|| "topologies/default.toml".to_string());
+    if replay.topology.as_ref().is_some_and(|recorded| *recorded != config_path) {
+        println!("⚠️ Replaying against {} but the run was recorded on {}", config_path, replay.topology.as_deref().unwrap_or_default());
+    }
 
-    let simulation_duration = std::env::args()
-        .nth(3)
-        .and_then(|s| s.parse::<u64>().ok())
-        .unwrap_or(60);
+    let seed = seed_arg.or(replay.seed).unwrap_or_else(rand::random::<u64>);
+    println!("🎲 Simulation seed {}", seed);
+
+    // seconds before the controller stops every node; without it the run lasts until the window closes (or the script ends)
+    // a limit that does not parse is refused rather than running without one
+    let simulation_duration = duration_arg
+        .or_else(|| args.get(3).cloned())
+        .map(|s| s.parse::<u64>().map_err(|_| format!("--duration expects a number of seconds, got '{}'", s)))
+        .transpose()?
+        .map(Duration::from_secs);
 
     let (event_sender, event_receiverr) = unbounded::<DroneEvent>();
-    let (command_sender, command_receiver) = unbounded::<DroneCommand>();
+    let (command_sender, _command_receiver) = unbounded::<DroneCommand>();
 
     println!("✅ Channels created");
 
     let gui_input_queue = new_gui_input_queue();
-    let simulation_log = Arc::new(Mutex::new(Vec::new()));
+    let gui_event_queue = new_gui_event_queue();
+    let simulation_log = new_sim_log();
 
     let config = TOML_parser::parse_config(&config_path)?;
     println!("✅ Parsed config from {}", config_path);
@@ -44,7 +88,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     let parsed_config = Arc::new(Mutex::new(config.clone()));
 
     let drone_factory = Arc::new(
-        |id, controller_send, controller_recv, packet_recv, packet_send, pdr| {
+        move |id, controller_send, controller_recv, packet_recv, packet_send, pdr| {
             println!("🔧 Creating MyDrone {}", id);
             Box::new(MyDrone::new(
                 id,
@@ -53,7 +97,7 @@ fn main() -> Result<(), Box<dyn Error>> {
                 packet_recv,
                 packet_send,
                 pdr,
-            )) as Box<dyn Drone>
+            ).with_seed(node_seed(seed, id))) as Box<dyn Drone>
         },
     );
 
@@ -70,6 +114,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         simulation_log.clone(),
         shared_senders.clone(),
     )?));
+    initializer.lock().unwrap().set_seed(seed);
     println!("✅ NetworkInitializer created");
 
     let (receivers, senders, event_receiver) = initializer
@@ -115,8 +160,20 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     )));
 
+    let recorder = new_recorder(seed, &config_path);
+    controller.lock().unwrap().attach_log(simulation_log.clone());
+    controller.lock().unwrap().attach_recorder(recorder.clone());
     println!("✅ SimulationController created");
 
+    if let Some(path) = &trace_path {
+        let trace = controller.lock().unwrap().packet_trace();
+        let opened = trace.lock().unwrap().open_file(Path::new(path));
+        match opened {
+            Ok(()) => println!("✅ Tracing every drone event to {}", path),
+            Err(e) => println!("❌ Could not open trace file {}: {}", path, e),
+        }
+    }
+
     initializer.lock().unwrap().set_controller(controller.clone());
     println!("✅ Controller injected into initializer");
 
@@ -128,50 +185,86 @@ fn main() -> Result<(), Box<dyn Error>> {
     initializer.lock().unwrap().drone_impls = drone_impls;
 
     println!("⏳ Calling initializer.initialize()");
-    initializer.lock().unwrap().initialize(gui_input_queue.clone(),host_receivers.clone())?;
+    initializer.lock().unwrap().initialize(gui_input_queue.clone(),gui_event_queue.clone(),host_receivers.clone())?;
     println!("✅ initializer.initialize() completed");
 
     SimulationController::start_background_thread(controller.clone(), event_receiver.clone());
     println!("✅ Background thread started");
 
+    if headless {
+        println!("🤖 Running headless, commands from {}", script.as_deref().unwrap_or("stdin"));
+        let mut runner = HeadlessRunner::new(controller.clone(), gui_input_queue.clone(), gui_event_queue.clone());
+        let (done_tx, done_rx) = unbounded::<i32>();
+        thread::spawn(move || {
+            let _ = done_tx.send(runner.run_script(script.as_deref()));
+        });
+        let code = match simulation_duration {
+            Some(duration) => done_rx.recv_timeout(duration).unwrap_or_else(|_| {
+                println!("⏱ Simulation duration of {}s reached", duration.as_secs());
+                EXIT_OK
+            }),
+            None => done_rx.recv().unwrap_or(EXIT_FAILED),
+        };
+        save_recording(&recorder, record_path.as_deref());
+        println!("{}", controller.lock().unwrap().shutdown(SHUTDOWN_GRACE));
+        // a script still waiting on stdin is ended with the process
+        std::process::exit(code);
+    }
+
     println!("🖥️ Starting GUI");
     run_gui_application(
+        controller.clone(),
         event_sender.clone(),
-        //event_receiver.clone(),
-        command_sender.clone(),
-        command_receiver.clone(),
         parsed_config,
-        drone_factory,
         &config_path,
         gui_input_queue.clone(),
+        gui_event_queue.clone(),
         simulation_log.clone(),
-        packet_senders.clone(),
-        packet_receivers.clone(),
-        command_senders.clone(),
         shared_senders.clone(),
-        host_senders.clone(),
-        inbox_senders.clone(),
+        recorder.clone(),
+        simulation_duration,
     )?;
     println!("✅ GUI exited cleanly");
+    save_recording(&recorder, record_path.as_deref());
+    println!("{}", controller.lock().unwrap().shutdown(SHUTDOWN_GRACE));
 
     Ok(())
 }
 
+/// Writes what was recorded to `path` (`--record`), as a script `--replay` can run.
+fn save_recording(recorder: &SharedRecorder, path: Option<&str>) {
+    let Some(path) = path else { return };
+    match recorder.lock().unwrap().save(Path::new(path)) {
+        Ok(()) => println!("⏺ Recorded run saved to {}", path),
+        Err(e) => println!("❌ Could not save the recorded run to {}: {}", path, e),
+    }
+}
+
+/// Removes `flag` from `args`, telling whether it was there.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != before
+}
+
+/// Removes `name` and the value after it from `args`, giving the value.
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == name)?;
+    args.remove(index);
+    (index < args.len()).then(|| args.remove(index))
+}
+
 fn run_gui_application(
+    controller: Arc<Mutex<SimulationController>>,
     event_sender: Sender<DroneEvent>,
-    command_sender: Sender<DroneCommand>,
-    command_receiver: Receiver<DroneCommand>,
     config: Arc<Mutex<ParsedConfig>>,
-    drone_factory: Arc<dyn Fn(NodeId, Sender<DroneEvent>, Receiver<DroneCommand>, Receiver<Packet>, HashMap<NodeId, Sender<Packet>>, f32) -> Box<dyn Drone> + Send + Sync>,
     config_path: &str,
     gui_input_queue: SharedGuiInput,
-    simulation_log: Arc<Mutex<Vec<String>>>,
-    packet_senders: Arc<Mutex<HashMap<NodeId, HashMap<NodeId, Sender<Packet>>>>>,
-    packet_receivers: Arc<Mutex<HashMap<NodeId, Receiver<Packet>>>>,
-    command_senders: Arc<Mutex<HashMap<NodeId, Sender<DroneCommand>>>>,
+    gui_event_queue: SharedGuiEvents,
+    simulation_log: SharedSimLog,
     shared_senders: Arc<Mutex<HashMap<(NodeId, NodeId), Sender<Packet>>>>,
-    host_senders: HashMap<NodeId, Sender<Packet>>,
-    inbox_senders: Arc<Mutex<HashMap<NodeId, Sender<Packet>>>>,
+    recorder: SharedRecorder,
+    run_for: Option<Duration>,
 
 ) -> Result<(), Box<dyn Error>> {
 
@@ -187,20 +280,16 @@ fn run_gui_application(
         Box::new(|cc| {
             Ok(Box::new(NetworkApp::new_with_network(
                 cc,
+                controller.clone(),
                 event_sender.clone(),
-                command_sender.clone(),
-                command_receiver.clone(),
                 config.clone(),
-                drone_factory.clone(),
                 config_path,
                 gui_input_queue.clone(),
+                gui_event_queue.clone(),
                 simulation_log.clone(),
-                packet_senders.clone(),
-                packet_receivers.clone(),
-                command_senders.clone(),
                 shared_senders.clone(),
-                host_senders.clone(),
-                inbox_senders.clone(),
+                recorder.clone(),
+                run_for,
             )))
 
         }),
@@ -2,6 +2,9 @@ use std::fs;
 use serde::{ Serialize,Deserialize};
 use wg_2024::network::NodeId;
 use crate::network::initializer::ParsedConfig;
+use crate::nodes::client_node::ClientKind;
+use crate::nodes::protocol::ServerService;
+use crate::nodes::storage::StorageConfig;
 
 #[derive(Debug, Clone,Serialize,Deserialize)]
 pub struct Drone {
@@ -14,13 +17,27 @@ pub struct Drone {
 pub struct Client {
     pub id: NodeId,
     pub connected_drone_ids: Vec<NodeId>,
+    // "client1" or "client2"; when missing the initializer falls back to the old parity rule
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implementation: Option<ClientKind>,
+    // base directory for downloaded media, "downloads" when missing; each client saves in client_<id>/ below it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downloads: Option<String>,
 }
 
 #[derive(Debug, Clone,Serialize,Deserialize)]
 pub struct Server {
     pub id: NodeId,
     pub connected_drone_ids: Vec<NodeId>,
-
+    // memory (default) or file-backed storage, and how many messages each conversation keeps
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageConfig>,
+    // role of the server, e.g. ["chat"], ["text"] or ["media"]; chat and media when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub services: Option<Vec<ServerService>>,
+    // directory whose text files / images the server publishes at startup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
 }
 
 #[derive(Debug, Clone,Serialize,Deserialize)]
@@ -36,3 +53,18 @@ pub fn parse_config(path: &str) -> Result<ParsedConfig, Box<dyn std::error::Erro
     Ok(parsed)
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_services_and_content_are_read_from_the_toml() {
+        let server: Server = toml::from_str("id = 40\nconnected_drone_ids = [1]\nservices = [\"text\"]\ncontent = \"documents\"").unwrap();
+        assert_eq!(server.services, Some(vec![ServerService::Text]));
+        assert_eq!(server.content.as_deref(), Some("documents"));
+
+        let server: Server = toml::from_str("id = 41\nconnected_drone_ids = [1]").unwrap();
+        assert_eq!((server.services, server.content), (None, None), "both are optional");
+    }
+}
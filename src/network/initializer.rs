@@ -3,28 +3,34 @@ use crate::TOML_parser::Server;
 use crate::TOML_parser::Config;
 
 use crate::nodes::server;
-use crate::nodes::client1;
-use crate::nodes::client2;
+use crate::nodes::client_node::ClientKind;
+use crate::nodes::media;
 
 
 use crate::Drone as OrigDrone;
 use toml;
-use crossbeam_channel::{unbounded, select, Receiver, Sender};
+use crossbeam_channel::{unbounded, select_biased, Receiver, Sender};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::Deserialize;
 use wg_2024::controller::{DroneCommand, DroneEvent};
-use wg_2024::packet::Packet;
+use wg_2024::network::SourceRoutingHeader;
+use wg_2024::packet::{FloodRequest, FloodResponse, Nack, NackType, NodeType, Packet, PacketType};
 
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::Debug;
 use std::fs;
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::thread::{self, JoinHandle};
 use crossbeam::channel;
 
 use wg_2024::network::NodeId;
 use crate::simulation_controller::SC_backend::SimulationController;
 use crate::simulation_controller::gui_input_queue::SharedGuiInput;
+use crate::simulation_controller::gui_event_queue::SharedGuiEvents;
+use crate::simulation_controller::sim_log::SharedSimLog;
+use crate::simulation_controller::replay::node_seed;
 
 use log::warn;
 
@@ -289,6 +295,120 @@ pub struct MyDrone {
     packet_recv: Receiver<Packet>,
     packet_send: HashMap<NodeId, Sender<Packet>>,
     pdr: f32,
+    rng: StdRng, // drop decisions, seeded with `with_seed` for replays
+    seen_floods: HashSet<(u64, NodeId)>, // (flood id, initiator)
+}
+
+impl MyDrone {
+    /// Seeds the drone's drops, so that a replay drops the same packets.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    fn handle_command(&mut self, command: DroneCommand) -> bool {
+        match command {
+            DroneCommand::AddSender(id, sender) => {
+                self.packet_send.insert(id, sender);
+            }
+            DroneCommand::RemoveSender(id) => {
+                self.packet_send.remove(&id);
+            }
+            DroneCommand::SetPacketDropRate(pdr) => self.pdr = pdr,
+            DroneCommand::Crash => return false,
+        }
+        true
+    }
+
+    fn handle_packet(&mut self, mut packet: Packet, crashing: bool) {
+        if let PacketType::FloodRequest(request) = &packet.pack_type {
+            if !crashing {
+                self.handle_flood_request(packet.session_id, request.clone());
+            }
+            return;
+        }
+        if packet.routing_header.current_hop() != Some(self.id) {
+            self.nack(&packet, NackType::UnexpectedRecipient(self.id));
+            return;
+        }
+        packet.routing_header.increase_hop_index();
+        let Some(next_hop) = packet.routing_header.current_hop() else {
+            packet.routing_header.decrease_hop_index();
+            self.nack(&packet, NackType::DestinationIsDrone);
+            return;
+        };
+        let is_fragment = matches!(packet.pack_type, PacketType::MsgFragment(_));
+        if is_fragment && crashing {
+            packet.routing_header.decrease_hop_index();
+            self.nack(&packet, NackType::ErrorInRouting(self.id));
+        } else if !self.packet_send.contains_key(&next_hop) {
+            packet.routing_header.decrease_hop_index();
+            if is_fragment {
+                self.nack(&packet, NackType::ErrorInRouting(next_hop));
+            } else {
+                self.event(DroneEvent::ControllerShortcut(packet));
+            }
+        } else if is_fragment && self.rng.random::<f32>() < self.pdr {
+            self.event(DroneEvent::PacketDropped(packet.clone()));
+            packet.routing_header.decrease_hop_index();
+            self.nack(&packet, NackType::Dropped);
+        } else {
+            self.send(next_hop, packet);
+        }
+    }
+
+    fn handle_flood_request(&mut self, session_id: u64, mut request: FloodRequest) {
+        let sender = request.path_trace.last().map(|(id, _)| *id);
+        request.path_trace.push((self.id, NodeType::Drone));
+        let neighbors: Vec<NodeId> = self.packet_send.keys().copied().filter(|id| Some(*id) != sender).collect();
+        if self.seen_floods.insert((request.flood_id, request.initiator_id)) && !neighbors.is_empty() {
+            for neighbor in neighbors {
+                let packet = Packet::new_flood_request(SourceRoutingHeader::empty_route(), session_id, request.clone());
+                self.send(neighbor, packet);
+            }
+        } else {
+            let hops: Vec<NodeId> = request.path_trace.iter().rev().map(|(id, _)| *id).collect();
+            let response = FloodResponse { flood_id: request.flood_id, path_trace: request.path_trace };
+            let packet = Packet::new_flood_response(SourceRoutingHeader::new(hops, 1), session_id, response);
+            if let Some(next_hop) = packet.routing_header.current_hop() {
+                self.send(next_hop, packet);
+            }
+        }
+    }
+
+    /// Sends a nack about `packet` back along the hops it came from.
+    fn nack(&mut self, packet: &Packet, nack_type: NackType) {
+        let fragment_index = match &packet.pack_type {
+            PacketType::MsgFragment(fragment) => fragment.fragment_index,
+            _ => {
+                // only fragments get nacks, the rest goes through the controller
+                self.event(DroneEvent::ControllerShortcut(packet.clone()));
+                return;
+            }
+        };
+        let header = &packet.routing_header;
+        let mut hops: Vec<NodeId> = header.hops[..header.hop_index.min(header.hops.len())].to_vec();
+        hops.push(self.id);
+        hops.reverse();
+        let nack = Packet::new_nack(SourceRoutingHeader::new(hops, 1), packet.session_id, Nack { fragment_index, nack_type });
+        match nack.routing_header.current_hop() {
+            Some(next_hop) if self.packet_send.contains_key(&next_hop) => self.send(next_hop, nack),
+            _ => self.event(DroneEvent::ControllerShortcut(nack)),
+        }
+    }
+
+    fn send(&mut self, next_hop: NodeId, packet: Packet) {
+        match self.packet_send.get(&next_hop).map(|sender| sender.send(packet.clone())) {
+            Some(Ok(())) => self.event(DroneEvent::PacketSent(packet)),
+            _ => warn!("Drone {} could not send {:?} to {}", self.id, packet.pack_type, next_hop),
+        }
+    }
+
+    fn event(&self, event: DroneEvent) {
+        if let Err(e) = self.controller_send.send(event) {
+            warn!("Drone {} could not reach the controller: {}", self.id, e);
+        }
+    }
 }
 
 use wg_2024::drone::Drone as DroneTrait;
@@ -321,12 +441,29 @@ impl wg_2024::drone::Drone for MyDrone {
             packet_recv,
             packet_send,
             pdr,
+            rng: StdRng::from_os_rng(),
+            seen_floods: HashSet::new(),
         }
     }
 
     fn run(&mut self) {
         println!("Running drone {} with PDR {}", self.id, self.pdr);
-
+        loop {
+            select_biased! {
+                recv(self.controller_recv) -> command => match command {
+                    Ok(command) => if !self.handle_command(command) { break },
+                    Err(_) => break,
+                },
+                recv(self.packet_recv) -> packet => match packet {
+                    Ok(packet) => self.handle_packet(packet, false),
+                    Err(_) => return,
+                },
+            }
+        }
+        // crashed: what is still queued is nacked or passed on, until the neighbors drop their senders
+        while let Ok(packet) = self.packet_recv.recv() {
+            self.handle_packet(packet, true);
+        }
     }
 }
 
@@ -405,9 +542,11 @@ pub struct NetworkInitializer {
     controller_tx: Sender<DroneEvent>,
     controller_rx: Receiver<DroneCommand>,
     simulation_controller: Option<Arc<Mutex<SimulationController>>>,
-    simulation_log: Arc<Mutex<Vec<String>>>,
+    simulation_log: SharedSimLog,
+    seed: u64, // of the whole simulation, each node gets `replay::node_seed(seed, id)`
 
     pub(crate) shared_senders: Option<Arc<Mutex<HashMap<(NodeId, NodeId), Sender<Packet>>>>>,
+    node_threads: Vec<(NodeId, JoinHandle<()>)>, // one per drone, client and server started here
 }
 
 
@@ -415,7 +554,7 @@ pub struct NetworkInitializer {
 
 
 impl NetworkInitializer {
-    pub fn new(config_path: &str, drone_impls: Vec<DroneWithId>, simulation_log: Arc<Mutex<Vec<String>>>, shared_senders: Arc<Mutex<HashMap<(NodeId, NodeId), Sender<Packet>>>>) -> Result<Self, Box<dyn Error>> {
+    pub fn new(config_path: &str, drone_impls: Vec<DroneWithId>, simulation_log: SharedSimLog, shared_senders: Arc<Mutex<HashMap<(NodeId, NodeId), Sender<Packet>>>>) -> Result<Self, Box<dyn Error>> {
         // Read config file
         let config_str = fs::read_to_string(config_path)?;
 
@@ -447,7 +586,9 @@ impl NetworkInitializer {
             controller_rx,
             simulation_controller: None,
             simulation_log,
+            seed: 0,
             shared_senders: Some(shared_senders),
+            node_threads: Vec::new(),
         })
 
     }
@@ -456,7 +597,16 @@ impl NetworkInitializer {
         self.simulation_controller = Some(ctrl);
     }
 
-    pub fn initialize(&mut self, gui_input_queue: SharedGuiInput, host_receivers: HashMap<NodeId, Receiver<Packet>>,
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// Handles of the node threads started so far, for the controller to join at shutdown.
+    pub fn take_node_threads(&mut self) -> Vec<(NodeId, JoinHandle<()>)> {
+        std::mem::take(&mut self.node_threads)
+    }
+
+    pub fn initialize(&mut self, gui_input_queue: SharedGuiInput, gui_events: SharedGuiEvents, host_receivers: HashMap<NodeId, Receiver<Packet>>,
     ) -> Result<(), Box<dyn Error>> {
        // Validate the network configuration
         self.validate_config()?;
@@ -465,7 +615,7 @@ impl NetworkInitializer {
         self.initialize_drones();
 
         // Spawn client threads
-        self.initialize_clients(gui_input_queue.clone(),self.simulation_log.clone(),&host_receivers);
+        self.initialize_clients(gui_input_queue.clone(),gui_events,self.simulation_log.clone(),&host_receivers);
 
         // Spawn server threads
         self.initialize_servers(gui_input_queue.clone(),self.simulation_log.clone(),&host_receivers);
@@ -774,15 +924,17 @@ impl NetworkInitializer {
                 println!("🛸 Drone {} uses fallback MyDrone", id);
             }
 
-            thread::spawn(move || {
+            let handle = thread::spawn(move || {
                 instance.run();
             });
+            self.node_threads.push((id, handle));
         }
     }
 
 
 
-    fn initialize_clients(&mut self, gui_input: SharedGuiInput, log: Arc<Mutex<Vec<String>>>, host_receivers: &HashMap<NodeId, Receiver<Packet>>) {
+    fn initialize_clients(&mut self, gui_input: SharedGuiInput, gui_events: SharedGuiEvents, log: SharedSimLog, host_receivers: &HashMap<NodeId, Receiver<Packet>>) {
+        let mut handles = Vec::new();
         for (i, client) in self.config.client.iter().enumerate() {
             let log_clone=log.clone();
 
@@ -802,54 +954,31 @@ impl NetworkInitializer {
                 .clone();
 
             let gui_clone = gui_input.clone();
+            let events_clone = gui_events.clone();
             let log_clone = self.simulation_log.clone();
             let shared_senders = Arc::clone(self.shared_senders.as_ref().unwrap());
             let shortcut_rx = host_receivers.get(&client_id).cloned().unwrap();
 
-            if self.config.client.len() == 2 {
-                if client_id % 2 == 0 {
-                    // client2
-                    thread::spawn(move || {
-                        println!("client2 spawned");
-                        let mut cl2 = client2::MyClient::new(client_id, client_rx, senders, None,Some(shortcut_rx));
-                        cl2.shared_senders= Some(shared_senders.clone());
-                        cl2.attach_log(log_clone);
-                        cl2.run(gui_clone);
-
-                    });
-                } else {
-                    // client1
-                    thread::spawn(move || {
-                        println!("client1 spawned");
-                        let mut cl1 = client1::MyClient::new(client_id, client_rx, senders, HashMap::new(), None, HashSet::new(), None,Some(shortcut_rx));
-                        cl1.shared_senders= Some(shared_senders.clone());
-                        cl1.attach_log(log_clone);
-                        cl1.run(gui_clone);
-                    });
-                }
-            } else {
-                if i % 2 == 0 {
-                    thread::spawn(move || {
-                        println!("client2 spawned");
-                        let mut cl2 = client2::MyClient::new(client_id, client_rx, senders, None,Some(shortcut_rx));
-                        cl2.shared_senders= Some(shared_senders.clone());
-                        cl2.attach_log(log_clone);
-                        cl2.run(gui_clone);
-                    });
-                } else {
-                    thread::spawn(move || {
-                        println!("client1 spawned");
-                        let mut cl1 = client1::MyClient::new(client_id, client_rx, senders, HashMap::new(), None, HashSet::new(), None,Some(shortcut_rx));
-                        cl1.shared_senders= Some(shared_senders.clone());
-                        cl1.attach_log(log_clone);
-                        cl1.run(gui_clone);
-                    });
-                }
-            }
+            let kind = client
+                .implementation
+                .unwrap_or_else(|| ClientKind::by_parity(i, client_id, self.config.client.len()));
+            let downloads = media::downloads_dir(std::path::Path::new(client.downloads.as_deref().unwrap_or(media::DEFAULT_DOWNLOADS_DIR)), client_id);
+
+            let handle = thread::spawn(move || {
+                println!("{} spawned", kind);
+                let mut cl = kind.build(client_id, client_rx, senders, Some(shared_senders), Some(shortcut_rx));
+                cl.attach_log(log_clone);
+                cl.attach_gui_events(events_clone);
+                cl.set_downloads_dir(downloads);
+                cl.run(gui_clone);
+            });
+            handles.push((client_id, handle));
         }
+        self.node_threads.extend(handles);
     }
 
-    fn initialize_servers(&mut self, gui_input: SharedGuiInput, log: Arc<Mutex<Vec<String>>>, host_receivers: &HashMap<NodeId, Receiver<Packet>>) {
+    fn initialize_servers(&mut self, gui_input: SharedGuiInput, log: SharedSimLog, host_receivers: &HashMap<NodeId, Receiver<Packet>>) {
+        let mut handles = Vec::new();
         for server in &self.config.server {
             let log_clone=log.clone();
             let server_id = server.id;
@@ -869,15 +998,29 @@ impl NetworkInitializer {
             let gui_clone = gui_input.clone();
             let shared_senders = Arc::clone(self.shared_senders.as_ref().unwrap());
             let shortcut_rx = host_receivers.get(&server_id).cloned().unwrap();
+            let storage = server.storage.clone().unwrap_or_default();
+            let services = server.services.clone();
+            let content = server.content.clone();
+            let seed = node_seed(self.seed, server_id);
 
-
-            thread::spawn(move || {
+            let handle = thread::spawn(move || {
                 let mut srv = server::server::new(server_id as u8, senders, server_rx, None,Some(shortcut_rx));
                 srv.attach_log(log_clone);
+                srv.set_seed(seed);
+                srv.attach_storage(storage.build(server_id));
+                srv.set_media_quota(storage.media_quota());
+                if let Some(services) = services {
+                    srv.set_services(services);
+                }
+                if let Some(dir) = content {
+                    srv.load_content(std::path::Path::new(&dir));
+                }
                 srv.shared_senders= Some(shared_senders.clone());
                 srv.run(gui_clone);
             });
+            handles.push((server_id, handle));
         }
+        self.node_threads.extend(handles);
     }
 
 
@@ -896,7 +1039,9 @@ impl NetworkInitializer {
             impl_counts[i] += 1;
         }
 
-        let group_keys: Vec<String> = group_implementations.keys().cloned().collect();
+        // sorted, so that a topology always gets the same implementation on each drone
+        let mut group_keys: Vec<String> = group_implementations.keys().cloned().collect();
+        group_keys.sort();
 
         let mut impl_index = 0;
         let mut count = 0;
@@ -918,7 +1063,7 @@ impl NetworkInitializer {
 
             let (drone_impl, group_name) = if num_impls == 0 {
                 (
-                    Box::new(MyDrone::new(id, event_send, command_recv, packet_recv, packet_send, pdr)) as Box<dyn DroneImplementation>,
+                    Box::new(MyDrone::new(id, event_send, command_recv, packet_recv, packet_send, pdr).with_seed(node_seed(self.seed, id))) as Box<dyn DroneImplementation>,
                     None,
                 )
             } else {
@@ -931,7 +1076,7 @@ impl NetworkInitializer {
                 } else {
                     warn!("⚠️ Unknown group key, falling back to MyDrone");
                     (
-                        Box::new(MyDrone::new(id, event_send, command_recv, packet_recv, packet_send, pdr)) as Box<dyn DroneImplementation>,
+                        Box::new(MyDrone::new(id, event_send, command_recv, packet_recv, packet_send, pdr).with_seed(node_seed(self.seed, id))) as Box<dyn DroneImplementation>,
                         None,
                     )
                 }
@@ -1116,3 +1261,107 @@ impl NetworkInitializer {
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wg_2024::drone::Drone;
+    use wg_2024::packet::Fragment;
+
+    /// Fragments 0..64 from 1 to 3 through drone 2 with `pdr`; gives the indices 2 dropped and those 3 got.
+    fn run_drone(pdr: f32, seed: u64) -> (Vec<u64>, Vec<u64>) {
+        let (event_tx, event_rx) = unbounded();
+        let (_command_tx, command_rx) = unbounded();
+        let (packet_tx, packet_rx) = unbounded();
+        let (back_tx, _back_rx) = unbounded();
+        let (forward_tx, forward_rx) = unbounded();
+        let mut drone = MyDrone::new(2, event_tx, command_rx, packet_rx, HashMap::from([(1, back_tx), (3, forward_tx)]), pdr).with_seed(seed);
+        for index in 0..64 {
+            let fragment = Fragment::from_string(index, 64, "hi".to_string());
+            packet_tx.send(Packet::new_fragment(SourceRoutingHeader::new(vec![1, 2, 3], 1), 7, fragment)).unwrap();
+        }
+        drop(packet_tx);
+        Drone::run(&mut drone);
+
+        let dropped = event_rx.try_iter().filter_map(|event| match event {
+            DroneEvent::PacketDropped(packet) => Some(packet.get_fragment_index()),
+            _ => None,
+        }).collect();
+        (dropped, forward_rx.try_iter().map(|packet| packet.get_fragment_index()).collect())
+    }
+
+    /// Drone 2 between 1 and 3, never dropping: the packets and commands are queued first, then it runs
+    /// until the packet channel closes. Gives what it sent towards 1, towards 3 and to the controller.
+    fn run_queued(packets: Vec<Packet>, commands: Vec<DroneCommand>) -> (Vec<Packet>, Vec<Packet>, Vec<DroneEvent>) {
+        let (event_tx, event_rx) = unbounded();
+        let (command_tx, command_rx) = unbounded();
+        let (packet_tx, packet_rx) = unbounded();
+        let (back_tx, back_rx) = unbounded();
+        let (forward_tx, forward_rx) = unbounded();
+        let mut drone = MyDrone::new(2, event_tx, command_rx, packet_rx, HashMap::from([(1, back_tx), (3, forward_tx)]), 0.0);
+        commands.into_iter().for_each(|command| command_tx.send(command).unwrap());
+        packets.into_iter().for_each(|packet| packet_tx.send(packet).unwrap());
+        drop(packet_tx);
+        Drone::run(&mut drone);
+        (back_rx.try_iter().collect(), forward_rx.try_iter().collect(), event_rx.try_iter().collect())
+    }
+
+    fn fragment(hops: Vec<NodeId>) -> Packet {
+        Packet::new_fragment(SourceRoutingHeader::new(hops, 1), 7, Fragment::from_string(0, 1, "hi".to_string()))
+    }
+
+    /// The nack type and route of the only packet in `sent`.
+    fn nack_of(sent: &[Packet]) -> (NackType, Vec<NodeId>) {
+        match sent {
+            [Packet { pack_type: PacketType::Nack(nack), routing_header, .. }] => (nack.nack_type, routing_header.hops.clone()),
+            other => panic!("expected one nack, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_drone_nacks_packets_it_cannot_route() {
+        let (back, forward, _) = run_queued(vec![fragment(vec![1, 5, 3])], vec![]);
+        assert_eq!(nack_of(&back), (NackType::UnexpectedRecipient(2), vec![2, 1]));
+        assert!(forward.is_empty());
+
+        let (back, _, _) = run_queued(vec![fragment(vec![1, 2])], vec![]);
+        assert_eq!(nack_of(&back), (NackType::DestinationIsDrone, vec![2, 1]));
+
+        let (back, _, _) = run_queued(vec![fragment(vec![1, 2, 4])], vec![]);
+        assert_eq!(nack_of(&back), (NackType::ErrorInRouting(4), vec![2, 1]), "4 is not a neighbor");
+    }
+
+    #[test]
+    fn test_crashed_drone_nacks_the_fragments_still_queued() {
+        let (back, forward, _) = run_queued(vec![fragment(vec![1, 2, 3])], vec![DroneCommand::Crash]);
+        assert_eq!(nack_of(&back), (NackType::ErrorInRouting(2), vec![2, 1]));
+        assert!(forward.is_empty(), "nothing is forwarded after the crash");
+    }
+
+    #[test]
+    fn test_drone_answers_a_flood_it_has_already_seen() {
+        let request = FloodRequest { flood_id: 9, initiator_id: 1, path_trace: vec![(1, NodeType::Client)] };
+        let flood = || Packet::new_flood_request(SourceRoutingHeader::empty_route(), 7, request.clone());
+        let (back, forward, _) = run_queued(vec![flood(), flood()], vec![]);
+
+        assert_eq!(forward.len(), 1, "the first copy goes on to the other neighbors");
+        assert!(matches!(&forward[0].pack_type, PacketType::FloodRequest(r) if r.path_trace == vec![(1, NodeType::Client), (2, NodeType::Drone)]));
+        match &back[..] {
+            [Packet { pack_type: PacketType::FloodResponse(response), routing_header, .. }] => {
+                assert_eq!(response.path_trace, vec![(1, NodeType::Client), (2, NodeType::Drone)]);
+                assert_eq!((routing_header.hops.clone(), routing_header.hop_index), (vec![2, 1], 1));
+            }
+            other => panic!("expected one flood response, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_seeded_drone_drops_the_same_fragments_on_every_run() {
+        let (dropped, forwarded) = run_drone(0.5, 42);
+        assert!(!dropped.is_empty() && !forwarded.is_empty());
+        assert_eq!(dropped.len() + forwarded.len(), 64, "every fragment is either dropped or forwarded");
+        assert_eq!(run_drone(0.5, 42), (dropped.clone(), forwarded), "same seed, same drops");
+        assert_ne!(run_drone(0.5, 43).0, dropped);
+        assert_eq!(run_drone(0.0, 42).1.len(), 64);
+    }
+}
@@ -1,11 +1,7 @@
 use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
-use std::process::Command;
-use std::env::temp_dir;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use once_cell::sync::Lazy;
-use base64::{Engine, engine::general_purpose::STANDARD};
-use image::load_from_memory;
 use petgraph::graph::NodeIndex;
 use petgraph::stable_graph::StableGraph;
 use wg_2024::network::{NodeId, SourceRoutingHeader};
@@ -13,9 +9,14 @@ use wg_2024::packet::{Ack, FloodRequest, FloodResponse, Fragment, Nack, NackType
 use crossbeam_channel::{select_biased, Receiver, Sender};
 use log::{info, warn, error};
 use petgraph::visit::{IntoEdgeReferences};
-use crate::simulation_controller::gui_input_queue::{SharedGuiInput};
-
-static SESSION_COUNTER : Lazy<Mutex<u64>> = Lazy::new(|| Mutex::new(0));
+use crate::simulation_controller::gui_input_queue::{SharedGuiInput, SHUTDOWN_COMMAND};
+use crate::simulation_controller::gui_event_queue::{new_gui_event_queue, push_gui_event, GuiEvent, SharedGuiEvents};
+use crate::simulation_controller::sim_log::{new_sim_log, push_sim_event, SharedSimLog, SimEvent, SimEventKind};
+use crate::nodes::protocol::{self, Capability, ChatProtocol, ReceiptKind, ServerService};
+use crate::nodes::reliable::{AckOutcome, ReliableSender};
+use crate::nodes::transfer::{self, Download, DownloadStep, Resume, Upload};
+use crate::nodes::media::{self, MediaEnvelope};
+use crate::nodes::client_node::{ClientKind, ClientNode, ClientSnapshot};
 
 #[derive(Debug, Clone)]
 pub struct ReceivedMessageState {
@@ -25,14 +26,6 @@ pub struct ReceivedMessageState {
     pub last_fragment_len: Option<u8>,
 }
 
-#[derive(Debug, Clone)]
-pub struct SentMessageInfo {
-    pub fragments : Vec<Fragment>,
-    pub original_routing_header : SourceRoutingHeader,
-    pub received_ack_indices : HashSet<u64>,
-    pub route_needs_recalculation : bool,
-}
-
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct NodeInfo {
     id : NodeId,
@@ -44,18 +37,26 @@ pub struct MyClient{
     pub id: NodeId,
     pub packet_recv: Receiver<Packet>, //receives packets from other nodes
     pub packet_send: HashMap<NodeId, Sender<Packet>>, //sends packets to neighbors
-    pub sent_messages: HashMap<u64, SentMessageInfo>,
-    pub received_messages : HashMap<u64, ReceivedMessageState>, //to determine when the message is complete (it has all the fragments)
+    pub sent_messages: ReliableSender, //fragments waiting for an ACK, resent on NACK or timeout
+    pub received_messages : HashMap<(u64, NodeId), ReceivedMessageState>, //(session, sender) -> fragments so far, to determine when the message is complete
     pub network_graph : StableGraph<NodeInfo, usize>, //graph to memorize info about nodes
     pub node_id_to_index : HashMap<NodeId, NodeIndex>, //mapping from node_id to inner indices of the graph
     pub active_flood_discoveries: HashMap<u64, FloodDiscoveryState>, //structure to take track of flood_request/response
     pub connected_server_id : Option<NodeId>,
+    pub server_capabilities : Option<Vec<Capability>>, //negotiated on login, None until the LoginAck arrives
     pub seen_flood_ids : HashSet<(u64, NodeId)>,
     pub route_cache : HashMap<NodeId, Vec<NodeId>>,
-    pub simulation_log: Arc<Mutex<Vec<String>>>,
+    pub simulation_log: SharedSimLog,
+    pub gui_events: SharedGuiEvents, //what arrived from the network, for the chat window
     pub shared_senders: Option<Arc<Mutex<HashMap<(NodeId, NodeId), Sender<Packet>>>>>,
     shortcut_receiver: Option<Receiver<Packet>>, // added to receive packets from sc (shortcut)
-    pub pending_messages_after_flood: Vec<(NodeId, String)>, // (dest_id, gui_command)
+    pub pending_messages_after_flood: Vec<(NodeId, ChatProtocol)>, // (dest_id, message)
+    pub server_services: HashMap<NodeId, Vec<ServerService>>, //from the ServerTypeResponses, to pick content servers
+    pub text_files: HashMap<String, NodeId>, //text file name -> text server that listed it
+    pub downloads: HashMap<String, Download>, //chunked media downloads in progress, by media name
+    pub uploads: HashMap<String, Upload>, //chunked media uploads in progress, by media name
+    pub downloads_dir: PathBuf, //where downloaded media are saved
+    pub session_counter: u64, //last session/flood id used; per client, so a replay numbers them the same way
 
 }
 
@@ -72,7 +73,7 @@ impl MyClient {
         id: NodeId,
         packet_recv: Receiver<Packet>,
         packet_send: HashMap<NodeId, Sender<Packet>>,
-        sent_messages: HashMap<u64, SentMessageInfo>,
+        sent_messages: ReliableSender,
         connected_server_id: Option<NodeId>,
         seen_flood_ids : HashSet<(u64, NodeId)>,
         shared_senders: Option<Arc<Mutex<HashMap<(NodeId,NodeId), Sender<Packet>>>>>,
@@ -88,12 +89,20 @@ impl MyClient {
             node_id_to_index: HashMap::new(),
             active_flood_discoveries: HashMap::new(),
             connected_server_id,
+            server_capabilities: None,
             seen_flood_ids,
             route_cache : HashMap::new(),
-            simulation_log: Arc::new(Mutex::new(Vec::new())),
+            simulation_log: new_sim_log(),
+            gui_events: new_gui_event_queue(),
             shared_senders, // ✅ store reference
             shortcut_receiver,
             pending_messages_after_flood: Vec::new(),
+            server_services: HashMap::new(),
+            text_files: HashMap::new(),
+            downloads: HashMap::new(),
+            uploads: HashMap::new(),
+            downloads_dir: media::downloads_dir(Path::new(media::DEFAULT_DOWNLOADS_DIR), id),
+            session_counter: 0,
 
         }
     }
@@ -116,12 +125,18 @@ impl MyClient {
                     if !msgs.is_empty() {
                         let msg = msgs.remove(0); // remove the first message
                         drop(map); // release lock early
+                        if msg == SHUTDOWN_COMMAND {
+                            info!("🛑 Client {} stopping on controller request", self.id);
+                            return;
+                        }
                         self.process_gui_command(self.id, msg); // process the message
                     }
                 }
             }
 
             self.check_flood_discoveries_timeouts();
+            self.retransmit_unacked_fragments();
+            self.resume_stalled_transfers();
 
             select_biased! {
                 recv(self.packet_recv) -> packet => {
@@ -145,16 +160,50 @@ impl MyClient {
         }
     }
 
-    pub fn attach_log(&mut self, log: Arc<Mutex<Vec<String>>>) {
+    pub fn attach_log(&mut self, log: SharedSimLog) {
         self.simulation_log = log;
     }
 
-    fn log(&self, message: impl ToString) {
-        if let Ok(mut log) = self.simulation_log.lock() {
-            log.push(message.to_string());
+    pub fn attach_gui_events(&mut self, events: SharedGuiEvents) {
+        self.gui_events = events;
+    }
+
+    /// Saves a downloaded media under the name and type its envelope carries; nothing is opened.
+    fn save_download(&self, media_name: &str, media_data: &[u8]) {
+        let envelope = MediaEnvelope::open(media_name, media_data);
+        match envelope.save_in(&self.downloads_dir) {
+            Ok(path) => {
+                info!("💾 Client {} saved '{}' ({}, {} bytes) to {}", self.id, media_name, envelope.content_type, envelope.data.len(), path.display());
+                push_gui_event(&self.gui_events, self.id, GuiEvent::MediaDownloaded {
+                    name: media_name.to_string(),
+                    file_name: envelope.file_name,
+                    content_type: envelope.content_type,
+                    size: envelope.data.len(),
+                    path: path.display().to_string(),
+                });
+            }
+            Err(e) => {
+                error!("Client {} failed to save media '{}' in {}: {}", self.id, media_name, self.downloads_dir.display(), e);
+                push_gui_event(&self.gui_events, self.id, GuiEvent::Error { reason: format!("media_save_failed::{}", media_name) });
+            }
         }
     }
 
+    fn log(&self, kind: SimEventKind, message: impl ToString) {
+        push_sim_event(&self.simulation_log, SimEvent::new(Some(self.id), kind, message));
+    }
+
+    /// Like `log`, for an event about one session, so the log can be filtered by it.
+    fn log_session(&self, kind: SimEventKind, session_id: u64, message: impl ToString) {
+        push_sim_event(&self.simulation_log, SimEvent::new(Some(self.id), kind, message).with_session(session_id));
+    }
+
+    /// Next session (or flood) id: counted per client from 1, so the same run gives the same ids.
+    fn next_session_id(&mut self) -> u64 {
+        self.session_counter = self.session_counter.saturating_add(1);
+        self.session_counter
+    }
+
     fn check_flood_discoveries_timeouts(&mut self) {
         let now = Instant::now();
         let timeout_duration = Duration::from_millis(2000);
@@ -189,14 +238,16 @@ impl MyClient {
                     let routing_header = SourceRoutingHeader { hops: path, hop_index: 1 };
 
                     // Fragment the pending message data just like in process_gui_command
-                    let data_bytes = pending_cmd.as_bytes();
+                    let data_bytes = match pending_cmd.encode() {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            error!("Client {} could not send pending message to {}: {}", self.id, dest, e);
+                            continue;
+                        }
+                    };
                     const FRAGMENT_SIZE: usize = 128;
                     let total_frags = (data_bytes.len() + FRAGMENT_SIZE - 1) / FRAGMENT_SIZE;
-                    let session_id = {
-                        let mut id_counter = SESSION_COUNTER.lock().unwrap();
-                        *id_counter = id_counter.saturating_add(1);
-                        *id_counter
-                    };
+                    let session_id = self.next_session_id();
                     // Prepare fragments
                     let mut fragments = Vec::new();
                     for i in 0..total_frags {
@@ -214,12 +265,7 @@ impl MyClient {
                         });
                     }
                     // Store message info for ACK tracking
-                    self.sent_messages.insert(session_id, SentMessageInfo {
-                        fragments: fragments.clone(),
-                        original_routing_header: routing_header.clone(),
-                        received_ack_indices: HashSet::new(),
-                        route_needs_recalculation: false,
-                    });
+                    self.sent_messages.track(session_id, routing_header.clone(), fragments.clone());
                     // Send each fragment along the new route
                     if routing_header.hops.len() > routing_header.hop_index {
                         let first_hop = routing_header.hops[routing_header.hop_index];
@@ -246,14 +292,15 @@ impl MyClient {
                     }
 
                     // If the pending command was a logout, update client state
-                    if pending_cmd.starts_with("[Logout]") {
+                    if pending_cmd == ChatProtocol::Logout {
                         println!("🚪 🚪 🚪  Client {} logout completed after flood. Disconnected from server {}",
                                  self.id, dest);
                         self.connected_server_id = None;
+                        self.server_capabilities = None;
                     }
                 } else {
                     warn!("Client {} still cannot find route to {} after flood; dropping message '{}'",
-                              self.id, dest, pending_cmd);
+                              self.id, dest, pending_cmd.tag());
                     warn!("Please add a sender such that it connects the client to the server");
                 }
             }
@@ -261,6 +308,81 @@ impl MyClient {
     }
 
 
+    /// Media transfers with no progress for a while ask again from their last verified chunk.
+    fn resume_stalled_transfers(&mut self) {
+        let now = Instant::now();
+        let mut resend = Vec::new();
+        let mut given_up = Vec::new();
+        for download in self.downloads.values_mut() {
+            match download.poll(now) {
+                Resume::Resend(request) => resend.push((download.server_id, request)),
+                Resume::GiveUp => given_up.push(download.name.clone()),
+                Resume::Wait => {}
+            }
+        }
+        for upload in self.uploads.values_mut() {
+            match upload.poll(now) {
+                Resume::Resend(chunk) => resend.push((upload.server_id, chunk)),
+                Resume::GiveUp => given_up.push(upload.name.clone()),
+                Resume::Wait => {}
+            }
+        }
+        for name in given_up {
+            warn!("Client {} gave up the transfer of media '{}'", self.id, name);
+            self.downloads.remove(&name);
+            self.uploads.remove(&name);
+            push_gui_event(&self.gui_events, self.id, GuiEvent::Error { reason: format!("media_transfer_failed::{}", name) });
+        }
+        for (server_id, message) in resend {
+            info!("⏯ Client {} resuming {} with server {}", self.id, message.tag(), server_id);
+            self.send_to(server_id, message);
+        }
+    }
+
+    /// Starts fetching a media chunk by chunk, or resumes the download already in progress for it.
+    fn start_download(&mut self, server_id: NodeId, name: &str) {
+        let download = self.downloads.entry(name.to_string()).or_insert_with(|| Download::new(server_id, name));
+        if download.server_id != server_id {
+            *download = Download::new(server_id, name);
+        }
+        info!("Client {} downloading '{}' from server {} starting at byte {}", self.id, name, server_id, download.verified());
+        let request = download.next_request();
+        self.send_to(server_id, request);
+    }
+
+    fn retransmit_unacked_fragments(&mut self) {
+        let timeouts = self.sent_messages.poll_timeouts(Instant::now());
+        for session in timeouts.given_up {
+            warn!("Client {} gave up on session {} to {}: {} fragment(s) never ACKed", self.id, session.session_id, session.destination, session.unacked());
+            self.log_session(SimEventKind::Delivery, session.session_id, format!("Client {} could not deliver session {} to {}", self.id, session.session_id, session.destination));
+        }
+        for retransmission in timeouts.resend {
+            let mut routing_header = retransmission.routing_header;
+            if retransmission.route_needs_recalculation {
+                if let Some(path) = self.best_path(self.id, retransmission.destination) {
+                    self.route_cache.insert(retransmission.destination, path.clone());
+                    routing_header = SourceRoutingHeader { hops: path, hop_index: 1 };
+                    if let Some(session) = self.sent_messages.get_mut(retransmission.session_id, retransmission.destination) {
+                        session.reroute(routing_header.clone());
+                    }
+                }
+            }
+            let Some(&first_hop) = routing_header.hops.get(routing_header.hop_index) else {
+                continue;
+            };
+            let fragment_index = retransmission.fragment.fragment_index;
+            let packet = Packet {
+                pack_type: PacketType::MsgFragment(retransmission.fragment),
+                routing_header,
+                session_id: retransmission.session_id,
+            };
+            match self.send_to_neighbor(first_hop, packet) {
+                Ok(_) => info!("⏰ ⏰ ⏰  Client {} resent fragment {} of session {} after ACK timeout", self.id, fragment_index, retransmission.session_id),
+                Err(e) => warn!("Client {} failed to resend timed out fragment {} of session {}: {}", self.id, fragment_index, retransmission.session_id, e),
+            }
+        }
+    }
+
     fn finalize_flood_discovery_topology(&mut self, flood_id: u64) {
         if let Some(discovery_state) = self.active_flood_discoveries.remove(&flood_id) {
             println!("Client {} processing {} collected responses for flood ID {}", self.id, discovery_state.received_responses.len(), flood_id);
@@ -295,15 +417,17 @@ impl MyClient {
             },
             PacketType::Ack(ack) => {
                 println!("Client {} received ACK for session {}, fragment {}", self.id, packet.session_id, ack.fragment_index);
-                if let Some(sent_msg_info) = self.sent_messages.get_mut(&packet.session_id) {
-                    sent_msg_info.received_ack_indices.insert(ack.fragment_index);
-                    info!("Client {} marked fragment {} of session {} as ACKed", self.id, ack.fragment_index, packet.session_id);
-                    if sent_msg_info.received_ack_indices.len() == sent_msg_info.fragments.len() {
+                let acker = packet.routing_header.hops.first().copied().unwrap_or_default();
+                match self.sent_messages.on_ack(packet.session_id, acker, ack.fragment_index) {
+                    AckOutcome::Pending => {
+                        info!("Client {} marked fragment {} of session {} as ACKed", self.id, ack.fragment_index, packet.session_id);
+                    },
+                    AckOutcome::Completed => {
                         println!("✅ ✅ ✅  Client {} received all ACKs for session {}. Message considered successfully sent", self.id, packet.session_id);
-                        //self.sent_messages.remove(&packet.session_id);
-                    }
-                } else {
-                    warn!("Client {} received ACK for unknown session {}", self.id, packet.session_id);
+                    },
+                    AckOutcome::Unknown => {
+                        warn!("Client {} received ACK for unknown session {}", self.id, packet.session_id);
+                    },
                 }
             },
             PacketType::Nack(nack) => {
@@ -390,11 +514,7 @@ impl MyClient {
     fn start_flood_discovery(&mut self) {
         info!("🦋 🦋 🦋  Client {} starting flood discovery", self.id);
         //1.generating unique flood_id
-        let new_flood_id = {
-            let mut id_counter = SESSION_COUNTER.lock().unwrap();
-            *id_counter = id_counter.saturating_add(1);
-            *id_counter
-        };
+        let new_flood_id = self.next_session_id();
         //2.crating flood_request
         let flood_request = FloodRequest {
             flood_id: new_flood_id,
@@ -433,7 +553,12 @@ impl MyClient {
             error!("Client {} error: Received packet with empty hops in routing header for session {}", self.id, session_id);
             0
         });
-        let key = session_id;
+        let key = (session_id, src_id);
+        let buffered_total = self.received_messages.get(&key).map(|state| state.total_fragments);
+        if let Err(e) = protocol::check_fragment(fragment, buffered_total) {
+            warn!("Client {} dropped fragment {} for session {}: {}", self.id, fragment.fragment_index, session_id, e);
+            return;
+        }
         let fragment_len = fragment.length as usize;
         let offset = (fragment.fragment_index * 128) as usize;
         let state = self.received_messages.entry(key).or_insert_with(|| {
@@ -472,8 +597,10 @@ impl MyClient {
                 } else if total_fragments_local == 1 {
                     full_message_data.truncate(fragment.length as usize);
                 }
-                let message_string = String::from_utf8_lossy(&full_message_data).to_string();
-                self.process_received_high_level_message(message_string, src_id, session_id);
+                match ChatProtocol::decode(&full_message_data) {
+                    Ok(message) => self.process_received_high_level_message(message, src_id, session_id),
+                    Err(e) => warn!("Client {} received unrecognized high-level message for session {}: {}", self.id, session_id, e),
+                }
             }
         } else {
             warn!("Received fragment {} for session {} with offset {} and length {} which exceeds excepted message size {}. Ignoring fragment", fragment.fragment_index, session_id, offset, fragment_len, state.data.len());
@@ -481,160 +608,156 @@ impl MyClient {
     }
 
     //function in which the correctly reassembled high level messages are elaborated
-    fn process_received_high_level_message(&mut self, message_string: String, _source_id: NodeId, session_id: u64) {
-        //println!("Client {} processing high-level message for session {} from source {}: {}", self.id, session_id, source_id, message_string);
-        let tokens: Vec<&str> = message_string.trim().splitn(3, "::").collect();
-
-        if tokens.is_empty() {
-            warn!("Client {} received empty high-level message for session {}", self.id, session_id);
-            return;
+    fn process_received_high_level_message(&mut self, message: ChatProtocol, source_id: NodeId, session_id: u64) {
+        //println!("Client {} processing high-level message for session {} from source {}: {}", self.id, session_id, source_id, message);
+        if let Some(event) = GuiEvent::from_message(source_id, &message) {
+            push_gui_event(&self.gui_events, self.id, event);
         }
-
-        match tokens.as_slice() {
-            ["[MessageFrom]", sender_id_str, content_str] => {
-                // format: [MessageFrom]::sender_id::message_content
-                if let Ok(sender_id) = sender_id_str.parse::<NodeId>() {
-                    let content = *content_str;
-                    info!("Client {} received chat message from client {}", self.id, sender_id);
-                } else {
-                    warn!("Client {} received MessageFrom with invalid sender_id: {}", self.id, sender_id_str);
-                }
+        match message {
+            ChatProtocol::MessageFrom { sender_id, message_id, message: _content } => {
+                info!("Client {} received chat message {} from client {}", self.id, message_id, sender_id);
+                self.send_to_server(ChatProtocol::Receipt { peer_id: sender_id, message_id, kind: ReceiptKind::Delivered });
+            },
+            ChatProtocol::Receipt { peer_id, message_id, kind } => {
+                info!("Client {} message {} was {} by client {}", self.id, message_id, kind.name(), peer_id);
+            },
+            ChatProtocol::DeliveryStatus { target_id, status } => {
+                info!("Client {} message to client {} was {}", self.id, target_id, status.name());
             },
-            ["[ClientListResponse]", list_str] => {
-                //format: [ClientListResponse]::[client1_id, client2_id, ...]
-                let client_ids: Vec<NodeId> = list_str.trim_start_matches('[').trim_end_matches(']').split(',').filter(|s| !s.trim().is_empty()).filter_map(|s| s.trim().parse::<NodeId>().ok()).collect();
+            ChatProtocol::ClientListResponse { clients: client_ids } => {
                 info!("Client {} received CLIENT LIST: {:?}", self.id, client_ids);
             },
-            ["[ChatStart]", status_str] => {
-                //format: [ChatStart]::bool
-                match status_str.to_lowercase().as_str() {
-                    "true" => {
-                        info!("Client {} CHAT REQUEST accepted. Chat started", self.id);
-                    },
-                    "false" => {
-                        info!("Client {} CHAT REQUEST denied", self.id);
-                    },
-                    _ => warn!("Client {} received ChatStart with invalid boolean value: {}", self.id, status_str),
+            ChatProtocol::ServerTypeResponse { services } => {
+                info!("Client {} learned that server {} offers {:?}", self.id, source_id, services);
+                self.server_services.insert(source_id, services);
+            },
+            ChatProtocol::TextListResponse { files } => {
+                info!("Client {} received TEXT LIST from server {}: {:?}", self.id, source_id, files);
+                for name in files {
+                    self.text_files.insert(name, source_id);
                 }
             },
-            ["[ChatFinish]"] => {
-                //format: [ChatFinish]
-                info!("Client {} CHAT TERMINATED", self.id);
+            ChatProtocol::TextUploadAck { name } => {
+                info!("Client {} published text file '{}' on server {}", self.id, name, source_id);
+                self.text_files.insert(name, source_id);
+            },
+            ChatProtocol::TextResponse { name, content } => {
+                // like a browser: once the page is here, fetch what it links
+                let media = protocol::referenced_media(&content);
+                info!("Client {} received text file '{}' linking media {:?}", self.id, name, media);
+                if !media.is_empty() {
+                    match self.servers_offering(ServerService::Media).first().copied() {
+                        Some(media_server) => {
+                            for media_name in media {
+                                self.start_download(media_server, &media_name);
+                            }
+                        }
+                        None => self.no_server_offering(ServerService::Media),
+                    }
+                }
+            },
+            ChatProtocol::RoomMessageFrom { room, sender_id, message_id, .. } => {
+                info!("Client {} received message {} from client {} in room '{}'", self.id, message_id, sender_id, room);
             },
-            ["[ChatRequest]", peer_id_str] => {
-                //format: [ChatRequest]::{peer_id}
-                if let Ok(requester_id) = peer_id_str.parse::<NodeId>() {
-                    info!("Client {} received incoming CHAT REQUEST from client {}", self.id, requester_id);
+            ChatProtocol::RoomUpdate { room, members } => {
+                info!("Client {} room '{}' now has members {:?}", self.id, room, members);
+            },
+            ChatProtocol::RoomListResponse { rooms } => {
+                info!("Client {} received ROOM LIST: {:?}", self.id, rooms);
+            },
+            ChatProtocol::ChatStart { success } => {
+                if success {
+                    info!("Client {} CHAT REQUEST accepted. Chat started", self.id);
                 } else {
-                    warn!("Client {} received ChatRequest with invalid requester_id: {}", self.id, peer_id_str);
+                    info!("Client {} CHAT REQUEST denied", self.id);
                 }
             },
-            ["[HistoryResponse]", content_str] => {
-                //format: [HistoryResponse]::message_content
-                let history_content = *content_str;
+            ChatProtocol::ChatFinish { .. } => {
+                info!("Client {} CHAT TERMINATED", self.id);
+            },
+            ChatProtocol::ChatRequest { peer_id: requester_id } => {
+                info!("Client {} received incoming CHAT REQUEST from client {}", self.id, requester_id);
+            },
+            ChatProtocol::HistoryResponse { history: history_content } => {
                 info!("Client {} received CHAT HISTORY: {}", self.id, history_content);
             },
-            ["[MediaUploadAck]", media] => {
-                //format: [MediaUploadAck]::media_name
-                if tokens.len() >= 2 {
-                    let media_name = media;
-                    info!("Client {} received MEDIA UPLOAD ACK for media '{}'.", self.id, media_name);
-                } else {
-                    warn!("Client {} received invalid MEDIA UPLOAD ACK format: {}.", self.id, message_string);
+            ChatProtocol::MediaUploadAck { name: media_name } => {
+                info!("Client {} received MEDIA UPLOAD ACK for media '{}'.", self.id, media_name);
+                self.uploads.remove(&media_name);
+            },
+            ChatProtocol::MediaNotFound => {
+                info!("Client {} received MEDIA DOWNLOAD RESPONSE: Media not found.", self.id);
+                // the reply does not name the media: drop what this server could not have started sending
+                self.downloads.retain(|_, download| download.server_id != source_id || download.verified() > 0);
+            },
+            ChatProtocol::MediaChunk { name, offset, total_size, chunk_checksum, file_checksum, data } => {
+                let Some(download) = self.downloads.get_mut(&name) else {
+                    warn!("Client {} received a chunk of '{}' it is not downloading", self.id, name);
+                    return;
+                };
+                let server_id = download.server_id;
+                match download.receive(offset, total_size, data, chunk_checksum, file_checksum) {
+                    DownloadStep::Request(request) => {
+                        push_gui_event(&self.gui_events, self.id, GuiEvent::MediaProgress { name, done: download.verified(), total: total_size, upload: false });
+                        self.send_to(server_id, request);
+                    },
+                    DownloadStep::Ignored => info!("Client {} ignored chunk of '{}' at offset {}", self.id, name, offset),
+                    DownloadStep::Complete(media_data) => {
+                        self.downloads.remove(&name);
+                        // handled from here on like a media sent in one piece
+                        self.process_received_high_level_message(ChatProtocol::MediaDownloadResponse { name, data: media_data }, source_id, session_id);
+                    },
+                    DownloadStep::Corrupted => {
+                        warn!("Client {} downloaded '{}' but its checksum does not match", self.id, name);
+                        self.downloads.remove(&name);
+                        push_gui_event(&self.gui_events, self.id, GuiEvent::Error { reason: format!("media_checksum_mismatch::{}", name) });
+                    },
                 }
             },
-            ["[MediaDownloadResponse]", media, base64] => {
-                if tokens.len() >= 3 {
-                    let media_name = media;
-                    let base64_data = base64;
-
-                    if media_name.to_string() == "ERROR" && base64_data.to_string() == "NotFound" {
-                        info!("Client {} received MEDIA DOWNLOAD RESPONSE: Media not found.", self.id)
-                    } else {
-                        info!("Client {} received MEDIA DOWNLOAD RESPONSE for media '{}'.", self.id, media_name);
-
-                        match STANDARD.decode(&base64_data) {
-                            Ok(image_bytes) => {
-                                match load_from_memory(&image_bytes) {
-                                    Ok(img) => {
-                                        info!("Client {} successfully loaded image data for media '{}'.", self.id, media_name);
-
-                                        let extension = match img.color() {
-                                            image::ColorType::Rgb8
-                                            | image::ColorType::Rgba8
-                                            | image::ColorType::L8
-                                            | image::ColorType::La8
-                                            | image::ColorType::Rgb16
-                                            | image::ColorType::Rgba16
-                                            | image::ColorType::L16
-                                            | image::ColorType::La16 => "png",
-                                            _ => "bin",
-                                        };
-
-                                        // Create a temp file with a proper extension
-                                        let mut temp_file_path = temp_dir();
-                                        let filename = format!("media_preview_{}.{}", self.id, extension);
-                                        temp_file_path.push(filename);
-
-                                        // Save temporarily
-                                        if let Err(e) = img.save(&temp_file_path) {
-                                            warn!("Client {} failed to temporarily save image: {}", self.id, e);
-                                            return;
-                                        }
-
-                                        // Open with system viewer
-                                        let open_command = if cfg!(target_os = "windows") {
-                                            "cmd"
-                                        } else if cfg!(target_os = "macos") {
-                                            "open"
-                                        } else {
-                                            "xdg-open"
-                                        };
-
-                                        let path_str = temp_file_path.to_string_lossy().into_owned();
-                                        let args = if cfg!(target_os = "windows") {
-                                            vec!["/C", "start", "", &path_str]
-                                        } else {
-                                            vec![path_str.as_str()]
-                                        };
-
-                                        match Command::new(open_command).args(args.clone()).spawn() {
-                                            Ok(_) => info!("🖼️ 🖼️ 🖼️ Opened media '{}' for client {}", media_name, self.id),
-                                            Err(e) => error!("Failed to open image: {}", e),
-                                        }
-                                    },
-                                    Err(e) => error!("Client {} failed to load image from memory: {}", self.id, e),
-                                }
-                            },
-                            Err(e) => error!("Client {} failed to decode base64 data: {}", self.id, e),
-                        }
+            ChatProtocol::MediaUploadChunkAck { name, next_offset } => {
+                if let Some(upload) = self.uploads.get_mut(&name) {
+                    let server_id = upload.server_id;
+                    if let Some(chunk) = upload.acknowledge(next_offset) {
+                        push_gui_event(&self.gui_events, self.id, GuiEvent::MediaProgress { name, done: next_offset, total: upload.total_size(), upload: true });
+                        self.send_to(server_id, chunk);
                     }
                 }
             },
-            ["[MediaListResponse]", list_str] => {
-                info!("Client {} received MEDIA LIST: {}.", self.id, list_str);
-                let media_list : Vec<String> = list_str.split(',').filter(|s| !s.trim().is_empty()).map(|s| s.trim().to_string()).collect();
+            ChatProtocol::MediaDownloadResponse { name: media_name, data: media_data } => {
+                info!("Client {} received MEDIA DOWNLOAD RESPONSE for media '{}'.", self.id, media_name);
+                self.save_download(&media_name, &media_data);
+            },
+            ChatProtocol::MediaListResponse { media: media_list } => {
+                let names: Vec<&str> = media_list.iter().map(|media| media.name.as_str()).collect();
+                info!("Client {} received MEDIA LIST: {}.", self.id, names.join(","));
                 info!("Available media files: {:?}", media_list);
             },
 
-            ["[LoginAck]", session_id] => {
-                //format: [LoginAck]::session_id
-                if let Ok(parsed_session_id) = session_id.parse::<u64>() {
-                    info!("🔑 🔑 🔑  Client {} received LOGIN ACK for session {}. Successfully logged in!", self.id, parsed_session_id);
-                } else {
-                    warn!("Client {} received LOGIN ACK with invalid session ID: {}", self.id, session_id);
-                }
+            ChatProtocol::LoginAck { session_id: parsed_session_id, version, capabilities } => {
+                info!("🔑 🔑 🔑  Client {} received LOGIN ACK for session {}. Successfully logged in!", self.id, parsed_session_id);
+                self.log(SimEventKind::Login, format!("Client {} logged in with protocol v{} and capabilities {:?}", self.id, version, capabilities));
+                self.server_capabilities = Some(capabilities);
             },
-            ["[MediaBroadcastAck]", media_name, "Broadcasted"] => {
+            ChatProtocol::MediaBroadcastAck { name: media_name } => {
                 info!("Client {} received MEDIA BROADCAST ACK for media '{}' (broadcasted successfully)", self.id, media_name);
-                self.log(format!("Client {} received MEDIA BROADCAST ACK for media '{}' (broadcasted successfully)", self.id, media_name));
+                self.log(SimEventKind::Media, format!("Client {} received MEDIA BROADCAST ACK for media '{}' (broadcasted successfully)", self.id, media_name));
             },
-
-            [type_tag, ..] => {
-                warn!("Client {} received unrecognized high-level message type: {}", self.id, type_tag);
+            ChatProtocol::MediaVisibilityAck { name: media_name, visibility } => {
+                info!("🔐 Client {} made '{}' visible to {}", self.id, media_name, visibility);
+            },
+            ChatProtocol::MediaDeleteAck { name: media_name } => {
+                info!("🗑 Client {} deleted '{}' from the server", self.id, media_name);
+                self.log(SimEventKind::Media, format!("Client {} deleted the '{media_name}' from the server", self.id));
             },
-            [] => {
-                warn!("Client {} received empty high-level message for session {}", self.id, session_id);
+            ChatProtocol::Error { reason } => {
+                warn!("Client {} received an error from the server for session {}: {}", self.id, session_id, reason);
+                if let Some(name) = transfer::refused_upload(&reason) {
+                    self.uploads.remove(name);
+                }
+            },
+
+            other => {
+                warn!("Client {} received unrecognized high-level message type: {}", self.id, other.tag());
             },
         }
     }
@@ -693,23 +816,20 @@ impl MyClient {
                     }
                 }
 
-                if let Some(info) = self.sent_messages.get_mut(&packet.session_id) {
+                if let Some(info) = self.sent_messages.for_nack(packet.session_id, &packet.routing_header.hops) {
                     info.route_needs_recalculation = true;
-                    if let Some(&dest_id) = info.original_routing_header.hops.last() {
-                        self.route_cache.remove(&dest_id);
-                    }
+                    self.route_cache.remove(&info.destination);
                 }
             }
 
             NackType::DestinationIsDrone => {
                 info!("Client {} received DestinationIsDrone NACK for session {}", self.id, packet.session_id);
-                if let Some(info) = self.sent_messages.get_mut(&packet.session_id) {
+                if let Some(info) = self.sent_messages.for_nack(packet.session_id, &packet.routing_header.hops) {
                     info.route_needs_recalculation = true;
                     info!("Client {} marked route for session {} for recalculation due to DestinationIsDrone", self.id, packet.session_id);
-                    if let Some(&dest_id) = info.original_routing_header.hops.last() {
-                        self.route_cache.remove(&dest_id);
-                        info!("Client {} invaliding cached route for {} due to DestinationIsDrone NACK", self.id, dest_id);
-                    }
+                    let dest_id = info.destination;
+                    self.route_cache.remove(&dest_id);
+                    info!("Client {} invaliding cached route for {} due to DestinationIsDrone NACK", self.id, dest_id);
                 } else {
                     warn!("Client {} received DestinationIsDrone NACK for unknown session {}", self.id, packet.session_id);
                 }
@@ -724,17 +844,16 @@ impl MyClient {
                     self.increment_drop(from, to);
                     self.increment_drop(to, from);
                 }
-                if let Some(info) = self.sent_messages.get_mut(&packet.session_id) {
+                if let Some(info) = self.sent_messages.for_nack(packet.session_id, &packet.routing_header.hops) {
                     info.route_needs_recalculation = true;
-                    if let Some(&dest_id) = info.original_routing_header.hops.last() {
-                        self.route_cache.remove(&dest_id);
-                    }
+                    self.route_cache.remove(&info.destination);
                 }
             }
             NackType::Dropped => {
                 info!("Client {} received Dropped NACK for session {}, fragment {}", self.id, packet.session_id, nack.fragment_index);
-                let resend_info = self.sent_messages.get(&packet.session_id).and_then(|info| {
-                    info.fragments.iter().find(|f| f.fragment_index == nack.fragment_index).map(|frag| (frag.clone(), info.original_routing_header.clone()))
+                let resend_info = self.sent_messages.for_nack(packet.session_id, &packet.routing_header.hops).and_then(|info| {
+                    info.mark_resent(nack.fragment_index);
+                    info.fragment(nack.fragment_index).map(|frag| (frag.clone(), info.routing_header.clone()))
                 });
                 if let Some((fragment, routing)) = resend_info {
                     let route = &packet.routing_header.hops;
@@ -837,12 +956,11 @@ impl MyClient {
     fn process_gui_command(&mut self, dest_id: NodeId, command_string: String) {
         info!("Client {} processing GUI command '{}' for {}", self.id, command_string, dest_id);
         let tokens: Vec<&str> = command_string.trim().split("::").collect();
-        let command_type_str = tokens.get(0).unwrap_or(&"");
 
         if tokens.len() >= 2 && tokens[0] == "[FloodRequired]" {
             let action = tokens[1..].join("::");
             info!("Client {} received FLOOD REQUIRED command due to action: {}", self.id, action);
-            self.log(format!("Client {} received a call to flooding the network", self.id));
+            self.log(SimEventKind::Topology, format!("Client {} received a call to flooding the network", self.id));
 
             if let Some(parts) = action.strip_prefix("AddSender::") {
                 let nodes: Vec<&str> = parts.splitn(2, "::").collect();
@@ -964,140 +1082,211 @@ impl MyClient {
             return;
         }
 
-        let high_level_message_info: Option<String> = match tokens.as_slice() {
-            ["[Login]", server_id] => {
-                if let Ok(parsed_server_id) = server_id.parse::<NodeId>() {
-                    info!("Client {} processing LOGIN command for server {}", self.id, parsed_server_id);
-                    self.connected_server_id = Some(parsed_server_id);
-                    self.log(format!("Login command from client: {}", self.id));
-                    Some(format!("[Login]::{}",parsed_server_id))
-                } else {
-                    warn!("Client {} received LOGIN command with invalid server {}", self.id, server_id);
-                    None
-                }
+        let command = match command_string.parse::<ChatProtocol>() {
+            Ok(command) => command,
+            Err(e) => {
+                warn!("Client {} received unrecognized GUI command: {} ({})", self.id, command_string, e);
+                return;
+            }
+        };
+        if let (Some(required), Some(agreed)) = (command.required_capability(), &self.server_capabilities) {
+            if !agreed.contains(&required) {
+                warn!("Client {} dropped {}: server did not agree on '{}' at login", self.id, command.tag(), required.name());
+                self.log(SimEventKind::Error, format!("Client {} can't send {}: '{}' not supported by the server", self.id, command.tag(), required.name()));
+                return;
+            }
+        }
+        let high_level_message_info: Option<ChatProtocol> = match &command {
+            ChatProtocol::Login { server_id: parsed_server_id, .. } => {
+                info!("Client {} processing LOGIN command for server {}", self.id, parsed_server_id);
+                self.connected_server_id = Some(*parsed_server_id);
+                self.server_capabilities = None;
+                self.log(SimEventKind::Login, format!("Login command from client: {}", self.id));
+                Some(command.clone())
             },
-            ["[Logout]"] => {
+            ChatProtocol::Logout => {
                 if let Some(mem_server_id) = self.connected_server_id {
                     info!("Client {} processing LOGOUT command via server {}", self.id, mem_server_id);
-                    self.log(format!("Logout command from client: {}", self.id));
-                    Some("[Logout]".to_string())
+                    self.log(SimEventKind::Login, format!("Logout command from client: {}", self.id));
+                    Some(command.clone())
                 } else {
                     info!("Client {} received LOGOUT command while not logged in. Ignoring", self.id);
                     None
                 }
             },
-            ["[ClientListRequest]"] => {
+            ChatProtocol::ClientListRequest => {
                 if let Some(mem_server_id) = self.connected_server_id {
                     info!("Client {} processing CLIENT LIST REQUEST command via server {}", self.id, mem_server_id);
-                    self.log(format!("ClientListRequest command from client: {}", self.id));
-                    Some("[ClientListRequest]".to_string())
+                    self.log(SimEventKind::Login, format!("ClientListRequest command from client: {}", self.id));
+                    Some(command.clone())
                 } else {
                     info!("Client {} received CLIENT LIST REQUEST command while not logged in. Ignoring", self.id);
                     None
                 }
             },
-            ["[MessageTo]", target_id, message_content] => {
-                if let Ok(target_client_id) = target_id.parse::<NodeId>() {
-                    if let Some(mem_server_id) = self.connected_server_id {
-                        info!("Client {} processing MESSAGE TO command for client {} via server {} with content: {}", self.id, target_client_id, mem_server_id, message_content);
-                        self.log(format!("Client {} sent a message to::{target_client_id} : {message_content}", self.id));
-                        Some(format!("[MessageTo]::{target_client_id}::{message_content}"))
-                    } else {
-                        info!("Client {} received MESSAGE TO command while not logged in. Ignoring", self.id);
-                        None
-                    }
+            ChatProtocol::MessageTo { target_id: target_client_id, message: message_content, .. } => {
+                if let Some(mem_server_id) = self.connected_server_id {
+                    info!("Client {} processing MESSAGE TO command for client {} via server {} with content: {}", self.id, target_client_id, mem_server_id, message_content);
+                    self.log(SimEventKind::Chat, format!("Client {} sent a message to::{target_client_id} : {message_content}", self.id));
+                    Some(command.clone())
                 } else {
-                    warn!("Client {} received MESSAGE TO command with invalid target_id: {}", self.id, target_id);
+                    info!("Client {} received MESSAGE TO command while not logged in. Ignoring", self.id);
                     None
                 }
             },
-            ["[ChatRequest]", peer_id] => {
-                if let Ok(_peer_id) = peer_id.parse::<NodeId>() {
-                    if let Some(mem_server_id) = self.connected_server_id {
-                        info!("Client {} processing CHAT REQUEST command for peer {} via server {}", self.id, _peer_id, mem_server_id);
-                        self.log(format!("Chat Request::{_peer_id} command from client: {}", self.id));
-                        Some(format!("[ChatRequest]::{_peer_id}"))
-                    } else {
-                        info!("Client {} received CHAT REQUEST command while not logged in. Ignoring", self.id);
-                        None
-                    }
+            ChatProtocol::Receipt { peer_id, message_id, kind } => {
+                if let Some(mem_server_id) = self.connected_server_id {
+                    info!("Client {} sending {} receipt for message {} of client {} via server {}", self.id, kind.name(), message_id, peer_id, mem_server_id);
+                    Some(command.clone())
                 } else {
-                    warn!("Client {} received CHAT REQUEST command with invalid peer_id: {}", self.id, peer_id);
+                    info!("Client {} received RECEIPT command while not logged in. Ignoring", self.id);
                     None
                 }
             },
-            ["[ChatFinish]", peer_id] => {
-                if let Ok(_peer_id) = peer_id.parse::<NodeId>() {
-                    if let Some(mem_server_id) = self.connected_server_id {
-                        info!("Client {} processing CHAT FINISH command for peer {} via server {}", self.id, _peer_id, mem_server_id);
-                        self.log(format!("ChatFinish::{_peer_id} command from client: {}", self.id));
-                        Some(format!("[ChatFinish]::{_peer_id}"))
-                    } else {
-                        info!("Client {} received CHAT FINISH command while not logged in. Ignoring", self.id);
-                        None
-                    }
+            ChatProtocol::RoomCreate { room } | ChatProtocol::RoomJoin { room } | ChatProtocol::RoomLeave { room }
+            | ChatProtocol::RoomMessageTo { room, .. } | ChatProtocol::RoomHistoryRequest { room } => {
+                if let Some(mem_server_id) = self.connected_server_id {
+                    info!("Client {} processing {} command for room '{}' via server {}", self.id, command.tag(), room, mem_server_id);
+                    self.log(SimEventKind::Room, format!("Client {} sent {} for room '{room}'", self.id, command.tag()));
+                    Some(command.clone())
+                } else {
+                    info!("Client {} received {} command while not logged in. Ignoring", self.id, command.tag());
+                    None
+                }
+            },
+            ChatProtocol::RoomListRequest => {
+                if let Some(mem_server_id) = self.connected_server_id {
+                    info!("Client {} processing ROOM LIST REQUEST command via server {}", self.id, mem_server_id);
+                    Some(command.clone())
+                } else {
+                    info!("Client {} received ROOM LIST REQUEST command while not logged in. Ignoring", self.id);
+                    None
+                }
+            },
+            ChatProtocol::ChatRequest { peer_id: _peer_id } => {
+                if let Some(mem_server_id) = self.connected_server_id {
+                    info!("Client {} processing CHAT REQUEST command for peer {} via server {}", self.id, _peer_id, mem_server_id);
+                    self.log(SimEventKind::Chat, format!("Chat Request::{_peer_id} command from client: {}", self.id));
+                    Some(command.clone())
+                } else {
+                    info!("Client {} received CHAT REQUEST command while not logged in. Ignoring", self.id);
+                    None
+                }
+            },
+            ChatProtocol::ChatFinish { peer_id: _peer_id } => {
+                if let Some(mem_server_id) = self.connected_server_id {
+                    info!("Client {} processing CHAT FINISH command for peer {} via server {}", self.id, _peer_id, mem_server_id);
+                    self.log(SimEventKind::Chat, format!("ChatFinish::{_peer_id} command from client: {}", self.id));
+                    Some(command.clone())
                 } else {
-                    warn!("Client {} received CHAT FINISH command with invalid peer_id: {}", self.id, peer_id);
+                    info!("Client {} received CHAT FINISH command while not logged in. Ignoring", self.id);
                     None
                 }
             },
-            ["[MediaUpload]", media_name, base64_data] => {
+            ChatProtocol::MediaUpload { name: media_name, data: media_data } => {
                 if let Some(mem_server_id) = self.connected_server_id {
                     info!("Client {} processing MEDIA UPLOAD command for media '{}' via server {}.", self.id, media_name, mem_server_id);
-                    self.log(format!("Client {} uploaded the '{media_name}' to the server", self.id));
-                    Some(format!("[MediaUpload]::{media_name}::{base64_data}"))
+                    self.log(SimEventKind::Media, format!("Client {} uploaded the '{media_name}' to the server", self.id));
+                    // sent in chunks, so a failed route only costs the chunk in flight
+                    let mut upload = Upload::new(mem_server_id, media_name, MediaEnvelope::wrap(media_name, media_data.clone()));
+                    let chunk = upload.next_chunk();
+                    self.uploads.insert(media_name.clone(), upload);
+                    self.send_to(mem_server_id, chunk);
                 } else {
                     info!("Client {} received MEDIA UPLOAD command while not logged in. Ignoring.", self.id);
-                    None
                 }
+                None
             },
-            ["[MediaDownloadRequest]", media_name] => {
+            ChatProtocol::MediaDownloadRequest { name: media_name } => {
                 if let Some(mem_server_id) = self.connected_server_id {
                     info!("Client {} processing MEDIA DOWNLOAD REQUEST command for media '{}' via server {}.", self.id, media_name, mem_server_id);
-                    self.log(format!("Client {} downloaded the '{media_name}'", self.id));
-                    Some(format!("[MediaDownloadRequest]::{media_name}"))
+                    self.log(SimEventKind::Media, format!("Client {} downloaded the '{media_name}'", self.id));
+                    // asking again for a media still downloading resumes it
+                    self.start_download(mem_server_id, media_name);
+                    None
                 } else {
                     info!("Client {} received MEDIA DOWNLOAD REQUEST command while not logged in. Ignoring.", self.id);
                     None
                 }
             },
 
-            ["[HistoryRequest]", client_id, target_id] => {
-                if let (Some(_client_id), Some(_target_id)) = (client_id.parse::<NodeId>().ok(), target_id.parse::<NodeId>().ok()) {
-                    if let Some(mem_server_id) = self.connected_server_id {
-                        info!("Client {} processing HISTORY REQUEST command for history between {} and {} via server {}", self.id, _client_id, _target_id, mem_server_id);
-                        self.log(format!(" Client {} requested chat history between ::{_client_id}::{_target_id}", self.id));
-                        Some(format!("[HistoryRequest]::{_client_id}::{_target_id}"))
-                    } else {
-                        info!("Client {} received HISTORY REQUEST command while not logged in. Ignoring", self.id);
-                        None
-                    }
+            ChatProtocol::HistoryRequest { client_id: _client_id, target_id: _target_id } => {
+                if let Some(mem_server_id) = self.connected_server_id {
+                    info!("Client {} processing HISTORY REQUEST command for history between {} and {} via server {}", self.id, _client_id, _target_id, mem_server_id);
+                    self.log(SimEventKind::Chat, format!(" Client {} requested chat history between ::{_client_id}::{_target_id}", self.id));
+                    Some(command.clone())
                 } else {
-                    warn!("Client {} received HISTORY REQUEST command with invalid client/target id: {}", self.id, command_string);
+                    info!("Client {} received HISTORY REQUEST command while not logged in. Ignoring", self.id);
                     None
                 }
             },
-            ["[MediaListRequest]"] => {
+            ChatProtocol::MediaListRequest => {
                 if let Some(mem_server_id) = self.connected_server_id {
                     info!("Client {} processing MEDIA LIST REQUEST command via server {}.", self.id, mem_server_id);
-                    self.log(format!("MediaListRequest command from client: {}", self.id));
-                    Some("[MediaListRequest]".to_string())
+                    self.log(SimEventKind::Media, format!("MediaListRequest command from client: {}", self.id));
+                    Some(command.clone())
                 } else {
                     info!("Client {} received MEDIA LIST REQUEST command while not logged in. Ignoring.", self.id);
                     None
                 }
             },
-            ["[MediaBroadcast]", media_name, base64_data] => {
+            ChatProtocol::MediaSetVisibility { name: media_name, .. } | ChatProtocol::MediaDelete { name: media_name } => {
+                if let Some(mem_server_id) = self.connected_server_id {
+                    info!("Client {} processing {} command for media '{}' via server {}.", self.id, command.tag(), media_name, mem_server_id);
+                    Some(command.clone())
+                } else {
+                    info!("Client {} received {} command while not logged in. Ignoring.", self.id, command.tag());
+                    None
+                }
+            },
+            ChatProtocol::MediaBroadcast { name: media_name, data: media_data } => {
                 if let Some(mem_server_id) = self.connected_server_id {
                     info!("Client {} processing MEDIA BROADCAST command for media '{}' via server {}.", self.id, media_name, mem_server_id);
-                    self.log(format!("Client {} did a Media Broadcast of '{media_name}'", self.id));
-                    Some(format!("[MediaBroadcast]::{media_name}::{base64_data}"))
+                    self.log(SimEventKind::Media, format!("Client {} did a Media Broadcast of '{media_name}'", self.id));
+                    Some(ChatProtocol::MediaBroadcast { name: media_name.clone(), data: MediaEnvelope::wrap(media_name, media_data.clone()) })
                 } else {
                     info!("Client {} received MEDIA BROADCAST command while not logged in. Ignoring.", self.id);
                     None
                 }
             },
 
+            ChatProtocol::TextListRequest => {
+                let servers = self.servers_offering(ServerService::Text);
+                if servers.is_empty() {
+                    self.no_server_offering(ServerService::Text);
+                }
+                for server_id in servers {
+                    info!("Client {} asking text server {} for its files", self.id, server_id);
+                    self.send_to(server_id, ChatProtocol::TextListRequest);
+                }
+                None
+            },
+            ChatProtocol::TextRequest { name } | ChatProtocol::TextUpload { name, .. } => {
+                // fetched from the server that listed it, published on the first text server
+                let server = self.text_files.get(name).copied().or_else(|| self.servers_offering(ServerService::Text).first().copied());
+                match server {
+                    Some(server_id) => {
+                        info!("Client {} sending {} for '{}' to text server {}", self.id, command.tag(), name, server_id);
+                        self.send_to(server_id, command.clone());
+                    }
+                    None => self.no_server_offering(ServerService::Text),
+                }
+                None
+            },
+            ChatProtocol::ServerTypeRequest => {
+                // asked to every server in the topology, no login needed
+                let servers = self.known_servers();
+                if servers.is_empty() {
+                    info!("Client {} knows no server yet. Flooding before asking for server types", self.id);
+                    self.start_flood_discovery();
+                }
+                for server_id in servers {
+                    info!("Client {} asking server {} for its type", self.id, server_id);
+                    self.send_to(server_id, ChatProtocol::ServerTypeRequest);
+                }
+                None
+            },
+
             _ => {
                 warn!("Client {} received unrecognized GUI command: {}", self.id, command_string);
                 None
@@ -1105,98 +1294,137 @@ impl MyClient {
         };
         //- - - - route evaluation towards destination server - - - -
         if let Some(high_level_message_content) = high_level_message_info {
-            let target_dest_id_for_sending = self.connected_server_id;
-            if let Some(id_to_send_to) = target_dest_id_for_sending {
-                let route_option = self.best_path(self.id, id_to_send_to);
-                let route = match route_option {
-                    Some(path) => {
-                        info!("Client {} computed route to server {}: {:?}",
-                                 self.id, id_to_send_to, path);
-                        self.route_cache.insert(id_to_send_to, path.clone());
-                        path
-                    },
-                    None => {
-                        warn!(
-                            "Client {} could not compute route to server {}. Starting flood discovery",
-                            self.id, id_to_send_to
-                        );
-                        // ^^^ Changed to use the actual target server ID for the log
-
-                        // Start flood discovery (only if one isn’t already active)
-                        let active_flood = self.active_flood_discoveries.values().any(|state| {
-                            state.initiator_id == self.id && state.start_time.elapsed() < Duration::from_secs(5)
-                        });
-                        if !active_flood {
-                            self.start_flood_discovery();
-                        }
+            self.send_to_server(high_level_message_content);
+        }
+    }
 
-                        // Queue the message to send after the path is discovered
-                        self.pending_messages_after_flood.push((id_to_send_to, command_string));
-                        // ^^^ Store the server’s NodeId (destination) instead of self.id
-                        return;
-                    }
-                };
-                let routing_header = SourceRoutingHeader {
-                    hops: route.clone(),
-                    hop_index: 1,
-                };
-                info!("🚕🚕🚕🚕🚕 BEST PATH IS : {:?}",routing_header.hops);
-                let message_data_bytes = high_level_message_content.into_bytes();
-                const FRAGMENT_SIZE: usize = 128;
-                let total_fragments = (message_data_bytes.len() + FRAGMENT_SIZE - 1) / FRAGMENT_SIZE;
-                let mut fragments = Vec::new();
-                let session_id = {
-                    let mut id_counter = SESSION_COUNTER.lock().unwrap();
-                    *id_counter = id_counter.saturating_add(1);
-                    *id_counter
-                };
-                for i in 0..total_fragments {
-                    let start = i * FRAGMENT_SIZE;
-                    let end = (start + FRAGMENT_SIZE).min(message_data_bytes.len());
-                    let fragment_data_slice = &message_data_bytes[start..end];
-                    let mut data: [u8; FRAGMENT_SIZE] = [0; FRAGMENT_SIZE];
-                    data[..fragment_data_slice.len()].copy_from_slice(fragment_data_slice);
-                    let len = fragment_data_slice.len();
-                    fragments.push(Fragment {
-                        fragment_index: i as u64,
-                        total_n_fragments: total_fragments as u64,
-                        length: len as u8,
-                        data,
-                    });
-                }
-                //println!("Client {} fragmented message into {} fragments for session {}", self.id, total_fragments, session_id);
-                self.sent_messages.insert(session_id, SentMessageInfo {
-                    fragments: fragments.clone(),
-                    original_routing_header: routing_header.clone(),
-                    received_ack_indices: HashSet::new(),
-                    route_needs_recalculation: false,
+    /// Servers found by the floods so far, sorted.
+    fn known_servers(&self) -> Vec<NodeId> {
+        let mut servers: Vec<NodeId> = self
+            .network_graph
+            .node_weights()
+            .filter(|info| info.node_type == NodeType::Server)
+            .map(|info| info.id)
+            .collect();
+        servers.sort_unstable();
+        servers.dedup();
+        servers
+    }
+
+    /// Servers that answered a ServerTypeRequest with `service`, sorted.
+    fn servers_offering(&self, service: ServerService) -> Vec<NodeId> {
+        let mut servers: Vec<NodeId> = self
+            .server_services
+            .iter()
+            .filter(|(_, services)| services.contains(&service))
+            .map(|(&id, _)| id)
+            .collect();
+        servers.sort_unstable();
+        servers
+    }
+
+    /// Tells the GUI and asks every known server for its type again, so a retry can find one.
+    fn no_server_offering(&mut self, service: ServerService) {
+        warn!("Client {} knows no server offering '{}'", self.id, service.name());
+        push_gui_event(&self.gui_events, self.id, GuiEvent::Error { reason: format!("no_server_offering::{}", service.name()) });
+        for server_id in self.known_servers() {
+            self.send_to(server_id, ChatProtocol::ServerTypeRequest);
+        }
+    }
+
+    fn send_to_server(&mut self, high_level_message_content: ChatProtocol) {
+        match self.connected_server_id {
+            Some(server_id) => self.send_to(server_id, high_level_message_content),
+            None => info!("Client {} cannot send message: not connected to any server", self.id),
+        }
+    }
+
+    /// Routes and fragments a message for `id_to_send_to`, queueing it behind a flood when no route is known.
+    fn send_to(&mut self, id_to_send_to: NodeId, high_level_message_content: ChatProtocol) {
+        let route_option = self.best_path(self.id, id_to_send_to);
+        let route = match route_option {
+            Some(path) => {
+                info!("Client {} computed route to server {}: {:?}",
+                         self.id, id_to_send_to, path);
+                self.route_cache.insert(id_to_send_to, path.clone());
+                path
+            },
+            None => {
+                warn!(
+                    "Client {} could not compute route to server {}. Starting flood discovery",
+                    self.id, id_to_send_to
+                );
+                // ^^^ Changed to use the actual target server ID for the log
+
+                // Start flood discovery (only if one isn’t already active)
+                let active_flood = self.active_flood_discoveries.values().any(|state| {
+                    state.initiator_id == self.id && state.start_time.elapsed() < Duration::from_secs(5)
                 });
-                info!("Client {} stored message info for session {}", self.id, session_id);
-                if routing_header.hops.len() > routing_header.hop_index {
-                    let first_hop = routing_header.hops[routing_header.hop_index];
-                    info!("Client {} sending message fragments for session {} starting with hop {}", self.id, session_id, first_hop);
-                    for fragment in fragments {
-                        let packet = Packet {
-                            pack_type: PacketType::MsgFragment(fragment.clone()),
-                            routing_header: routing_header.clone(),
-                            session_id,
-                        };
-                        match self.send_to_neighbor(first_hop, packet) {
-                            Ok(()) => info!("Client {} sent fragment {} for session {} to {}", self.id, fragment.fragment_index, session_id, first_hop),
-                            Err(e) => warn!("Client {} failed to send fragment {} for session {} to {}: {}", self.id, fragment.fragment_index, session_id, first_hop, e),
-                        }
-                    }
-                } else {
-                    error!("Client {} has no valid first hop in computed route {:?} to send the message to!", self.id, route);
+                if !active_flood {
                     self.start_flood_discovery();
                 }
-                if command_type_str == &"[Logout]" {
-                    info!("🚪 🚪 🚪  Client {} successfully sent Logout message. Disconnecting internally", self.id);
-                    self.connected_server_id = None;
+
+                // Queue the message to send after the path is discovered
+                self.pending_messages_after_flood.push((id_to_send_to, high_level_message_content));
+                // ^^^ Store the server’s NodeId (destination) instead of self.id
+                return;
+            }
+        };
+        let routing_header = SourceRoutingHeader {
+            hops: route.clone(),
+            hop_index: 1,
+        };
+        info!("🚕🚕🚕🚕🚕 BEST PATH IS : {:?}",routing_header.hops);
+        let message_data_bytes = match high_level_message_content.encode() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("Client {} could not send message to {}: {}", self.id, id_to_send_to, e);
+                return;
+            }
+        };
+        const FRAGMENT_SIZE: usize = 128;
+        let total_fragments = (message_data_bytes.len() + FRAGMENT_SIZE - 1) / FRAGMENT_SIZE;
+        let mut fragments = Vec::new();
+        let session_id = self.next_session_id();
+        for i in 0..total_fragments {
+            let start = i * FRAGMENT_SIZE;
+            let end = (start + FRAGMENT_SIZE).min(message_data_bytes.len());
+            let fragment_data_slice = &message_data_bytes[start..end];
+            let mut data: [u8; FRAGMENT_SIZE] = [0; FRAGMENT_SIZE];
+            data[..fragment_data_slice.len()].copy_from_slice(fragment_data_slice);
+            let len = fragment_data_slice.len();
+            fragments.push(Fragment {
+                fragment_index: i as u64,
+                total_n_fragments: total_fragments as u64,
+                length: len as u8,
+                data,
+            });
+        }
+        //println!("Client {} fragmented message into {} fragments for session {}", self.id, total_fragments, session_id);
+        self.sent_messages.track(session_id, routing_header.clone(), fragments.clone());
+        info!("Client {} stored message info for session {}", self.id, session_id);
+        if routing_header.hops.len() > routing_header.hop_index {
+            let first_hop = routing_header.hops[routing_header.hop_index];
+            info!("Client {} sending message fragments for session {} starting with hop {}", self.id, session_id, first_hop);
+            for fragment in fragments {
+                let packet = Packet {
+                    pack_type: PacketType::MsgFragment(fragment.clone()),
+                    routing_header: routing_header.clone(),
+                    session_id,
+                };
+                match self.send_to_neighbor(first_hop, packet) {
+                    Ok(()) => info!("Client {} sent fragment {} for session {} to {}", self.id, fragment.fragment_index, session_id, first_hop),
+                    Err(e) => warn!("Client {} failed to send fragment {} for session {} to {}: {}", self.id, fragment.fragment_index, session_id, first_hop, e),
                 }
-            } else {
-                info!("Client {} cannot send message: not connected to any server", self.id);
             }
+        } else {
+            error!("Client {} has no valid first hop in computed route {:?} to send the message to!", self.id, route);
+            self.start_flood_discovery();
+        }
+        if high_level_message_content == ChatProtocol::Logout {
+            info!("🚪 🚪 🚪  Client {} successfully sent Logout message. Disconnecting internally", self.id);
+            self.connected_server_id = None;
+            self.server_capabilities = None;
         }
     }
 
@@ -1375,6 +1603,51 @@ impl MyClient {
 }
 
 
+impl ClientNode for MyClient {
+    fn id(&self) -> NodeId {
+        self.id
+    }
+
+    fn attach_log(&mut self, log: SharedSimLog) {
+        MyClient::attach_log(self, log);
+    }
+
+    fn attach_gui_events(&mut self, events: SharedGuiEvents) {
+        MyClient::attach_gui_events(self, events);
+    }
+
+    fn run(&mut self, gui_input: SharedGuiInput) {
+        MyClient::run(self, gui_input);
+    }
+
+    fn handle_packet(&mut self, packet: Packet) {
+        self.process_packet(packet);
+    }
+
+    fn handle_gui_command(&mut self, command: String) {
+        self.process_gui_command(self.id, command);
+    }
+
+    fn set_downloads_dir(&mut self, dir: PathBuf) {
+        self.downloads_dir = dir;
+    }
+
+    fn snapshot(&self) -> ClientSnapshot {
+        let mut neighbors: Vec<NodeId> = self.packet_send.keys().copied().collect();
+        neighbors.sort_unstable();
+        let mut known_nodes: Vec<NodeId> = self.node_id_to_index.keys().copied().collect();
+        known_nodes.sort_unstable();
+        ClientSnapshot {
+            id: self.id,
+            kind: ClientKind::Client1,
+            connected_server: self.connected_server_id,
+            server_capabilities: self.server_capabilities.clone(),
+            neighbors,
+            known_nodes,
+        }
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -1451,7 +1724,7 @@ mod tests {
             client_id,
             packet_recv_rx,
             packet_send_map,
-            HashMap::new(), // sent_messages
+            ReliableSender::new(), // sent_messages
             None, // connected_server_id
             HashSet::new(), // seen_flood_ids
             Some(shared_senders_arc), // shared_senders
@@ -1477,7 +1750,7 @@ mod tests {
             client_id,
             packet_recv_rx,
             packet_send_map,
-            HashMap::new(),
+            ReliableSender::new(),
             None,
             HashSet::new(),
             Some(shared_senders_mock.clone()),
@@ -1495,12 +1768,8 @@ mod tests {
         let neighbor_ids = vec![neighbor1_id, neighbor2_id];
 
         //- - - - preparing the environment for the test - - - -
-        {
-            let mut counter = SESSION_COUNTER.lock().unwrap();
-            *counter = 0;
-        }
         let (mut client, _client_incoming_packet_tx, neighbor_receivers, _gui_input, _shortcut_tx) = setup_client(client_id, neighbor_ids.clone());
-        let initial_session_counter_value = *SESSION_COUNTER.lock().unwrap();
+        let initial_session_counter_value = client.session_counter;
 
         client.start_flood_discovery();
 
@@ -1508,9 +1777,9 @@ mod tests {
         //1.unique and progressive flood_id
         let expected_flood_id = initial_session_counter_value + 1;
         assert_eq!(
-            *SESSION_COUNTER.lock().unwrap(),
+            client.session_counter,
             expected_flood_id,
-            "SESSION_COUNTER should be incremented by 1 to have a unique id"
+            "the session counter should be incremented by 1 to have a unique id"
         );
         assert!(
             client.active_flood_discoveries.contains_key(&expected_flood_id),
@@ -1657,10 +1926,6 @@ mod tests {
         let test_initiator_id = 50;
 
         //- - - - preparing the environment for the test - - - -
-        {
-            let mut counter = SESSION_COUNTER.lock().unwrap();
-            *counter = 0;
-        }
         let (mut client, client_incoming_packet_tx, neighbor_outbound_receivers, _gui_input, _shortcut_tx) =
             setup_client(client_id, neighbor_ids.clone());
         assert!(!client.seen_flood_ids.contains(&(test_flood_id, test_initiator_id)),
@@ -2071,10 +2336,6 @@ mod tests {
         let server_id = 200;
 
         //- - - - preparing the environment for the test - - - -
-        {
-            let mut counter = SESSION_COUNTER.lock().unwrap();
-            *counter = 0;
-        }
         let (mut client, _client_incoming_packet_tx, _neighbor_outbound_receivers, _gui_input, _shortcut_tx) = setup_client(client_id, vec![]);
         assert_eq!(client.network_graph.node_count(), 0, "network graph should be empty at the beginning");
         assert!(client.active_flood_discoveries.is_empty(), "no flood discovery active at the beginning");
@@ -2137,12 +2398,7 @@ mod tests {
         let nack = Nack { fragment_index: nack_packet_id, nack_type: nack_type.clone() };
         let mut packet = Packet { pack_type: PacketType::Nack(nack.clone()), routing_header: original_rh.clone(), session_id };
 
-        client.sent_messages.insert(session_id, SentMessageInfo {
-            fragments: vec![],
-            original_routing_header: original_rh.clone(),
-            received_ack_indices: HashSet::new(),
-            route_needs_recalculation: false,
-        });
+        client.sent_messages.track(session_id, original_rh.clone(), vec![]);
         client.route_cache.insert(server_id, vec![client_id, drone1_id, problem_node_id, server_id]);
 
         assert!(client.route_cache.contains_key(&server_id), "The route to the server should initially be in the cache");
@@ -2159,7 +2415,7 @@ mod tests {
         assert!(!client.network_graph.node_weights().any(|ni| ni.id == problem_node_id), "The node should not be present in the node weights of the graph after the NACK");
 
         //2.that the route for this session has been marked for recalculation
-        assert!(client.sent_messages.get(&session_id).unwrap().route_needs_recalculation, "The route for the session should be marked for recalculation");
+        assert!(client.sent_messages.get(session_id, server_id).unwrap().route_needs_recalculation, "The route for the session should be marked for recalculation");
 
         //3.that the route_cache for the destination (server_id) has been invalidated
         assert!(!client.route_cache.contains_key(&server_id), "The cached route for the destination should be removed");
@@ -2229,12 +2485,7 @@ mod tests {
         let nack = Nack { fragment_index: nack_packet_id, nack_type: nack_type.clone() };
         let mut packet = Packet { pack_type: PacketType::Nack(nack.clone()), routing_header: original_rh.clone(), session_id };
 
-        client.sent_messages.insert(session_id, SentMessageInfo {
-            fragments: vec![],
-            original_routing_header: original_rh.clone(),
-            received_ack_indices: HashSet::new(),
-            route_needs_recalculation: false,
-        });
+        client.sent_messages.track(session_id, original_rh.clone(), vec![]);
         client.route_cache.insert(server_id, vec![client_id, drone1_id, problem_node_id, drone3_id, server_id]);
 
         assert!(client.route_cache.contains_key(&server_id), "The route to the server should initially be in the cache");
@@ -2267,7 +2518,7 @@ mod tests {
         assert!(client.network_graph.contains_edge(drone3_idx, to_idx), "The link from drone3 to drone2 should still exist");
 
         //4.that the route for this session has been marked for recalculation
-        assert!(client.sent_messages.get(&session_id).unwrap().route_needs_recalculation, "The route for the session should be marked for recalculation");
+        assert!(client.sent_messages.get(session_id, server_id).unwrap().route_needs_recalculation, "The route for the session should be marked for recalculation");
 
         //5.that the route_cache for destination (server_id) has been invalidated
         assert!(!client.route_cache.contains_key(&server_id), "The cached route for the destination should be removed");
@@ -2304,12 +2555,7 @@ mod tests {
         let nack_type = NackType::DestinationIsDrone;
         let nack = Nack { fragment_index: nack_packet_id, nack_type: nack_type.clone() };
         let mut packet = Packet { pack_type: PacketType::Nack(nack.clone()), routing_header: original_rh.clone(), session_id };
-        client.sent_messages.insert(session_id, SentMessageInfo {
-            fragments: vec![],
-            original_routing_header: original_rh.clone(),
-            received_ack_indices: HashSet::new(),
-            route_needs_recalculation: false,
-        });
+        client.sent_messages.track(session_id, original_rh.clone(), vec![]);
         client.route_cache.insert(drone_destination_id, vec![client_id, drone1_id, drone_destination_id]);
         assert!(client.route_cache.contains_key(&drone_destination_id), "route towards destination drone should be initially in the cache");
 
@@ -2317,7 +2563,7 @@ mod tests {
 
         //- - - - verifying - - - -
         //1.that the route for this session has been marked for the recalculation
-        assert!(client.sent_messages.get(&session_id).unwrap().route_needs_recalculation, "route for the session should be marked for the recalculation");
+        assert!(client.sent_messages.get(session_id, drone_destination_id).unwrap().route_needs_recalculation, "route for the session should be marked for the recalculation");
 
         //2.that the route in cache for the destination (drone_destination_id) has been invalidated
         assert!(!client.route_cache.contains_key(&drone_destination_id), "route in cache for the destination of the drone should be removed");
@@ -2368,15 +2614,10 @@ mod tests {
 
         let mut packet = Packet { pack_type: PacketType::Nack(nack.clone()), routing_header: nack_return_rh.clone(), session_id };
 
-        client.sent_messages.insert(session_id, SentMessageInfo {
-            fragments: vec![],
-            original_routing_header: SourceRoutingHeader {
-                hops: vec![client_id, drone1_id, drone_expected_recipient, server_id],
-                hop_index: 2,
-            },
-            received_ack_indices: HashSet::new(),
-            route_needs_recalculation: false,
-        });
+        client.sent_messages.track(session_id, SourceRoutingHeader {
+            hops: vec![client_id, drone1_id, drone_expected_recipient, server_id],
+            hop_index: 2,
+        }, vec![]);
 
         client.route_cache.insert(server_id, vec![client_id, drone1_id, drone_expected_recipient, server_id]);
 
@@ -2405,7 +2646,7 @@ mod tests {
         assert_eq!(new_weight_backward, initial_weight_backward.saturating_add(1), "weight of the backward link should be incremented");
 
         //2.that the route for this session has been marked for the recalculation
-        assert!(client.sent_messages.get(&session_id).unwrap().route_needs_recalculation, "the route for the session should be marked for the recalculation");
+        assert!(client.sent_messages.get(session_id, server_id).unwrap().route_needs_recalculation, "the route for the session should be marked for the recalculation");
 
         //3.that the route in cache for the destination (server_id) has been invalidated
         assert!(!client.route_cache.contains_key(&server_id), "the route in cache for server should be removed");
@@ -2455,12 +2696,7 @@ mod tests {
             data: [0xAA; 128],
         };
 
-        client.sent_messages.insert(session_id, SentMessageInfo {
-            fragments: vec![fragment_to_resend.clone()],
-            original_routing_header: original_rh_for_resend.clone(),
-            received_ack_indices: HashSet::new(),
-            route_needs_recalculation: false,
-        });
+        client.sent_messages.track(session_id, original_rh_for_resend.clone(), vec![fragment_to_resend.clone()]);
 
         let from_node_for_assert = rh_nack_from_dropper.hops[rh_nack_from_dropper.hop_index - 1];
         let dropped_at_node_for_assert = rh_nack_from_dropper.hops[rh_nack_from_dropper.hop_index];
@@ -2603,11 +2839,6 @@ mod tests {
         let drone1_id = 1;
         let drone2_id = 2;
 
-        {
-            let mut counter = SESSION_COUNTER.lock().unwrap();
-            *counter = 0;
-        }
-
         let (client_to_d1_tx, d1_from_client_rx) = unbounded::<Packet>();
         let (d1_to_client_tx, client_from_d1_rx) = unbounded::<Packet>();
         let (d1_to_d2_tx, d2_from_d1_rx) = unbounded::<Packet>();
@@ -2645,7 +2876,7 @@ mod tests {
         assert_eq!(client.connected_server_id, None, "client shouldn't be connected to a server initially");
 
         let login_command_string = format!("[Login]::{}", server_id);
-        let initial_session_counter = *SESSION_COUNTER.lock().unwrap();
+        let initial_session_counter = client.session_counter;
         client.process_gui_command(client_id, login_command_string);
 
         assert_eq!(client.connected_server_id, Some(server_id), "id of the connected server should be updated after the Login command");
@@ -2653,8 +2884,8 @@ mod tests {
         let expected_session_id_login = initial_session_counter + 1;
         let drone1_receiver = neighbor_outbound_receivers.get_mut(&drone1_id).unwrap();
 
-        let expected_login_message_content = format!("[Login]::{}", server_id);
-        let expected_login_fragments = (expected_login_message_content.as_bytes().len() + 128 - 1) / 128;
+        let expected_login_message_content = ChatProtocol::login(server_id);
+        let expected_login_fragments = (expected_login_message_content.encode().unwrap().len() + 128 - 1) / 128;
         assert_eq!(expected_login_fragments, 1, "the Login message should be 1 fragment long");
 
         let received_login_packet = drone1_receiver.recv_timeout(Duration::from_millis(100))
@@ -2666,8 +2897,8 @@ mod tests {
                 assert_eq!(received_login_packet.routing_header.hops, vec![client_id, drone1_id, drone2_id, server_id], "wrong path hops for the Login fragment");
                 assert_eq!(received_login_packet.routing_header.hop_index, 1, "hop_index for the Login fragment must be equal to 1");
                 let reassembled_data = fragment.data[..fragment.length as usize].to_vec();
-                let reassembled_message = String::from_utf8_lossy(&reassembled_data);
-                assert_eq!(reassembled_message, expected_login_message_content, "content of the reassembled Login message doesn't correspond");
+                let reassembled_message = ChatProtocol::decode(&reassembled_data);
+                assert_eq!(reassembled_message, Ok(expected_login_message_content.clone()), "content of the reassembled Login message doesn't correspond");
             },
             _ => panic!("expected MsgFragment for the Login, but received different packet type: {:?}", received_login_packet.pack_type),
         }
@@ -2675,14 +2906,14 @@ mod tests {
         assert_eq!(client.connected_server_id, Some(server_id), "client should be still connected before the Logout test");
 
         let logout_command_string = "[Logout]".to_string();
-        let initial_session_counter_logout = *SESSION_COUNTER.lock().unwrap();
+        let initial_session_counter_logout = client.session_counter;
         client.process_gui_command(client_id, logout_command_string);
 
         assert_eq!(client.connected_server_id, None, "id of the connected server should be None after the Logout command");
 
         let expected_session_id_logout = initial_session_counter_logout + 1;
-        let expected_logout_message_content = "[Logout]".to_string();
-        let expected_logout_fragments = (expected_logout_message_content.as_bytes().len() + 128 - 1) / 128;
+        let expected_logout_message_content = ChatProtocol::Logout;
+        let expected_logout_fragments = (expected_logout_message_content.encode().unwrap().len() + 128 - 1) / 128;
         assert_eq!(expected_logout_fragments, 1, "the Logout message should be 1 fragment long");
 
         let received_logout_packet = drone1_receiver.recv_timeout(Duration::from_millis(100))
@@ -2694,19 +2925,19 @@ mod tests {
                 assert_eq!(received_logout_packet.routing_header.hops, vec![client_id, drone1_id, drone2_id, server_id], "wrong hops path for the Logout fragment");
                 assert_eq!(received_logout_packet.routing_header.hop_index, 1, "hop_index for the Logout fragment should be equal to 1");
                 let reassembled_data = fragment.data[..fragment.length as usize].to_vec();
-                let reassembled_message = String::from_utf8_lossy(&reassembled_data);
-                assert_eq!(reassembled_message, expected_logout_message_content, "content of the reassembled Logout message doesn't correspond");
+                let reassembled_message = ChatProtocol::decode(&reassembled_data);
+                assert_eq!(reassembled_message, Ok(expected_logout_message_content.clone()), "content of the reassembled Logout message doesn't correspond");
             },
             _ => panic!("expected MsgFragment for the Logout, but received different packet type: {:?}", received_logout_packet.pack_type),
         }
 
         assert_eq!(client.connected_server_id, None, "client should be disconnected for this part of the test");
         while drone1_receiver.try_recv().is_ok() {} // Svuota il canale
-        let initial_session_counter_no_logout = *SESSION_COUNTER.lock().unwrap();
+        let initial_session_counter_no_logout = client.session_counter;
         client.process_gui_command(client_id, "[Logout]".to_string());
         assert_eq!(client.connected_server_id, None, "id of the connected server should remain None if client not logged in");
         assert!(drone1_receiver.try_recv().is_err(), "no packet should be sent when logging out while not connected");
-        assert_eq!(*SESSION_COUNTER.lock().unwrap(), initial_session_counter_no_logout, "session counter shouldn't increment if the Logout command is ignored");
+        assert_eq!(client.session_counter, initial_session_counter_no_logout, "session counter shouldn't increment if the Logout command is ignored");
     }
 
     #[test]
@@ -2716,11 +2947,6 @@ mod tests {
         let drone2_id = 2;
         let server_id = 200;
 
-        {
-            let mut counter = SESSION_COUNTER.lock().unwrap();
-            *counter = 0;
-        }
-
         let (mut client, _client_incoming_packet_tx, mut neighbor_outbound_receivers, _gui_input, _shortcut_tx) =
             setup_client(client_id, vec![drone1_id]);
 
@@ -2773,4 +2999,266 @@ mod tests {
 
         assert!(drone1_receiver.try_recv().is_err(), "Only one ACK packet was expected for the neighbor");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_client_resends_fragments_whose_ack_timed_out() {
+        let client_id = 101;
+        let drone1_id = 1;
+        let server_id = 200;
+        let route = SourceRoutingHeader { hops: vec![client_id, drone1_id, server_id], hop_index: 1 };
+        let fragment = Fragment { fragment_index: 0, total_n_fragments: 1, length: 1, data: [0; 128] };
+
+        let (mut client, _client_incoming_packet_tx, neighbor_outbound_receivers, _gui_input, _shortcut_tx) =
+            setup_client(client_id, vec![drone1_id]);
+        client.sent_messages = ReliableSender::with_limits(Duration::ZERO, 1);
+        client.sent_messages.track(9, route, vec![fragment]);
+        client.retransmit_unacked_fragments();
+        let resent = neighbor_outbound_receivers.get(&drone1_id).unwrap().try_recv().expect("the client should resend a fragment whose ACK never arrived");
+        assert_eq!(resent.session_id, 9, "wrong session for the resent fragment");
+        assert!(matches!(resent.pack_type, PacketType::MsgFragment(ref f) if f.fragment_index == 0), "the resent packet should carry the timed out fragment");
+    }
+
+    #[test]
+    fn test_login_ack_limits_requests_to_the_negotiated_capabilities() {
+        let client_id = 101;
+        let drone1_id = 1;
+        let server_id = 200;
+        let (mut client, _client_incoming_packet_tx, neighbor_outbound_receivers, _gui_input, _shortcut_tx) =
+            setup_client(client_id, vec![drone1_id]);
+        client.connected_server_id = Some(server_id);
+        client.process_received_high_level_message(
+            ChatProtocol::LoginAck { session_id: 1, version: 1, capabilities: vec![Capability::Chat] },
+            server_id,
+            1,
+        );
+        assert_eq!(client.server_capabilities, Some(vec![Capability::Chat]), "the LoginAck should store the negotiated capabilities");
+
+        let drone1_receiver = neighbor_outbound_receivers.get(&drone1_id).unwrap();
+        client.process_gui_command(client_id, ChatProtocol::MediaListRequest.to_string());
+        assert!(drone1_receiver.try_recv().is_err(), "a request the server did not agree on should not be sent");
+        assert!(client.pending_messages_after_flood.is_empty(), "a request the server did not agree on should not be queued");
+
+        client.process_gui_command(client_id, ChatProtocol::ClientListRequest.to_string());
+        assert_eq!(client.pending_messages_after_flood.len(), 1, "requests that need no capability still go out");
+    }
+
+    #[test]
+    fn test_received_messages_are_reported_to_the_gui_event_queue() {
+        use crate::simulation_controller::gui_event_queue::drain_gui_events;
+
+        let client_id = 7;
+        let server_id = 30;
+        let (mut client, _tx, _neighbors, _gui_input, _shortcut_tx) = setup_client(client_id, vec![1]);
+        let events = new_gui_event_queue();
+        client.attach_gui_events(events.clone());
+
+        client.process_received_high_level_message(ChatProtocol::LoginAck { session_id: 1, version: 1, capabilities: vec![Capability::Chat] }, server_id, 1);
+        client.process_received_high_level_message(ChatProtocol::MessageFrom { sender_id: 8, message_id: 1, message: "hi".to_string() }, server_id, 2);
+        client.process_received_high_level_message(ChatProtocol::ClientListResponse { clients: vec![7, 8] }, server_id, 3);
+        client.process_received_high_level_message(ChatProtocol::Error { reason: "nope".to_string() }, server_id, 4);
+        client.process_received_high_level_message(ChatProtocol::MediaListRequest, server_id, 5);
+
+        assert_eq!(drain_gui_events(&events), vec![
+            (client_id, GuiEvent::LoggedIn { server_id, capabilities: vec![Capability::Chat] }),
+            (client_id, GuiEvent::ChatMessage { from: 8, message_id: 1, message: "hi".to_string() }),
+            (client_id, GuiEvent::ClientList { clients: vec![7, 8] }),
+            (client_id, GuiEvent::Error { reason: "nope".to_string() }),
+        ], "every reply should reach the GUI in order, requests should not");
+        assert!(drain_gui_events(&events).is_empty(), "draining should empty the queue");
+    }
+
+    #[test]
+    fn test_fragments_of_different_senders_sharing_a_session_id_are_kept_apart() {
+        use crate::simulation_controller::gui_event_queue::drain_gui_events;
+
+        let client_id = 7;
+        let (mut client, _tx, _neighbors, _gui_input, _shortcut_tx) = setup_client(client_id, vec![1]);
+        let events = new_gui_event_queue();
+        client.attach_gui_events(events.clone());
+        let fragments = |server_id: NodeId, text: &str| -> Vec<Packet> {
+            let bytes = ChatProtocol::MessageFrom { sender_id: 8, message_id: server_id as u64, message: text.to_string() }.encode().unwrap();
+            let chunks: Vec<&[u8]> = bytes.chunks(128).collect();
+            chunks.iter().enumerate().map(|(i, chunk)| {
+                let mut data = [0u8; 128];
+                data[..chunk.len()].copy_from_slice(chunk);
+                let fragment = Fragment { fragment_index: i as u64, total_n_fragments: chunks.len() as u64, length: chunk.len() as u8, data };
+                Packet::new_fragment(SourceRoutingHeader::new(vec![server_id, 1, client_id], 2), 5, fragment)
+            }).collect()
+        };
+        let from_30 = fragments(30, &"a".repeat(200));
+        let from_31 = fragments(31, &"b".repeat(200));
+        assert!(from_30.len() > 1 && from_31.len() > 1);
+
+        for mut packet in from_30.into_iter().zip(from_31).flat_map(|(a, b)| [a, b]) {
+            let PacketType::MsgFragment(fragment) = packet.pack_type.clone() else { unreachable!() };
+            client.reassemble_packet(&fragment, &mut packet);
+        }
+        assert_eq!(drain_gui_events(&events), vec![
+            (client_id, GuiEvent::ChatMessage { from: 8, message_id: 30, message: "a".repeat(200) }),
+            (client_id, GuiEvent::ChatMessage { from: 8, message_id: 31, message: "b".repeat(200) }),
+        ], "both messages should be reassembled, each from its own sender's fragments");
+    }
+
+    #[test]
+    fn test_forged_fragment_counts_and_indices_are_not_buffered() {
+        let client_id = 7;
+        let (mut client, _tx, _neighbors, _gui_input, _shortcut_tx) = setup_client(client_id, vec![1]);
+        let packet = |fragment_index, total_n_fragments| {
+            let fragment = Fragment { fragment_index, total_n_fragments, length: 128, data: [0; 128] };
+            (fragment.clone(), Packet::new_fragment(SourceRoutingHeader::new(vec![30, 1, client_id], 2), 5, fragment))
+        };
+
+        for (fragment, mut pkt) in [packet(0, u64::MAX), packet(2, 2), packet(0, 0)] {
+            client.reassemble_packet(&fragment, &mut pkt);
+        }
+        assert!(client.received_messages.is_empty(), "nothing is buffered for a forged fragment");
+
+        let (first, mut first_pkt) = packet(0, 3);
+        client.reassemble_packet(&first, &mut first_pkt);
+        let (other, mut other_pkt) = packet(1, 4);
+        client.reassemble_packet(&other, &mut other_pkt);
+        let state = &client.received_messages[&(5, 30)];
+        assert_eq!((state.total_fragments, state.received_indices.len()), (3, 1), "a different total is ignored");
+    }
+
+    #[test]
+    fn test_reassembled_chat_message_is_acknowledged_with_a_delivered_receipt() {
+        let client_id = 7;
+        let server_id = 30;
+        let (mut client, _tx, _neighbors, _gui_input, _shortcut_tx) = setup_client(client_id, vec![1]);
+        client.connected_server_id = Some(server_id);
+
+        client.process_received_high_level_message(ChatProtocol::MessageFrom { sender_id: 8, message_id: 42, message: "hi".to_string() }, server_id, 1);
+
+        let receipt = ChatProtocol::Receipt { peer_id: 8, message_id: 42, kind: ReceiptKind::Delivered };
+        assert_eq!(client.pending_messages_after_flood, vec![(server_id, receipt)], "with no route yet the receipt waits for the flood");
+    }
+
+    #[test]
+    fn test_downloaded_media_are_saved_per_client_under_their_file_name() {
+        use crate::simulation_controller::gui_event_queue::drain_gui_events;
+        use crate::test_support::TempDir;
+
+        let dir = TempDir::new("downloads_test");
+        let base = dir.path();
+        let (mut client, _tx, _neighbors, _gui_input, _shortcut_tx) = setup_client(7, vec![1]);
+        let events = new_gui_event_queue();
+        client.attach_gui_events(events.clone());
+        client.set_downloads_dir(media::downloads_dir(base, 7));
+        let envelope = MediaEnvelope::new("song.mp3", vec![b'I', b'D', b'3', 4, 0, 0]);
+        client.process_received_high_level_message(ChatProtocol::MediaDownloadResponse { name: "song".to_string(), data: envelope.encode() }, 30, 1);
+        let path = base.join("client_7").join("song.mp3");
+        assert_eq!(std::fs::read(&path).unwrap(), envelope.data, "the original bytes land in the client's downloads dir");
+        assert_eq!(drain_gui_events(&events), vec![(7, GuiEvent::MediaDownloaded {
+            name: "song".to_string(),
+            file_name: "song.mp3".to_string(),
+            content_type: "audio/mpeg".to_string(),
+            size: envelope.data.len(),
+            path: path.display().to_string(),
+        })]);
+    }
+
+    #[test]
+    fn test_client_run_loop_stops_on_the_controller_shutdown_command() {
+        use crate::simulation_controller::gui_input_queue::push_gui_message;
+
+        let (mut client, _tx, _neighbors, gui_input, _shortcut_tx) = setup_client(7, vec![1]);
+        push_gui_message(&gui_input, 7, ChatProtocol::ClientListRequest.to_string());
+        push_gui_message(&gui_input, 7, SHUTDOWN_COMMAND.to_string());
+        push_gui_message(&gui_input, 7, ChatProtocol::Logout.to_string());
+        let queue = gui_input.clone();
+        let handle = std::thread::spawn(move || client.run(queue));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !handle.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(handle.is_finished(), "the run loop should return after [Shutdown]");
+        handle.join().unwrap();
+        assert_eq!(gui_input.lock().unwrap().get(&7).map(Vec::len), Some(1), "commands after [Shutdown] are left alone");
+    }
+
+    #[test]
+    fn test_server_type_is_asked_to_every_known_server_without_logging_in() {
+        use crate::nodes::protocol::ServerService;
+        use crate::simulation_controller::gui_event_queue::drain_gui_events;
+
+        let client_id = 7;
+        let (mut client, _tx, _neighbors, _gui_input, _shortcut_tx) = setup_client(client_id, vec![1]);
+        let events = new_gui_event_queue();
+        client.attach_gui_events(events.clone());
+        for (id, node_type) in [(31, PktNodeType::Server), (1, PktNodeType::Drone), (30, PktNodeType::Server)] {
+            let idx = client.network_graph.add_node(NodeInfo { id, node_type });
+            client.node_id_to_index.insert(id, idx);
+        }
+
+        client.process_gui_command(client_id, ChatProtocol::ServerTypeRequest.to_string());
+        assert_eq!(client.connected_server_id, None);
+        assert_eq!(client.pending_messages_after_flood, vec![(30, ChatProtocol::ServerTypeRequest), (31, ChatProtocol::ServerTypeRequest)],
+            "each server found so far gets its own request");
+
+        let response = ChatProtocol::ServerTypeResponse { services: vec![ServerService::Chat, ServerService::Text] };
+        client.process_received_high_level_message(response, 31, 1);
+        assert_eq!(drain_gui_events(&events), vec![
+            (client_id, GuiEvent::ServerType { server_id: 31, services: vec![ServerService::Chat, ServerService::Text] }),
+        ], "the answer is reported for the server that sent it");
+    }
+
+    #[test]
+    fn test_browser_flow_fetches_a_document_then_the_media_it_links() {
+        use crate::nodes::protocol::ServerService;
+        use crate::nodes::transfer::MEDIA_CHUNK_SIZE;
+
+        let page = "# Home\n![logo](pic1.png) and ![again](pic1.png), ![photo]( pic2.png )";
+        let response = ChatProtocol::TextResponse { name: "index.md".to_string(), content: page.to_string() };
+        let list = ChatProtocol::TextListResponse { files: vec!["a.md".to_string(), "index.md".to_string()] };
+
+        let client_id = 7;
+        let (mut client, _tx, _neighbors, _gui_input, _shortcut_tx) = setup_client(client_id, vec![1]);
+        client.process_received_high_level_message(ChatProtocol::ServerTypeResponse { services: vec![ServerService::Text] }, 40, 1);
+        client.process_received_high_level_message(ChatProtocol::ServerTypeResponse { services: vec![ServerService::Media] }, 41, 2);
+        client.process_received_high_level_message(list, 40, 3);
+        client.process_gui_command(client_id, "[TextRequest]::index.md".to_string());
+        client.process_received_high_level_message(response, 40, 4);
+
+        assert_eq!(client.connected_server_id, None, "nothing here needs a login");
+        assert_eq!(client.pending_messages_after_flood, vec![
+            (40, ChatProtocol::TextRequest { name: "index.md".to_string() }),
+            (41, ChatProtocol::MediaChunkRequest { name: "pic1.png".to_string(), offset: 0, length: MEDIA_CHUNK_SIZE }),
+            (41, ChatProtocol::MediaChunkRequest { name: "pic2.png".to_string(), offset: 0, length: MEDIA_CHUNK_SIZE }),
+        ], "the page comes from the text server, its media from the media server");
+    }
+
+    #[test]
+    fn test_asking_again_for_a_media_resumes_its_download() {
+        use crate::nodes::transfer::{media_chunk, MEDIA_CHUNK_SIZE};
+        let media: Vec<u8> = (0..MEDIA_CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        let chunk_at = |offset: u64| match media_chunk("pic.png", &media, offset, MEDIA_CHUNK_SIZE) {
+            ChatProtocol::MediaChunk { offset, total_size, chunk_checksum, file_checksum, data, .. } => (offset, total_size, data, chunk_checksum, file_checksum),
+            other => panic!("a server chunk was expected, got {}", other.tag()),
+        };
+
+        let client_id = 7;
+        let (mut client, _tx, _neighbors, _gui_input, _shortcut_tx) = setup_client(client_id, vec![1]);
+        client.connected_server_id = Some(41);
+        client.process_gui_command(client_id, "[MediaDownloadRequest]::pic.png".to_string());
+        let (offset, total, data, chunk_sum, file_sum) = chunk_at(0);
+        client.process_received_high_level_message(ChatProtocol::MediaChunk { name: "pic.png".to_string(), offset, total_size: total, chunk_checksum: chunk_sum, file_checksum: file_sum, data }, 41, 1);
+        client.process_gui_command(client_id, "[MediaDownloadRequest]::pic.png".to_string());
+        assert_eq!(client.pending_messages_after_flood.last(), Some(&(41, ChatProtocol::MediaChunkRequest {
+            name: "pic.png".to_string(), offset: MEDIA_CHUNK_SIZE as u64, length: MEDIA_CHUNK_SIZE,
+        })), "asking again resumes instead of starting over");
+    }
+
+    #[test]
+    fn test_client_events_are_logged_with_its_id_and_session() {
+        let (mut client, _tx, _neighbors, _gui_input, _shortcut_tx) = setup_client(1, vec![2]);
+        let log = new_sim_log();
+        client.attach_log(log.clone());
+        client.log(SimEventKind::Chat, "hello from 1");
+        client.log_session(SimEventKind::Delivery, 9, "could not deliver");
+
+        let events: Vec<SimEvent> = log.lock().unwrap().iter().cloned().collect();
+        assert_eq!((events[0].node, events[0].kind, events[0].payload.as_str(), events[0].session), (Some(1), SimEventKind::Chat, "hello from 1", None));
+        assert_eq!((events[1].node, events[1].session), (Some(1), Some(9)));
+    }
+}
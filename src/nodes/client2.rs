@@ -5,47 +5,33 @@ use std::string::String;
 use std::default::Default;
 use std::{fs, io, thread};
 use std::io::{ErrorKind, Write};
-use wg_2024::controller::{DroneCommand, DroneEvent};
+use wg_2024::controller::DroneCommand;
 use wg_2024::network::{NodeId, SourceRoutingHeader};
 use wg_2024::packet::{Ack, FloodRequest, FloodResponse, Fragment, Nack, NodeType, NackType,  Packet, PacketType};
 use wg_2024::packet::PacketType::MsgFragment;
-use wg_2024::drone::Drone;
-use crossbeam_channel::{select, select_biased, Receiver, Sender};
+use crossbeam_channel::{select_biased, Receiver, Sender};
 use log::{error, info, warn};
-use petgraph::graph::{Graph, NodeIndex, UnGraph};
-use petgraph::algo::dijkstra;
-use petgraph::data::Build;
+use petgraph::graph::{Graph, NodeIndex};
 use petgraph::prelude::EdgeRef;
 use petgraph::Undirected;
-use wg_2024::packet::NodeType::{Client,Server};
-use once_cell::sync::Lazy;
+use wg_2024::packet::NodeType::Client;
 use std::sync::Mutex;
 use std::sync::Arc;
-use base64::{decode, Engine};
-use base64::engine::general_purpose::STANDARD;
-use std::io::Cursor;
-use image::{ImageReader, DynamicImage, open};
-use petgraph::visit::NodeIndexable;
-use serde::de::StdError;
-use serde::de::Unexpected::Str;
 use std::cmp::Reverse;
 use std::fs::OpenOptions;
-use std::path::Path;
-use std::time::Duration;
-use bincode::error::IntegerType::Usize;
-use rand::random;
-use crate::simulation_controller::gui_input_queue::{push_gui_message, new_gui_input_queue, SharedGuiInput};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::simulation_controller::gui_input_queue::{SharedGuiInput, SHUTDOWN_COMMAND};
+use crate::simulation_controller::gui_event_queue::{new_gui_event_queue, push_gui_event, GuiEvent, SharedGuiEvents};
+use crate::simulation_controller::sim_log::{new_sim_log, push_sim_event, SharedSimLog, SimEvent, SimEventKind};
+use crate::nodes::protocol::{self, Capability, ChatProtocol, ReceiptKind, ServerService};
+use crate::nodes::reliable::{AckOutcome, ReliableSender};
+use crate::nodes::transfer::{self, Download, DownloadStep, Resume, Upload};
+use crate::nodes::media::{self, MediaEnvelope};
+use crate::nodes::client_node::{ClientKind, ClientNode, ClientSnapshot};
 use std::process::{Command, exit};
 
 
-
-
-//the first two global variable are kept to ensure consistency throughout the various chats
-static FLOOD_IDS: Lazy<Mutex<u64>> = Lazy::new(|| Mutex::new(0));
-static SESSION_IDS: Lazy<Mutex<u64>> = Lazy::new(|| Mutex::new(0));
-//the chatting status is used to keep track of: chat activity, user we are chatting with, server we are connected to
-static CHATTING_STATUS: Lazy<Mutex<(bool , NodeId , NodeId)>> = Lazy::new(|| Mutex::new((false , 0 , 0)));
-
 #[derive(Debug,Clone)]
 pub struct MyClient{
     pub id: NodeId,
@@ -53,13 +39,23 @@ pub struct MyClient{
     pub packet_send: HashMap<NodeId, Sender<Packet>>,
     pub sim_contr_recv: Receiver<DroneCommand>,
     pub shared_senders: Option<Arc<Mutex<HashMap<(NodeId, NodeId), Sender<Packet>>>>>,
-    sent_messages: HashMap<u64, Vec<Fragment>>,
+    sent_messages: ReliableSender,
     net_graph: Graph<u8, u8, Undirected>,
     node_map: HashMap<NodeId , (NodeIndex , NodeType)>,
-    received_packets: HashMap<u64 , Vec<u8>>,
+    received_packets: HashMap<(u64 , NodeId) , Vec<u8>>, // (session, sender) -> fragments so far
     seen_flood_ids : HashSet<(u64 , NodeId)>,
-    simulation_log: Arc<Mutex<Vec<String>>>,
+    server_capabilities: Option<Vec<Capability>>, //what the server agreed on at login
+    simulation_log: SharedSimLog,
+    gui_events: SharedGuiEvents, //what arrived from the network, for the chat window
     shortcut_receiver: Option<Receiver<Packet>>,
+    server_services: HashMap<NodeId, Vec<ServerService>>, //what each server answered to our ServerTypeRequest
+    text_files: HashMap<String, NodeId>, //file name -> text server that listed it
+    downloads: HashMap<String, Download>, //media being fetched chunk by chunk
+    uploads: HashMap<String, Upload>, //media being sent chunk by chunk
+    downloads_dir: PathBuf, //where downloaded media are saved
+    flood_ids: u64, //last flood id used
+    session_ids: u64, //next session id; both are per client, so a replay numbers them the same way
+    chatting_status: (bool , NodeId , NodeId), //chat activity, user we are chatting with, server we are connected to
 
 }
 
@@ -77,17 +73,53 @@ impl MyClient {
             sim_contr_recv: crossbeam_channel::never(),
             packet_recv,
             packet_send,
-            sent_messages: HashMap::new(),
+            sent_messages: ReliableSender::new(),
             net_graph: Graph::<u8, u8, Undirected>::new_undirected(),
             node_map: HashMap::new(),
             received_packets: HashMap::new(),
             seen_flood_ids: HashSet::new(),
-            simulation_log: Arc::new(Mutex::new(Vec::new())),
+            server_capabilities: None,
+            simulation_log: new_sim_log(),
+            gui_events: new_gui_event_queue(),
             shared_senders,
             shortcut_receiver,
+            server_services: HashMap::new(),
+            text_files: HashMap::new(),
+            downloads: HashMap::new(),
+            uploads: HashMap::new(),
+            downloads_dir: media::downloads_dir(Path::new(media::DEFAULT_DOWNLOADS_DIR), id),
+            flood_ids: 0,
+            session_ids: 0,
+            chatting_status: (false , 0 , 0),
         }
     }
 
+    fn save_download(&self, media_name: &str, media_data: &[u8]) {
+        let envelope = MediaEnvelope::open(media_name, media_data);
+        match envelope.save_in(&self.downloads_dir) {
+            Ok(path) => {
+                info!("💾 Saved '{}' ({}, {} bytes) to {}", media_name, envelope.content_type, envelope.data.len(), path.display());
+                push_gui_event(&self.gui_events, self.id, GuiEvent::MediaDownloaded {
+                    name: media_name.to_string(),
+                    file_name: envelope.file_name,
+                    content_type: envelope.content_type,
+                    size: envelope.data.len(),
+                    path: path.display().to_string(),
+                });
+            }
+            Err(e) => {
+                warn!("Failed to save media {} in {}: {}", media_name, self.downloads_dir.display(), e);
+                push_gui_event(&self.gui_events, self.id, GuiEvent::Error { reason: format!("media_save_failed::{}", media_name) });
+            }
+        }
+    }
+
+    fn servers_offering(&self, service: ServerService) -> Vec<NodeId> {
+        let mut servers: Vec<NodeId> = self.server_services.iter().filter(|(_, services)| services.contains(&service)).map(|(&id, _)| id).collect();
+        servers.sort();
+        servers
+    }
+
     pub(crate) fn run(&mut self, gui_input: SharedGuiInput) {
         let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
             self.inner_run(gui_input.clone());
@@ -149,6 +181,18 @@ impl MyClient {
         }
     }
 
+    fn dispatch_gui_command(&mut self, command_string: String) {
+        match self.process_gui_command(command_string) {
+            Ok(Some(message)) => {
+                self.send_packet(message);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("⚠ Error during process_gui_command: {:?}", e);
+            }
+        }
+    }
+
     fn inner_run(&mut self, gui_input: SharedGuiInput) {
         info!("Client {} starting run loop", self.id);
         self.send_flood_request();
@@ -158,20 +202,18 @@ impl MyClient {
                     if !msgs.is_empty() {
                         let msg = msgs.remove(0);
                         drop(map); // Release lock early
-                        match self.process_gui_command(msg) {
-                            Ok(message) => {
-                                if message != "NO_CHAT_COMMAND" {
-                                    self.send_packet(message);
-                                }
-                            }
-                            Err(e) => {
-                                warn!("⚠ Error during process_gui_command: {:?}", e);
-                            }
+                        if msg == SHUTDOWN_COMMAND {
+                            info!("🛑 Client {} stopping on controller request", self.id);
+                            return;
                         }
+                        self.dispatch_gui_command(msg);
                     }
                 }
             }
 
+            self.retransmit_unacked_fragments();
+            self.resume_stalled_transfers();
+
             select_biased! {
             recv(self.packet_recv) -> first_packet => {
                 if let Ok(packet) = first_packet {
@@ -210,8 +252,11 @@ impl MyClient {
             PacketType::MsgFragment(fragment) => {
                 self.reassemble_packet(fragment , &mut (packet.clone()));
             },
-            PacketType::Ack(_ack) => {
-                //we just use it to know everything is working fine so no handling is needed
+            PacketType::Ack(ack) => {
+                let acker = packet.routing_header.hops.first().copied().unwrap_or_default();
+                if self.sent_messages.on_ack(packet.session_id, acker, ack.fragment_index) == AckOutcome::Completed {
+                    info!("Message with session_id {} fully acknowledged", packet.session_id);
+                }
             },
             PacketType::Nack(nack ) => {
                 self.process_nack(nack , &mut (packet.clone()));
@@ -225,13 +270,13 @@ impl MyClient {
         updated_header.increase_hop_index();
         let mut updated_request = request.get_incremented(self.id , Client);
         if self.seen_flood_ids.contains(&(request.flood_id , request.initiator_id)) || self.packet_send.len() == 1{
-            let mut response_packet = updated_request.generate_response(SESSION_IDS.lock().unwrap().clone());
+            let mut response_packet = updated_request.generate_response(self.session_ids);
             response_packet.routing_header.hop_index += 1;
             let response_sender = self.packet_send.get(&updated_request.path_trace[updated_request.path_trace.len()-2].0).unwrap();
             response_packet.clone().routing_header.hops;
             (*response_sender).send(response_packet).unwrap_or_default();
             info!("Successfully sent response packet to {:?} from {:?}, RResponse: {:?}" , request.initiator_id , self.id , updated_request.path_trace);
-            self.increment_ids(&SESSION_IDS);
+            self.session_ids += 1;
         }
         else {
             self.seen_flood_ids.insert((request.flood_id , request.initiator_id));
@@ -250,7 +295,7 @@ impl MyClient {
                         path_trace: updated_request.path_trace.clone(),
                     }),
                     routing_header: updated_header.clone(),
-                    session_id:SESSION_IDS.lock().unwrap().clone(),
+                    session_id:self.session_ids,
                 };
                 if Some(*neighbor_id) != sender_id{
                     sender.send(packet.clone()).unwrap_or_default();
@@ -260,7 +305,7 @@ impl MyClient {
                 }
 
             }
-            self.increment_ids(&SESSION_IDS);
+            self.session_ids += 1;
         }
     }
 
@@ -290,17 +335,21 @@ impl MyClient {
 
     fn reassemble_packet(&mut self, fragment: &Fragment, packet: &mut Packet) {
         let session_id  = packet.session_id;
+        let source_id   = packet.routing_header.hops.first().copied().unwrap_or_default();
         let total_frags = fragment.total_n_fragments as usize;
         let frag_len    = fragment.length as usize;
         let slot_bytes  = 128;
 
+        let buffered_total = self.received_packets.get(&(session_id, source_id)).map(|buf| (buf.len() / slot_bytes) as u64);
+        if let Err(e) = protocol::check_fragment(fragment, buffered_total) {
+            warn!("Client {} dropped fragment {} of session {}: {}", self.id, fragment.fragment_index, session_id, e);
+            return;
+        }
+
         let (need_ack, is_complete) = {
             let buf = self.received_packets
-                .entry(session_id)
+                .entry((session_id, source_id))
                 .or_insert_with(|| vec![0u8; total_frags * slot_bytes]);
-            if buf.len() != total_frags * slot_bytes {
-                buf.resize(total_frags * slot_bytes, 0);
-            }
 
             let offset = (fragment.fragment_index as usize) * slot_bytes;
             buf[offset .. offset + frag_len]
@@ -326,37 +375,39 @@ impl MyClient {
         }
 
         if is_complete {
-            let buf = self.received_packets.remove(&session_id).unwrap();
+            let buf = self.received_packets.remove(&(session_id, source_id)).unwrap();
             let full_len= (total_frags - 1) * slot_bytes + frag_len;
             let message= buf[..full_len].to_vec();
 
-            self.packet_command_handling(message);
+            self.packet_command_handling(message, source_id);
             info!("👻👻👻👻👻👻  Packet with session_id {} fully reassembled 👻👻👻👻👻👻", session_id);
         }
     }
 
     fn process_nack(&mut self, nack: &Nack, packet: &mut Packet) {
-        let message_list = self.sent_messages.clone();
         match nack.nack_type {
             NackType::Dropped => {
                 self.increase_cost(packet.routing_header.hops[0]); // to properly use our pathfinding algorithms the links between the drones are weighted based on the number of "Dropped" Nacks we receive from each drone
-                if let Some(fragments) = message_list.get(&packet.session_id) {
-                    for fragment in fragments {
-                        if fragment.fragment_index == nack.fragment_index {
-                            info!("Fragment found");
-                            let mut new_packet = Packet {
-                                routing_header: SourceRoutingHeader {
-                                    hop_index: 1, // the hop index is initialized to 1 to stay consistent with the logic of the drones
-                                    hops: self.best_path(self.id, (*CHATTING_STATUS.lock().unwrap()).2).unwrap(),
-                                },
-                                session_id: packet.session_id,
-                                pack_type: MsgFragment(fragment.clone()),
-                            };
-                            let sender_id = &new_packet.routing_header.hops[1];
-                            let sender = self.packet_send.get(sender_id).unwrap();
-                            (*sender).send(new_packet.clone()).unwrap_or_default();
-                            info!("resending packet:{:?}", new_packet.clone());
+                let resend = self.sent_messages.for_nack(packet.session_id, &packet.routing_header.hops).and_then(|session| {
+                    session.mark_resent(nack.fragment_index);
+                    session.fragment(nack.fragment_index).map(|fragment| (fragment.clone(), session.destination))
+                });
+                if let Some((fragment, destination)) = resend {
+                    info!("Fragment found");
+                    if let Some(hops) = self.best_path(self.id, destination) {
+                        let routing_header = SourceRoutingHeader {
+                            hop_index: 1, // the hop index is initialized to 1 to stay consistent with the logic of the drones
+                            hops,
+                        };
+                        if let Some(session) = self.sent_messages.get_mut(packet.session_id, destination) {
+                            session.reroute(routing_header.clone());
                         }
+                        let new_packet = Packet {
+                            routing_header,
+                            session_id: packet.session_id,
+                            pack_type: MsgFragment(fragment),
+                        };
+                        self.forward_to_next_hop(new_packet);
                     }
                 }
             },
@@ -395,19 +446,29 @@ impl MyClient {
         }
     }
 
-    pub fn send_packet(&mut self, input: String) {
-        let message = input.trim_end();
-        let chunks: Vec<&[u8]> = message.as_bytes().chunks(128).collect();
+    pub fn send_packet(&mut self, message: ChatProtocol) {
+        let target = self.chatting_status.2;
+        self.send_packet_to(target, message);
+    }
+
+    fn send_packet_to(&mut self, target: NodeId, message: ChatProtocol) {
+        let data = match message.encode() {
+            Ok(data) => data,
+            Err(e) => {
+                error!("❌ Could not send message to {}: {}", target, e);
+                return;
+            }
+        };
+        let chunks: Vec<&[u8]> = data.chunks(128).collect();
         let total_fragments = chunks.len() as u64;
-        let target = (*CHATTING_STATUS.lock().unwrap()).2;
 
         let Some(hops) = self.best_path(self.id, target) else {
             error!("❌ Still no best path. Aborting message.");
-            self.log("Client could not calculate a best path after".to_string());
+            self.log(SimEventKind::Topology, "Client could not calculate a best path after".to_string());
             return;
         };
 
-        let session_id = SESSION_IDS.lock().unwrap().clone();
+        let session_id = self.session_ids;
 
         for (i, chunk) in chunks.iter().enumerate() {
             let mut data = [0u8; 128];
@@ -431,7 +492,7 @@ impl MyClient {
 
             println!("♥♥ BEST PATH IS : {:?}", packet.routing_header.hops);
 
-            self.sent_messages.entry(session_id).or_insert_with(Vec::new).push(fragment);
+            self.sent_messages.track(session_id, packet.routing_header.clone(), vec![fragment]);
 
             if let Some(&next_hop) = packet.routing_header.hops.get(packet.routing_header.hop_index) {
                 if let Some(sender) = self.packet_send.get(&next_hop) {
@@ -457,20 +518,87 @@ impl MyClient {
             }
         }
 
-        self.increment_ids(&SESSION_IDS);
+        self.session_ids += 1;
+    }
+
+    fn retransmit_unacked_fragments(&mut self) {
+        let timeouts = self.sent_messages.poll_timeouts(Instant::now());
+        for session in timeouts.given_up {
+            warn!("❌ Giving up on session {}: {} fragment(s) never acknowledged by {}", session.session_id, session.unacked(), session.destination);
+            self.log_session(SimEventKind::Delivery, session.session_id, format!("Client {} could not deliver session {} to {}", self.id, session.session_id, session.destination));
+        }
+        for retransmission in timeouts.resend {
+            info!("⏰ Resending fragment {} of session {} after ACK timeout", retransmission.fragment.fragment_index, retransmission.session_id);
+            self.forward_to_next_hop(Packet {
+                routing_header: retransmission.routing_header,
+                session_id: retransmission.session_id,
+                pack_type: MsgFragment(retransmission.fragment),
+            });
+        }
+    }
+
+    //a transfer that made no progress for a while asks again from its last verified chunk
+    fn resume_stalled_transfers(&mut self) {
+        let now = Instant::now();
+        let mut resend = Vec::new();
+        let mut given_up = Vec::new();
+        for download in self.downloads.values_mut() {
+            match download.poll(now) {
+                Resume::Resend(request) => resend.push((download.server_id, request)),
+                Resume::GiveUp => given_up.push(download.name.clone()),
+                Resume::Wait => {},
+            }
+        }
+        for upload in self.uploads.values_mut() {
+            match upload.poll(now) {
+                Resume::Resend(chunk) => resend.push((upload.server_id, chunk)),
+                Resume::GiveUp => given_up.push(upload.name.clone()),
+                Resume::Wait => {},
+            }
+        }
+        for name in given_up {
+            warn!("❌ Giving up on the transfer of media {}", name);
+            self.downloads.remove(&name);
+            self.uploads.remove(&name);
+            push_gui_event(&self.gui_events, self.id, GuiEvent::Error { reason: format!("media_transfer_failed::{}", name) });
+        }
+        for (server_id, message) in resend {
+            info!("⏯ Resuming {} with server {}", message.tag(), server_id);
+            self.send_packet_to(server_id, message);
+        }
+    }
+
+    //asking again for a media that is still downloading picks up where it stopped
+    fn start_download(&mut self, server_id: NodeId, name: &str) {
+        let download = self.downloads.entry(name.to_string()).or_insert_with(|| Download::new(server_id, name));
+        if download.server_id != server_id {
+            *download = Download::new(server_id, name);
+        }
+        let request = download.next_request();
+        self.send_packet_to(server_id, request);
     }
 
-    fn send_flood_request(&self) {
-        println!("🦋🦋🦋Incrementing FLOOD_IDS...");
-        self.increment_ids(&FLOOD_IDS);
+    fn forward_to_next_hop(&self, packet: Packet) {
+        match packet.routing_header.hops.get(packet.routing_header.hop_index).and_then(|hop| self.packet_send.get(hop)) {
+            Some(sender) => {
+                sender.send(packet.clone()).unwrap_or_default();
+                info!("resending packet:{:?}", packet);
+            }
+            None => warn!("❌ No sender found for next hop in path {:?}", packet.routing_header.hops),
+        }
+    }
+
+    fn send_flood_request(&mut self) {
+        println!("🦋🦋🦋Incrementing the flood id...");
+        self.flood_ids += 1;
 
         println!("🦋🦋🦋Building FloodRequest...");
-        let flood_request = FloodRequest::initialize(FLOOD_IDS.lock().unwrap().clone(), self.id, Client);
+        let flood_request = FloodRequest::initialize(self.flood_ids, self.id, Client);
 
         println!("🦋🦋🦋Building Packet...");
         let packet = Packet {
             pack_type: PacketType::FloodRequest(flood_request),
-            session_id: SESSION_IDS.lock().unwrap().clone(),
+            session_id: self.session_ids,
             routing_header: SourceRoutingHeader {
                 hop_index: 0,
                 hops: vec![self.id],
@@ -483,8 +611,8 @@ impl MyClient {
                 println!("❌ Error sending FloodRequest: {:?}", e);
             }
         }
-        info!("🦋🦋🦋Starting the flood n. {}", FLOOD_IDS.lock().unwrap());
-        self.increment_ids(&SESSION_IDS);
+        info!("🦋🦋🦋Starting the flood n. {}", self.flood_ids);
+        self.session_ids += 1;
     }
 
 
@@ -512,22 +640,69 @@ impl MyClient {
         }
     }
 
-    fn packet_command_handling(&self, message : Vec<u8>) {
-        let chatting_status = *CHATTING_STATUS.lock().unwrap();
-        let message_string = String::from_utf8_lossy(&message).to_string();
-        let tokens: Vec<&str> = message_string.trim().splitn(3, "::").collect();
-        match tokens.as_slice() {
-            ["[LoginAck]" , _session]=>{
+    fn packet_command_handling(&mut self, message : Vec<u8>, source_id: NodeId) {
+        let chatting_status = self.chatting_status;
+        let message = match ChatProtocol::decode(&message) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Wrong message format. The message doesn't respect any known format: {}", e);
+                return;
+            }
+        };
+        if let Some(event) = GuiEvent::from_message(source_id, &message) {
+            push_gui_event(&self.gui_events, self.id, event);
+        }
+        match message {
+            ChatProtocol::LoginAck { version, capabilities, .. }=>{
                 info!("You successfully logged in!");
+                self.log(SimEventKind::Login, format!("Client {} logged in with protocol v{} and capabilities {:?}", self.id, version, capabilities));
+                self.server_capabilities = Some(capabilities);
             },
-            ["[MessageFrom]", client_id_str, msg]=>{
-                let client_id: NodeId = client_id_str.parse().unwrap_or_default();
-
+            ChatProtocol::MessageFrom { sender_id: client_id, message_id, message: msg }=>{
                 self.change_chat_status(true, client_id , chatting_status.2);
                 info!("Received message from client id {}. Message : {}", client_id , msg);
+                self.send_packet(ChatProtocol::Receipt { peer_id: client_id, message_id, kind: ReceiptKind::Delivered });
+            },
+            ChatProtocol::Receipt { peer_id, message_id, kind }=>{
+                info!("Message {} was {} by client {}", message_id, kind.name(), peer_id);
+            },
+            ChatProtocol::ServerTypeResponse { services }=>{
+                info!("Server {} offers: {:?}", source_id, services);
+                self.server_services.insert(source_id, services);
             },
-            ["[ChatStart]", success]=>{
-                if success.trim_end_matches('\0') == "true"{
+            ChatProtocol::TextListResponse { files }=>{
+                info!("Text files on server {}: {:?}", source_id, files);
+                for name in files {
+                    self.text_files.insert(name, source_id);
+                }
+            },
+            ChatProtocol::TextUploadAck { name }=>{
+                info!("The text file {} has been published on server {}", name, source_id);
+                self.text_files.insert(name, source_id);
+            },
+            ChatProtocol::TextResponse { name, content }=>{
+                //the media the document links come from a media server, as a browser would do
+                let media = protocol::referenced_media(&content);
+                info!("Received text file {} linking {:?}", name, media);
+                match self.servers_offering(ServerService::Media).first() {
+                    Some(&media_server) => for media_name in media {
+                        self.start_download(media_server, &media_name);
+                    },
+                    None if !media.is_empty() => warn!("No media server known, can't fetch {:?}", media),
+                    None => {},
+                }
+            },
+            ChatProtocol::RoomMessageFrom { room, sender_id, message, .. }=>{
+                info!("Received message from client id {} in room {}. Message : {}", sender_id, room, message);
+            },
+            ChatProtocol::RoomUpdate { room, members }=>{
+                info!("Members of room {}: {:?}", room, members);
+            },
+            ChatProtocol::RoomListResponse { rooms }=>{
+                info!("Rooms available on the server: {:?}", rooms);
+            },
+            ChatProtocol::ChatStart { success }=>{
+                if success {
                     info!("Chat started successfully");
                 }
                 else {
@@ -535,55 +710,97 @@ impl MyClient {
                     info!("Chat start failed");
                 }
             },
-            ["[ClientListResponse]", client_list]=>{
-                info!("Clients available for chat: {}" , client_list);
+            ChatProtocol::DeliveryStatus { target_id, status }=>{
+                info!("Message to client {} was {}", target_id, status.name());
+            },
+            ChatProtocol::ClientListResponse { clients: client_list }=>{
+                info!("Clients available for chat: {:?}" , client_list);
             },
-            ["[HistoryResponse]", response]=>{
+            ChatProtocol::HistoryResponse { history: response }=>{
                 info!("Most recent chat history with current client: {}" , response);
             },
-            ["[MediaUploadAck]", media_name]=>{
+            ChatProtocol::MediaUploadAck { name: media_name }=>{
                 info!("The media {} has been uploaded", media_name);
+                self.uploads.remove(&media_name);
+            },
+            ChatProtocol::MediaChunk { name, offset, total_size, chunk_checksum, file_checksum, data }=>{
+                let Some(download) = self.downloads.get_mut(&name) else {
+                    warn!("Received a chunk of {} which is not being downloaded", name);
+                    return;
+                };
+                let server_id = download.server_id;
+                match download.receive(offset, total_size, data, chunk_checksum, file_checksum) {
+                    DownloadStep::Request(request) => {
+                        push_gui_event(&self.gui_events, self.id, GuiEvent::MediaProgress { name, done: download.verified(), total: total_size, upload: false });
+                        self.send_packet_to(server_id, request);
+                    },
+                    DownloadStep::Ignored => info!("Ignoring chunk of {} at offset {}", name, offset),
+                    DownloadStep::Complete(media_data) => {
+                        self.downloads.remove(&name);
+                        // saved as is: the whole file may be larger than a single message
+                        self.save_download(&name, &media_data);
+                    },
+                    DownloadStep::Corrupted => {
+                        warn!("The media {} does not match its checksum", name);
+                        self.downloads.remove(&name);
+                        push_gui_event(&self.gui_events, self.id, GuiEvent::Error { reason: format!("media_checksum_mismatch::{}", name) });
+                    },
+                }
             },
-            ["[MediaListResponse]" , media_list]=>{
-                info!("Here's a list of the media available for download: {}" , media_list);
+            ChatProtocol::MediaUploadChunkAck { name, next_offset }=>{
+                if let Some(upload) = self.uploads.get_mut(&name) {
+                    let server_id = upload.server_id;
+                    if let Some(chunk) = upload.acknowledge(next_offset) {
+                        push_gui_event(&self.gui_events, self.id, GuiEvent::MediaProgress { name, done: next_offset, total: upload.total_size(), upload: true });
+                        self.send_packet_to(server_id, chunk);
+                    }
+                }
             },
-            ["[MediaDownloadResponse]","ERROR","NotFound"]=>{
+            ChatProtocol::MediaListResponse { media: media_list }=>{
+                let names: Vec<&str> = media_list.iter().map(|media| media.name.as_str()).collect();
+                info!("Here's a list of the media available for download: {}" , names.join(","));
+            },
+            ChatProtocol::MediaNotFound=>{
                 info!("The media could not be found.");
+                self.downloads.retain(|_, download| download.server_id != source_id || download.verified() > 0);
             },
-            ["[MediaDownloadResponse]", media_name, base64_data]=>{
-                let base64_data_clean = base64_data.trim_end_matches('\0');
+            ChatProtocol::MediaDownloadResponse { name: media_name, data: media_data }=>{
                 println!("🚀🚀🚀🚀🚀
                         ← client: {} bytes, prefix = {:?}",
-                         base64_data.len(),
-                         &base64_data[0..20]
+                         media_data.len(),
+                         &media_data[0..20.min(media_data.len())]
                 );
-                if let Err(e) = Self::display_media( media_name , base64_data_clean) {
-                    info!("Failed to display image: {}", e);
-                }
+                self.save_download(&media_name, &media_data);
             },
-            ["[MediaBroadcastAck]", media_name, "Broadcasted"]=>{
+            ChatProtocol::MediaBroadcastAck { name: media_name }=>{
                 info!("{} successful broadcast",media_name);
             },
-            _=>{
-                warn!("Wrong message format. The message: {} , doesn't respect any known format", message_string);
+            ChatProtocol::MediaVisibilityAck { name: media_name, visibility }=>{
+                info!("The media {} is now visible to {}", media_name, visibility);
+            },
+            ChatProtocol::MediaDeleteAck { name: media_name }=>{
+                info!("The media {} has been deleted", media_name);
+            },
+            ChatProtocol::Error { reason }=>{
+                warn!("The server refused the request: {}", reason);
+                if let Some(name) = transfer::refused_upload(&reason) {
+                    self.uploads.remove(name);
+                }
+            },
+            other=>{
+                warn!("Wrong message format. The message: {} , isn't expected by a client", other.tag());
             },
         }
     }
 
-    fn process_gui_command(&mut self, command_string: String)->Result<String , Box<dyn std::error::Error>> {
-        let chatting_status = match CHATTING_STATUS.lock() {
-            Ok(guard) => *guard,
-            Err(poisoned) => {
-                eprintln!("⚠ Mutex poisoned! Recovering.");
-                *poisoned.into_inner()
-            }
-        };
+    fn process_gui_command(&mut self, command_string: String)->Result<Option<ChatProtocol> , Box<dyn std::error::Error>> {
+        let chatting_status = self.chatting_status;
         println!("Client {} processing GUI command '{}'", self.id, command_string.clone());
         let tokens: Vec<&str> = command_string.trim().split("::").collect();
         if tokens.len() >= 2 && tokens[0] == "[FloodRequired]" {
             let action = tokens[1..].join("::");
             println!("Client {} received FLOOD REQUIRED command due to action: {}.", self.id, action);
-            self.log(format!("Client {} received a call to flooding the network", self.id));
+            self.log(SimEventKind::Topology, format!("Client {} received a call to flooding the network", self.id));
             if let Some(parts) = action.strip_prefix("AddSender::") {
                 let shared_senders = self.shared_senders.clone();
                 let nodes: Vec<&str> = parts.splitn(2, "::").collect();
@@ -607,7 +824,7 @@ impl MyClient {
                                         println!("Client {} added link to {} via AddSender", self.id, peer);
                                         self.send_flood_request();
                                         info!("retunring from addsender");
-                                        return Ok("NO_CHAT_COMMAND".to_string());
+                                        return Ok(None);
                                     }
                                 }
 
@@ -631,7 +848,7 @@ impl MyClient {
                                     println!("Client {} removed link to {} via RemoveSender", self.id, peer);
                                     self.send_flood_request();
                                     info!("returning from remove sender");
-                                    return Ok("NO_CHAT_COMMAND".to_string());
+                                    return Ok(None);
                                 }
 
                             }
@@ -660,17 +877,17 @@ impl MyClient {
                                                 self.packet_send.insert(drone_id, sender.clone());
                                                 println!("Client {} added sender to drone {} (from shared_senders)", self.id, drone_id);
                                                 self.send_flood_request();
-                                                return Ok("NO_CHAT_COMMAND".to_string());
+                                                return Ok(None);
                                             }
                                             else if *to == self.id && *from == drone_id {
                                                 self.packet_send.insert(drone_id, sender.clone());
                                                 println!("Client {} added sender from drone {} (from shared_senders)", self.id, drone_id);
                                                 self.send_flood_request();
-                                                return Ok("NO_CHAT_COMMAND".to_string());
+                                                return Ok(None);
                                             }
                                         }
                                         self.send_flood_request();
-                                        return Ok("NO_CHAT_COMMAND".to_string());
+                                        return Ok(None);
                                     }
                                 }
                             }
@@ -696,7 +913,7 @@ impl MyClient {
                         self.node_map.remove(&crashed_id).map(|(index, _)| index);
                         self.safe_remove_node(crashed_id);
                         self.send_flood_request();
-                        return Ok("NO_CHAT_COMMAND".to_string());
+                        return Ok(None);
 
                     } else {
                         println!("Client {} received invalid Crash ID: {}", self.id, parts[1]);
@@ -704,91 +921,154 @@ impl MyClient {
                 } else {
                     println!("Client {} received malformed Crash command: {}", self.id, action);
                 }
-                return Ok("NO_CHAT_COMMAND".to_string());
+                return Ok(None);
             }
             //info!("returning from crash");
             //self.send_flood_request();
-            return Ok("NO_CHAT_COMMAND".to_string());
+            return Ok(None);
         }
-        match tokens.as_slice() {
-            ["[Login]", server_id_str] => {
-                let server_id: NodeId = match server_id_str.parse() {
-                    Ok(id) => id,
-                    Err(e) => {
-                        return Err(Box::new(e))
-                    },
-                };
-                self.change_chat_status(false, 0 , server_id);
-                info!("Sending login request to server: {}", chatting_status.2);
-                self.log(format!("Login from client {}",self.id));
-                Ok(command_string)
+        let command: ChatProtocol = match command_string.parse() {
+            Ok(command) => command,
+            Err(e) => {
+                println!("Unknown format");
+                return Err(Box::new(io::Error::new(ErrorKind::NotFound, e)));
+            }
+        };
+        if let (Some(required), Some(agreed)) = (command.required_capability(), &self.server_capabilities) {
+            if !agreed.contains(&required) {
+                return Err(Box::new(io::Error::new(ErrorKind::Unsupported, format!("The server does not support '{}'", required.name()))));
+            }
+        }
+        match &command {
+            ChatProtocol::Login { server_id, .. } => {
+                self.server_capabilities = None;
+                self.change_chat_status(false, 0 , *server_id);
+                info!("Sending login request to server: {}", server_id);
+                self.log(SimEventKind::Login, format!("Login from client {}",self.id));
+                Ok(Some(command))
             },
-            ["[Logout]"] => {
+            ChatProtocol::Logout => {
                 if chatting_status.0 == true { //we make sure to not log out while in the middle of a chat
                     Err(Box::new(io::Error::new(ErrorKind::Interrupted, "You are still in a chat with another user. End the chat before logging out")))
                 } else if chatting_status.2 != 0 {
-                    self.log(format!("Logout from client {}",self.id));
-                    Ok(command_string)
+                    self.server_capabilities = None;
+                    self.log(SimEventKind::Login, format!("Logout from client {}",self.id));
+                    Ok(Some(command))
                 } else { //if we are yet to log in to any server we can log out of it
                     Err(Box::new(io::Error::new(ErrorKind::NotFound, "You have yet to login to any server")))
                 }
             },
-            ["[ClientListRequest]"] => {
+            ChatProtocol::ClientListRequest => {
 
                 info!("Requesting the list of clients available for chat");
-                Ok(command_string)
+                Ok(Some(command))
             },
-            ["[MessageTo]", client_id, message_str] => {
-                self.log(format!("Client {} is sending a message to client {}",self.id , client_id));
+            ChatProtocol::MessageTo { target_id: client_id, message: message_str, .. } => {
+                self.log(SimEventKind::Chat, format!("Client {} is sending a message to client {}",self.id , client_id));
                 info!("Sending message: {} to client {}", message_str, client_id);
-                Ok(command_string)
+                Ok(Some(command))
+            },
+            ChatProtocol::Receipt { peer_id, message_id, kind } => {
+                info!("Sending {} receipt for message {} to client {}", kind.name(), message_id, peer_id);
+                Ok(Some(command))
             },
-            ["[ChatRequest]", client_id] => {
+            ChatProtocol::ChatRequest { peer_id } => {
                 if (chatting_status.0 , chatting_status.1).eq(&(false, 0 )) { //when requesting a chat we need to make sure that we are not in the middle of chatting with someone else
-                    self.log(format!("Client {} is requesting to chat with client: {}", self.id , client_id));
-                    let peer_id: NodeId = match client_id.parse() {
-                        Ok(id) => id,
-                        Err(e) => {
-                            return Err(Box::new(e))
-                        },
-                    };
-                    self.change_chat_status(true , peer_id ,chatting_status.2);
-                    Ok(command_string)
+                    self.log(SimEventKind::Chat, format!("Client {} is requesting to chat with client: {}", self.id , peer_id));
+                    self.change_chat_status(true , *peer_id ,chatting_status.2);
+                    Ok(Some(command))
                 } else {
                     Err(Box::new(io::Error::new(ErrorKind::Interrupted, "You are already in a chat with another user.")))
                 }
             },
-            ["[HistoryRequest]", personal_id, peer_id] => {
+            ChatProtocol::HistoryRequest { client_id: personal_id, target_id: peer_id } => {
                 info!("Requesting chat history between client {} and client {}", personal_id, peer_id);
-                Ok(command_string)
+                Ok(Some(command))
+            },
+            ChatProtocol::RoomCreate { room } | ChatProtocol::RoomJoin { room } | ChatProtocol::RoomLeave { room }
+            | ChatProtocol::RoomMessageTo { room, .. } | ChatProtocol::RoomHistoryRequest { room } => {
+                if chatting_status.2 != 0 { //rooms live on the server we are logged into
+                    self.log(SimEventKind::Room, format!("Client {} sent {} for room {}", self.id, command.tag(), room));
+                    Ok(Some(command))
+                } else {
+                    Err(Box::new(io::Error::new(ErrorKind::NotFound, "You have yet to login to any server")))
+                }
             },
-            ["[MediaUpload]", media_name, encoded_media] => {
-                self.log(format!("Client {} is uploading media with name: {} , encoded as: {}", self.id, media_name, encoded_media));
-                Ok(command_string)
+            ChatProtocol::RoomListRequest => {
+                info!("Requesting the list of rooms to server: {}", chatting_status.2);
+                Ok(Some(command))
             },
-            ["[MediaDownloadRequest]", media_name] => {
-                self.log(format!("Client {} is requesting to download media: {}", self.id, media_name));
-                Ok(command_string)
+            ChatProtocol::TextListRequest => { //text servers answer without a login
+                let servers = self.servers_offering(ServerService::Text);
+                if servers.is_empty() {
+                    return Err(Box::new(io::Error::new(ErrorKind::NotFound, "No text server known, ask the servers for their type first")));
+                }
+                for server_id in servers {
+                    self.send_packet_to(server_id, ChatProtocol::TextListRequest);
+                }
+                Ok(None)
+            },
+            ChatProtocol::TextRequest { name } | ChatProtocol::TextUpload { name, .. } => {
+                let server = self.text_files.get(name).copied().or_else(|| self.servers_offering(ServerService::Text).first().copied());
+                match server {
+                    Some(server_id) => {
+                        info!("Sending {} for {} to text server {}", command.tag(), name, server_id);
+                        self.send_packet_to(server_id, command.clone());
+                        Ok(None)
+                    },
+                    None => Err(Box::new(io::Error::new(ErrorKind::NotFound, "No text server known, ask the servers for their type first"))),
+                }
             },
-            ["[ChatFinish]" , _client_id] => {
+            ChatProtocol::ServerTypeRequest => { //no login needed: we ask every server we found while flooding
+                let mut servers: Vec<NodeId> = self.node_map.iter().filter(|(_, (_, node_type))| *node_type == NodeType::Server).map(|(&id, _)| id).collect();
+                servers.sort();
+                if servers.is_empty() {
+                    self.send_flood_request();
+                    return Err(Box::new(io::Error::new(ErrorKind::NotFound, "No server discovered yet, try again after the flood")));
+                }
+                for server_id in servers {
+                    info!("Asking server {} for its type", server_id);
+                    self.send_packet_to(server_id, ChatProtocol::ServerTypeRequest);
+                }
+                Ok(None)
+            },
+            ChatProtocol::MediaUpload { name: media_name, data: media_data } => {
+                self.log(SimEventKind::Media, format!("Client {} is uploading media with name: {} ({} bytes)", self.id, media_name, media_data.len()));
+                let mut upload = Upload::new(chatting_status.2, media_name, MediaEnvelope::wrap(media_name, media_data.clone()));
+                let chunk = upload.next_chunk();
+                self.uploads.insert(media_name.clone(), upload);
+                self.send_packet_to(chatting_status.2, chunk);
+                Ok(None)
+            },
+            ChatProtocol::MediaDownloadRequest { name: media_name } => {
+                self.log(SimEventKind::Media, format!("Client {} is requesting to download media: {}", self.id, media_name));
+                self.start_download(chatting_status.2, media_name);
+                Ok(None)
+            },
+            ChatProtocol::ChatFinish { .. } => {
                 if chatting_status.0 == true {
-                    self.log(format!("Client {} is trying to end current chat" , self.id));
+                    self.log(SimEventKind::Chat, format!("Client {} is trying to end current chat" , self.id));
                     self.change_chat_status(false , 0 , chatting_status.2);
-                    Ok(command_string)
+                    Ok(Some(command))
                 } else {
                     Err(Box::new(io::Error::new(ErrorKind::Interrupted, "You are not chatting with any user.")))
                 }
             },
-            ["[MediaBroadcast]", media_name, encoded_media] => {
-                self.log(format!("Client {} is broadcasting {} to all connected clients", self.id, media_name));
-                if let Err(e) = Self::display_media( media_name , encoded_media) {
-                    warn!("Failed to display image: {}", e);
-                }
-                Ok(command_string)
+            ChatProtocol::MediaBroadcast { name: media_name, data: media_data } => {
+                self.log(SimEventKind::Media, format!("Client {} is broadcasting {} to all connected clients", self.id, media_name));
+                Ok(Some(ChatProtocol::MediaBroadcast { name: media_name.clone(), data: MediaEnvelope::wrap(media_name, media_data.clone()) }))
             },
-            ["[MediaListRequest]"] => {
+            ChatProtocol::MediaListRequest => {
                 info!("Requesting media list to server: {}" , chatting_status.2);
-                Ok(command_string)
+                Ok(Some(command))
+            },
+            ChatProtocol::MediaSetVisibility { name: media_name, .. } | ChatProtocol::MediaDelete { name: media_name } => {
+                if chatting_status.2 != 0 { //only the owner can do this, so we must be logged in
+                    self.log(SimEventKind::Media, format!("Client {} sent {} for media {}", self.id, command.tag(), media_name));
+                    Ok(Some(command))
+                } else {
+                    Err(Box::new(io::Error::new(ErrorKind::NotFound, "You have yet to login to any server")))
+                }
             },
             _ => {
                 println!("Unknown format");
@@ -797,14 +1077,21 @@ impl MyClient {
         }
     }
 
-    pub fn attach_log(&mut self, log: Arc<Mutex<Vec<String>>>) {
+    pub fn attach_log(&mut self, log: SharedSimLog) {
         self.simulation_log = log;
     }
 
-    fn log(&self, message: impl ToString) {
-        if let Ok(mut log) = self.simulation_log.lock() {
-            log.push(message.to_string());
-        }
+    pub fn attach_gui_events(&mut self, events: SharedGuiEvents) {
+        self.gui_events = events;
+    }
+
+    fn log(&self, kind: SimEventKind, message: impl ToString) {
+        push_sim_event(&self.simulation_log, SimEvent::new(Some(self.id), kind, message));
+    }
+
+    /// Like `log`, for an event about one session, so the log can be filtered by it.
+    fn log_session(&self, kind: SimEventKind, session_id: u64, message: impl ToString) {
+        push_sim_event(&self.simulation_log, SimEvent::new(Some(self.id), kind, message).with_session(session_id));
     }
 
     fn add_node_no_duplicate(graph: &mut Graph<u8, u8, Undirected>, node_map: &mut HashMap<NodeId, (NodeIndex, NodeType)>, value: u8, node_type: NodeType) -> NodeIndex {
@@ -992,162 +1279,53 @@ impl MyClient {
 
 
 
-    fn increment_ids( &self , counter: &Lazy<Mutex<u64>>) {
-        let mut val = counter.lock().unwrap();
-        *val += 1;
+    fn change_chat_status(&mut self, chatting: bool, peer_id: NodeId, server_id: NodeId) {
+        self.chatting_status = (chatting, peer_id, server_id);
     }
+}
 
-    fn change_chat_status(&self, chatting: bool, peer_id: NodeId, server_id: NodeId) {
-        match CHATTING_STATUS.lock() {
-            Ok(mut status) => {
-                status.0 = chatting;
-                status.1 = peer_id;
-                status.2 = server_id;
-            }
-            Err(poisoned) => {
-                eprintln!("⚠ CHATTING_STATUS mutex was poisoned! Recovering and updating anyway.");
-                let mut status = poisoned.into_inner();
-                status.0 = chatting;
-                status.1 = peer_id;
-                status.2 = server_id;
-            }
-        }
+impl ClientNode for MyClient {
+    fn id(&self) -> NodeId {
+        self.id
     }
 
-
-    fn display_media(media_name: &str, base64_data: &str) -> Result<(), Box<dyn std::error::Error>> {
-        // Decode base64
-        let decoded = match STANDARD.decode(base64_data) {
-            Ok(d) => d,
-            Err(e) => {
-                warn!("Base64 decode error: {}", e);
-                return Err(Box::new(e));
-            }
-        };
-
-        // Determine file extension based on media type
-        let file_extension = Self::detect_media_format(&decoded)?;
-        let file_path = format!("{}.{}", media_name, file_extension);
-
-        // Handle different media types
-        match file_extension.as_str() {
-            "png" | "jpg" | "jpeg" | "gif" | "bmp" | "webp" => {
-                Self::display_image(&decoded, &file_path)?;
-            }
-            "mp4" | "avi" | "mov" | "mkv" | "webm" => {
-                Self::play_video(&decoded, &file_path)?;
-            }
-            "mp3" | "wav" | "flac" | "ogg" => {
-                Self::play_audio(&decoded, &file_path)?;
-            }
-            _ => {
-                // For unknown formats, just save and try to open
-                fs::write(&file_path, &decoded)?;
-                Self::open_with_system(&file_path)?;
-            }
-        }
-
-        Ok(())
+    fn attach_log(&mut self, log: SharedSimLog) {
+        MyClient::attach_log(self, log);
     }
-    fn detect_media_format(data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
-        if data.len() < 12 {
-            return Ok("bin".to_string());
-        }
 
-        match &data[0..4] {
-            [0x89, 0x50, 0x4E, 0x47] => Ok("png".to_string()),
-            [0xFF, 0xD8, 0xFF, _] => Ok("jpg".to_string()),
-            [0x47, 0x49, 0x46, 0x38] => Ok("gif".to_string()),
-            [0x42, 0x4D, _, _] => Ok("bmp".to_string()),
-            _ => {
-                if data.len() >= 12 {
-                    match &data[4..12] {
-                        [0x66, 0x74, 0x79, 0x70, 0x69, 0x73, 0x6F, 0x6D] => Ok("mp4".to_string()),
-                        [0x66, 0x74, 0x79, 0x70, 0x6D, 0x70, 0x34, 0x32] => Ok("mp4".to_string()),
-                        _ => {
-                            if data.len() >= 3 && &data[0..3] == [0x49, 0x44, 0x33] {
-                                Ok("mp3".to_string())
-                            } else if data.len() >= 4 && &data[0..4] == [0x52, 0x49, 0x46, 0x46] {
-                                Ok("wav".to_string())
-                            } else if data.len() >= 4 && &data[0..4] == [0x66, 0x4C, 0x61, 0x43] {
-                                Ok("flac".to_string())
-                            } else {
-                                Ok("bin".to_string())
-                            }
-                        }
-                    }
-                } else {
-                    Ok("bin".to_string())
-                }
-            }
-        }
+    fn attach_gui_events(&mut self, events: SharedGuiEvents) {
+        MyClient::attach_gui_events(self, events);
     }
 
-    fn display_image(decoded_data: &[u8], file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        // Read image from memory
-        let cursor = Cursor::new(decoded_data);
-        let img = match ImageReader::new(cursor).with_guessed_format()?.decode() {
-            Ok(i) => i,
-            Err(e) => {
-                warn!("Image decode error: {}", e);
-                return Err(Box::new(e));
-            }
-        };
-
-        // Save image to file
-        if let Err(e) = img.save(file_path) {
-            warn!("Failed to save image: {}", e);
-            return Err(Box::new(e));
-        }
-
-        // Display the image using system default application
-        Self::open_with_system(file_path)?;
-
-        Ok(())
+    fn run(&mut self, gui_input: SharedGuiInput) {
+        MyClient::run(self, gui_input);
     }
 
-    fn play_video(decoded_data: &[u8], file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        // Save video file
-        fs::write(file_path, decoded_data)?;
-
-        // Open with system default video player
-        Self::open_with_system(file_path)?;
-
-        Ok(())
+    fn handle_packet(&mut self, packet: Packet) {
+        self.process_packet(packet);
     }
 
-    fn play_audio(decoded_data: &[u8], file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        // Save audio file
-        fs::write(file_path, decoded_data)?;
-
-        // Open with system default audio player
-        Self::open_with_system(file_path)?;
-
-        Ok(())
+    fn handle_gui_command(&mut self, command: String) {
+        self.dispatch_gui_command(command);
     }
 
-    fn open_with_system(file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        #[cfg(target_os = "windows")]
-        {
-            Command::new("cmd")
-                .args(["/C", "start", "", file_path])
-                .spawn()?;
-        }
-
-        #[cfg(target_os = "macos")]
-        {
-            std::process::Command::new("open")
-                .arg(file_path)
-                .spawn()?;
-        }
-
-        #[cfg(target_os = "linux")]
-        {
-            std::process::Command::new("xdg-open")
-                .arg(file_path)
-                .spawn()?;
-        }
+    fn set_downloads_dir(&mut self, dir: PathBuf) {
+        self.downloads_dir = dir;
+    }
 
-       Ok(())
+    fn snapshot(&self) -> ClientSnapshot {
+        let server = self.chatting_status.2;
+        let mut neighbors: Vec<NodeId> = self.packet_send.keys().copied().collect();
+        neighbors.sort_unstable();
+        let mut known_nodes: Vec<NodeId> = self.node_map.keys().copied().collect();
+        known_nodes.sort_unstable();
+        ClientSnapshot {
+            id: self.id,
+            kind: ClientKind::Client2,
+            connected_server: (server != 0).then_some(server),
+            server_capabilities: self.server_capabilities.clone(),
+            neighbors,
+            known_nodes,
         }
+    }
 }
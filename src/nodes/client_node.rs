@@ -0,0 +1,135 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use crossbeam_channel::{Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use wg_2024::network::NodeId;
+use wg_2024::packet::Packet;
+use crate::nodes::protocol::Capability;
+use crate::nodes::reliable::ReliableSender;
+use crate::nodes::{client1, client2};
+use crate::simulation_controller::gui_input_queue::SharedGuiInput;
+use crate::simulation_controller::gui_event_queue::SharedGuiEvents;
+use crate::simulation_controller::sim_log::SharedSimLog;
+
+/// Which client implementation a `[[client]]` entry runs (`implementation = "client1"` in the TOML).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientKind {
+    Client1,
+    Client2,
+}
+
+impl ClientKind {
+    /// Choice made when the TOML does not name an implementation: the old parity rule
+    /// (by id when there are exactly two clients, by position otherwise).
+    pub fn by_parity(position: usize, id: NodeId, client_count: usize) -> Self {
+        let even = if client_count == 2 { id % 2 == 0 } else { position % 2 == 0 };
+        if even { ClientKind::Client2 } else { ClientKind::Client1 }
+    }
+
+    pub fn build(
+        self,
+        id: NodeId,
+        packet_recv: Receiver<Packet>,
+        packet_send: HashMap<NodeId, Sender<Packet>>,
+        shared_senders: Option<Arc<Mutex<HashMap<(NodeId, NodeId), Sender<Packet>>>>>,
+        shortcut_receiver: Option<Receiver<Packet>>,
+    ) -> Box<dyn ClientNode> {
+        match self {
+            ClientKind::Client1 => Box::new(client1::MyClient::new(id, packet_recv, packet_send, ReliableSender::new(), None, HashSet::new(), shared_senders, shortcut_receiver)),
+            ClientKind::Client2 => Box::new(client2::MyClient::new(id, packet_recv, packet_send, shared_senders, shortcut_receiver)),
+        }
+    }
+}
+
+impl fmt::Display for ClientKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientKind::Client1 => write!(f, "client1"),
+            ClientKind::Client2 => write!(f, "client2"),
+        }
+    }
+}
+
+/// Point-in-time view of a client, the same shape whichever implementation produced it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientSnapshot {
+    pub id: NodeId,
+    pub kind: ClientKind,
+    pub connected_server: Option<NodeId>,
+    pub server_capabilities: Option<Vec<Capability>>,
+    /// Neighbours the client holds a sender for, sorted.
+    pub neighbors: Vec<NodeId>,
+    /// Nodes in the client's view of the topology, sorted.
+    pub known_nodes: Vec<NodeId>,
+}
+
+/// Behaviour shared by every client implementation, so the initializer and the tests can drive either one.
+pub trait ClientNode: Send {
+    fn id(&self) -> NodeId;
+    fn attach_log(&mut self, log: SharedSimLog);
+    /// Queue the client reports received chat messages, lists, login results and errors to.
+    fn attach_gui_events(&mut self, events: SharedGuiEvents);
+    /// Blocks running the client loop, taking GUI commands from `gui_input`.
+    fn run(&mut self, gui_input: SharedGuiInput);
+    fn handle_packet(&mut self, packet: Packet);
+    /// Handles one command in the textual form the GUI pushes (`[Tag]::a::b` or `[FloodRequired]::...`).
+    fn handle_gui_command(&mut self, command: String);
+    /// Directory the client saves downloaded media in.
+    fn set_downloads_dir(&mut self, dir: PathBuf);
+    fn snapshot(&self) -> ClientSnapshot;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::unbounded;
+    use crate::network::initializer::ParsedConfig;
+
+    #[test]
+    fn test_client_implementation_is_read_from_the_toml() {
+        let config: ParsedConfig = toml::from_str(
+            r#"
+            drone = []
+            server = []
+            [[client]]
+            id = 4
+            connected_drone_ids = [1, 2]
+            implementation = "client2"
+            [[client]]
+            id = 5
+            connected_drone_ids = [1]
+            "#,
+        ).expect("config should parse");
+        assert_eq!(config.client[0].implementation, Some(ClientKind::Client2));
+        assert_eq!(config.client[1].implementation, None, "the key is optional");
+        assert!(toml::from_str::<ParsedConfig>(
+            "drone = []\nserver = []\n[[client]]\nid = 4\nconnected_drone_ids = []\nimplementation = \"client3\"\n"
+        ).is_err(), "unknown implementations should be rejected");
+    }
+
+    #[test]
+    fn test_client_kind_falls_back_to_the_parity_rule() {
+        assert_eq!(ClientKind::by_parity(0, 4, 2), ClientKind::Client2);
+        assert_eq!(ClientKind::by_parity(0, 5, 2), ClientKind::Client1);
+        assert_eq!(ClientKind::by_parity(1, 4, 3), ClientKind::Client1);
+        assert_eq!(ClientKind::by_parity(2, 5, 3), ClientKind::Client2);
+    }
+
+    #[test]
+    fn test_both_implementations_are_driven_through_the_trait() {
+        for kind in [ClientKind::Client1, ClientKind::Client2] {
+            let (_tx, rx) = unbounded::<Packet>();
+            let senders = [9, 3].into_iter().map(|id| (id, unbounded::<Packet>().0)).collect();
+            let client = kind.build(42, rx, senders, None, None);
+            let snapshot = client.snapshot();
+            assert_eq!(client.id(), 42);
+            assert_eq!(snapshot.kind, kind);
+            assert_eq!(snapshot.neighbors, vec![3, 9], "{} should report its neighbours sorted", kind);
+            assert_eq!(snapshot.connected_server, None);
+            assert_eq!(snapshot.server_capabilities, None);
+        }
+    }
+}
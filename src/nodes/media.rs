@@ -0,0 +1,163 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use bincode::{Decode, Encode};
+use wg_2024::network::NodeId;
+
+/// Where clients save what they download, one `client_<id>` directory each, unless the TOML says otherwise.
+pub const DEFAULT_DOWNLOADS_DIR: &str = "downloads";
+/// Content type of bytes nothing is known about.
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Marks media bytes that start with an envelope; anything else is a bare file from an older sender.
+const ENVELOPE_MAGIC: &[u8; 4] = b"KME1";
+
+/// Extension -> content type, for files named by the user.
+const CONTENT_TYPES: [(&str, &str); 22] = [
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("bmp", "image/bmp"),
+    ("webp", "image/webp"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("html", "text/html"),
+    ("csv", "text/csv"),
+    ("json", "application/json"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("flac", "audio/flac"),
+    ("ogg", "audio/ogg"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mov", "video/quicktime"),
+    ("avi", "video/x-msvideo"),
+    ("mkv", "video/x-matroska"),
+];
+
+/// What travels as the `data` of media messages: the file as the uploader had it, with its
+/// original name and content type, so the receiver can save it without guessing.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct MediaEnvelope {
+    pub file_name: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl MediaEnvelope {
+    /// Envelope for a file called `file_name`, typed from its extension or, failing that, from its first bytes.
+    pub fn new(file_name: &str, data: Vec<u8>) -> Self {
+        let content_type = content_type_for(file_name).or_else(|| sniff_content_type(&data)).unwrap_or(OCTET_STREAM);
+        Self { file_name: file_name.to_string(), content_type: content_type.to_string(), data }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = ENVELOPE_MAGIC.to_vec();
+        bytes.extend(bincode::encode_to_vec(self, bincode::config::standard()).expect("encoding a MediaEnvelope into a Vec cannot fail"));
+        bytes
+    }
+
+    /// Reads the envelope in `bytes`; bytes without one (older clients, files put on a server by hand)
+    /// become an envelope named `media_name`.
+    pub fn open(media_name: &str, bytes: &[u8]) -> Self {
+        bytes
+            .strip_prefix(ENVELOPE_MAGIC)
+            .and_then(|payload| bincode::decode_from_slice(payload, bincode::config::standard()).ok())
+            .map(|(envelope, _)| envelope)
+            .unwrap_or_else(|| Self::new(media_name, bytes.to_vec()))
+    }
+
+    /// `bytes` wrapped for sending, left as they are when they already carry an envelope.
+    pub fn wrap(media_name: &str, bytes: Vec<u8>) -> Vec<u8> {
+        if Self::is_enveloped(&bytes) {
+            bytes
+        } else {
+            Self::new(media_name, bytes).encode()
+        }
+    }
+
+    fn is_enveloped(bytes: &[u8]) -> bool {
+        bytes
+            .strip_prefix(ENVELOPE_MAGIC)
+            .is_some_and(|payload| bincode::decode_from_slice::<MediaEnvelope, _>(payload, bincode::config::standard()).is_ok())
+    }
+
+    /// Writes the file into `dir` under its original name (path components dropped) and returns where.
+    pub fn save_in(&self, dir: &Path) -> io::Result<PathBuf> {
+        let file_name = Path::new(&self.file_name)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| !name.is_empty() && *name != "..")
+            .unwrap_or("download");
+        fs::create_dir_all(dir)?;
+        let path = dir.join(file_name);
+        fs::write(&path, &self.data)?;
+        Ok(path)
+    }
+}
+
+/// Downloads directory of one client.
+pub fn downloads_dir(base: &Path, client_id: NodeId) -> PathBuf {
+    base.join(format!("client_{}", client_id))
+}
+
+/// Content type for the extension of `file_name`, if it is a known one.
+pub fn content_type_for(file_name: &str) -> Option<&'static str> {
+    let extension = Path::new(file_name).extension()?.to_str()?.to_lowercase();
+    CONTENT_TYPES.iter().find(|(ext, _)| *ext == extension).map(|(_, content_type)| *content_type)
+}
+
+/// Content type from the magic bytes of the common formats.
+pub fn sniff_content_type(data: &[u8]) -> Option<&'static str> {
+    let content_type = match data {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [b'B', b'M', ..] => "image/bmp",
+        [b'%', b'P', b'D', b'F', ..] => "application/pdf",
+        [b'I', b'D', b'3', ..] => "audio/mpeg",
+        [b'f', b'L', b'a', b'C', ..] => "audio/flac",
+        [b'O', b'g', b'g', b'S', ..] => "audio/ogg",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => "audio/wav",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => "video/mp4",
+        _ => return None,
+    };
+    Some(content_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_any_file_type_round_trips_in_an_envelope() {
+        let files: Vec<(&str, Vec<u8>, &str)> = vec![
+            ("notes.txt", b"hello".to_vec(), "text/plain"),
+            ("paper.pdf", b"%PDF-1.7 ...".to_vec(), "application/pdf"),
+            ("song.mp3", vec![b'I', b'D', b'3', 4, 0, 0], "audio/mpeg"),
+            ("clip.mp4", vec![0, 0, 0, 24, b'f', b't', b'y', b'p', b'm', b'p', b'4', b'2'], "video/mp4"),
+            ("blob.xyz", vec![0, 1, 2], OCTET_STREAM),
+        ];
+        for (file_name, data, content_type) in files {
+            let envelope = MediaEnvelope::new(file_name, data);
+            assert_eq!(envelope.content_type, content_type, "{}", file_name);
+            let bytes = envelope.encode();
+            assert_eq!(MediaEnvelope::open("renamed", &bytes), envelope, "name, type and bytes survive the trip");
+            assert_eq!(MediaEnvelope::wrap(file_name, bytes.clone()), bytes, "wrapping twice changes nothing");
+        }
+        let raw = MediaEnvelope::open("untitled", b"%PDF-1.4");
+        assert_eq!((raw.file_name.as_str(), raw.content_type.as_str()), ("untitled", "application/pdf"), "bare bytes are sniffed");
+    }
+
+    #[test]
+    fn test_saved_media_cannot_escape_the_downloads_dir() {
+        let dir = TempDir::new("envelope_save");
+        let escaping = MediaEnvelope::new("../../etc/evil.txt", b"x".to_vec());
+        assert_eq!(escaping.save_in(dir.path()).unwrap(), dir.path().join("evil.txt"), "path components are dropped");
+    }
+}
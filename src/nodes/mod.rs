@@ -1,4 +1,10 @@
 pub(crate) mod client1;
 pub(crate) mod client2;
 
-pub(crate) mod server;
\ No newline at end of file
+pub(crate) mod server;
+pub(crate) mod protocol;
+pub(crate) mod reliable;
+pub(crate) mod client_node;
+pub(crate) mod storage;
+pub(crate) mod transfer;
+pub(crate) mod media;
\ No newline at end of file
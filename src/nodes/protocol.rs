@@ -0,0 +1,1144 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use wg_2024::network::NodeId;
+use wg_2024::packet::Fragment;
+use crate::nodes::media;
+
+/// Size of the fixed header written in front of every encoded message: protocol version (u16),
+/// capability bitmask (u16) and payload length (u32), all little-endian.
+const HEADER_SIZE: usize = 8;
+/// Largest bincode payload a host sends or accepts: a whole media up to the default quota
+/// (`storage::DEFAULT_MEDIA_QUOTA`) plus room for its name and envelope.
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024 + 64 * 1024;
+/// Most fragments a peer may split one frame into: the header plus a `MAX_MESSAGE_SIZE` payload.
+pub const MAX_FRAGMENTS: u64 = (HEADER_SIZE + MAX_MESSAGE_SIZE).div_ceil(128) as u64;
+
+/// Checks a received fragment before any reassembly buffer is allocated or indexed with it.
+/// `buffered_total` is the fragment count of the session already being reassembled, if any.
+pub fn check_fragment(fragment: &Fragment, buffered_total: Option<u64>) -> Result<(), String> {
+    if fragment.total_n_fragments > MAX_FRAGMENTS {
+        return Err(format!("{} fragments announced, over the {} fragments limit", fragment.total_n_fragments, MAX_FRAGMENTS));
+    }
+    if fragment.fragment_index >= fragment.total_n_fragments {
+        return Err(format!("fragment {} out of {} fragments", fragment.fragment_index, fragment.total_n_fragments));
+    }
+    if fragment.length as usize > 128 {
+        return Err(format!("fragment length {} over 128 bytes", fragment.length));
+    }
+    match buffered_total {
+        Some(total) if total != fragment.total_n_fragments => {
+            Err(format!("{} fragments announced but {} already buffered for this session", fragment.total_n_fragments, total))
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Bincode configuration of the wire format; the limit stops a forged length from allocating more than that.
+fn wire_config() -> impl bincode::config::Config {
+    bincode::config::standard().with_limit::<MAX_MESSAGE_SIZE>()
+}
+
+/// Version of the protocol spoken by this build, announced in the header of every frame.
+/// Bump it with every change to the bincode layout of `ChatProtocol`:
+/// - 2: `message_id` on `MessageTo`/`MessageFrom`, new `Receipt`
+/// - 3: `timestamp` on `Relay`
+/// - 4: `ServerTypeRequest`/`ServerTypeResponse` moved after `Error`, keeping the older variants' indices
+pub const PROTOCOL_VERSION: u16 = 4;
+/// Oldest version whose frames are still decoded; older ones are refused from their header alone.
+/// Raise it along with `PROTOCOL_VERSION` when existing variants change, not only new ones get appended.
+pub const MIN_PROTOCOL_VERSION: u16 = 4;
+
+/// Optional features negotiated on login; a host only sends requests the other side agreed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Encode, Decode)]
+pub enum Capability {
+    Chat,
+    History,
+    Media,
+    Broadcast,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 4] = [Capability::Chat, Capability::History, Capability::Media, Capability::Broadcast];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Chat => "chat",
+            Capability::History => "history",
+            Capability::Media => "media",
+            Capability::Broadcast => "broadcast",
+        }
+    }
+
+    /// Bit of the capability in a frame header. Never reuse a bit: older peers read them all.
+    pub fn bit(&self) -> u16 {
+        match self {
+            Capability::Chat => 1,
+            Capability::History => 1 << 1,
+            Capability::Media => 1 << 2,
+            Capability::Broadcast => 1 << 3,
+        }
+    }
+}
+
+/// Fixed-layout header in front of every bincode payload. Its layout never changes, so a host can read the
+/// version and capabilities of any peer, and refuse one it can't decode, before looking at the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u16,
+    pub capabilities: Vec<Capability>,
+}
+
+impl FrameHeader {
+    /// Header of every frame this build sends.
+    pub fn current() -> Self {
+        Self { version: PROTOCOL_VERSION, capabilities: Capability::ALL.to_vec() }
+    }
+
+    /// Reads the header of a reassembled frame, with the length of the payload that follows it.
+    /// Bits of capabilities this build does not know are ignored.
+    pub fn read(bytes: &[u8]) -> Result<(Self, usize), String> {
+        let header: [u8; HEADER_SIZE] = bytes
+            .get(..HEADER_SIZE)
+            .and_then(|h| h.try_into().ok())
+            .ok_or_else(|| format!("frame of {} bytes is too short for the header", bytes.len()))?;
+        let version = u16::from_le_bytes([header[0], header[1]]);
+        let mask = u16::from_le_bytes([header[2], header[3]]);
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let capabilities = Capability::ALL.into_iter().filter(|c| mask & c.bit() != 0).collect();
+        Ok((Self { version, capabilities }, length))
+    }
+
+    fn write(&self, length: usize, frame: &mut Vec<u8>) {
+        let mask = self.capabilities.iter().fold(0u16, |mask, c| mask | c.bit());
+        frame.extend_from_slice(&self.version.to_le_bytes());
+        frame.extend_from_slice(&mask.to_le_bytes());
+        frame.extend_from_slice(&(length as u32).to_le_bytes());
+    }
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Capability::ALL
+            .into_iter()
+            .find(|c| c.name() == s.trim().to_lowercase())
+            .ok_or_else(|| format!("unknown capability '{}'", s))
+    }
+}
+
+/// Result of a login handshake: the lower of the two versions and the capabilities both sides offer,
+/// in the order the answering side lists them.
+pub fn negotiate(offered_version: u16, offered: &[Capability], own_version: u16, own: &[Capability]) -> (u16, Vec<Capability>) {
+    let capabilities = own.iter().copied().filter(|c| offered.contains(c)).collect();
+    (offered_version.min(own_version), capabilities)
+}
+
+/// Service a server advertises in its `ServerTypeResponse`, so clients can pick one before logging in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Encode, Decode, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerService {
+    Chat,
+    Media,
+    /// Stores text files clients can list and fetch.
+    Text,
+}
+
+impl ServerService {
+    pub const ALL: [ServerService; 3] = [ServerService::Chat, ServerService::Media, ServerService::Text];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerService::Chat => "chat",
+            ServerService::Media => "media",
+            ServerService::Text => "text",
+        }
+    }
+
+    /// Login capabilities that only make sense on a server offering this service.
+    pub fn capabilities(&self) -> &'static [Capability] {
+        match self {
+            ServerService::Chat => &[Capability::Chat, Capability::History],
+            ServerService::Media => &[Capability::Media, Capability::Broadcast],
+            ServerService::Text => &[],
+        }
+    }
+}
+
+impl FromStr for ServerService {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ServerService::ALL
+            .into_iter()
+            .find(|service| service.name() == s.trim().to_lowercase())
+            .ok_or_else(|| format!("unknown server service '{}'", s))
+    }
+}
+
+/// What the server did with a `MessageTo`, reported back to its sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Encode, Decode)]
+pub enum DeliveryStatus {
+    /// The target is logged out; the message waits in its offline queue.
+    Queued,
+    /// The message was forwarded to the target.
+    Delivered,
+    /// Unknown target or full offline queue; the message was dropped.
+    Rejected,
+}
+
+impl DeliveryStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Queued => "queued",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Rejected => "rejected",
+        }
+    }
+}
+
+impl FromStr for DeliveryStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [DeliveryStatus::Queued, DeliveryStatus::Delivered, DeliveryStatus::Rejected]
+            .into_iter()
+            .find(|d| d.name() == s.trim().to_lowercase())
+            .ok_or_else(|| format!("unknown delivery status '{}'", s))
+    }
+}
+
+/// Receipt the destination client sends back for a chat message, routed to the sender through the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Encode, Decode)]
+pub enum ReceiptKind {
+    /// The message was fully reassembled by the destination client.
+    Delivered,
+    /// The chat window displayed it.
+    Read,
+}
+
+impl ReceiptKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReceiptKind::Delivered => "delivered",
+            ReceiptKind::Read => "read",
+        }
+    }
+}
+
+impl FromStr for ReceiptKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [ReceiptKind::Delivered, ReceiptKind::Read]
+            .into_iter()
+            .find(|k| k.name() == s.trim().to_lowercase())
+            .ok_or_else(|| format!("unknown receipt '{}'", s))
+    }
+}
+
+/// One chat message as the servers store and replicate it. `(sender_id, message_id)` identifies it on
+/// every server, `timestamp` (ms since the Unix epoch, from the first server that stored it) orders it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Encode, Decode, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub sender_id: NodeId,
+    pub message_id: u64,
+    pub timestamp: u64,
+    pub message: String,
+}
+
+impl HistoryEntry {
+    /// Entry stamped with the current time.
+    pub fn new(sender_id: NodeId, message_id: u64, message: String) -> Self {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default();
+        Self { sender_id, message_id, timestamp, message }
+    }
+
+    pub fn id(&self) -> (NodeId, u64) {
+        (self.sender_id, self.message_id)
+    }
+
+    /// Position in a merged history; ties on the timestamp are broken by the id so every server agrees.
+    pub fn order(&self) -> (u64, NodeId, u64) {
+        (self.timestamp, self.sender_id, self.message_id)
+    }
+}
+
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:\n {}", self.sender_id, self.message)
+    }
+}
+
+/// Who may list and download a media; its owner always can.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Encode, Decode, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaVisibility {
+    Owner,
+    Clients(Vec<NodeId>),
+    #[default]
+    Everyone,
+}
+
+impl fmt::Display for MediaVisibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaVisibility::Owner => write!(f, "owner"),
+            MediaVisibility::Everyone => write!(f, "everyone"),
+            MediaVisibility::Clients(clients) => write!(f, "{:?}", clients),
+        }
+    }
+}
+
+impl FromStr for MediaVisibility {
+    type Err = String;
+
+    /// `owner`, `everyone` or the list of clients, `[101, 102]` or `101,102`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "owner" => Ok(MediaVisibility::Owner),
+            "everyone" => Ok(MediaVisibility::Everyone),
+            clients => parse_id_list(clients).map(MediaVisibility::Clients),
+        }
+    }
+}
+
+/// A stored media as a server lists it. The bytes are kept once per `hash` (FNV-1a of the content,
+/// moved to the next free value when other bytes already have it), however many names point at them; `uploaded_at` is in ms since the Unix epoch and `content_type`
+/// comes from the media's envelope (see `nodes::media`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Encode, Decode, Serialize, Deserialize)]
+pub struct MediaInfo {
+    pub name: String,
+    pub size: u64,
+    pub owner: NodeId,
+    pub hash: u64,
+    pub uploaded_at: u64,
+    #[serde(default)]
+    pub visibility: MediaVisibility,
+    #[serde(default = "unknown_content_type")]
+    pub content_type: String,
+}
+
+fn unknown_content_type() -> String {
+    media::OCTET_STREAM.to_string()
+}
+
+impl MediaInfo {
+    pub fn visible_to(&self, client_id: NodeId) -> bool {
+        client_id == self.owner
+            || match &self.visibility {
+                MediaVisibility::Owner => false,
+                MediaVisibility::Clients(clients) => clients.contains(&client_id),
+                MediaVisibility::Everyone => true,
+            }
+    }
+}
+
+/// Summary of one conversation exchanged during anti-entropy: equal digests mean the same set of messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Encode, Decode, Serialize, Deserialize)]
+pub struct ConversationDigest {
+    pub conversation: (NodeId, NodeId),
+    pub count: u32,
+    /// XOR of a hash of every message id, so it does not depend on the order.
+    pub fingerprint: u64,
+}
+
+impl ConversationDigest {
+    pub fn of<'a>(conversation: (NodeId, NodeId), entries: impl IntoIterator<Item = &'a HistoryEntry>) -> Self {
+        let (count, fingerprint) = entries.into_iter().fold((0u32, 0u64), |(count, fingerprint), entry| {
+            (count + 1, fingerprint ^ mix(((entry.sender_id as u64) << 56) ^ entry.message_id))
+        });
+        Self { conversation, count, fingerprint }
+    }
+}
+
+/// splitmix64 finalizer: spreads the bits of an id so XOR-ing many of them rarely cancels out.
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Room names travel inside `::`-separated text and comma-separated lists, so they can't contain either.
+pub fn is_valid_room_name(room: &str) -> bool {
+    !room.trim().is_empty() && room == room.trim() && !room.contains("::") && !room.contains(',')
+}
+
+/// File names travel the same way as room names.
+pub fn is_valid_file_name(name: &str) -> bool {
+    is_valid_room_name(name)
+}
+
+/// Media a text file links with `![alt](name)`, in order of appearance and without repetitions.
+pub fn referenced_media(text: &str) -> Vec<String> {
+    let mut media: Vec<String> = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("![") {
+        rest = &rest[start + 2..];
+        let Some(close) = rest.find("](") else { break };
+        rest = &rest[close + 2..];
+        let Some(end) = rest.find(')') else { break };
+        let name = rest[..end].trim();
+        if !name.is_empty() && !media.iter().any(|m| m == name) {
+            media.push(name.to_string());
+        }
+        rest = &rest[end + 1..];
+    }
+    media
+}
+
+/// High-level messages exchanged between clients and servers once the fragments are reassembled.
+/// On the wire they are bincode-encoded behind a `FrameHeader`; the textual form (`[Tag]::a::b`,
+/// media as base64) is only used by the GUI input queue.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum ChatProtocol {
+    /// The client's version and capabilities are those of the frame header (see `FrameHeader`).
+    Login { server_id: NodeId },
+    LoginAck { session_id: u64, version: u16, capabilities: Vec<Capability> },
+    Logout,
+    ClientListRequest,
+    ClientListResponse { clients: Vec<NodeId> },
+    /// `message_id` is chosen by the sender and, with its id, identifies the message end to end.
+    MessageTo { target_id: NodeId, message_id: u64, message: String },
+    MessageFrom { sender_id: NodeId, message_id: u64, message: String },
+    /// Client to server: `peer_id` is the sender of the message; server to client: the client that produced the receipt.
+    Receipt { peer_id: NodeId, message_id: u64, kind: ReceiptKind },
+    DeliveryStatus { target_id: NodeId, status: DeliveryStatus },
+    ChatRequest { peer_id: NodeId },
+    ChatStart { success: bool },
+    ChatFinish { peer_id: NodeId },
+    HistoryRequest { client_id: NodeId, target_id: NodeId },
+    HistoryResponse { history: String },
+    /// Server to server: messages of one conversation to merge into the local copy (never a replacement).
+    ChatHistoryUpdate { source_server: NodeId, conversation: (NodeId, NodeId), entries: Vec<HistoryEntry> },
+    /// Server to server anti-entropy: the sender's digests; `reply` is set on the answer to another summary.
+    HistorySummary { source_server: NodeId, reply: bool, conversations: Vec<ConversationDigest> },
+    /// Server to server: the clients currently logged into `server_id`.
+    ClientDirectory { server_id: NodeId, clients: Vec<NodeId> },
+    /// Server to server: `message` is for `target_id`, a client logged into the receiving server.
+    /// For a `MessageFrom`, `timestamp` is the one its history entry got on the origin server.
+    Relay { target_id: NodeId, timestamp: u64, message: Box<ChatProtocol> },
+    RoomCreate { room: String },
+    RoomJoin { room: String },
+    RoomLeave { room: String },
+    RoomListRequest,
+    RoomListResponse { rooms: Vec<String> },
+    /// Sent to every logged in member after the room's membership changed, and to whoever just left it.
+    RoomUpdate { room: String, members: Vec<NodeId> },
+    RoomMessageTo { room: String, message_id: u64, message: String },
+    RoomMessageFrom { room: String, sender_id: NodeId, message_id: u64, message: String },
+    RoomHistoryRequest { room: String },
+    MediaUpload { name: String, data: Vec<u8> },
+    MediaUploadAck { name: String },
+    MediaListRequest,
+    MediaListResponse { media: Vec<MediaInfo> },
+    MediaDownloadRequest { name: String },
+    MediaDownloadResponse { name: String, data: Vec<u8> },
+    MediaNotFound,
+    MediaBroadcast { name: String, data: Vec<u8> },
+    MediaBroadcastAck { name: String },
+    /// Chunked transfers (see `nodes::transfer`): every chunk carries its own checksum and the whole file's,
+    /// so a transfer can resume from the last verified offset instead of starting over.
+    MediaChunkRequest { name: String, offset: u64, length: u32 },
+    MediaChunk { name: String, offset: u64, total_size: u64, chunk_checksum: u64, file_checksum: u64, data: Vec<u8> },
+    MediaUploadChunk { name: String, offset: u64, total_size: u64, chunk_checksum: u64, file_checksum: u64, data: Vec<u8> },
+    /// The offset the server expects next; a chunk that did not verify is simply asked again.
+    MediaUploadChunkAck { name: String, next_offset: u64 },
+    /// Owner-only: who else may list and download the media, and its removal.
+    MediaSetVisibility { name: String, visibility: MediaVisibility },
+    MediaVisibilityAck { name: String, visibility: MediaVisibility },
+    MediaDelete { name: String },
+    MediaDeleteAck { name: String },
+    /// Text servers: documents anyone can list and fetch without logging in.
+    TextUpload { name: String, content: String },
+    TextUploadAck { name: String },
+    TextListRequest,
+    TextListResponse { files: Vec<String> },
+    TextRequest { name: String },
+    TextResponse { name: String, content: String },
+    Error { reason: String },
+    /// Sent to any discovered server, logged in or not.
+    ServerTypeRequest,
+    ServerTypeResponse { services: Vec<ServerService> },
+}
+
+impl ChatProtocol {
+    pub fn login(server_id: NodeId) -> Self {
+        ChatProtocol::Login { server_id }
+    }
+
+    /// Capability the receiver must have agreed to before this request is sent.
+    pub fn required_capability(&self) -> Option<Capability> {
+        match self {
+            ChatProtocol::ChatRequest { .. } | ChatProtocol::MessageTo { .. } | ChatProtocol::ChatFinish { .. } | ChatProtocol::Receipt { .. } => Some(Capability::Chat),
+            ChatProtocol::RoomCreate { .. } | ChatProtocol::RoomJoin { .. } | ChatProtocol::RoomLeave { .. } | ChatProtocol::RoomListRequest | ChatProtocol::RoomMessageTo { .. } => Some(Capability::Chat),
+            ChatProtocol::HistoryRequest { .. } | ChatProtocol::RoomHistoryRequest { .. } => Some(Capability::History),
+            ChatProtocol::MediaUpload { .. } | ChatProtocol::MediaListRequest | ChatProtocol::MediaDownloadRequest { .. } => Some(Capability::Media),
+            ChatProtocol::MediaChunkRequest { .. } | ChatProtocol::MediaUploadChunk { .. } => Some(Capability::Media),
+            ChatProtocol::MediaSetVisibility { .. } | ChatProtocol::MediaDelete { .. } => Some(Capability::Media),
+            ChatProtocol::MediaBroadcast { .. } => Some(Capability::Broadcast),
+            _ => None,
+        }
+    }
+
+    /// Service a server must offer to answer this request; `None` for what any server handles.
+    pub fn required_service(&self) -> Option<ServerService> {
+        match self {
+            ChatProtocol::ClientListRequest | ChatProtocol::MessageTo { .. } | ChatProtocol::Receipt { .. } | ChatProtocol::ChatRequest { .. }
+            | ChatProtocol::ChatFinish { .. } | ChatProtocol::HistoryRequest { .. } => Some(ServerService::Chat),
+            ChatProtocol::ChatHistoryUpdate { .. } | ChatProtocol::HistorySummary { .. } | ChatProtocol::ClientDirectory { .. } | ChatProtocol::Relay { .. } => Some(ServerService::Chat),
+            ChatProtocol::RoomCreate { .. } | ChatProtocol::RoomJoin { .. } | ChatProtocol::RoomLeave { .. } | ChatProtocol::RoomListRequest
+            | ChatProtocol::RoomMessageTo { .. } | ChatProtocol::RoomHistoryRequest { .. } => Some(ServerService::Chat),
+            ChatProtocol::MediaUpload { .. } | ChatProtocol::MediaListRequest | ChatProtocol::MediaDownloadRequest { .. } | ChatProtocol::MediaBroadcast { .. } => Some(ServerService::Media),
+            ChatProtocol::MediaChunkRequest { .. } | ChatProtocol::MediaUploadChunk { .. } => Some(ServerService::Media),
+            ChatProtocol::MediaSetVisibility { .. } | ChatProtocol::MediaDelete { .. } => Some(ServerService::Media),
+            ChatProtocol::TextUpload { .. } | ChatProtocol::TextListRequest | ChatProtocol::TextRequest { .. } => Some(ServerService::Text),
+            _ => None,
+        }
+    }
+
+    /// Name and size of the media this request would store, counted against the sender's quota.
+    pub fn media_upload(&self) -> Option<(&str, u64)> {
+        match self {
+            ChatProtocol::MediaUpload { name, data } | ChatProtocol::MediaBroadcast { name, data } => Some((name, data.len() as u64)),
+            ChatProtocol::MediaUploadChunk { name, total_size, .. } => Some((name, *total_size)),
+            _ => None,
+        }
+    }
+
+    /// Bytes that get fragmented and sent over the network: a `FrameHeader` followed by the bincode payload.
+    /// Fails for messages larger than `MAX_MESSAGE_SIZE`, which no peer would accept.
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        let payload = bincode::encode_to_vec(self, wire_config()).map_err(|e| format!("could not encode {}: {}", self.tag(), e))?;
+        if payload.len() > MAX_MESSAGE_SIZE {
+            return Err(format!("{} of {} bytes is over the {} bytes limit", self.tag(), payload.len(), MAX_MESSAGE_SIZE));
+        }
+        let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+        FrameHeader::current().write(payload.len(), &mut frame);
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    /// Decodes a reassembled frame; anything after the announced length (fragment padding) is ignored.
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        Self::decode_frame(bytes).map(|(_, message)| message)
+    }
+
+    /// Like `decode`, also returning the sender's header. Frames older than `MIN_PROTOCOL_VERSION` are
+    /// refused without decoding their payload, whose layout may have changed since.
+    pub fn decode_frame(bytes: &[u8]) -> Result<(FrameHeader, Self), String> {
+        let (header, length) = FrameHeader::read(bytes)?;
+        if header.version < MIN_PROTOCOL_VERSION {
+            return Err(format!("unsupported protocol version {} (oldest supported is {})", header.version, MIN_PROTOCOL_VERSION));
+        }
+        if length > MAX_MESSAGE_SIZE {
+            return Err(format!("frame announces {} bytes, over the {} bytes limit", length, MAX_MESSAGE_SIZE));
+        }
+        let payload = bytes
+            .get(HEADER_SIZE..HEADER_SIZE + length)
+            .ok_or_else(|| format!("frame announces {} bytes but only {} were received", length, bytes.len() - HEADER_SIZE))?;
+        let (message, read) = bincode::decode_from_slice(payload, wire_config())
+            .map_err(|e| format!("malformed message: {}", e))?;
+        if read != length {
+            return Err(format!("message used {} of the {} announced bytes", read, length));
+        }
+        Ok((header, message))
+    }
+
+    /// Tag of the message, useful for logging without dumping the whole payload.
+    pub fn tag(&self) -> &'static str {
+        match self {
+            ChatProtocol::ServerTypeRequest => "[ServerTypeRequest]",
+            ChatProtocol::ServerTypeResponse { .. } => "[ServerTypeResponse]",
+            ChatProtocol::Login { .. } => "[Login]",
+            ChatProtocol::LoginAck { .. } => "[LoginAck]",
+            ChatProtocol::Logout => "[Logout]",
+            ChatProtocol::ClientListRequest => "[ClientListRequest]",
+            ChatProtocol::ClientListResponse { .. } => "[ClientListResponse]",
+            ChatProtocol::MessageTo { .. } => "[MessageTo]",
+            ChatProtocol::MessageFrom { .. } => "[MessageFrom]",
+            ChatProtocol::Receipt { .. } => "[Receipt]",
+            ChatProtocol::DeliveryStatus { .. } => "[DeliveryStatus]",
+            ChatProtocol::ChatRequest { .. } => "[ChatRequest]",
+            ChatProtocol::ChatStart { .. } => "[ChatStart]",
+            ChatProtocol::ChatFinish { .. } => "[ChatFinish]",
+            ChatProtocol::HistoryRequest { .. } => "[HistoryRequest]",
+            ChatProtocol::HistoryResponse { .. } => "[HistoryResponse]",
+            ChatProtocol::ChatHistoryUpdate { .. } => "[ChatHistoryUpdate]",
+            ChatProtocol::HistorySummary { .. } => "[HistorySummary]",
+            ChatProtocol::ClientDirectory { .. } => "[ClientDirectory]",
+            ChatProtocol::Relay { .. } => "[Relay]",
+            ChatProtocol::RoomCreate { .. } => "[RoomCreate]",
+            ChatProtocol::RoomJoin { .. } => "[RoomJoin]",
+            ChatProtocol::RoomLeave { .. } => "[RoomLeave]",
+            ChatProtocol::RoomListRequest => "[RoomListRequest]",
+            ChatProtocol::RoomListResponse { .. } => "[RoomListResponse]",
+            ChatProtocol::RoomUpdate { .. } => "[RoomUpdate]",
+            ChatProtocol::RoomMessageTo { .. } => "[RoomMessageTo]",
+            ChatProtocol::RoomMessageFrom { .. } => "[RoomMessageFrom]",
+            ChatProtocol::RoomHistoryRequest { .. } => "[RoomHistoryRequest]",
+            ChatProtocol::MediaUpload { .. } => "[MediaUpload]",
+            ChatProtocol::MediaUploadAck { .. } => "[MediaUploadAck]",
+            ChatProtocol::MediaListRequest => "[MediaListRequest]",
+            ChatProtocol::MediaListResponse { .. } => "[MediaListResponse]",
+            ChatProtocol::MediaDownloadRequest { .. } => "[MediaDownloadRequest]",
+            ChatProtocol::MediaDownloadResponse { .. } | ChatProtocol::MediaNotFound => "[MediaDownloadResponse]",
+            ChatProtocol::MediaBroadcast { .. } => "[MediaBroadcast]",
+            ChatProtocol::MediaBroadcastAck { .. } => "[MediaBroadcastAck]",
+            ChatProtocol::MediaChunkRequest { .. } => "[MediaChunkRequest]",
+            ChatProtocol::MediaChunk { .. } => "[MediaChunk]",
+            ChatProtocol::MediaUploadChunk { .. } => "[MediaUploadChunk]",
+            ChatProtocol::MediaUploadChunkAck { .. } => "[MediaUploadChunkAck]",
+            ChatProtocol::MediaSetVisibility { .. } => "[MediaSetVisibility]",
+            ChatProtocol::MediaVisibilityAck { .. } => "[MediaVisibilityAck]",
+            ChatProtocol::MediaDelete { .. } => "[MediaDelete]",
+            ChatProtocol::MediaDeleteAck { .. } => "[MediaDeleteAck]",
+            ChatProtocol::TextUpload { .. } => "[TextUpload]",
+            ChatProtocol::TextUploadAck { .. } => "[TextUploadAck]",
+            ChatProtocol::TextListRequest => "[TextListRequest]",
+            ChatProtocol::TextListResponse { .. } => "[TextListResponse]",
+            ChatProtocol::TextRequest { .. } => "[TextRequest]",
+            ChatProtocol::TextResponse { .. } => "[TextResponse]",
+            ChatProtocol::Error { .. } => "[Error]",
+        }
+    }
+}
+
+impl fmt::Display for ChatProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tag = self.tag();
+        match self {
+            ChatProtocol::Login { server_id } => write!(f, "{tag}::{server_id}"),
+            ChatProtocol::LoginAck { session_id, version, capabilities } => {
+                write!(f, "{tag}::{session_id}::{version}::{}", join_capabilities(capabilities))
+            }
+            ChatProtocol::Logout | ChatProtocol::ClientListRequest | ChatProtocol::MediaListRequest | ChatProtocol::RoomListRequest | ChatProtocol::ServerTypeRequest
+            | ChatProtocol::TextListRequest => write!(f, "{tag}"),
+            ChatProtocol::ServerTypeResponse { services } => {
+                write!(f, "{tag}::{}", services.iter().map(ServerService::name).collect::<Vec<_>>().join(","))
+            }
+            ChatProtocol::ClientListResponse { clients } => write!(f, "{tag}::{:?}", clients),
+            ChatProtocol::MessageTo { target_id, message_id, message } => write!(f, "{tag}::{target_id}::{message_id}::{message}"),
+            ChatProtocol::MessageFrom { sender_id, message_id, message } => write!(f, "{tag}::{sender_id}::{message_id}::{message}"),
+            ChatProtocol::Receipt { peer_id, message_id, kind } => write!(f, "{tag}::{peer_id}::{message_id}::{}", kind.name()),
+            ChatProtocol::DeliveryStatus { target_id, status } => write!(f, "{tag}::{target_id}::{}", status.name()),
+            ChatProtocol::ChatRequest { peer_id } => write!(f, "{tag}::{peer_id}"),
+            ChatProtocol::ChatStart { success } => write!(f, "{tag}::{success}"),
+            ChatProtocol::ChatFinish { peer_id } => write!(f, "{tag}::{peer_id}"),
+            ChatProtocol::HistoryRequest { client_id, target_id } => write!(f, "{tag}::{client_id}::{target_id}"),
+            ChatProtocol::HistoryResponse { history } => write!(f, "{tag}::{history}"),
+            ChatProtocol::ChatHistoryUpdate { source_server, conversation: (a, b), entries } => {
+                write!(f, "{tag}::{source_server}::{a}::{b}::{}", serde_json::to_string(entries).map_err(|_| fmt::Error)?)
+            }
+            ChatProtocol::HistorySummary { source_server, reply, conversations } => {
+                write!(f, "{tag}::{source_server}::{reply}::{}", serde_json::to_string(conversations).map_err(|_| fmt::Error)?)
+            }
+            ChatProtocol::ClientDirectory { server_id, clients } => write!(f, "{tag}::{server_id}::{:?}", clients),
+            ChatProtocol::Relay { target_id, timestamp, message } => write!(f, "{tag}::{target_id}::{timestamp}::{message}"),
+            ChatProtocol::RoomCreate { room } | ChatProtocol::RoomJoin { room } | ChatProtocol::RoomLeave { room } | ChatProtocol::RoomHistoryRequest { room } => write!(f, "{tag}::{room}"),
+            ChatProtocol::RoomListResponse { rooms } => write!(f, "{tag}::{}", rooms.join(",")),
+            ChatProtocol::RoomUpdate { room, members } => write!(f, "{tag}::{room}::{:?}", members),
+            ChatProtocol::RoomMessageTo { room, message_id, message } => write!(f, "{tag}::{room}::{message_id}::{message}"),
+            ChatProtocol::RoomMessageFrom { room, sender_id, message_id, message } => write!(f, "{tag}::{room}::{sender_id}::{message_id}::{message}"),
+            ChatProtocol::MediaUpload { name, data } => write!(f, "{tag}::{name}::{}", STANDARD.encode(data)),
+            ChatProtocol::MediaUploadAck { name } => write!(f, "{tag}::{name}"),
+            ChatProtocol::MediaListResponse { media } => write!(f, "{tag}::{}", serde_json::to_string(media).map_err(|_| fmt::Error)?),
+            ChatProtocol::MediaDownloadRequest { name } => write!(f, "{tag}::{name}"),
+            ChatProtocol::MediaDownloadResponse { name, data } => write!(f, "{tag}::{name}::{}", STANDARD.encode(data)),
+            ChatProtocol::MediaNotFound => write!(f, "{tag}::ERROR::NotFound"),
+            ChatProtocol::MediaBroadcast { name, data } => write!(f, "{tag}::{name}::{}", STANDARD.encode(data)),
+            ChatProtocol::MediaBroadcastAck { name } => write!(f, "{tag}::{name}::Broadcasted"),
+            ChatProtocol::MediaChunkRequest { name, offset, length } => write!(f, "{tag}::{name}::{offset}::{length}"),
+            ChatProtocol::MediaChunk { name, offset, total_size, chunk_checksum, file_checksum, data }
+            | ChatProtocol::MediaUploadChunk { name, offset, total_size, chunk_checksum, file_checksum, data } => {
+                write!(f, "{tag}::{name}::{offset}::{total_size}::{chunk_checksum:016x}::{file_checksum:016x}::{}", STANDARD.encode(data))
+            }
+            ChatProtocol::MediaUploadChunkAck { name, next_offset } => write!(f, "{tag}::{name}::{next_offset}"),
+            ChatProtocol::MediaSetVisibility { name, visibility } | ChatProtocol::MediaVisibilityAck { name, visibility } => write!(f, "{tag}::{name}::{visibility}"),
+            ChatProtocol::MediaDelete { name } | ChatProtocol::MediaDeleteAck { name } => write!(f, "{tag}::{name}"),
+            ChatProtocol::TextUpload { name, content } | ChatProtocol::TextResponse { name, content } => write!(f, "{tag}::{name}::{content}"),
+            ChatProtocol::TextUploadAck { name } | ChatProtocol::TextRequest { name } => write!(f, "{tag}::{name}"),
+            ChatProtocol::TextListResponse { files } => write!(f, "{tag}::{}", files.join(",")),
+            ChatProtocol::Error { reason } => write!(f, "{tag}::{reason}"),
+        }
+    }
+}
+
+impl FromStr for ChatProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (tag, rest) = match s.split_once("::") {
+            Some((tag, rest)) => (tag, Some(rest)),
+            None => (s, None),
+        };
+        let body = || rest.ok_or_else(|| format!("{} is missing its arguments", tag));
+        let pair = || body().and_then(|r| r.split_once("::").ok_or_else(|| format!("{} expects two arguments", tag)));
+        let triple = || {
+            let (first, rest) = pair()?;
+            let (second, third) = rest.split_once("::").ok_or_else(|| format!("{} expects three arguments", tag))?;
+            Ok::<_, String>((first, second, third))
+        };
+
+        let message = match tag {
+            "[ServerTypeRequest]" => ChatProtocol::ServerTypeRequest,
+            "[ServerTypeResponse]" => ChatProtocol::ServerTypeResponse {
+                services: rest
+                    .unwrap_or_default()
+                    .split(',')
+                    .filter(|service| !service.trim().is_empty())
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?,
+            },
+            "[Login]" => ChatProtocol::Login { server_id: parse_id(body()?)? },
+            "[LoginAck]" => {
+                let (session_id, version, capabilities) = parse_handshake(body()?)?;
+                ChatProtocol::LoginAck {
+                    session_id: session_id.trim().parse().map_err(|e| format!("invalid session id: {}", e))?,
+                    version,
+                    capabilities,
+                }
+            }
+            "[Logout]" => ChatProtocol::Logout,
+            "[ClientListRequest]" => ChatProtocol::ClientListRequest,
+            "[ClientListResponse]" => ChatProtocol::ClientListResponse { clients: parse_id_list(body()?)? },
+            "[MessageTo]" => {
+                let (target, message_id, message) = triple()?;
+                ChatProtocol::MessageTo { target_id: parse_id(target)?, message_id: parse_message_id(message_id)?, message: message.to_string() }
+            }
+            "[MessageFrom]" => {
+                let (sender, message_id, message) = triple()?;
+                ChatProtocol::MessageFrom { sender_id: parse_id(sender)?, message_id: parse_message_id(message_id)?, message: message.to_string() }
+            }
+            "[Receipt]" => {
+                let (peer, message_id, kind) = triple()?;
+                ChatProtocol::Receipt { peer_id: parse_id(peer)?, message_id: parse_message_id(message_id)?, kind: kind.parse()? }
+            }
+            "[DeliveryStatus]" => {
+                let (target, status) = pair()?;
+                ChatProtocol::DeliveryStatus { target_id: parse_id(target)?, status: status.parse()? }
+            }
+            "[ChatRequest]" => ChatProtocol::ChatRequest { peer_id: parse_id(body()?)? },
+            "[ChatStart]" => ChatProtocol::ChatStart {
+                success: body()?.trim().to_lowercase().parse().map_err(|e| format!("invalid ChatStart flag: {}", e))?,
+            },
+            "[ChatFinish]" => ChatProtocol::ChatFinish { peer_id: parse_id(body()?)? },
+            "[HistoryRequest]" => {
+                let (client, target) = pair()?;
+                ChatProtocol::HistoryRequest { client_id: parse_id(client)?, target_id: parse_id(target)? }
+            }
+            "[HistoryResponse]" => ChatProtocol::HistoryResponse { history: body()?.to_string() },
+            "[ChatHistoryUpdate]" => {
+                let (source, a, rest) = triple()?;
+                let (b, entries) = rest.split_once("::").ok_or_else(|| format!("{} expects four arguments", tag))?;
+                ChatProtocol::ChatHistoryUpdate {
+                    source_server: parse_id(source)?,
+                    conversation: (parse_id(a)?, parse_id(b)?),
+                    entries: serde_json::from_str(entries).map_err(|e| format!("invalid history entries: {}", e))?,
+                }
+            }
+            "[HistorySummary]" => {
+                let (source, reply, conversations) = triple()?;
+                ChatProtocol::HistorySummary {
+                    source_server: parse_id(source)?,
+                    reply: reply.trim().parse().map_err(|e| format!("invalid reply flag: {}", e))?,
+                    conversations: serde_json::from_str(conversations).map_err(|e| format!("invalid digests: {}", e))?,
+                }
+            }
+            "[ClientDirectory]" => {
+                let (server, clients) = pair()?;
+                ChatProtocol::ClientDirectory { server_id: parse_id(server)?, clients: parse_id_list(clients)? }
+            }
+            "[Relay]" => {
+                let (target, timestamp, message) = triple()?;
+                ChatProtocol::Relay {
+                    target_id: parse_id(target)?,
+                    timestamp: timestamp.trim().parse().map_err(|e| format!("invalid timestamp: {}", e))?,
+                    message: Box::new(message.parse()?),
+                }
+            }
+            "[RoomCreate]" => ChatProtocol::RoomCreate { room: parse_room(body()?)? },
+            "[RoomJoin]" => ChatProtocol::RoomJoin { room: parse_room(body()?)? },
+            "[RoomLeave]" => ChatProtocol::RoomLeave { room: parse_room(body()?)? },
+            "[RoomListRequest]" => ChatProtocol::RoomListRequest,
+            "[RoomListResponse]" => ChatProtocol::RoomListResponse {
+                rooms: body()?
+                    .split(',')
+                    .filter(|room| !room.trim().is_empty())
+                    .map(|room| room.trim().to_string())
+                    .collect(),
+            },
+            "[RoomUpdate]" => {
+                let (room, members) = pair()?;
+                ChatProtocol::RoomUpdate { room: parse_room(room)?, members: parse_id_list(members)? }
+            }
+            "[RoomMessageTo]" => {
+                let (room, message_id, message) = triple()?;
+                ChatProtocol::RoomMessageTo { room: parse_room(room)?, message_id: parse_message_id(message_id)?, message: message.to_string() }
+            }
+            "[RoomMessageFrom]" => {
+                let (room, sender, rest) = triple()?;
+                let (message_id, message) = rest.split_once("::").ok_or_else(|| format!("{} expects four arguments", tag))?;
+                ChatProtocol::RoomMessageFrom {
+                    room: parse_room(room)?,
+                    sender_id: parse_id(sender)?,
+                    message_id: parse_message_id(message_id)?,
+                    message: message.to_string(),
+                }
+            }
+            "[RoomHistoryRequest]" => ChatProtocol::RoomHistoryRequest { room: parse_room(body()?)? },
+            "[MediaUpload]" => {
+                let (name, data) = pair()?;
+                ChatProtocol::MediaUpload { name: name.to_string(), data: parse_media(data)? }
+            }
+            "[MediaUploadAck]" => ChatProtocol::MediaUploadAck { name: body()?.to_string() },
+            "[MediaListRequest]" => ChatProtocol::MediaListRequest,
+            "[MediaListResponse]" => ChatProtocol::MediaListResponse {
+                media: serde_json::from_str(body()?).map_err(|e| format!("invalid media list: {}", e))?,
+            },
+            "[MediaDownloadRequest]" => ChatProtocol::MediaDownloadRequest { name: body()?.to_string() },
+            "[MediaDownloadResponse]" => match pair()? {
+                ("ERROR", "NotFound") => ChatProtocol::MediaNotFound,
+                (name, data) => ChatProtocol::MediaDownloadResponse { name: name.to_string(), data: parse_media(data)? },
+            },
+            "[MediaBroadcast]" => {
+                let (name, data) = pair()?;
+                ChatProtocol::MediaBroadcast { name: name.to_string(), data: parse_media(data)? }
+            }
+            "[MediaBroadcastAck]" => {
+                let (name, _status) = pair()?;
+                ChatProtocol::MediaBroadcastAck { name: name.to_string() }
+            }
+            "[MediaChunkRequest]" => {
+                let (name, offset, length) = triple()?;
+                ChatProtocol::MediaChunkRequest {
+                    name: name.to_string(),
+                    offset: parse_offset(offset)?,
+                    length: length.trim().parse().map_err(|e| format!("invalid chunk length '{}': {}", length, e))?,
+                }
+            }
+            "[MediaChunk]" | "[MediaUploadChunk]" => {
+                let parts: Vec<&str> = body()?.splitn(6, "::").collect();
+                let [name, offset, total_size, chunk_checksum, file_checksum, data] = parts[..] else {
+                    return Err(format!("{} expects six arguments", tag));
+                };
+                let (name, offset, total_size) = (name.to_string(), parse_offset(offset)?, parse_offset(total_size)?);
+                let (chunk_checksum, file_checksum, data) = (parse_checksum(chunk_checksum)?, parse_checksum(file_checksum)?, parse_media(data)?);
+                if tag == "[MediaChunk]" {
+                    ChatProtocol::MediaChunk { name, offset, total_size, chunk_checksum, file_checksum, data }
+                } else {
+                    ChatProtocol::MediaUploadChunk { name, offset, total_size, chunk_checksum, file_checksum, data }
+                }
+            }
+            "[MediaUploadChunkAck]" => {
+                let (name, next_offset) = pair()?;
+                ChatProtocol::MediaUploadChunkAck { name: name.to_string(), next_offset: parse_offset(next_offset)? }
+            }
+            "[MediaSetVisibility]" | "[MediaVisibilityAck]" => {
+                let (name, visibility) = pair()?;
+                let (name, visibility) = (name.to_string(), visibility.parse()?);
+                if tag == "[MediaSetVisibility]" {
+                    ChatProtocol::MediaSetVisibility { name, visibility }
+                } else {
+                    ChatProtocol::MediaVisibilityAck { name, visibility }
+                }
+            }
+            "[MediaDelete]" => ChatProtocol::MediaDelete { name: body()?.to_string() },
+            "[MediaDeleteAck]" => ChatProtocol::MediaDeleteAck { name: body()?.to_string() },
+            "[TextUpload]" => {
+                let (name, content) = pair()?;
+                ChatProtocol::TextUpload { name: parse_file_name(name)?, content: content.to_string() }
+            }
+            "[TextUploadAck]" => ChatProtocol::TextUploadAck { name: parse_file_name(body()?)? },
+            "[TextListRequest]" => ChatProtocol::TextListRequest,
+            "[TextListResponse]" => ChatProtocol::TextListResponse {
+                files: rest.unwrap_or_default().split(',').filter(|name| !name.trim().is_empty()).map(parse_file_name).collect::<Result<_, _>>()?,
+            },
+            "[TextRequest]" => ChatProtocol::TextRequest { name: parse_file_name(body()?)? },
+            "[TextResponse]" => {
+                let (name, content) = pair()?;
+                ChatProtocol::TextResponse { name: parse_file_name(name)?, content: content.to_string() }
+            }
+            "[Error]" => ChatProtocol::Error { reason: body()?.to_string() },
+            other => return Err(format!("unrecognized message tag {}", other)),
+        };
+        Ok(message)
+    }
+}
+
+fn parse_id(s: &str) -> Result<NodeId, String> {
+    s.trim().parse::<NodeId>().map_err(|e| format!("invalid node id '{}': {}", s, e))
+}
+
+/// `[1, 2]` as written by `{:?}`, or a bare `1,2`.
+fn parse_id_list(s: &str) -> Result<Vec<NodeId>, String> {
+    s.trim()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .split(',')
+        .filter(|id| !id.trim().is_empty())
+        .map(parse_id)
+        .collect()
+}
+
+fn parse_room(s: &str) -> Result<String, String> {
+    if is_valid_room_name(s) {
+        Ok(s.to_string())
+    } else {
+        Err(format!("invalid room name '{}'", s))
+    }
+}
+
+fn parse_file_name(s: &str) -> Result<String, String> {
+    let name = s.trim();
+    if is_valid_file_name(name) {
+        Ok(name.to_string())
+    } else {
+        Err(format!("invalid file name '{}'", s))
+    }
+}
+
+fn parse_offset(s: &str) -> Result<u64, String> {
+    s.trim().parse::<u64>().map_err(|e| format!("invalid offset '{}': {}", s, e))
+}
+
+fn parse_checksum(s: &str) -> Result<u64, String> {
+    u64::from_str_radix(s.trim(), 16).map_err(|e| format!("invalid checksum '{}': {}", s, e))
+}
+
+fn parse_message_id(s: &str) -> Result<u64, String> {
+    s.trim().parse::<u64>().map_err(|e| format!("invalid message id '{}': {}", s, e))
+}
+
+/// Splits `id[::version[::cap,cap]]`; a bare id stands for the current version with every capability.
+fn parse_handshake(body: &str) -> Result<(&str, u16, Vec<Capability>), String> {
+    let mut parts = body.splitn(3, "::");
+    let id = parts.next().unwrap_or_default();
+    let version = match parts.next() {
+        Some(v) => v.trim().parse().map_err(|e| format!("invalid protocol version '{}': {}", v, e))?,
+        None => return Ok((id, PROTOCOL_VERSION, Capability::ALL.to_vec())),
+    };
+    let capabilities = parts
+        .next()
+        .unwrap_or_default()
+        .split(',')
+        .filter(|c| !c.trim().is_empty())
+        .map(str::parse)
+        .collect::<Result<_, _>>()?;
+    Ok((id, version, capabilities))
+}
+
+fn join_capabilities(capabilities: &[Capability]) -> String {
+    capabilities.iter().map(Capability::name).collect::<Vec<_>>().join(",")
+}
+
+fn parse_media(s: &str) -> Result<Vec<u8>, String> {
+    STANDARD.decode(s.trim()).map_err(|e| format!("invalid base64 media: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_protocol_round_trip() {
+        let messages = vec![
+            ChatProtocol::login(200),
+            ChatProtocol::LoginAck { session_id: 7, version: 1, capabilities: vec![Capability::Chat, Capability::Media] },
+            ChatProtocol::Logout,
+            ChatProtocol::ClientListResponse { clients: vec![101, 102] },
+            ChatProtocol::MessageTo { target_id: 102, message_id: 3, message: "see you at 10::30".to_string() },
+            ChatProtocol::MessageFrom { sender_id: 101, message_id: 4, message: "hello".to_string() },
+            ChatProtocol::DeliveryStatus { target_id: 102, status: DeliveryStatus::Queued },
+            ChatProtocol::ChatStart { success: true },
+            ChatProtocol::HistoryRequest { client_id: 101, target_id: 102 },
+            ChatProtocol::MediaListResponse { media: vec![MediaInfo { name: "a.png".to_string(), size: 3, owner: 101, hash: 0xfeed, uploaded_at: 1, visibility: MediaVisibility::Clients(vec![102]), content_type: "image/png".to_string() }] },
+            ChatProtocol::MediaNotFound,
+            ChatProtocol::MediaBroadcastAck { name: "a.png".to_string() },
+        ];
+        for message in messages {
+            let decoded = ChatProtocol::decode(&message.encode().unwrap()).expect("encoded message should decode");
+            assert_eq!(decoded, message, "round trip changed the message");
+        }
+    }
+
+    #[test]
+    fn test_chat_protocol_rejects_unknown_or_malformed_messages() {
+        assert!("[Logn]::200".parse::<ChatProtocol>().is_err(), "a misspelled tag should not decode");
+        assert!("[Login]".parse::<ChatProtocol>().is_err(), "Login without a server id should not decode");
+        assert!("[ChatRequest]::abc".parse::<ChatProtocol>().is_err(), "a non numeric peer id should not decode");
+        assert_eq!("[Login]::200".parse::<ChatProtocol>(), Ok(ChatProtocol::login(200)), "GUI commands use the same textual form");
+    }
+
+    #[test]
+    fn test_chat_protocol_binary_framing_carries_raw_media() {
+        let media: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+        let upload = ChatProtocol::MediaUpload { name: "photo.png".to_string(), data: media.clone() };
+        let frame = upload.encode().unwrap();
+        let base64_len = upload.to_string().len();
+        assert!(frame.len() < base64_len, "binary frame ({} bytes) should be smaller than the base64 text ({} bytes)", frame.len(), base64_len);
+        assert!((frame.len() + 127) / 128 < (base64_len + 127) / 128, "raw media should need fewer fragments than base64");
+
+        let mut padded = frame.clone();
+        padded.resize((frame.len() + 127) / 128 * 128, 0);
+        assert_eq!(ChatProtocol::decode(&padded), Ok(upload.clone()), "fragment padding after the frame should be ignored");
+        assert!(ChatProtocol::decode(&frame[..frame.len() - 1]).is_err(), "a truncated frame should not decode");
+        assert!(ChatProtocol::decode(&[1, 0]).is_err(), "a frame shorter than the length prefix should not decode");
+
+        let text = ChatProtocol::MessageTo { target_id: 102, message_id: u64::MAX, message: "a::b\0 [Logout] 🎉\n".to_string() };
+        assert_eq!(ChatProtocol::decode(&text.encode().unwrap()), Ok(text.clone()), "chat text may contain any characters");
+        assert_eq!(upload.to_string().parse::<ChatProtocol>(), Ok(upload), "the GUI text form should still carry media as base64");
+    }
+
+    #[test]
+    fn test_chat_protocol_frames_are_bounded() {
+        let oversized = ChatProtocol::MediaUpload { name: "huge.bin".to_string(), data: vec![0; MAX_MESSAGE_SIZE] };
+        assert!(oversized.encode().is_err(), "a message no peer would accept is not sent");
+
+        let mut forged = Vec::new();
+        FrameHeader::current().write(MAX_MESSAGE_SIZE + 1, &mut forged);
+        forged.resize(HEADER_SIZE + 64, 0);
+        assert!(ChatProtocol::decode(&forged).unwrap_err().contains("limit"), "a length over the limit is refused before decoding");
+    }
+
+    #[test]
+    fn test_login_negotiates_from_the_frame_header() {
+        let frame = ChatProtocol::login(200).encode().unwrap();
+        assert_eq!(ChatProtocol::decode_frame(&frame), Ok((FrameHeader::current(), ChatProtocol::login(200))));
+        assert_eq!(
+            negotiate(1, &[Capability::Chat, Capability::History], 2, &Capability::ALL),
+            (1, vec![Capability::Chat, Capability::History]),
+            "the handshake keeps the lower version and the common capabilities"
+        );
+
+        let mut newer = frame.clone();
+        newer[2..4].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(FrameHeader::read(&newer).unwrap().0.capabilities, Capability::ALL.to_vec(), "unknown capability bits are ignored");
+
+        let mut older = frame;
+        older[..2].copy_from_slice(&(MIN_PROTOCOL_VERSION - 1).to_le_bytes());
+        assert_eq!(FrameHeader::read(&older).unwrap().0.version, MIN_PROTOCOL_VERSION - 1, "the header reads whatever the version");
+        assert!(ChatProtocol::decode(&older).unwrap_err().contains("unsupported protocol version"), "the payload of a too old frame is not decoded");
+        assert!("[LoginAck]::1::1::teleport".parse::<ChatProtocol>().is_err(), "unknown capabilities should not decode");
+    }
+
+    #[test]
+    fn test_delivery_status_text_form() {
+        let status = ChatProtocol::DeliveryStatus { target_id: 7, status: DeliveryStatus::Rejected };
+        assert_eq!(status.to_string(), "[DeliveryStatus]::7::rejected");
+        assert_eq!(status.to_string().parse::<ChatProtocol>(), Ok(status));
+        assert!("[DeliveryStatus]::7::lost".parse::<ChatProtocol>().is_err());
+    }
+
+    #[test]
+    fn test_receipt_text_form() {
+        let receipt = ChatProtocol::Receipt { peer_id: 8, message_id: 42, kind: ReceiptKind::Delivered };
+        assert_eq!(receipt.to_string(), "[Receipt]::8::42::delivered");
+        assert_eq!(receipt.to_string().parse::<ChatProtocol>(), Ok(receipt));
+        assert!("[Receipt]::8::42::seen".parse::<ChatProtocol>().is_err());
+    }
+
+    #[test]
+    fn test_room_messages_text_form() {
+        let message = ChatProtocol::RoomMessageFrom { room: "book club".to_string(), sender_id: 3, message_id: 7, message: "see::you".to_string() };
+        assert_eq!(message.to_string(), "[RoomMessageFrom]::book club::3::7::see::you");
+        assert_eq!(message.to_string().parse::<ChatProtocol>(), Ok(message));
+        let update = ChatProtocol::RoomUpdate { room: "lobby".to_string(), members: vec![5, 9] };
+        assert_eq!(update.to_string().parse::<ChatProtocol>(), Ok(update));
+        assert!("[RoomCreate]::a,b".parse::<ChatProtocol>().is_err(), "room names can't hold list separators");
+    }
+
+    #[test]
+    fn test_server_to_server_directory_and_relay_round_trip() {
+        let directory = ChatProtocol::ClientDirectory { server_id: 30, clients: vec![101, 102] };
+        assert_eq!(directory.to_string(), "[ClientDirectory]::30::[101, 102]");
+        assert_eq!(directory.to_string().parse::<ChatProtocol>(), Ok(directory.clone()));
+        assert_eq!(ChatProtocol::decode(&directory.encode().unwrap()), Ok(directory));
+
+        let relayed = ChatProtocol::MessageFrom { sender_id: 101, message_id: 9, message: "from the other server::hi".to_string() };
+        let relay = ChatProtocol::Relay { target_id: 105, timestamp: 1_700_000_000_000, message: Box::new(relayed.clone()) };
+        assert_eq!(relay.to_string(), format!("[Relay]::105::1700000000000::{}", relayed));
+        assert_eq!(relay.to_string().parse::<ChatProtocol>(), Ok(relay.clone()));
+        assert_eq!(ChatProtocol::decode(&relay.encode().unwrap()), Ok(relay.clone()));
+        assert_eq!(relay.required_capability(), None, "servers relay without a client login");
+        assert!("[Relay]::105::0::[Nope]".parse::<ChatProtocol>().is_err());
+        assert!("[Relay]::105::[Logout]".parse::<ChatProtocol>().is_err(), "the timestamp is required");
+    }
+
+    #[test]
+    fn test_history_replication_messages_round_trip() {
+        let entry = |sender_id: NodeId, message_id: u64, timestamp: u64| HistoryEntry { sender_id, message_id, timestamp, message: format!("{}/{}", sender_id, message_id) };
+        let key = (101, 102);
+        let digest = ConversationDigest::of(key, &[entry(101, 1, 10), entry(102, 1, 20)]);
+        assert_eq!(digest.count, 2);
+        assert_eq!(digest, ConversationDigest::of(key, &[entry(102, 1, 20), entry(101, 1, 10)]), "the order does not matter");
+        assert_ne!(digest, ConversationDigest::of(key, &[entry(101, 1, 10), entry(102, 2, 20)]));
+
+        let delta = ChatProtocol::ChatHistoryUpdate { source_server: 30, conversation: key, entries: vec![entry(101, 3, 40)] };
+        assert_eq!(delta.to_string().parse::<ChatProtocol>(), Ok(delta.clone()));
+        assert_eq!(ChatProtocol::decode(&delta.encode().unwrap()), Ok(delta));
+        let summary = ChatProtocol::HistorySummary { source_server: 30, reply: false, conversations: vec![digest] };
+        assert_eq!(summary.to_string().parse::<ChatProtocol>(), Ok(summary.clone()));
+        assert_eq!(ChatProtocol::decode(&summary.encode().unwrap()), Ok(summary));
+    }
+
+    #[test]
+    fn test_server_type_response_round_trip() {
+        let response = ChatProtocol::ServerTypeResponse { services: vec![ServerService::Chat, ServerService::Text] };
+        assert_eq!(response.to_string(), "[ServerTypeResponse]::chat,text");
+        assert_eq!(response.to_string().parse::<ChatProtocol>(), Ok(response.clone()));
+        assert_eq!(ChatProtocol::decode(&response.encode().unwrap()), Ok(response.clone()));
+        assert_eq!(response.required_capability(), None);
+        assert_eq!(ChatProtocol::ServerTypeRequest.required_capability(), None, "asked before logging in");
+    }
+
+    #[test]
+    fn test_text_server_messages_and_linked_media() {
+        assert_eq!(ChatProtocol::TextListRequest.required_service(), Some(ServerService::Text));
+        assert_eq!(ChatProtocol::MediaDownloadRequest { name: "a.png".to_string() }.required_service(), Some(ServerService::Media));
+        assert_eq!(ChatProtocol::TextRequest { name: "a.md".to_string() }.required_capability(), None, "documents are public");
+        assert!(ServerService::Text.capabilities().is_empty());
+
+        let page = "# Home\n![logo](pic1.png) and ![again](pic1.png), ![photo]( pic2.png )";
+        assert_eq!(referenced_media(page), vec!["pic1.png".to_string(), "pic2.png".to_string()]);
+        assert!(referenced_media("![broken](pic3.png").is_empty());
+        let response = ChatProtocol::TextResponse { name: "index.md".to_string(), content: page.to_string() };
+        assert_eq!(response.to_string().parse::<ChatProtocol>(), Ok(response.clone()));
+        assert_eq!(ChatProtocol::decode(&response.encode().unwrap()), Ok(response));
+        let list = ChatProtocol::TextListResponse { files: vec!["a.md".to_string(), "index.md".to_string()] };
+        assert_eq!(list.to_string(), "[TextListResponse]::a.md,index.md");
+        assert_eq!(list.to_string().parse::<ChatProtocol>(), Ok(list));
+        assert!("[TextRequest]::a,b".parse::<ChatProtocol>().is_err());
+    }
+
+    #[test]
+    fn test_media_list_text_form() {
+        let info = |name: &str, owner: NodeId| MediaInfo {
+            name: name.to_string(), size: 10, owner, hash: 0xfeed, uploaded_at: 1_700_000_000_000,
+            visibility: MediaVisibility::default(), content_type: "image/png".to_string(),
+        };
+        let response = ChatProtocol::MediaListResponse { media: vec![info("a.png", 101), info("b.png", 102)] };
+        assert_eq!(response.to_string().parse::<ChatProtocol>(), Ok(response.clone()), "size, owner and hash survive the text form");
+        assert_eq!(ChatProtocol::decode(&response.encode().unwrap()), Ok(response));
+    }
+
+    #[test]
+    fn test_media_visibility_commands_round_trip() {
+        for visibility in [MediaVisibility::Owner, MediaVisibility::Everyone, MediaVisibility::Clients(vec![102, 103])] {
+            let command = ChatProtocol::MediaSetVisibility { name: "a.png".to_string(), visibility };
+            assert_eq!(command.to_string().parse::<ChatProtocol>(), Ok(command.clone()));
+            assert_eq!(ChatProtocol::decode(&command.encode().unwrap()), Ok(command));
+        }
+        assert_eq!("101,102".parse::<MediaVisibility>(), Ok(MediaVisibility::Clients(vec![101, 102])));
+        assert_eq!(ChatProtocol::MediaDelete { name: "a.png".to_string() }.required_capability(), Some(Capability::Media));
+
+        let upload = ChatProtocol::MediaUploadChunk { name: "big.png".to_string(), offset: 0, total_size: 1 << 30, chunk_checksum: 0, file_checksum: 0, data: vec![] };
+        assert_eq!(upload.media_upload(), Some(("big.png", 1 << 30)), "a chunked upload is counted at its full size from the first chunk");
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use wg_2024::network::{NodeId, SourceRoutingHeader};
+use wg_2024::packet::Fragment;
+
+/// How long a fragment may stay un-acked before it is sent again.
+pub const ACK_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Timeout-driven retransmissions allowed per fragment before the whole session is given up.
+pub const MAX_RETRIES: u8 = 5;
+/// How often hosts should call [`ReliableSender::poll_timeouts`].
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+struct PendingFragment {
+    fragment: Fragment,
+    last_sent: Instant,
+    retries: u8,
+}
+
+/// One outgoing message: the fragments still waiting for an Ack and the route they were sent on.
+#[derive(Debug, Clone)]
+pub struct OutgoingSession {
+    pub session_id: u64,
+    pub destination: NodeId,
+    pub routing_header: SourceRoutingHeader,
+    pub route_needs_recalculation: bool,
+    pending: BTreeMap<u64, PendingFragment>,
+}
+
+impl OutgoingSession {
+    /// Fragment that has not been acked yet.
+    pub fn fragment(&self, fragment_index: u64) -> Option<&Fragment> {
+        self.pending.get(&fragment_index).map(|p| &p.fragment)
+    }
+
+    /// Restarts the ack timer of a fragment the host resent on its own (e.g. after a Nack).
+    pub fn mark_resent(&mut self, fragment_index: u64) {
+        if let Some(pending) = self.pending.get_mut(&fragment_index) {
+            pending.last_sent = Instant::now();
+        }
+    }
+
+    /// Route used from now on for this session's retransmissions.
+    pub fn reroute(&mut self, routing_header: SourceRoutingHeader) {
+        self.routing_header = routing_header;
+        self.route_needs_recalculation = false;
+    }
+
+    pub fn unacked(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckOutcome {
+    /// The Ack matches no tracked fragment (duplicate, or the session was already collected).
+    Unknown,
+    /// Recorded, but the session still has un-acked fragments.
+    Pending,
+    /// Last missing Ack: the session is complete and has been dropped.
+    Completed,
+}
+
+/// A fragment whose Ack did not arrive in time and has to be sent again.
+#[derive(Debug, Clone)]
+pub struct Retransmission {
+    pub session_id: u64,
+    pub destination: NodeId,
+    pub fragment: Fragment,
+    pub routing_header: SourceRoutingHeader,
+    pub route_needs_recalculation: bool,
+}
+
+/// Outcome of [`ReliableSender::poll_timeouts`].
+#[derive(Debug, Default)]
+pub struct Timeouts {
+    pub resend: Vec<Retransmission>,
+    /// Sessions dropped because a fragment ran out of retries.
+    pub given_up: Vec<OutgoingSession>,
+}
+
+/// Sender side of the reliable delivery shared by every host.
+/// Sessions are keyed by `(session_id, destination)` since a server answers many clients with their own session ids.
+#[derive(Debug, Clone)]
+pub struct ReliableSender {
+    sessions: HashMap<(u64, NodeId), OutgoingSession>,
+    ack_timeout: Duration,
+    max_retries: u8,
+}
+
+impl Default for ReliableSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReliableSender {
+    pub fn new() -> Self {
+        Self::with_limits(ACK_TIMEOUT, MAX_RETRIES)
+    }
+
+    pub fn with_limits(ack_timeout: Duration, max_retries: u8) -> Self {
+        Self { sessions: HashMap::new(), ack_timeout, max_retries }
+    }
+
+    /// Starts tracking fragments that were just sent along `routing_header`.
+    /// Fragments sent again under an already tracked session are added to it.
+    pub fn track(&mut self, session_id: u64, routing_header: SourceRoutingHeader, fragments: Vec<Fragment>) {
+        let destination = routing_header.hops.last().copied().unwrap_or_default();
+        let now = Instant::now();
+        let session = self.sessions.entry((session_id, destination)).or_insert_with(|| OutgoingSession {
+            session_id,
+            destination,
+            routing_header: routing_header.clone(),
+            route_needs_recalculation: false,
+            pending: BTreeMap::new(),
+        });
+        session.routing_header = routing_header;
+        for fragment in fragments {
+            session.pending.insert(fragment.fragment_index, PendingFragment { fragment, last_sent: now, retries: 0 });
+        }
+    }
+
+    /// Records the Ack sent by `from` (the first hop of the Ack's route); completed sessions are collected right away.
+    pub fn on_ack(&mut self, session_id: u64, from: NodeId, fragment_index: u64) -> AckOutcome {
+        let key = (session_id, from);
+        let Some(session) = self.sessions.get_mut(&key) else {
+            return AckOutcome::Unknown;
+        };
+        if session.pending.remove(&fragment_index).is_none() {
+            return AckOutcome::Unknown;
+        }
+        if session.is_complete() {
+            self.sessions.remove(&key);
+            AckOutcome::Completed
+        } else {
+            AckOutcome::Pending
+        }
+    }
+
+    pub fn get(&self, session_id: u64, destination: NodeId) -> Option<&OutgoingSession> {
+        self.sessions.get(&(session_id, destination))
+    }
+
+    pub fn get_mut(&mut self, session_id: u64, destination: NodeId) -> Option<&mut OutgoingSession> {
+        self.sessions.get_mut(&(session_id, destination))
+    }
+
+    /// Session a Nack refers to. The Nack only names the session, so when several destinations share it
+    /// the one whose route starts like the (reversed) route of the Nack wins.
+    pub fn for_nack(&mut self, session_id: u64, nack_hops: &[NodeId]) -> Option<&mut OutgoingSession> {
+        let back_route: Vec<NodeId> = nack_hops.iter().rev().copied().collect();
+        let key = self
+            .sessions
+            .values()
+            .filter(|s| s.session_id == session_id)
+            .max_by_key(|s| s.routing_header.hops.starts_with(&back_route))
+            .map(|s| (s.session_id, s.destination))?;
+        self.sessions.get_mut(&key)
+    }
+
+    /// Collects the fragments whose Ack is overdue and restarts their timer.
+    /// Sessions with a fragment past the retry limit are removed and reported instead.
+    pub fn poll_timeouts(&mut self, now: Instant) -> Timeouts {
+        let mut timeouts = Timeouts::default();
+        let mut expired = Vec::new();
+        for (key, session) in self.sessions.iter_mut() {
+            let overdue: Vec<u64> = session
+                .pending
+                .iter()
+                .filter(|(_, p)| now.duration_since(p.last_sent) >= self.ack_timeout)
+                .map(|(&index, _)| index)
+                .collect();
+            if overdue.iter().any(|index| session.pending[index].retries >= self.max_retries) {
+                expired.push(*key);
+                continue;
+            }
+            for index in overdue {
+                let pending = session.pending.get_mut(&index).unwrap();
+                pending.retries += 1;
+                pending.last_sent = now;
+                timeouts.resend.push(Retransmission {
+                    session_id: session.session_id,
+                    destination: session.destination,
+                    fragment: pending.fragment.clone(),
+                    routing_header: session.routing_header.clone(),
+                    route_needs_recalculation: session.route_needs_recalculation,
+                });
+            }
+        }
+        for key in expired {
+            if let Some(session) = self.sessions.remove(&key) {
+                timeouts.given_up.push(session);
+            }
+        }
+        timeouts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: NodeId = 200;
+
+    fn route() -> SourceRoutingHeader {
+        SourceRoutingHeader { hops: vec![101, 1, SERVER], hop_index: 1 }
+    }
+
+    fn fragment(index: u64) -> Fragment {
+        Fragment { fragment_index: index, total_n_fragments: 2, length: 1, data: [index as u8; 128] }
+    }
+
+    #[test]
+    fn test_reliable_sender_resends_only_unacked_fragments() {
+        let mut sender = ReliableSender::with_limits(Duration::ZERO, 2);
+        sender.track(7, route(), vec![fragment(0), fragment(1)]);
+        assert_eq!(sender.poll_timeouts(Instant::now()).resend.len(), 2, "both un-acked fragments should time out");
+        assert_eq!(sender.on_ack(7, SERVER, 0), AckOutcome::Pending);
+        let resend = sender.poll_timeouts(Instant::now()).resend;
+        assert_eq!(resend.iter().map(|r| r.fragment.fragment_index).collect::<Vec<_>>(), vec![1], "only the un-acked fragment should be resent");
+        assert_eq!(sender.on_ack(7, SERVER, 1), AckOutcome::Completed);
+        assert!(sender.get(7, SERVER).is_none(), "a fully acked session should be collected");
+        assert_eq!(sender.on_ack(7, SERVER, 1), AckOutcome::Unknown, "late duplicate ACKs are ignored");
+    }
+
+    #[test]
+    fn test_reliable_sender_gives_up_after_the_retry_limit() {
+        let mut sender = ReliableSender::with_limits(Duration::ZERO, 2);
+        sender.track(8, route(), vec![fragment(0)]);
+        assert_eq!(sender.poll_timeouts(Instant::now()).resend.len(), 1);
+        assert_eq!(sender.poll_timeouts(Instant::now()).resend.len(), 1);
+        let timeouts = sender.poll_timeouts(Instant::now());
+        assert!(timeouts.resend.is_empty(), "no resend past the retry limit");
+        assert_eq!(timeouts.given_up.iter().map(|s| s.session_id).collect::<Vec<_>>(), vec![8], "the session should be given up");
+        assert!(sender.get(8, SERVER).is_none(), "a given up session should be collected");
+    }
+}
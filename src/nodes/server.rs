@@ -1,22 +1,25 @@
 //SERVER
-use petgraph::algo::dijkstra;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::mem::transmute;
-use crossbeam_channel::{Receiver, RecvError, Sender};
-use eframe::egui::accesskit::Node;
+use crossbeam_channel::{Receiver, Sender};
 use wg_2024::packet::{Ack, FloodRequest, FloodResponse, Fragment, Nack, NackType, NodeType, Packet, PacketType};
 use wg_2024::network::{NodeId, SourceRoutingHeader};
-use log::{info, error, warn, debug};
+use log::{info, error, warn};
 use petgraph::graph::{DiGraph, NodeIndex};
 use petgraph::visit::{EdgeRef, NodeIndexable};
-use std::collections::VecDeque;
 use std::fs;
-use std::io::Cursor;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use crate::simulation_controller::gui_input_queue::SharedGuiInput;
+use crate::simulation_controller::gui_input_queue::{SharedGuiInput, SHUTDOWN_COMMAND};
+use crate::simulation_controller::sim_log::{new_sim_log, push_sim_event, SharedSimLog, SimEvent, SimEventKind};
+use crate::nodes::protocol::{self, Capability, ChatProtocol, ConversationDigest, DeliveryStatus, FrameHeader, HistoryEntry, ServerService};
+use crate::nodes::reliable::{self, AckOutcome, ReliableSender};
+use crate::nodes::storage::{self, ConversationKey, MemoryStorage, QueuedMessage, ServerStorage};
+use crate::nodes::transfer;
+use crate::nodes::media::{self, MediaEnvelope};
 use crossbeam_channel::select;
-use rand::random;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 #[derive(Clone, Debug)]
 pub struct NetworkGraph {
@@ -234,8 +237,7 @@ impl NetworkGraph {
 }
 
 
-const MAX_CHAT_HISTORY: usize = 50; //50 messagges max for the chronology
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct server {
     pub id: u8,
     pub received_fragments: HashMap<(u64, NodeId), Vec<Option<[u8; 128]>>>,
@@ -244,12 +246,19 @@ pub struct server {
     pub packet_receiver: Receiver<Packet>,
 
     seen_floods: HashSet<(u64, NodeId)>,
-    registered_clients: Vec<NodeId>,
+    capabilities: Vec<Capability>,
+    client_capabilities: HashMap<NodeId, Vec<Capability>>,
+    services: Vec<ServerService>, //advertised to clients asking for the server type
     network_graph: NetworkGraph,
-    sent_fragments: HashMap<(u64, u64), (Fragment, NodeId)>,
-    chat_history: HashMap<(NodeId, NodeId), VecDeque<String>>,
-    media_storage: HashMap<String, (NodeId, String)>,
-    simulation_log: Arc<Mutex<Vec<String>>>,
+    sent_fragments: ReliableSender,
+    storage: Box<dyn ServerStorage>, //registered clients, chat histories and media
+    remote_clients: HashMap<NodeId, Vec<NodeId>>, //other server -> clients logged into it, from their ClientDirectory
+    reachable_servers: HashSet<NodeId>, //other servers with a route at the last check, for the history resync
+    topology_changed: bool, //links were learned or lost since the last reachability check
+    partial_uploads: HashMap<(NodeId, String), Vec<u8>>, //verified prefix of each chunked upload in progress
+    media_quota: u64, //bytes of media each client may own here
+    simulation_log: SharedSimLog,
+    rng: StdRng, //flood and session ids, seeded by the initializer for replays
     pub shared_senders: Option<Arc<Mutex<HashMap<(NodeId, NodeId), Sender<Packet>>>>>,
     shortcut_receiver: Option<Receiver<Packet>>,
 
@@ -257,15 +266,178 @@ pub struct server {
 
 impl server {
 
-    pub fn attach_log(&mut self, log: Arc<Mutex<Vec<String>>>) {
+    pub fn attach_log(&mut self, log: SharedSimLog) {
         self.simulation_log = log;
     }
 
-    fn log(&self, message: impl ToString) {
-        if let Ok(mut log) = self.simulation_log.lock() {
-            log.push(message.to_string());
+    pub fn attach_storage(&mut self, storage: Box<dyn ServerStorage>) {
+        self.storage = storage;
+    }
+
+    /// Makes the ids this server draws reproducible (see `replay::node_seed`).
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn set_media_quota(&mut self, media_quota: u64) {
+        self.media_quota = media_quota;
+    }
+
+    /// Role of the server: what it answers to and which capabilities its logins can agree on.
+    pub fn set_services(&mut self, mut services: Vec<ServerService>) {
+        services.sort();
+        services.dedup();
+        self.capabilities = Capability::ALL
+            .into_iter()
+            .filter(|capability| services.iter().any(|service| service.capabilities().contains(capability)))
+            .collect();
+        self.services = services;
+    }
+
+    /// Publishes the files in `dir` the server's services can hand out: `.txt`/`.md` files on a
+    /// text server, any file of a known content type on a media server. They are owned by the server itself.
+    pub fn load_content(&mut self, dir: &Path) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("⚠ Server {} cannot read content from {}: {}", self.id, dir.display(), e);
+                return;
+            }
+        };
+        for path in entries.flatten().map(|entry| entry.path()) {
+            let (Some(name), Some(ext)) = (path.file_name().and_then(|n| n.to_str()), path.extension().and_then(|e| e.to_str())) else { continue };
+            if !protocol::is_valid_file_name(name) {
+                continue;
+            }
+            let ext = ext.to_lowercase();
+            if self.services.contains(&ServerService::Text) && ["txt", "md"].contains(&ext.as_str()) {
+                match fs::read_to_string(&path) {
+                    Ok(content) => self.storage.store_text(name, self.id, content),
+                    Err(e) => warn!("⚠ Server {} skipped {}: {}", self.id, path.display(), e),
+                }
+            } else if self.services.contains(&ServerService::Media) && media::content_type_for(name).is_some() {
+                match fs::read(&path).map(|data| self.storage.store_media(name, self.id, MediaEnvelope::new(name, data).encode())) {
+                    Ok(true) => {},
+                    Ok(false) => warn!("⚠ Server {} skipped {}: a client owns that name", self.id, path.display()),
+                    Err(e) => warn!("⚠ Server {} skipped {}: {}", self.id, path.display(), e),
+                }
+            }
         }
+        info!("📚 Server {} serves {} text files and {} media from {}", self.id, self.storage.text_names().len(), self.storage.media_names().len(), dir.display());
+    }
+
+    /// Bytes of the media if `client_id` may see it; media it may not see are reported as not found.
+    fn visible_media(&self, name: &str, client_id: NodeId) -> Option<Vec<u8>> {
+        let info = self.storage.media_info(name).filter(|info| info.visible_to(client_id))?;
+        self.storage.media(&info.name).map(|(_, data)| data)
+    }
+
+    fn log(&self, kind: SimEventKind, message: impl ToString) {
+        push_sim_event(&self.simulation_log, SimEvent::new(Some(self.id), kind, message));
+    }
+
+    /// Like `log`, for an event about one session, so the log can be filtered by it.
+    fn log_session(&self, kind: SimEventKind, session_id: u64, message: impl ToString) {
+        push_sim_event(&self.simulation_log, SimEvent::new(Some(self.id), kind, message).with_session(session_id));
     }
+    /// Registered clients that agreed on `capability` at login.
+    fn clients_with(&self, capability: Capability) -> Vec<NodeId> {
+        self.storage
+            .registered_clients()
+            .into_iter()
+            .filter(|id| self.client_capabilities.get(id).map_or(true, |agreed| agreed.contains(&capability)))
+            .collect()
+    }
+
+    /// Every other server this one found during network discovery.
+    fn other_servers(&self) -> Vec<NodeId> {
+        let mut servers: Vec<NodeId> = self.network_graph
+            .node_types
+            .iter()
+            .filter(|(&node_id, node_type)| node_id != self.id && **node_type == NodeType::Server)
+            .map(|(&node_id, _)| node_id)
+            .collect();
+        servers.sort();
+        servers
+    }
+
+    /// Server a client not registered here is logged into, according to the other servers' directories.
+    fn home_server_of(&self, client_id: NodeId) -> Option<NodeId> {
+        self.remote_clients
+            .iter()
+            .filter(|(_, clients)| clients.contains(&client_id))
+            .map(|(&server_id, _)| server_id)
+            .min()
+    }
+
+    /// Tells every other server which clients are logged in here, so they can relay messages for them.
+    fn announce_clients(&mut self) {
+        if !self.services.contains(&ServerService::Chat) {
+            return;
+        }
+        let clients = self.storage.registered_clients();
+        for server_id in self.other_servers() {
+            info!("📇 Sending client directory {:?} to server {}", clients, server_id);
+            self.send_chat_message(server_id, ChatProtocol::ClientDirectory { server_id: self.id, clients: clients.clone() });
+        }
+    }
+
+    /// Hands `message` to `target_id`'s home server, which delivers it as if it came from its own client.
+    /// `timestamp` is that of the history entry of a relayed chat message, 0 for anything else.
+    fn relay_to(&mut self, target_id: NodeId, timestamp: u64, message: ChatProtocol) -> bool {
+        match self.home_server_of(target_id) {
+            Some(server_id) => {
+                info!("🔀 Relaying {} for client {} through server {}", message.tag(), target_id, server_id);
+                self.send_chat_message(server_id, ChatProtocol::Relay { target_id, timestamp, message: Box::new(message) });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stores a chat message and sends it as a delta to every other server.
+    fn record_message(&mut self, key: ConversationKey, entry: HistoryEntry) {
+        if self.storage.append_message(key, entry.clone()) {
+            self.replicate(key, vec![entry]);
+        }
+    }
+
+    fn replicate(&mut self, conversation: ConversationKey, entries: Vec<HistoryEntry>) {
+        for server_id in self.other_servers() {
+            let update = ChatProtocol::ChatHistoryUpdate { source_server: self.id, conversation, entries: entries.clone() };
+            self.send_chat_message(server_id, update);
+        }
+    }
+
+    fn history_summary(&self, reply: bool) -> ChatProtocol {
+        let conversations = self
+            .storage
+            .conversations()
+            .into_iter()
+            .map(|key| ConversationDigest::of(key, &self.storage.history(key).unwrap_or_default()))
+            .collect();
+        ChatProtocol::HistorySummary { source_server: self.id, reply, conversations }
+    }
+
+    /// Anti-entropy: a server that just became reachable (found by the discovery, or back after a
+    /// partition) gets our digests, and the two exchange whatever the other is missing.
+    fn resync_rejoined_servers(&mut self) {
+        if !self.services.contains(&ServerService::Chat) {
+            return;
+        }
+        let servers = self.other_servers();
+        self.reachable_servers.retain(|id| servers.contains(id));
+        for server_id in servers {
+            if self.compute_best_path(self.id, server_id).is_none() {
+                self.reachable_servers.remove(&server_id);
+            } else if self.reachable_servers.insert(server_id) {
+                info!("🔄 Server {} is reachable, starting chat history resync", server_id);
+                let summary = self.history_summary(false);
+                self.send_chat_message(server_id, summary);
+            }
+        }
+    }
+
     pub(crate) fn new(id: u8, packet_sender: HashMap<NodeId,Sender<Packet>>, packet_receiver: Receiver<Packet>, shared_senders: Option<Arc<Mutex<HashMap<(NodeId,NodeId), Sender<Packet>>>>>,shortcut_receiver: Option<Receiver<Packet>>,) -> Self {
         info!("Server {} created.", id);
         let net_graph = NetworkGraph::new(shared_senders.clone());
@@ -277,12 +449,19 @@ impl server {
             packet_sender,
             packet_receiver,
             seen_floods: HashSet::new(),
-            registered_clients: Vec::new(),
+            capabilities: Capability::ALL.to_vec(),
+            client_capabilities: HashMap::new(),
+            services: vec![ServerService::Chat, ServerService::Media],
             network_graph: net_graph,
-            sent_fragments: Default::default(),
-            chat_history: HashMap::new(),
-            media_storage: HashMap::new(),
-            simulation_log: Arc::new(Mutex::new(Vec::new())),
+            sent_fragments: ReliableSender::new(),
+            storage: Box::new(MemoryStorage::new(storage::DEFAULT_HISTORY_RETENTION, storage::DEFAULT_OFFLINE_QUEUE_CAP)),
+            remote_clients: HashMap::new(),
+            reachable_servers: HashSet::new(),
+            topology_changed: false,
+            partial_uploads: HashMap::new(),
+            media_quota: storage::DEFAULT_MEDIA_QUOTA,
+            simulation_log: new_sim_log(),
+            rng: StdRng::from_os_rng(),
             shared_senders,
             shortcut_receiver,
 
@@ -290,7 +469,7 @@ impl server {
     }
     fn initiate_network_discovery(&mut self) {
 
-        let flood_id = random::<u64>(); //random flood id
+        let flood_id = self.rng.random::<u64>(); //random flood id, reproducible with a seed
         info!("Network discovery initialized.");
         let flood_request = FloodRequest {
             flood_id,
@@ -318,8 +497,9 @@ impl server {
     pub fn run(&mut self, gui_buffer_input: SharedGuiInput) {
 
         let tick = crossbeam_channel::tick(std::time::Duration::from_secs(1));
+        let retransmit_tick = crossbeam_channel::tick(reliable::POLL_INTERVAL);
         info!("Server {} started running.", self.id);
-        self.log("SERVER STARTED");
+        self.log(SimEventKind::Lifecycle, "SERVER STARTED");
         // println!("👋👋👋👋👋👋Server log addr i: {:p}", Arc::as_ptr(&self.simulation_log));
         if let Some(ref arc) = self.shared_senders {
             println!("👋👋👋👋👋👋 Server SHAREDSENDERS i: {:p}", Arc::as_ptr(arc));
@@ -327,6 +507,7 @@ impl server {
             println!("❌ shared_senders is None");
         }
         let mut discovery_started=false;
+        let mut stopping = false;
         //self.initiate_network_discovery();
 
 
@@ -340,17 +521,30 @@ impl server {
                             self.initiate_network_discovery();
                             info!("✅ Server {} initiated network discovery", self.id);
                         }
+                        if std::mem::take(&mut self.topology_changed) {
+                            self.resync_rejoined_servers();
+                        }
                         //self.network_graph.print_graph();
                         // Initial processing of any pending GUI messages
                         if let Ok(mut buffer) = gui_buffer_input.lock() {
                             if let Some(messages) = buffer.get_mut(&(self.id as NodeId)) {
-                                for message in messages.drain(..) {
+                                // like the clients, nothing queued after [Shutdown] is taken out of the buffer
+                                let shutdown_at = messages.iter().position(|message| message == SHUTDOWN_COMMAND);
+                                let popped: Vec<String> = messages.drain(..shutdown_at.unwrap_or(messages.len())).collect();
+                                if shutdown_at.is_some() {
+                                    messages.remove(0);
+                                    stopping = true;
+                                }
+                                for message in popped {
                                     info!("🧹🧹🧹 Server {} popped one msg from GUI 🧹🧹🧹", self.id);
                                     self.process_gui_message(message);
                                 }
                             }
                         }
                     },
+                    recv(retransmit_tick) -> _ => {
+                        self.retransmit_unacked_fragments();
+                    },
 
                 //receive packets from neighbors
                      recv(self.packet_receiver) -> packet_result => {
@@ -367,8 +561,9 @@ impl server {
                                         self.handle_nack(packet.session_id, nack, &packet.clone(), packet.routing_header);
                                         //RECUPERO SESSION ID E FRAGMENT INDEX;
                                     }
-                                    PacketType::Ack(_) => { /* no-op */
+                                    PacketType::Ack(ack) => {
                                         info!("Server {} received ACK packet", self.id);
+                                        self.handle_ack(packet.session_id, ack, &packet.routing_header);
                                     }
                                     PacketType::FloodRequest(flood_request) => {
                                         info!("server {} recevied FloodRequest {:?}", self.id, flood_request);
@@ -393,7 +588,9 @@ impl server {
                             Ok(packet) => {
                                  info!("📡📡📡📡📡 Shortcut packet received in host {}: {:?} 📡📡📡📡📡", self.id, packet);
                                 match packet.pack_type {
-                                    PacketType::Ack(_) => {}
+                                    PacketType::Ack(ref ack) => {
+                                        self.handle_ack(packet.session_id, ack, &packet.routing_header)
+                                    }
                                     PacketType::Nack(ref nack) => {
                                         self.handle_nack(packet.session_id, nack, &packet, packet.routing_header.clone())
                                     }
@@ -410,46 +607,45 @@ impl server {
                         }
                     }
                 }
+            if stopping {
+                info!("🛑 Server {} stopping on controller request", self.id);
+                self.log(SimEventKind::Lifecycle, "SERVER STOPPED");
+                break;
+            }
         }
     }
 
     fn process_gui_message(&mut self, message: String) {
         //MEDIABROADCAST
-        if let Some(stripped) = message.strip_prefix("[MediaBroadcast]::") {
-            info!("Server {} received message from GUI: {:?}", self.id, stripped);
-            let parts: Vec<&str> = stripped.splitn(2, "::").collect();
-            if parts.len() == 2 {
-                let media_name = parts[0].to_string();
-                let base64_data = parts[1].to_string();
-                self.media_storage.insert(media_name.clone(), (self.id, base64_data.clone()));
-                if let Some((owner, full_data)) = self.media_storage.get(&media_name) {
-                    let preview = full_data
-                        .chars()
-                        .take(10)
-                        .collect::<String>();
-                    info!(
-                        "Media stored in server '{}' is: ({}, \"{}…\")",
-                        media_name,
-                        owner,
-                        preview
-                    );
-                    self.log(format!("Media stored in server '{}' is: ({}, \"{}…\")",
-                                     media_name,
-                                     owner,
-                                     preview
-                    ));
-                }
-                info!("Registered clients to server: {:?}", self.registered_clients);
-                let clients= self.registered_clients.clone();
-
-                for target_id in clients {
-                    info!("Registered clients in {} are {:?}", self.id, self.registered_clients);
-                    let forward = format!("[MediaDownloadResponse]::{}::{}", media_name, base64_data);
-                    info!("Broadcasting the MediaDownloadResponse");
-                    self.send_chat_message(0, target_id, forward.clone());
-                }
-                //info!("Broadcasted media '{}' from GUI for server {}", media_name, self.id);
+        if let Ok(ChatProtocol::MediaBroadcast { name: media_name, data: media_data }) = message.parse::<ChatProtocol>() {
+            info!("Server {} received message from GUI: {}", self.id, media_name);
+            let media_data = MediaEnvelope::wrap(&media_name, media_data);
+            if !self.storage.store_media(&media_name, self.id, media_data.clone()) {
+                warn!("Server {} broadcasts '{}' without storing it: a client owns that name", self.id, media_name);
+            }
+            if let Some((owner, full_data)) = self.storage.media(&media_name) {
+                info!(
+                    "Media stored in server '{}' is: ({}, {} bytes)",
+                    media_name,
+                    owner,
+                    full_data.len()
+                );
+                self.log(SimEventKind::Media, format!("Media stored in server '{}' is: ({}, {} bytes)",
+                                 media_name,
+                                 owner,
+                                 full_data.len()
+                ));
             }
+            info!("Registered clients to server: {:?}", self.storage.registered_clients());
+            let clients = self.clients_with(Capability::Broadcast);
+
+            for target_id in clients {
+                info!("Registered clients in {} are {:?}", self.id, self.storage.registered_clients());
+                let forward = ChatProtocol::MediaDownloadResponse { name: media_name.clone(), data: media_data.clone() };
+                info!("Broadcasting the MediaDownloadResponse");
+                self.send_chat_message(target_id, forward);
+            }
+            //info!("Broadcasted media '{}' from GUI for server {}", media_name, self.id);
         }
         //FLOODREQUIRED
         if let Some(stripped) = message.strip_prefix("[FloodRequired]::") {
@@ -599,7 +795,7 @@ impl server {
                     if parts.len() == 2 {
                         if let Ok(drone_id) = parts[1].parse::<NodeId>() {
                             info!("Detected crash node {}! 💥💥💥💥💥", drone_id);
-                            self.log(format!("Drone {} crashed!", drone_id));
+                            self.log(SimEventKind::Topology, format!("Drone {} crashed!", drone_id));
 
                             if !self.network_graph.node_indices.contains_key(&drone_id) {
                                 warn!("🚫 Node {} not found in graph — cannot remove", drone_id);
@@ -640,6 +836,12 @@ impl server {
     /// Handle fragment processing
     fn handle_fragment(&mut self, session_id: u64, fragment: &Fragment, routing_header: SourceRoutingHeader) {
         let key = (session_id, routing_header.hops[0]);
+        // Refuse forged counts and indices before they size or index the buffer
+        let buffered_total = self.received_fragments.get(&key).map(|fragments| fragments.len() as u64);
+        if let Err(e) = protocol::check_fragment(fragment, buffered_total) {
+            warn!("Server {} dropped fragment {} of session {:?}: {}", self.id, fragment.fragment_index, key, e);
+            return;
+        }
         // Initialize storage for fragments if not already present
         let entry = self.received_fragments.entry(key).or_insert_with(|| vec![None; fragment.total_n_fragments as usize]);
         // Check if the fragment is already received --> if already received return and do nothing
@@ -671,201 +873,447 @@ impl server {
             message.extend_from_slice(&fragment.unwrap());
         }
         message.truncate(total_length);
-        //now trasform the message to string.
-        let message_string = String::from_utf8_lossy(&message).to_string();
         let session_id: u64 = key.0;
         let client_id = key.1;
 
-        //create a format to handle the
-        let tokens: Vec<&str> = message_string.trim().splitn(3, "::").collect();
+        let (header, request) = match ChatProtocol::decode_frame(&message) {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("Unrecognized message: {}", e);
+                info!("Reassembled message for session {:?}: {:?}", key, message);
+                // the header layout never changes, so even a client too old to decode can be told why
+                if FrameHeader::read(&message).is_ok_and(|(header, _)| header.version < protocol::MIN_PROTOCOL_VERSION) {
+                    self.send_chat_message(client_id, ChatProtocol::Error { reason: "unsupported_protocol_version".to_string() });
+                }
+                return;
+            }
+        };
         info!("Handling complete message");
-        match tokens.as_slice() {
-            ["[Login]", server_id_str] => {
+        // a logged in client only gets what was agreed on at login
+        if let (Some(required), Some(agreed)) = (request.required_capability(), self.client_capabilities.get(&client_id)) {
+            if !agreed.contains(&required) {
+                warn!("Client {} sent {} without negotiating '{}'", client_id, request.tag(), required.name());
+                let reason = format!("capability_not_negotiated::{}", required.name());
+                self.send_chat_message(client_id, ChatProtocol::Error { reason });
+                return;
+            }
+        }
+        // a content server only answers what its role covers
+        if let Some(service) = request.required_service().filter(|service| !self.services.contains(service)) {
+            warn!("Server {} does not offer '{}', dropping {} from {}", self.id, service.name(), request.tag(), client_id);
+            if self.network_graph.get_node_type(client_id) != Some(&NodeType::Server) {
+                let reason = format!("unsupported_service::{}", service.name());
+                self.send_chat_message(client_id, ChatProtocol::Error { reason });
+            }
+            return;
+        }
+        // uploads are refused before anything is stored once the sender's media would not fit its quota
+        if let Some((name, size)) = request.media_upload() {
+            let replaced = self.storage.media_info(name).filter(|info| info.owner == client_id).map_or(0, |info| info.size);
+            let used = self.storage.media_usage(client_id) - replaced;
+            if size > self.media_quota.saturating_sub(used) {
+                warn!("Client {} is over its media quota: {} + {} bytes of {}", client_id, used, size, self.media_quota);
+                let reason = format!("media_quota_exceeded::{}::{}::{}", name, used, self.media_quota);
+                self.partial_uploads.remove(&(client_id, name.to_string()));
+                self.send_chat_message(client_id, ChatProtocol::Error { reason });
+                return;
+            }
+        }
+        match request {
+            ChatProtocol::ServerTypeRequest => {
+                info!("🏷 Client {} asked for the services of server {}", client_id, self.id);
+                self.send_chat_message(client_id, ChatProtocol::ServerTypeResponse { services: self.services.clone() });
+            },
+            ChatProtocol::Login { server_id } => {
                 info!(" -------------- 🔔🔔🔔 Received login 🔔🔔🔔 ---------------");
-                if server_id_str.parse::<NodeId>() == Ok(self.id) {
-                    if !self.registered_clients.contains(&client_id) {
-                        self.registered_clients.push(client_id);
-                        self.log(format!("Client {} registered to this server", client_id));
-
-                        let login_acknowledgement = format!("[LoginAck]::{}", session_id);
-                        self.send_chat_message(session_id, client_id, login_acknowledgement);
-                        info!("🚗🚗🚗🚗 LoginAck sent");
-
+                if server_id == self.id {
+                    // clients restored from storage log in again after a restart, so every Login is acked
+                    self.storage.register_client(client_id);
+                    let (version, capabilities) = protocol::negotiate(header.version, &header.capabilities, protocol::PROTOCOL_VERSION, &self.capabilities);
+                    self.log(SimEventKind::Login, format!("Client {} registered to this server (protocol v{}, capabilities: {:?})", client_id, version, capabilities));
+                    self.client_capabilities.insert(client_id, capabilities.clone());
+
+                    self.send_chat_message(client_id, ChatProtocol::LoginAck { session_id, version, capabilities });
+                    self.announce_clients();
+
+                    // what arrived while the client was away, in order
+                    for QueuedMessage { sender_id, message_id, message } in self.storage.take_queued(client_id) {
+                        info!("📬 Delivering queued message from {} to {}", sender_id, client_id);
+                        self.send_chat_message(client_id, ChatProtocol::MessageFrom { sender_id, message_id, message });
+                        if self.storage.is_registered(sender_id) {
+                            self.send_chat_message(sender_id, ChatProtocol::DeliveryStatus { target_id: client_id, status: DeliveryStatus::Delivered });
+                        }
                     }
                 } else {
                     error!("server_id in Login request is not the id of the server receiving the fragment!")
                 }
             },
-            ["[ClientListRequest]"] => {
+            ChatProtocol::ClientListRequest => {
                 info!(" --------------------------- Received ClientListRequest -----------------------------");
-                let clients = self.registered_clients.clone();
+                // clients on other servers can be reached too, through a relay
+                let mut clients = self.storage.registered_clients();
+                clients.extend(self.remote_clients.values().flatten().copied());
+                clients.sort();
+                clients.dedup();
                 info!("server has the following connected clients: {:?}", clients);
-                self.log(format!("server has the following connected clients: {:?}", clients));
-                let response = format!("[ClientListResponse]::{:?}", clients);
-                self.send_chat_message(session_id, client_id, response);
+                self.log(SimEventKind::Login, format!("server has the following connected clients: {:?}", clients));
+                self.send_chat_message(client_id, ChatProtocol::ClientListResponse { clients });
             },
-            ["[ChatRequest]", target_id_str] => {
+            ChatProtocol::ChatRequest { peer_id: target_id } => {
                 info!(" --------------------------- Received ChatRequest ----------------------------");
-                self.log(format!("Server received chat request from {} to {}", client_id, target_id_str));
-                if let Ok(target_id) = target_id_str.parse::<NodeId>() {
-                    let success = self.registered_clients.contains(&target_id);
-                    let response = format!("[ChatStart]::{}", success);
+                self.log(SimEventKind::Chat, format!("Server received chat request from {} to {}", client_id, target_id));
+                let success = self.storage.is_registered(target_id) || self.home_server_of(target_id).is_some();
 
-                    // Ensure chat history exists even if no messages are sent
-                    let key = (client_id.min(target_id), client_id.max(target_id));
-                    self.chat_history.entry(key).or_insert_with(VecDeque::new);
+                // Ensure chat history exists even if no messages are sent
+                let key = (client_id.min(target_id), client_id.max(target_id));
+                self.storage.open_conversation(key);
 
-                    self.send_chat_message(session_id, client_id, response);
+                self.send_chat_message(client_id, ChatProtocol::ChatStart { success });
+            },
+            ChatProtocol::MessageTo { target_id, message_id, message: msg } => {
+                let key = (client_id.min(target_id), client_id.max(target_id));
+                let chat_entry = HistoryEntry::new(client_id, message_id, msg.clone());
+                let status = if self.storage.is_registered(target_id) {
+                    self.log(SimEventKind::Chat, format!("Server received chat message from {} to {}", client_id, target_id));
+                    self.record_message(key, chat_entry);
+                    self.send_chat_message(target_id, ChatProtocol::MessageFrom { sender_id: client_id, message_id, message: msg });
+                    DeliveryStatus::Delivered
+                } else if self.home_server_of(target_id).is_some() {
+                    self.log(SimEventKind::Chat, format!("Server relaying chat message from {} to {} (logged in elsewhere)", client_id, target_id));
+                    let timestamp = chat_entry.timestamp;
+                    self.record_message(key, chat_entry);
+                    self.relay_to(target_id, timestamp, ChatProtocol::MessageFrom { sender_id: client_id, message_id, message: msg });
+                    DeliveryStatus::Delivered
+                } else if self.storage.is_known(target_id) && self.storage.queue_message(target_id, QueuedMessage { sender_id: client_id, message_id, message: msg }) {
+                    self.log(SimEventKind::Chat, format!("Client {} is logged out, message from {} queued", target_id, client_id));
+                    self.record_message(key, chat_entry);
+                    DeliveryStatus::Queued
+                } else {
+                    warn!("Message from {} to {} rejected (unknown client or offline queue full)", client_id, target_id);
+                    DeliveryStatus::Rejected
+                };
+                self.send_chat_message(client_id, ChatProtocol::DeliveryStatus { target_id, status });
+            },
+            ChatProtocol::Receipt { peer_id: sender_id, message_id, kind } => {
+                // the client that got the message tells its sender, whoever that is logged in
+                let receipt = ChatProtocol::Receipt { peer_id: client_id, message_id, kind };
+                if self.storage.is_registered(sender_id) {
+                    info!("🧾 {} receipt for message {} from {} to {}", kind.name(), message_id, client_id, sender_id);
+                    self.send_chat_message(sender_id, receipt);
+                } else if self.relay_to(sender_id, 0, receipt) {
+                    info!("🧾 {} receipt for message {} from {} relayed to {}", kind.name(), message_id, client_id, sender_id);
+                } else {
+                    warn!("Dropping {} receipt from {}: client {} is not logged in", kind.name(), client_id, sender_id);
                 }
             },
-            ["[MessageTo]", target_id_str, msg] => {
-                if let Ok(target_id) = target_id_str.parse::<NodeId>() {
-                    if self.registered_clients.contains(&target_id) {
-                        self.log(format!("Server received chat message from {} to {}", client_id, target_id_str));
-                        let response = format!("[MessageFrom]::{}::{}", client_id, msg);
-                        self.send_chat_message(session_id, target_id, response);
-
-                        let entry = self
-                            .chat_history
-                            .entry((client_id.min(target_id), client_id.max(target_id)))
-                            .or_insert_with(VecDeque::new);
-
-                        let chat_entry = format!("{}:\n {}", client_id, msg);
-                        entry.push_back(chat_entry);
-                        if entry.len() > MAX_CHAT_HISTORY {
-                            entry.pop_front();
-                        }
-                    } else {
-                        self.send_chat_message(session_id, client_id, "error_wrong_client_id!".to_string());
+            ChatProtocol::HistoryRequest { client_id: source_id, target_id } => { //when client wants to see chronology
+                info!(" ----------------------- Received HistoryRequest ----------------------------");
+                self.log(SimEventKind::Chat, format!("Server received CHAT-HISTORY request from {} with {}", source_id, target_id));
+                let key = (source_id.min(target_id), source_id.max(target_id));
+                let history = if let Some(messages) = self.storage.history(key) {
+                    messages.iter().map(HistoryEntry::to_string).collect::<Vec<_>>().join("\n")
+                } else {
+                    "No history available".into()
+                };
+                self.send_chat_message(client_id, ChatProtocol::HistoryResponse { history });
+            },
+
+            ChatProtocol::RoomCreate { room } => {
+                info!(" ----------------------- Received RoomCreate ----------------------------");
+                if !protocol::is_valid_room_name(&room) {
+                    self.send_chat_message(client_id, ChatProtocol::Error { reason: "invalid_room_name".to_string() });
+                } else if !self.storage.create_room(&room) {
+                    self.send_chat_message(client_id, ChatProtocol::Error { reason: format!("room_exists::{}", room) });
+                } else {
+                    self.log(SimEventKind::Room, format!("Client {} created room '{}'", client_id, room));
+                    self.storage.join_room(&room, client_id);
+                    self.notify_room_members(&room, None);
+                }
+            },
+            ChatProtocol::RoomJoin { room } => {
+                if self.storage.join_room(&room, client_id) {
+                    self.log(SimEventKind::Room, format!("Client {} joined room '{}'", client_id, room));
+                    self.notify_room_members(&room, None);
+                } else {
+                    self.send_chat_message(client_id, ChatProtocol::Error { reason: format!("unknown_room::{}", room) });
+                }
+            },
+            ChatProtocol::RoomLeave { room } => {
+                if self.storage.leave_room(&room, client_id) {
+                    self.log(SimEventKind::Room, format!("Client {} left room '{}'", client_id, room));
+                    self.notify_room_members(&room, Some(client_id));
+                } else {
+                    self.send_chat_message(client_id, ChatProtocol::Error { reason: format!("not_in_room::{}", room) });
+                }
+            },
+            ChatProtocol::RoomListRequest => {
+                let rooms = self.storage.room_names();
+                self.send_chat_message(client_id, ChatProtocol::RoomListResponse { rooms });
+            },
+            ChatProtocol::RoomMessageTo { room, message_id, message } => {
+                let members = match self.storage.room(&room) {
+                    Some(r) if r.members.contains(&client_id) => r.members,
+                    _ => {
+                        warn!("Client {} wrote to room '{}' without being a member", client_id, room);
+                        self.send_chat_message(client_id, ChatProtocol::Error { reason: format!("not_in_room::{}", room) });
+                        return;
+                    }
+                };
+                self.log(SimEventKind::Room, format!("Server received message from {} for room '{}'", client_id, room));
+                self.storage.append_room_message(&room, format!("{}:\n {}", client_id, message));
+                // members that are logged out find it in the room history
+                for member in members {
+                    if member != client_id && self.storage.is_registered(member) {
+                        let msg = ChatProtocol::RoomMessageFrom { room: room.clone(), sender_id: client_id, message_id, message: message.clone() };
+                        self.send_chat_message(member, msg);
                     }
                 }
             },
-            ["[HistoryRequest]", source_id, target_id_str, ] => { //when client wants to see chronology
-                info!(" ----------------------- Received HistoryRequest ----------------------------");
-                self.log(format!("Server received CHAT-HISTORY request from {} with {}", source_id, target_id_str));
-                if let Ok(target_id) = target_id_str.parse::<NodeId>() {
-                    let c1 = source_id.parse::<NodeId>().unwrap_or(client_id);
-                    let c2 = target_id;
-                    let key = (c1.min(c2), c1.max(c2));
-                    let response = if let Some(messages) = self.chat_history.get(&key) {
-                        messages.iter().cloned().collect::<Vec<_>>().join("\n")
-                    } else {
-                        "No history available".into()
-                    };
-                    self.send_chat_message(session_id, client_id, format!("[HistoryResponse]::{}", response));
+            ChatProtocol::RoomHistoryRequest { room } => {
+                self.log(SimEventKind::Room, format!("Server received ROOM-HISTORY request from {} for '{}'", client_id, room));
+                let history = match self.storage.room(&room) {
+                    Some(r) if !r.history.is_empty() => r.history.into_iter().collect::<Vec<_>>().join("\n"),
+                    _ => "No history available".into(),
+                };
+                self.send_chat_message(client_id, ChatProtocol::HistoryResponse { history });
+            },
+
+            // the directory is filed under whoever sent it, not under the server it names
+            ChatProtocol::ClientDirectory { clients, .. } if self.is_server(client_id) => {
+                info!("📇 Server {} has clients {:?}", client_id, clients);
+                let first_contact = !self.remote_clients.contains_key(&client_id);
+                self.remote_clients.insert(client_id, clients);
+                // a server that just showed up does not know our clients yet
+                if first_contact && !self.storage.registered_clients().is_empty() {
+                    let clients = self.storage.registered_clients();
+                    self.send_chat_message(client_id, ChatProtocol::ClientDirectory { server_id: self.id, clients });
+                }
+            },
+            ChatProtocol::Relay { target_id, timestamp, message } if self.is_server(client_id) => {
+                match *message {
+                    msg @ (ChatProtocol::MessageFrom { .. } | ChatProtocol::Receipt { .. }) if self.storage.is_registered(target_id) => {
+                        if let ChatProtocol::MessageFrom { sender_id, message_id, message: ref text } = msg {
+                            // the origin server replicates it too; with its timestamp, merging keeps one identical copy
+                            let entry = HistoryEntry { sender_id, message_id, timestamp, message: text.clone() };
+                            self.storage.append_message((sender_id.min(target_id), sender_id.max(target_id)), entry);
+                        }
+                        self.send_chat_message(target_id, msg);
+                    }
+                    ChatProtocol::MessageFrom { sender_id, message_id, message } if self.storage.is_known(target_id) => {
+                        // logged out since the directory went out: wait here like a local message would
+                        if !self.storage.queue_message(target_id, QueuedMessage { sender_id, message_id, message }) {
+                            warn!("Relayed message from {} to {} dropped, offline queue full", sender_id, target_id);
+                        }
+                    }
+                    other => warn!("Server {} dropped relayed {} for client {}", self.id, other.tag(), target_id),
                 }
             },
 
-            ["[ChatHistoryUpdate]", source_server, serialized_entry] => {
-                if let Ok(((id1, id2), history)) = serde_json::from_str::<((NodeId, NodeId), VecDeque<String>)>(serialized_entry) {
-                    self.chat_history.insert((id1, id2), history);
-                    info!(" 🚨🚨🚨🚨 Received and saved full chat history entry from server {} 🚨🚨🚨🚨", source_server);
-                } else {
-                    error!("Failed to parse full chat history entry from {}", source_server);
+            ChatProtocol::ChatHistoryUpdate { conversation, entries, .. } if self.is_server(client_id) => {
+                let received = entries.len();
+                let added = self.storage.merge_history(conversation, entries);
+                info!(" 🚨🚨🚨🚨 Merged chat history of {:?} from server {}: {} of {} messages were new 🚨🚨🚨🚨", conversation, client_id, added.len(), received);
+            },
+            // answered to whoever sent it, whatever server it names
+            ChatProtocol::HistorySummary { reply, conversations, .. } if self.is_server(client_id) => {
+                let source_server = client_id;
+                let theirs: HashMap<ConversationKey, ConversationDigest> = conversations.iter().map(|d| (d.conversation, *d)).collect();
+                // send what differs on our side; merging on theirs makes it a no-op for what they already have
+                for key in self.storage.conversations() {
+                    let history = self.storage.history(key).unwrap_or_default();
+                    if !history.is_empty() && theirs.get(&key) != Some(&ConversationDigest::of(key, &history)) {
+                        info!("🔄 Resyncing {:?} ({} messages) to server {}", key, history.len(), source_server);
+                        let update = ChatProtocol::ChatHistoryUpdate { source_server: self.id, conversation: key, entries: history.into() };
+                        self.send_chat_message(source_server, update);
+                    }
+                }
+                // and ask for what they have that we lack, unless this already is their answer
+                let behind = conversations.iter().any(|d| {
+                    d.count > 0 && self.storage.history(d.conversation).map(|h| ConversationDigest::of(d.conversation, &h)) != Some(*d)
+                });
+                if behind && !reply {
+                    let summary = self.history_summary(true);
+                    self.send_chat_message(source_server, summary);
                 }
             },
 
-            ["[MediaUpload]", media_name, base64_data] => {
+            ChatProtocol::MediaUpload { name: media_name, data: media_data } => {
                 info!(" ------------------------ Received MediaUpload ---------------------------");
-                self.log(format!("Server received MediaUpload from {} of the media: {}", client_id, media_name));
-                // Save the image media in the hashmap
-                self.media_storage.insert(media_name.clone().parse().unwrap(), (client_id, base64_data.parse().unwrap()));
-                let confirm = format!("[MediaUploadAck]::{}", media_name);
-                self.send_chat_message(session_id, client_id, confirm);
+                self.log(SimEventKind::Media, format!("Server received MediaUpload from {} of the media: {}", client_id, media_name));
+                // Saved under its content hash; a name can only be replaced by whoever uploaded it
+                let response = if self.storage.store_media(&media_name, client_id, media_data) {
+                    ChatProtocol::MediaUploadAck { name: media_name }
+                } else {
+                    ChatProtocol::Error { reason: format!("media_name_taken::{}", media_name) }
+                };
+                self.send_chat_message(client_id, response);
             },
             //Providing Media list if asked by client --> so they can get to know before what to download
-            ["[MediaListRequest]"] => {
+            ChatProtocol::MediaListRequest => {
                 info!(" ------------------------ Received MediaListRequest ---------------------------");
-                self.log(format!("Server received MediaListRequest from {}", client_id));
-                let list = self.media_storage.keys()
-                    .cloned()
-                    .collect::<Vec<String>>()
-                    .join(",");
-                let response = format!("[MediaListResponse]::{}", list);
-                self.send_chat_message(session_id, client_id, response);
+                self.log(SimEventKind::Media, format!("Server received MediaListRequest from {}", client_id));
+                let media = self.storage.media_list().into_iter().filter(|info| info.visible_to(client_id)).collect();
+                self.send_chat_message(client_id, ChatProtocol::MediaListResponse { media });
             },
-            ["[MediaDownloadRequest]", media_name] => {
+            ChatProtocol::MediaDownloadRequest { name: media_name } => {
                 info!(" ------------------------ Received MediaDownload Request -----------------------");
-                self.log(format!("Server received MediaDownloadRequest from {}", client_id));
-                let response = if let Some((_owner, base64_data)) = self.media_storage.get(*media_name) {
-                    format!("[MediaDownloadResponse]::{}::{}", media_name, base64_data)
+                self.log(SimEventKind::Media, format!("Server received MediaDownloadRequest from {}", client_id));
+                let response = match self.visible_media(&media_name, client_id) {
+                    Some(media_data) => ChatProtocol::MediaDownloadResponse { name: media_name, data: media_data },
+                    None => ChatProtocol::MediaNotFound,
+                };
+                self.send_chat_message(client_id, response);
+            },
+            ChatProtocol::MediaChunkRequest { name: media_name, offset, length } => {
+                let response = match self.visible_media(&media_name, client_id) {
+                    Some(media_data) if offset <= media_data.len() as u64 => {
+                        info!("📦 Sending chunk of '{}' at offset {} to {}", media_name, offset, client_id);
+                        transfer::media_chunk(&media_name, &media_data, offset, length)
+                    }
+                    Some(_) => ChatProtocol::Error { reason: format!("invalid_chunk_offset::{}", media_name) },
+                    None => ChatProtocol::MediaNotFound,
+                };
+                self.send_chat_message(client_id, response);
+            },
+            ChatProtocol::MediaUploadChunk { name: media_name, offset, total_size, chunk_checksum, file_checksum, data } => {
+                let key = (client_id, media_name.clone());
+                let partial = self.partial_uploads.entry(key.clone()).or_default();
+                if offset == 0 && !partial.is_empty() {
+                    partial.clear(); // the client started over
+                }
+                // anything out of order or damaged is dropped; the ack tells the client where to resume
+                if offset == partial.len() as u64 && transfer::checksum(&data) == chunk_checksum && offset + data.len() as u64 <= total_size {
+                    partial.extend_from_slice(&data);
+                }
+                if (partial.len() as u64) < total_size {
+                    let next_offset = partial.len() as u64;
+                    self.send_chat_message(client_id, ChatProtocol::MediaUploadChunkAck { name: media_name, next_offset });
+                    return;
+                }
+                let media_data = self.partial_uploads.remove(&key).unwrap_or_default();
+                if transfer::checksum(&media_data) == file_checksum {
+                    info!("📦 Chunked upload of '{}' from {} complete ({} bytes)", media_name, client_id, media_data.len());
+                    self.log(SimEventKind::Media, format!("Server received MediaUpload from {} of the media: {}", client_id, media_name));
+                    let response = if self.storage.store_media(&media_name, client_id, media_data) {
+                        ChatProtocol::MediaUploadAck { name: media_name }
+                    } else {
+                        ChatProtocol::Error { reason: format!("media_name_taken::{}", media_name) }
+                    };
+                    self.send_chat_message(client_id, response);
                 } else {
-                    "[MediaDownloadResponse]::ERROR::NotFound".to_string()
+                    warn!("Chunked upload of '{}' from {} does not match its checksum", media_name, client_id);
+                    self.send_chat_message(client_id, ChatProtocol::Error { reason: format!("media_checksum_mismatch::{}", media_name) });
+                }
+            },
+            ChatProtocol::MediaSetVisibility { name: media_name, visibility } => {
+                let response = match self.storage.media_info(&media_name) {
+                    Some(info) if info.owner == client_id => {
+                        info!("🔐 Client {} made '{}' visible to {}", client_id, media_name, visibility);
+                        self.storage.set_media_visibility(&media_name, visibility.clone());
+                        ChatProtocol::MediaVisibilityAck { name: media_name, visibility }
+                    }
+                    Some(info) if info.visible_to(client_id) => ChatProtocol::Error { reason: format!("media_not_owned::{}", media_name) },
+                    _ => ChatProtocol::MediaNotFound,
                 };
-                self.send_chat_message(session_id, client_id, response);
+                self.send_chat_message(client_id, response);
+            },
+            ChatProtocol::MediaDelete { name: media_name } => {
+                let response = match self.storage.media_info(&media_name) {
+                    Some(info) if info.owner == client_id => {
+                        info!("🗑 Client {} deleted '{}'", client_id, media_name);
+                        self.log(SimEventKind::Media, format!("Server received MediaDelete from {} of the media: {}", client_id, media_name));
+                        self.storage.delete_media(&media_name);
+                        ChatProtocol::MediaDeleteAck { name: media_name }
+                    }
+                    Some(info) if info.visible_to(client_id) => ChatProtocol::Error { reason: format!("media_not_owned::{}", media_name) },
+                    _ => ChatProtocol::MediaNotFound,
+                };
+                self.send_chat_message(client_id, response);
             },
             //MEDIABROADCAST --> sending to all registered clients
-            ["[MediaBroadcast]", media_name, base64_data] => {
+            ChatProtocol::MediaBroadcast { name: media_name, data: media_data } => {
                 info!(" ------------------------ Received MediaBroadcast message by client: {} ----------------------", client_id);
-                self.log(format!("Server received MediaBroadcast from {} of the media; {}", client_id, media_name));
-                self.media_storage.insert(media_name.to_string(), (client_id, base64_data.to_string()));
+                self.log(SimEventKind::Media, format!("Server received MediaBroadcast from {} of the media; {}", client_id, media_name));
+                if !self.storage.store_media(&media_name, client_id, media_data.clone()) {
+                    warn!("Server {} broadcasts '{}' from {} without storing it: the name belongs to someone else", self.id, media_name, client_id);
+                }
 
-                let clients = self.registered_clients.clone();
+                let clients = self.clients_with(Capability::Broadcast);
                 for target_id in clients {
                     // Avoid sending to the sender
                     if target_id != client_id {
-                        let msg = format!("[MediaDownloadResponse]::{}::{}", media_name, base64_data);
-                        self.send_chat_message(session_id, target_id, msg.clone());
+                        let msg = ChatProtocol::MediaDownloadResponse { name: media_name.clone(), data: media_data.clone() };
+                        self.send_chat_message(target_id, msg);
                     }
                 }
                 // Confirm broadcast to the sender
-                let ack = format!("[MediaBroadcastAck]::{}::Broadcasted", media_name);
-                self.send_chat_message(session_id, client_id, ack);
+                self.send_chat_message(client_id, ChatProtocol::MediaBroadcastAck { name: media_name });
+            },
+            ChatProtocol::TextUpload { name, .. } if !protocol::is_valid_file_name(&name) => {
+                self.send_chat_message(client_id, ChatProtocol::Error { reason: "invalid_file_name".to_string() });
+            },
+            ChatProtocol::TextUpload { name, content } => {
+                info!("📄 Client {} published the text file '{}'", client_id, name);
+                self.log(SimEventKind::Text, format!("Server received TextUpload from {} of the file: {}", client_id, name));
+                self.storage.store_text(&name, client_id, content);
+                self.send_chat_message(client_id, ChatProtocol::TextUploadAck { name });
+            },
+            ChatProtocol::TextListRequest => {
+                self.log(SimEventKind::Text, format!("Server received TextListRequest from {}", client_id));
+                let files = self.storage.text_names();
+                self.send_chat_message(client_id, ChatProtocol::TextListResponse { files });
             },
-            ["[ChatFinish]", target_client_str] => {
+            ChatProtocol::TextRequest { name } => {
+                self.log(SimEventKind::Text, format!("Server received TextRequest from {} for {}", client_id, name));
+                let response = match self.storage.text(&name) {
+                    Some(content) => ChatProtocol::TextResponse { name, content },
+                    None => ChatProtocol::Error { reason: format!("text_not_found::{}", name) },
+                };
+                self.send_chat_message(client_id, response);
+            },
+            ChatProtocol::ChatFinish { peer_id: target_client_id } => {
                 info!("Client {} finished chat in session {}", client_id, session_id);
-                self.log(format!("Server received ChatFinish from {} for session: {}", client_id, session_id));
-                if let Ok(target_client_id) = target_client_str.parse::<NodeId>() {
-                    let key = (client_id.min(target_client_id), client_id.max(target_client_id));
-                    info!("🚨 Step 1: Looking up chat history for key {:?}", key);
-                    if let Some(history) = self.chat_history.get(&key) {
-                        info!("🚨 Step 2: History found, preparing to serialize and broadcast...");
-                        let full_entry = (key, history.clone());
-                        if let Ok(serialized) = serde_json::to_string(&full_entry) {
-                            let server_node_ids: Vec<NodeId> = self.network_graph
-                                .node_types
-                                .iter()
-                                .filter_map(|(&node_id, node_type)| {
-                                    if node_id != self.id && *node_type == NodeType::Server {
-                                        Some(node_id)
-                                    } else {
-                                        None
-                                    }
-                                })
-                                .collect();
-
-                            info!("🚨 Step 3: Sending chat history update to servers: {:?}", server_node_ids);
-                            for node_id in server_node_ids {
-                                if let Some(route) = self.compute_best_path(self.id, node_id) {
-                                    let msg = format!("[ChatHistoryUpdate]::{}::{}", self.id, serialized);
-                                    self.send_chat_message(session_id, node_id, msg);
-                                    info!("✅ Sent chat history update to server {}", node_id);
-                                    self.log(format!("Sent chat history update to server {}", node_id))
-                                } else {
-                                    warn!("⚠ No path found to server {}", node_id);
-                                }
-                            }
-                        } else {
-                            error!("❌❌❌ Failed to serialize chat history for {:?}", key);
-                        }
-                    } else {
-                        warn!("⚠ No chat history found for key {:?}", key);
-                    }
+                self.log(SimEventKind::Chat, format!("Server received ChatFinish from {} for session: {}", client_id, session_id));
+                // every message already went to the other servers as a delta when it was stored
+                let key = (client_id.min(target_client_id), client_id.max(target_client_id));
+                match self.storage.history(key) {
+                    Some(history) => info!("Chat {:?} closed with {} messages", key, history.len()),
+                    None => warn!("⚠ No chat history found for key {:?}", key),
                 }
             }
-            ["[Logout]"] => {
-                self.registered_clients.retain(|&id| id != client_id);
-                info!("👀👀👀 Client {} has been logged out, now the registered clients are: {:?} 👀👀👀", client_id, self.registered_clients);
-                self.log(format!("👀👀👀 Client {} has been logged out, now the registered clients are: {:?} 👀👀👀", client_id, self.registered_clients));
+            ChatProtocol::Logout => {
+                self.storage.unregister_client(client_id);
+                self.client_capabilities.remove(&client_id);
+                self.announce_clients();
+                info!("👀👀👀 Client {} has been logged out, now the registered clients are: {:?} 👀👀👀", client_id, self.storage.registered_clients());
+                self.log(SimEventKind::Login, format!("👀👀👀 Client {} has been logged out, now the registered clients are: {:?} 👀👀👀", client_id, self.storage.registered_clients()));
                 info!("Client {} logged out from session {}", client_id, session_id);
             },
-            _ => {
-                warn!("Unrecognized message: {}", message_string);
-                info!("Reassembled message for session {:?}: {:?}", key, message);
+            // a client could otherwise speak for any user or server
+            other @ (ChatProtocol::ClientDirectory { .. } | ChatProtocol::Relay { .. } | ChatProtocol::ChatHistoryUpdate { .. } | ChatProtocol::HistorySummary { .. }) => {
+                warn!("Server {} dropped {} from {}, which is not a server", self.id, other.tag(), client_id);
+            },
+            other => {
+                warn!("Server {} received a message it does not handle: {}", self.id, other.tag());
             }
         }
     }
 
+    /// Whether `node_id` is another server, the only ones allowed to send server-to-server messages.
+    fn is_server(&self, node_id: NodeId) -> bool {
+        self.network_graph.get_node_type(node_id) == Some(&NodeType::Server)
+    }
+
+    /// Sends the room's current members to each logged in member, and to `left` when someone just left it.
+    fn notify_room_members(&mut self, room: &str, left: Option<NodeId>) {
+        let members = self.storage.room(room).map(|r| r.members).unwrap_or_default();
+        let recipients = members.iter().copied().filter(|&id| self.storage.is_registered(id)).chain(left);
+        for target_id in recipients.collect::<Vec<_>>() {
+            let update = ChatProtocol::RoomUpdate { room: room.to_string(), members: members.clone() };
+            self.send_chat_message(target_id, update);
+        }
+    }
+
     fn handle_nack(&mut self, session_id: u64, nack: &Nack, packet: &Packet, routing_header: SourceRoutingHeader) {
         info!("Recieved NACK for fragment {} with type {:?} in session {}", nack.fragment_index, nack.nack_type, session_id);
 
@@ -884,33 +1332,25 @@ impl server {
                 }
                 warn!("Received Nack::Dropped, modifying the costs in the graph");
                 //server must resend dropped packet
-                let session_id = packet.session_id.clone();
+                let session_id = packet.session_id;
                 let fragment_index = nack.fragment_index;
-                if let Some(fragment) = self.sent_fragments.get(&(session_id, fragment_index)).cloned() {
-                    let target_id = fragment.1;
+                let resend = self.sent_fragments.for_nack(session_id, &routing_header.hops).and_then(|session| {
+                    session.mark_resent(fragment_index);
+                    session.fragment(fragment_index).map(|fragment| (fragment.clone(), session.destination))
+                });
+                if let Some((fragment, target_id)) = resend {
                     // Recompute best path
                     if let Some(hops) = self.network_graph.best_path(self.id, target_id) {
-                        let new_packet = Packet {
-                            session_id,
-                            routing_header: SourceRoutingHeader {
-                                hop_index: 1, // restart from the beginning
-                                hops: hops.clone(),
-                            },
-                            pack_type: PacketType::MsgFragment(fragment.0.clone()),
+                        let routing_header = SourceRoutingHeader {
+                            hop_index: 1, // restart from the beginning
+                            hops: hops.clone(),
                         };
-
-                        // Send to first hop in new path
-                        if let Some(&next_hop_id) = new_packet.routing_header.hops.get(1) {
-                            if let Some(sender) = self.packet_sender.get(&next_hop_id) {
-                                match sender.send(new_packet) {
-                                    Ok(()) => info!("⌚⌚⌚⌚⌚⌚ Retransmitted dropped fragment {} via new path {:?} in session {} ⌚⌚⌚⌚⌚⌚",fragment_index, hops, session_id),
-                                    Err(e) => error!("❌ Failed to retransmit dropped fragment: {:?}", e),
-                                }
-                            } else {
-                                error!("❌❌❌ No sender available for node {}", next_hop_id);
-                            }
-                        } else {
-                            error!("❌❌❌ Routing header missing next hop — cannot retransmit dropped fragment");
+                        if let Some(session) = self.sent_fragments.get_mut(session_id, target_id) {
+                            session.reroute(routing_header.clone());
+                        }
+                        match self.send_fragment(session_id, routing_header, fragment) {
+                            Ok(()) => info!("⌚⌚⌚⌚⌚⌚ Retransmitted dropped fragment {} via new path {:?} in session {} ⌚⌚⌚⌚⌚⌚",fragment_index, hops, session_id),
+                            Err(e) => error!("❌ Failed to retransmit dropped fragment: {}", e),
                         }
                     } else {
                         error!("❌❌❌ No valid path to {} — cannot retransmit dropped fragment", target_id);
@@ -933,7 +1373,8 @@ impl server {
                                 warn!("💥 Node {} not found in shared_senders — assuming crash", node_id);
                                 self.network_graph.remove_node(node_id);
                                 self.packet_sender.remove(&node_id);
-                                self.log(format!("Node {} crashed (removed from graph)", node_id));
+                                self.topology_changed = true;
+                                self.log(SimEventKind::Topology, format!("Node {} crashed (removed from graph)", node_id));
                             } else {
                                 warn!("🧹 Link failure: removing link between {} and {} (node still alive)", from, node_id);
                                 self.network_graph.remove_link(from, node_id);
@@ -942,7 +1383,8 @@ impl server {
                                 } else if self.id == node_id {
                                     self.packet_sender.remove(&from);
                                 }
-                                self.log(format!("Link removed between {} and {}", from, node_id));
+                                self.topology_changed = true;
+                                self.log(SimEventKind::Topology, format!("Link removed between {} and {}", from, node_id));
                             }
                         } else {
                             warn!("❓ Could not determine sender before node {} — hop_index too small or invalid", node_id);
@@ -953,11 +1395,17 @@ impl server {
                 } else {
                     warn!("❌❌❌ shared_senders is None — cannot analyze ErrorInRouting({})", node_id);
                 }
+                if let Some(session) = self.sent_fragments.for_nack(session_id, &routing_header.hops) {
+                    session.route_needs_recalculation = true;
+                }
 
                 self.network_graph.print_graph();
             }
             _ => {
                 warn!("Received DestinationIsDrone/UnexpectedRecipient NACK type, sending flood request");
+                if let Some(session) = self.sent_fragments.for_nack(session_id, &routing_header.hops) {
+                    session.route_needs_recalculation = true;
+                }
                 let flood_request = FloodRequest {
                     flood_id: session_id,
                     initiator_id: self.id as NodeId,
@@ -1097,9 +1545,19 @@ impl server {
                 self.network_graph.add_link(*a_id, *a_type, *b_id, *b_type);
             }
         }
+        self.topology_changed = true;
     }
-    fn send_chat_message(&mut self, session_id: u64, target_id: NodeId, msg: String) {
-        let data = msg.as_bytes();
+    /// Fragments `msg` for `target_id` under a fresh session id, so that its fragments never mix with those of
+    /// another message to the same node (the receiver reassembles by session and sender).
+    fn send_chat_message(&mut self, target_id: NodeId, msg: ChatProtocol) {
+        let session_id = self.rng.random::<u64>();
+        let data = match msg.encode() {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Server {} could not send message to {}: {}", self.id, target_id, e);
+                return;
+            }
+        };
         let total_fragments = ((data.len() + 127) / 128) as u64;
         let source = self.id;
         let hops = match self.network_graph.best_path(source, target_id) {
@@ -1110,6 +1568,11 @@ impl server {
             }
         };
 
+        let routing_header = SourceRoutingHeader {
+            hop_index: 1,
+            hops: hops.clone(), // includes source
+        };
+        let mut fragments = Vec::new();
         for (i, chunk) in data.chunks(128).enumerate() {
             let mut fragment_data = [0u8; 128];
             fragment_data[..chunk.len()].copy_from_slice(chunk);
@@ -1121,30 +1584,58 @@ impl server {
                 data: fragment_data,
             };
 
-            let packet = Packet {
-                session_id,
-                routing_header: SourceRoutingHeader {
-                    hop_index: 1,
-                    hops: hops.clone(), // includes source
-                },
-                pack_type: PacketType::MsgFragment(fragment.clone()),
-            };
+            fragments.push(fragment.clone());
+            info!("✈✈✈✈✈ Sending fragment {} to {}", i + 1, hops.get(1).copied().unwrap_or_default());
+            if let Err(e) = self.send_fragment(session_id, routing_header.clone(), fragment) {
+                warn!("⚠ {}", e);
+            }
+        }
+        // Save for NACK and timeout based resend
+        self.sent_fragments.track(session_id, routing_header, fragments);
+    }
 
-            // Save for possible NACK-based resend
-            self.sent_fragments.insert((session_id, i as u64), (fragment.clone(), target_id));
+    fn send_fragment(&self, session_id: u64, routing_header: SourceRoutingHeader, fragment: Fragment) -> Result<(), String> {
+        let next_hop_id = *routing_header.hops.get(routing_header.hop_index)
+            .ok_or_else(|| format!("No next hop available for fragment {}", fragment.fragment_index))?;
+        let sender = self.packet_sender.get(&next_hop_id)
+            .ok_or_else(|| format!("Packet sender missing for next hop {} — possible outdated link", next_hop_id))?;
+        let packet = Packet {
+            session_id,
+            routing_header,
+            pack_type: PacketType::MsgFragment(fragment),
+        };
+        sender.send(packet).map_err(|e| format!("Failed to send fragment to {}: {:?}", next_hop_id, e))
+    }
 
-            let next_hop_id_opt = packet.routing_header.hops.get(1); // first node after server
-            if let Some(&next_hop_id) = next_hop_id_opt {
-                if let Some(sender) = self.packet_sender.get(&next_hop_id) {
-                    info!("✈✈✈✈✈ Sending fragment {} to {}", i + 1, next_hop_id);
-                    if let Err(e) = sender.send(packet.clone()) {
-                        error!("❌ Failed to send fragment to {}: {:?}", next_hop_id, e);
+    fn handle_ack(&mut self, session_id: u64, ack: &Ack, routing_header: &SourceRoutingHeader) {
+        let acker = routing_header.hops.first().copied().unwrap_or_default();
+        if self.sent_fragments.on_ack(session_id, acker, ack.fragment_index) == AckOutcome::Completed {
+            info!("✅ Server {} delivered session {} to {}", self.id, session_id, acker);
+        }
+    }
+
+    fn retransmit_unacked_fragments(&mut self) {
+        let timeouts = self.sent_fragments.poll_timeouts(std::time::Instant::now());
+        for session in timeouts.given_up {
+            warn!("❌ Server {} gave up on session {} to {}: {} fragment(s) never ACKed", self.id, session.session_id, session.destination, session.unacked());
+            self.log_session(SimEventKind::Delivery, session.session_id, format!("Server {} could not deliver session {} to {}", self.id, session.session_id, session.destination));
+        }
+        for retransmission in timeouts.resend {
+            let mut routing_header = retransmission.routing_header;
+            // a NACK asked for a new route, or the first hop is gone since the last attempt
+            let first_hop_lost = routing_header.hops.get(routing_header.hop_index).is_none_or(|hop| !self.packet_sender.contains_key(hop));
+            if retransmission.route_needs_recalculation || first_hop_lost {
+                if let Some(hops) = self.compute_best_path(self.id, retransmission.destination) {
+                    routing_header = SourceRoutingHeader { hop_index: 1, hops };
+                    if let Some(session) = self.sent_fragments.get_mut(retransmission.session_id, retransmission.destination) {
+                        session.reroute(routing_header.clone());
                     }
-                } else {
-                    warn!("⚠ Packet sender missing for next hop {} — possible outdated link", next_hop_id);
                 }
-            } else {
-                error!("❌ No next hop available for fragment {}", i);
+            }
+            let fragment_index = retransmission.fragment.fragment_index;
+            match self.send_fragment(retransmission.session_id, routing_header, retransmission.fragment) {
+                Ok(()) => info!("⏰ Server {} resent fragment {} of session {} after ACK timeout", self.id, fragment_index, retransmission.session_id),
+                Err(e) => warn!("⚠ {}", e),
             }
         }
     }
@@ -1152,4 +1643,208 @@ impl server {
     pub fn compute_best_path(&mut self, from: NodeId, to: NodeId) -> Option<Vec<NodeId>> {
         self.network_graph.best_path(from,to)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::unbounded;
+
+    const SERVER: NodeId = 30;
+    const DRONE: NodeId = 1;
+
+    /// Server 30 with `clients` behind drone 1; the receiver gets everything the server sends.
+    fn setup_server(clients: &[NodeId]) -> (server, Receiver<Packet>) {
+        let (drone_tx, drone_rx) = unbounded();
+        let (_tx, rx) = unbounded();
+        let mut srv = server::new(SERVER, HashMap::from([(DRONE, drone_tx)]), rx, None, None);
+        srv.network_graph.add_link(SERVER, NodeType::Server, DRONE, NodeType::Drone);
+        for &client in clients {
+            srv.network_graph.add_link(DRONE, NodeType::Drone, client, NodeType::Client);
+        }
+        (srv, drone_rx)
+    }
+
+    /// Hands `message` to the server as the fragments `client` would send.
+    fn receive(srv: &mut server, client: NodeId, message: ChatProtocol) {
+        let session_id = rand::random::<u64>();
+        let data = message.encode().unwrap();
+        let chunks: Vec<&[u8]> = data.chunks(128).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut bytes = [0u8; 128];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            let fragment = Fragment { fragment_index: i as u64, total_n_fragments: chunks.len() as u64, length: chunk.len() as u8, data: bytes };
+            srv.handle_fragment(session_id, &fragment, SourceRoutingHeader::new(vec![client, DRONE, SERVER], 2));
+        }
+    }
+
+    /// Messages the server sent since the last call, with their destination, in sending order.
+    fn sent(drone_rx: &Receiver<Packet>) -> Vec<(NodeId, ChatProtocol)> {
+        let mut messages: Vec<(u64, NodeId, Vec<u8>)> = Vec::new();
+        for packet in drone_rx.try_iter() {
+            let PacketType::MsgFragment(fragment) = packet.pack_type else { continue };
+            let destination = *packet.routing_header.hops.last().unwrap();
+            let index = match messages.iter().position(|(session, to, _)| (*session, *to) == (packet.session_id, destination)) {
+                Some(index) => index,
+                None => {
+                    messages.push((packet.session_id, destination, Vec::new()));
+                    messages.len() - 1
+                }
+            };
+            messages[index].2.extend_from_slice(&fragment.data[..fragment.length as usize]);
+        }
+        messages.into_iter().map(|(_, to, bytes)| (to, ChatProtocol::decode(&bytes).expect("the server sends valid frames"))).collect()
+    }
+
+    #[test]
+    fn test_room_updates_and_messages_reach_the_logged_in_members() {
+        let (mut srv, drone_rx) = setup_server(&[5, 9]);
+        receive(&mut srv, 5, ChatProtocol::login(SERVER));
+        receive(&mut srv, 9, ChatProtocol::login(SERVER));
+        sent(&drone_rx);
+        let update = |members: Vec<NodeId>| ChatProtocol::RoomUpdate { room: "lobby".to_string(), members };
+
+        receive(&mut srv, 5, ChatProtocol::RoomCreate { room: "lobby".to_string() });
+        assert_eq!(sent(&drone_rx), vec![(5, update(vec![5]))], "the creator is the first member");
+        receive(&mut srv, 9, ChatProtocol::RoomJoin { room: "lobby".to_string() });
+        assert_eq!(sent(&drone_rx), vec![(5, update(vec![5, 9])), (9, update(vec![5, 9]))]);
+
+        receive(&mut srv, 9, ChatProtocol::RoomMessageTo { room: "lobby".to_string(), message_id: 1, message: "hi".to_string() });
+        assert_eq!(sent(&drone_rx), vec![(5, ChatProtocol::RoomMessageFrom { room: "lobby".to_string(), sender_id: 9, message_id: 1, message: "hi".to_string() })],
+            "everyone but the sender gets the message");
+
+        receive(&mut srv, 9, ChatProtocol::RoomLeave { room: "lobby".to_string() });
+        assert_eq!(sent(&drone_rx), vec![(5, update(vec![5])), (9, update(vec![5]))], "whoever left is told too");
+        receive(&mut srv, 9, ChatProtocol::RoomMessageTo { room: "lobby".to_string(), message_id: 2, message: "still here?".to_string() });
+        assert_eq!(sent(&drone_rx), vec![(9, ChatProtocol::Error { reason: "not_in_room::lobby".to_string() })]);
+    }
+
+    #[test]
+    fn test_relayed_messages_keep_the_origin_timestamp() {
+        let (mut srv, drone_rx) = setup_server(&[5]);
+        srv.network_graph.add_link(DRONE, NodeType::Drone, 40, NodeType::Server);
+        receive(&mut srv, 5, ChatProtocol::login(SERVER));
+        sent(&drone_rx);
+
+        let message = ChatProtocol::MessageFrom { sender_id: 7, message_id: 3, message: "hi".to_string() };
+        receive(&mut srv, 40, ChatProtocol::Relay { target_id: 5, timestamp: 1234, message: Box::new(message.clone()) });
+        assert_eq!(sent(&drone_rx), vec![(5, message)]);
+        let history = srv.storage.history((5, 7)).unwrap();
+        assert_eq!(history.iter().map(|e| (e.id(), e.timestamp)).collect::<Vec<_>>(), vec![((7, 3), 1234)], "the copy matches the origin's");
+    }
+
+    #[test]
+    fn test_upload_claiming_a_huge_size_is_refused_by_the_quota() {
+        let (mut srv, drone_rx) = setup_server(&[5]);
+        receive(&mut srv, 5, ChatProtocol::login(SERVER));
+        sent(&drone_rx);
+
+        let data = vec![1u8; 16];
+        let chunk = ChatProtocol::MediaUploadChunk {
+            name: "huge.bin".to_string(), offset: 0, total_size: u64::MAX,
+            chunk_checksum: transfer::checksum(&data), file_checksum: 0, data,
+        };
+        receive(&mut srv, 5, chunk);
+        let reason = format!("media_quota_exceeded::huge.bin::0::{}", storage::DEFAULT_MEDIA_QUOTA);
+        assert_eq!(sent(&drone_rx), vec![(5, ChatProtocol::Error { reason })]);
+        assert!(srv.storage.media_info("huge.bin").is_none());
+    }
+
+    #[test]
+    fn test_timed_out_fragments_are_rerouted_around_a_lost_drone() {
+        let (mut srv, drone_rx) = setup_server(&[5]);
+        let (other_tx, other_rx) = unbounded();
+        srv.packet_sender.insert(2, other_tx);
+        srv.network_graph.add_link(SERVER, NodeType::Server, 2, NodeType::Drone);
+        srv.network_graph.add_link(2, NodeType::Drone, 5, NodeType::Client);
+        srv.sent_fragments = ReliableSender::with_limits(std::time::Duration::ZERO, 5);
+        // the first route goes through drone 1, which is then lost
+        srv.network_graph.increment_drop(SERVER, 2);
+        srv.send_chat_message(5, ChatProtocol::MediaNotFound);
+        let sent_fragments = drone_rx.try_iter().count();
+        assert!(sent_fragments > 0);
+        srv.packet_sender.remove(&DRONE);
+        srv.network_graph.remove_link(SERVER, DRONE);
+
+        srv.retransmit_unacked_fragments();
+        let resent: Vec<Packet> = other_rx.try_iter().collect();
+        assert_eq!(resent.len(), sent_fragments, "the timed out fragments go out again on the new route");
+        assert_eq!(resent[0].routing_header.hops, vec![SERVER, 2, 5]);
+        let session = srv.sent_fragments.get(resent[0].session_id, 5).unwrap();
+        assert_eq!(session.routing_header.hops, vec![SERVER, 2, 5], "later retries keep the new route");
+    }
+
+    #[test]
+    fn test_clients_cannot_relay_or_publish_a_directory() {
+        let (mut srv, drone_rx) = setup_server(&[5, 9]);
+        srv.network_graph.add_link(DRONE, NodeType::Drone, 40, NodeType::Server);
+        receive(&mut srv, 5, ChatProtocol::login(SERVER));
+        sent(&drone_rx);
+
+        let spoofed = ChatProtocol::MessageFrom { sender_id: 7, message_id: 1, message: "it's me, 7".to_string() };
+        receive(&mut srv, 9, ChatProtocol::Relay { target_id: 5, timestamp: 1, message: Box::new(spoofed) });
+        receive(&mut srv, 9, ChatProtocol::ClientDirectory { server_id: 40, clients: vec![12] });
+        assert!(sent(&drone_rx).is_empty());
+        assert!(srv.storage.history((5, 7)).unwrap_or_default().is_empty(), "nothing spoofed reaches the history");
+        assert_eq!(srv.home_server_of(12), None);
+
+        receive(&mut srv, 40, ChatProtocol::ClientDirectory { server_id: 41, clients: vec![12] });
+        assert_eq!(srv.home_server_of(12), Some(40), "filed under the server that sent it");
+    }
+
+    #[test]
+    fn test_only_servers_can_sync_history() {
+        let (mut srv, drone_rx) = setup_server(&[5, 9]);
+        srv.network_graph.add_link(DRONE, NodeType::Drone, 40, NodeType::Server);
+        srv.storage.append_message((5, 7), HistoryEntry { sender_id: 5, message_id: 1, timestamp: 1, message: "hi".to_string() });
+        let forged = HistoryEntry { sender_id: 7, message_id: 2, timestamp: 2, message: "forged".to_string() };
+
+        receive(&mut srv, 9, ChatProtocol::ChatHistoryUpdate { source_server: 40, conversation: (5, 7), entries: vec![forged] });
+        receive(&mut srv, 9, ChatProtocol::HistorySummary { source_server: 40, reply: false, conversations: vec![] });
+        assert!(sent(&drone_rx).is_empty());
+        assert_eq!(srv.storage.history((5, 7)).unwrap().len(), 1, "nothing was merged");
+
+        receive(&mut srv, 40, ChatProtocol::HistorySummary { source_server: 41, reply: false, conversations: vec![] });
+        let sent = sent(&drone_rx);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, 40, "the history goes back to the server that asked");
+    }
+
+    #[test]
+    fn test_forged_fragment_counts_and_indices_are_dropped() {
+        let (mut srv, _drone_rx) = setup_server(&[5]);
+        let header = SourceRoutingHeader::new(vec![5, DRONE, SERVER], 2);
+        let fragment = |fragment_index, total_n_fragments| Fragment { fragment_index, total_n_fragments, length: 128, data: [0; 128] };
+
+        srv.handle_fragment(1, &fragment(0, u64::MAX), header.clone());
+        srv.handle_fragment(2, &fragment(3, 3), header.clone());
+        srv.handle_fragment(3, &fragment(0, 0), header.clone());
+        assert!(srv.received_fragments.is_empty(), "nothing is buffered for a forged fragment");
+
+        srv.handle_fragment(4, &fragment(0, 2), header.clone());
+        srv.handle_fragment(4, &fragment(5, 6), header.clone());
+        assert_eq!(srv.received_fragments[&(4, 5)].len(), 2, "a different total does not resize the session");
+        assert_eq!(srv.received_fragments[&(4, 5)].iter().filter(|slot| slot.is_some()).count(), 1);
+    }
+
+    #[test]
+    fn test_run_loop_stops_on_shutdown_and_leaves_later_commands_queued() {
+        use crate::simulation_controller::gui_input_queue::{new_gui_input_queue, push_gui_message};
+
+        let (drone_tx, _drone_rx) = unbounded();
+        let (_packet_tx, packet_rx) = unbounded();
+        let (_shortcut_tx, shortcut_rx) = unbounded();
+        let mut srv = server::new(SERVER, HashMap::from([(DRONE, drone_tx)]), packet_rx, None, Some(shortcut_rx));
+        let gui_input = new_gui_input_queue();
+        push_gui_message(&gui_input, SERVER, SHUTDOWN_COMMAND.to_string());
+        push_gui_message(&gui_input, SERVER, "[FloodRequired]::Crash::1".to_string());
+        let queue = gui_input.clone();
+        let handle = std::thread::spawn(move || srv.run(queue));
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(3);
+        while !handle.is_finished() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(handle.is_finished(), "the run loop should return after [Shutdown]");
+        handle.join().unwrap();
+        assert_eq!(gui_input.lock().unwrap().get(&SERVER).cloned(), Some(vec!["[FloodRequired]::Crash::1".to_string()]));
+    }
+}
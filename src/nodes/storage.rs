@@ -0,0 +1,974 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use wg_2024::network::NodeId;
+use crate::nodes::protocol::{HistoryEntry, MediaInfo, MediaVisibility};
+use crate::nodes::transfer::checksum;
+use crate::nodes::media::MediaEnvelope;
+
+/// Messages kept per conversation when the TOML does not say otherwise.
+pub const DEFAULT_HISTORY_RETENTION: usize = 50;
+/// Messages a logged-out client can have waiting before new ones are rejected.
+pub const DEFAULT_OFFLINE_QUEUE_CAP: usize = 20;
+/// Bytes of media a client may own on a server when the TOML does not say otherwise.
+pub const DEFAULT_MEDIA_QUOTA: u64 = 16 * 1024 * 1024;
+/// Where file-backed servers keep their data when no `dir` is given.
+pub const DEFAULT_STORAGE_DIR: &str = "server_data";
+
+/// Conversation key: the two clients, lower id first.
+pub type ConversationKey = (NodeId, NodeId);
+
+/// A chat message waiting for its logged-out target.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    pub sender_id: NodeId,
+    pub message_id: u64,
+    pub message: String,
+}
+
+/// A named group chat: its members (sorted) and its history, trimmed like the pairwise ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Room {
+    pub members: Vec<NodeId>,
+    pub history: VecDeque<String>,
+}
+
+/// Where a server keeps registered clients, chat histories, uploaded media and text files.
+pub trait ServerStorage: Send + Debug {
+    fn registered_clients(&self) -> Vec<NodeId>;
+    fn register_client(&mut self, client_id: NodeId);
+    fn unregister_client(&mut self, client_id: NodeId);
+    fn is_registered(&self, client_id: NodeId) -> bool {
+        self.registered_clients().contains(&client_id)
+    }
+    /// Whether the client ever logged in here, so messages for it can wait while it is away.
+    fn is_known(&self, client_id: NodeId) -> bool;
+    /// Queues a message for a logged-out client; false when its queue is full.
+    fn queue_message(&mut self, client_id: NodeId, message: QueuedMessage) -> bool;
+    /// Empties the client's offline queue, oldest message first.
+    fn take_queued(&mut self, client_id: NodeId) -> Vec<QueuedMessage>;
+
+    /// Makes sure the conversation exists, even before its first message.
+    fn open_conversation(&mut self, key: ConversationKey);
+    /// Conversations kept here, sorted.
+    fn conversations(&self) -> Vec<ConversationKey>;
+    /// Adds the messages not stored yet (one already stored keeps the earlier timestamp), keeps the
+    /// conversation in timestamp order and drops the oldest messages past the retention limit.
+    /// Returns the messages that were new.
+    fn merge_history(&mut self, key: ConversationKey, entries: Vec<HistoryEntry>) -> Vec<HistoryEntry>;
+    /// Merges a single message; false when it was already there.
+    fn append_message(&mut self, key: ConversationKey, entry: HistoryEntry) -> bool {
+        !self.merge_history(key, vec![entry]).is_empty()
+    }
+    fn history(&self, key: ConversationKey) -> Option<VecDeque<HistoryEntry>>;
+
+    /// Names of the rooms, sorted.
+    fn room_names(&self) -> Vec<String>;
+    fn room(&self, name: &str) -> Option<Room>;
+    /// Creates an empty room; false when the name is taken.
+    fn create_room(&mut self, name: &str) -> bool;
+    /// Adds the client to the room; false when the room does not exist.
+    fn join_room(&mut self, name: &str, client_id: NodeId) -> bool;
+    /// Removes the client from the room; false when it was not a member. Empty rooms are kept with their history.
+    fn leave_room(&mut self, name: &str, client_id: NodeId) -> bool;
+    fn append_room_message(&mut self, name: &str, entry: String);
+
+    /// Stores the media under its content hash and points `name` at it; identical bytes are kept once,
+    /// different bytes with the same hash are kept apart.
+    /// The owner may replace its own media (keeping its visibility), false when `name` belongs to someone else.
+    fn store_media(&mut self, name: &str, owner: NodeId, data: Vec<u8>) -> bool;
+    fn media(&self, name: &str) -> Option<(NodeId, Vec<u8>)>;
+    fn media_info(&self, name: &str) -> Option<MediaInfo>;
+    /// Every stored media, sorted by name.
+    fn media_list(&self) -> Vec<MediaInfo>;
+    /// Names of the stored media, sorted.
+    fn media_names(&self) -> Vec<String> {
+        self.media_list().into_iter().map(|info| info.name).collect()
+    }
+    /// False when there is no such media.
+    fn set_media_visibility(&mut self, name: &str, visibility: MediaVisibility) -> bool;
+    /// Removes the name; its bytes go once no other name points at them. False when there is no such media.
+    fn delete_media(&mut self, name: &str) -> bool;
+    /// Bytes of media owned by `owner`, each name counted in full even when its content is shared.
+    fn media_usage(&self, owner: NodeId) -> u64 {
+        self.media_list().iter().filter(|info| info.owner == owner).map(|info| info.size).sum()
+    }
+
+    fn store_text(&mut self, name: &str, owner: NodeId, content: String);
+    fn text(&self, name: &str) -> Option<String>;
+    /// Names of the stored text files, sorted.
+    fn text_names(&self) -> Vec<String>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    #[default]
+    Memory,
+    File,
+}
+
+/// `storage = { kind = "file", dir = "server_data", retention = 100, offline_queue = 20, media_quota = 1048576 }`
+/// on a `[[server]]` entry. `retention = 0` keeps every message, `media_quota` is in bytes per client.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct StorageConfig {
+    #[serde(default)]
+    pub kind: StorageKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offline_queue: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_quota: Option<u64>,
+}
+
+impl StorageConfig {
+    pub fn retention(&self) -> usize {
+        self.retention.unwrap_or(DEFAULT_HISTORY_RETENTION)
+    }
+
+    pub fn offline_queue_cap(&self) -> usize {
+        self.offline_queue.unwrap_or(DEFAULT_OFFLINE_QUEUE_CAP)
+    }
+
+    pub fn media_quota(&self) -> u64 {
+        self.media_quota.unwrap_or(DEFAULT_MEDIA_QUOTA)
+    }
+
+    /// Storage for `server_id`; a file store that cannot be opened falls back to memory.
+    pub fn build(&self, server_id: NodeId) -> Box<dyn ServerStorage> {
+        match self.kind {
+            StorageKind::Memory => Box::new(MemoryStorage::new(self.retention(), self.offline_queue_cap())),
+            StorageKind::File => {
+                let dir = Path::new(self.dir.as_deref().unwrap_or(DEFAULT_STORAGE_DIR)).join(format!("server_{}", server_id));
+                match FileStorage::open(&dir, self.retention(), self.offline_queue_cap()) {
+                    Ok(storage) => Box::new(storage),
+                    Err(e) => {
+                        warn!("⚠ Server {} cannot use storage in {}: {}. Keeping everything in memory", server_id, dir.display(), e);
+                        Box::new(MemoryStorage::new(self.retention(), self.offline_queue_cap()))
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Everything lives in the process and is gone when the simulation exits.
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    registered_clients: Vec<NodeId>,
+    chat_history: HashMap<ConversationKey, VecDeque<HistoryEntry>>,
+    media_index: HashMap<String, MediaInfo>, //media name -> hash of its content, owner, size, upload time
+    media_blobs: HashMap<u64, (Vec<u8>, usize)>, //content hash -> bytes and how many names point at them
+    rooms: HashMap<String, Room>,
+    texts: HashMap<String, (NodeId, String)>,
+    offline_queues: HashMap<NodeId, VecDeque<QueuedMessage>>, //one entry per known client
+    retention: usize,
+    offline_queue_cap: usize,
+}
+
+impl MemoryStorage {
+    pub fn new(retention: usize, offline_queue_cap: usize) -> Self {
+        Self {
+            registered_clients: Vec::new(),
+            chat_history: HashMap::new(),
+            media_index: HashMap::new(),
+            media_blobs: HashMap::new(),
+            rooms: HashMap::new(),
+            texts: HashMap::new(),
+            offline_queues: HashMap::new(),
+            retention,
+            offline_queue_cap,
+        }
+    }
+
+}
+
+/// Drops the oldest entries past `retention` (0 keeps everything).
+fn trim<T>(retention: usize, history: &mut VecDeque<T>) {
+    while retention > 0 && history.len() > retention {
+        history.pop_front();
+    }
+}
+
+impl ServerStorage for MemoryStorage {
+    fn registered_clients(&self) -> Vec<NodeId> {
+        self.registered_clients.clone()
+    }
+
+    fn register_client(&mut self, client_id: NodeId) {
+        if !self.registered_clients.contains(&client_id) {
+            self.registered_clients.push(client_id);
+        }
+        self.offline_queues.entry(client_id).or_default();
+    }
+
+    fn unregister_client(&mut self, client_id: NodeId) {
+        self.registered_clients.retain(|&id| id != client_id);
+    }
+
+    fn is_known(&self, client_id: NodeId) -> bool {
+        self.offline_queues.contains_key(&client_id)
+    }
+
+    fn queue_message(&mut self, client_id: NodeId, message: QueuedMessage) -> bool {
+        let queue = self.offline_queues.entry(client_id).or_default();
+        if queue.len() >= self.offline_queue_cap {
+            return false;
+        }
+        queue.push_back(message);
+        true
+    }
+
+    fn take_queued(&mut self, client_id: NodeId) -> Vec<QueuedMessage> {
+        self.offline_queues.get_mut(&client_id).map(|queue| queue.drain(..).collect()).unwrap_or_default()
+    }
+
+    fn open_conversation(&mut self, key: ConversationKey) {
+        self.chat_history.entry(key).or_default();
+    }
+
+    fn conversations(&self) -> Vec<ConversationKey> {
+        let mut keys: Vec<ConversationKey> = self.chat_history.keys().copied().collect();
+        keys.sort();
+        keys
+    }
+
+    fn merge_history(&mut self, key: ConversationKey, entries: Vec<HistoryEntry>) -> Vec<HistoryEntry> {
+        let history = self.chat_history.entry(key).or_default();
+        let mut added = Vec::new();
+        for entry in entries {
+            match history.iter_mut().find(|known| known.id() == entry.id()) {
+                Some(known) => known.timestamp = known.timestamp.min(entry.timestamp),
+                None => {
+                    added.push(entry.clone());
+                    history.push_back(entry);
+                }
+            }
+        }
+        history.make_contiguous().sort_by_key(HistoryEntry::order);
+        trim(self.retention, history);
+        // older than everything kept: it did not make it in
+        added.retain(|entry| history.iter().any(|known| known.id() == entry.id()));
+        added
+    }
+
+    fn history(&self, key: ConversationKey) -> Option<VecDeque<HistoryEntry>> {
+        self.chat_history.get(&key).cloned()
+    }
+
+    fn room_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.rooms.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    fn room(&self, name: &str) -> Option<Room> {
+        self.rooms.get(name).cloned()
+    }
+
+    fn create_room(&mut self, name: &str) -> bool {
+        if self.rooms.contains_key(name) {
+            return false;
+        }
+        self.rooms.insert(name.to_string(), Room::default());
+        true
+    }
+
+    fn join_room(&mut self, name: &str, client_id: NodeId) -> bool {
+        match self.rooms.get_mut(name) {
+            Some(room) => {
+                if let Err(position) = room.members.binary_search(&client_id) {
+                    room.members.insert(position, client_id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn leave_room(&mut self, name: &str, client_id: NodeId) -> bool {
+        match self.rooms.get_mut(name).map(|room| (room.members.binary_search(&client_id), room)) {
+            Some((Ok(position), room)) => {
+                room.members.remove(position);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn append_room_message(&mut self, name: &str, entry: String) {
+        if let Some(room) = self.rooms.get_mut(name) {
+            room.history.push_back(entry);
+            trim(self.retention, &mut room.history);
+        }
+    }
+
+    fn store_media(&mut self, name: &str, owner: NodeId, data: Vec<u8>) -> bool {
+        let uploaded_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default();
+        let content_type = MediaEnvelope::open(name, &data).content_type;
+        let info = MediaInfo { name: name.to_string(), size: data.len() as u64, owner, hash: checksum(&data), uploaded_at, visibility: MediaVisibility::default(), content_type };
+        self.insert_media(info, data)
+    }
+
+    fn media(&self, name: &str) -> Option<(NodeId, Vec<u8>)> {
+        let info = self.media_index.get(name)?;
+        self.media_blobs.get(&info.hash).map(|(data, _)| (info.owner, data.clone()))
+    }
+
+    fn media_info(&self, name: &str) -> Option<MediaInfo> {
+        self.media_index.get(name).cloned()
+    }
+
+    fn media_list(&self) -> Vec<MediaInfo> {
+        let mut media: Vec<MediaInfo> = self.media_index.values().cloned().collect();
+        media.sort_by(|a, b| a.name.cmp(&b.name));
+        media
+    }
+
+    fn set_media_visibility(&mut self, name: &str, visibility: MediaVisibility) -> bool {
+        match self.media_index.get_mut(name) {
+            Some(info) => {
+                info.visibility = visibility;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn delete_media(&mut self, name: &str) -> bool {
+        match self.media_index.remove(name) {
+            Some(info) => {
+                self.release_media_blob(info.hash);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn store_text(&mut self, name: &str, owner: NodeId, content: String) {
+        self.texts.insert(name.to_string(), (owner, content));
+    }
+
+    fn text(&self, name: &str) -> Option<String> {
+        self.texts.get(name).map(|(_, content)| content.clone())
+    }
+
+    fn text_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.texts.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+impl MemoryStorage {
+    /// Points `info.name` at `data`, keeping the upload time of `info`; see `store_media`.
+    fn insert_media(&mut self, mut info: MediaInfo, data: Vec<u8>) -> bool {
+        if self.media_index.get(&info.name).is_some_and(|old| old.owner != info.owner) {
+            return false;
+        }
+        if let Some(old) = self.media_index.remove(&info.name) {
+            info.visibility = old.visibility;
+            self.release_media_blob(old.hash);
+        }
+        info.hash = self.blob_key(info.hash, &data);
+        self.media_blobs.entry(info.hash).or_insert_with(|| (data, 0)).1 += 1;
+        self.media_index.insert(info.name.clone(), info);
+        true
+    }
+
+    /// Where `data` is kept: its hash, or the next free key when different bytes already sit there.
+    /// The hash is not collision resistant, so a match alone must not hand out someone else's media.
+    fn blob_key(&self, hash: u64, data: &[u8]) -> u64 {
+        let mut key = hash;
+        while self.media_blobs.get(&key).is_some_and(|(stored, _)| stored.as_slice() != data) {
+            key = key.wrapping_add(1);
+        }
+        key
+    }
+
+    /// One name less points at `hash`; the bytes go when none is left.
+    fn release_media_blob(&mut self, hash: u64) {
+        if let Some((_, refs)) = self.media_blobs.get_mut(&hash) {
+            *refs -= 1;
+            if *refs == 0 {
+                self.media_blobs.remove(&hash);
+            }
+        }
+    }
+}
+
+/// One text file as kept in `texts.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TextEntry {
+    name: String,
+    owner: NodeId,
+    content: String,
+}
+
+/// A `media.json` entry: the index written since media are content-addressed, or the older one
+/// that named the file holding each media's bytes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum MediaEntry {
+    Hashed(MediaInfo),
+    Legacy { name: String, owner: NodeId, file: String },
+}
+
+/// Keeps an in-memory copy and writes every change through to `dir`:
+/// `clients.json`, `history.json`, `offline.json`, `rooms.json`, `texts.json`, `media.json` (the name index)
+/// and the media bytes under `media/`, one file per content hash.
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    dir: PathBuf,
+    cache: MemoryStorage,
+}
+
+impl FileStorage {
+    /// Opens (creating it if needed) the store in `dir` and loads what a previous run left there.
+    pub fn open(dir: &Path, retention: usize, offline_queue_cap: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir.join("media"))?;
+        let mut storage = Self { dir: dir.to_path_buf(), cache: MemoryStorage::new(retention, offline_queue_cap) };
+
+        if let Some(clients) = storage.read_json::<Vec<NodeId>>("clients.json")? {
+            storage.cache.registered_clients = clients;
+        }
+        if let Some(histories) = storage.read_json::<Vec<(ConversationKey, Vec<HistoryEntry>)>>("history.json")? {
+            for (key, history) in histories {
+                storage.cache.merge_history(key, history);
+            }
+        }
+        if let Some(queues) = storage.read_json::<Vec<(NodeId, VecDeque<QueuedMessage>)>>("offline.json")? {
+            storage.cache.offline_queues = queues.into_iter().collect();
+        }
+        if let Some(rooms) = storage.read_json::<Vec<(String, Room)>>("rooms.json")? {
+            for (name, mut room) in rooms {
+                trim(retention, &mut room.history);
+                storage.cache.rooms.insert(name, room);
+            }
+        }
+        for entry in storage.read_json::<Vec<TextEntry>>("texts.json")?.unwrap_or_default() {
+            storage.cache.store_text(&entry.name, entry.owner, entry.content);
+        }
+        let mut migrated = false;
+        for entry in storage.read_json::<Vec<MediaEntry>>("media.json")?.unwrap_or_default() {
+            match entry {
+                MediaEntry::Hashed(info) => match fs::read(storage.blob_path(info.hash)) {
+                    Ok(data) => {
+                        storage.cache.insert_media(info, data);
+                    }
+                    Err(e) => warn!("⚠ Media '{}' listed in {} is unreadable: {}", info.name, dir.display(), e),
+                },
+                MediaEntry::Legacy { name, owner, file } => match fs::read(storage.dir.join("media").join(&file)) {
+                    Ok(data) => {
+                        storage.cache.store_media(&name, owner, data);
+                        let _ = fs::remove_file(storage.dir.join("media").join(&file));
+                        migrated = true;
+                    }
+                    Err(e) => warn!("⚠ Media '{}' listed in {} is unreadable: {}", name, dir.display(), e),
+                },
+            }
+        }
+        if migrated {
+            for (hash, (data, _)) in &storage.cache.media_blobs {
+                storage.write_blob(*hash, data);
+            }
+            storage.save_media_index();
+        }
+        info!("📂 Storage in {} loaded: {} clients, {} conversations, {} media ({} distinct)", dir.display(),
+            storage.cache.registered_clients.len(), storage.cache.chat_history.len(), storage.cache.media_index.len(), storage.cache.media_blobs.len());
+        Ok(storage)
+    }
+
+    fn read_json<T: for<'de> Deserialize<'de>>(&self, file: &str) -> io::Result<Option<T>> {
+        match fs::read_to_string(self.dir.join(file)) {
+            Ok(content) => serde_json::from_str(&content).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", file, e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_json<T: Serialize>(&self, file: &str, value: &T) {
+        let result = serde_json::to_string_pretty(value)
+            .map_err(io::Error::from)
+            .and_then(|content| fs::write(self.dir.join(file), content));
+        if let Err(e) = result {
+            warn!("⚠ Failed to save {} in {}: {}", file, self.dir.display(), e);
+        }
+    }
+
+    fn save_clients(&self) {
+        self.write_json("clients.json", &self.cache.registered_clients);
+    }
+
+    fn save_history(&self) {
+        let mut histories: Vec<(&ConversationKey, &VecDeque<HistoryEntry>)> = self.cache.chat_history.iter().collect();
+        histories.sort_by_key(|(key, _)| **key);
+        self.write_json("history.json", &histories);
+    }
+
+    fn save_offline_queues(&self) {
+        let mut queues: Vec<(&NodeId, &VecDeque<QueuedMessage>)> = self.cache.offline_queues.iter().collect();
+        queues.sort_by_key(|(id, _)| **id);
+        self.write_json("offline.json", &queues);
+    }
+
+    fn save_rooms(&self) {
+        let mut rooms: Vec<(&String, &Room)> = self.cache.rooms.iter().collect();
+        rooms.sort_by(|a, b| a.0.cmp(b.0));
+        self.write_json("rooms.json", &rooms);
+    }
+
+    fn save_texts(&self) {
+        let mut entries: Vec<TextEntry> = self
+            .cache
+            .texts
+            .iter()
+            .map(|(name, (owner, content))| TextEntry { name: name.clone(), owner: *owner, content: content.clone() })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        self.write_json("texts.json", &entries);
+    }
+
+    fn save_media_index(&self) {
+        self.write_json("media.json", &self.cache.media_list());
+    }
+
+    /// Media bytes are named after their hash, so names with path separators can't clash or escape `media/`.
+    fn blob_path(&self, hash: u64) -> PathBuf {
+        self.dir.join("media").join(format!("{:016x}", hash))
+    }
+
+    fn write_blob(&self, hash: u64, data: &[u8]) {
+        let path = self.blob_path(hash);
+        if path.exists() {
+            return;
+        }
+        if let Err(e) = fs::write(&path, data) {
+            warn!("⚠ Failed to save media {:016x} in {}: {}", hash, self.dir.display(), e);
+        }
+    }
+}
+
+impl ServerStorage for FileStorage {
+    fn registered_clients(&self) -> Vec<NodeId> {
+        self.cache.registered_clients()
+    }
+
+    fn register_client(&mut self, client_id: NodeId) {
+        let known = self.cache.is_known(client_id);
+        self.cache.register_client(client_id);
+        self.save_clients();
+        if !known {
+            self.save_offline_queues();
+        }
+    }
+
+    fn unregister_client(&mut self, client_id: NodeId) {
+        self.cache.unregister_client(client_id);
+        self.save_clients();
+    }
+
+    fn is_known(&self, client_id: NodeId) -> bool {
+        self.cache.is_known(client_id)
+    }
+
+    fn queue_message(&mut self, client_id: NodeId, message: QueuedMessage) -> bool {
+        let queued = self.cache.queue_message(client_id, message);
+        if queued {
+            self.save_offline_queues();
+        }
+        queued
+    }
+
+    fn take_queued(&mut self, client_id: NodeId) -> Vec<QueuedMessage> {
+        let messages = self.cache.take_queued(client_id);
+        if !messages.is_empty() {
+            self.save_offline_queues();
+        }
+        messages
+    }
+
+    fn open_conversation(&mut self, key: ConversationKey) {
+        if self.cache.history(key).is_none() {
+            self.cache.open_conversation(key);
+            self.save_history();
+        }
+    }
+
+    fn conversations(&self) -> Vec<ConversationKey> {
+        self.cache.conversations()
+    }
+
+    fn merge_history(&mut self, key: ConversationKey, entries: Vec<HistoryEntry>) -> Vec<HistoryEntry> {
+        let added = self.cache.merge_history(key, entries);
+        // timestamps of known messages may have moved too
+        self.save_history();
+        added
+    }
+
+    fn history(&self, key: ConversationKey) -> Option<VecDeque<HistoryEntry>> {
+        self.cache.history(key)
+    }
+
+    fn room_names(&self) -> Vec<String> {
+        self.cache.room_names()
+    }
+
+    fn room(&self, name: &str) -> Option<Room> {
+        self.cache.room(name)
+    }
+
+    fn create_room(&mut self, name: &str) -> bool {
+        let created = self.cache.create_room(name);
+        if created {
+            self.save_rooms();
+        }
+        created
+    }
+
+    fn join_room(&mut self, name: &str, client_id: NodeId) -> bool {
+        let joined = self.cache.join_room(name, client_id);
+        if joined {
+            self.save_rooms();
+        }
+        joined
+    }
+
+    fn leave_room(&mut self, name: &str, client_id: NodeId) -> bool {
+        let left = self.cache.leave_room(name, client_id);
+        if left {
+            self.save_rooms();
+        }
+        left
+    }
+
+    fn append_room_message(&mut self, name: &str, entry: String) {
+        self.cache.append_room_message(name, entry);
+        self.save_rooms();
+    }
+
+    fn store_media(&mut self, name: &str, owner: NodeId, data: Vec<u8>) -> bool {
+        let replaced = self.cache.media_info(name);
+        if replaced.as_ref().is_some_and(|info| info.owner != owner) {
+            return false;
+        }
+        self.cache.store_media(name, owner, data);
+        if let Some(info) = self.cache.media_info(name) {
+            if let Some((data, _)) = self.cache.media_blobs.get(&info.hash) {
+                self.write_blob(info.hash, data);
+            }
+        }
+        if let Some(old) = replaced.map(|info| info.hash).filter(|old| !self.cache.media_blobs.contains_key(old)) {
+            let _ = fs::remove_file(self.blob_path(old));
+        }
+        self.save_media_index();
+        true
+    }
+
+    fn media(&self, name: &str) -> Option<(NodeId, Vec<u8>)> {
+        self.cache.media(name)
+    }
+
+    fn media_info(&self, name: &str) -> Option<MediaInfo> {
+        self.cache.media_info(name)
+    }
+
+    fn media_list(&self) -> Vec<MediaInfo> {
+        self.cache.media_list()
+    }
+
+    fn set_media_visibility(&mut self, name: &str, visibility: MediaVisibility) -> bool {
+        let changed = self.cache.set_media_visibility(name, visibility);
+        if changed {
+            self.save_media_index();
+        }
+        changed
+    }
+
+    fn delete_media(&mut self, name: &str) -> bool {
+        let Some(info) = self.cache.media_info(name) else { return false };
+        self.cache.delete_media(name);
+        if !self.cache.media_blobs.contains_key(&info.hash) {
+            let _ = fs::remove_file(self.blob_path(info.hash));
+        }
+        self.save_media_index();
+        true
+    }
+
+    fn store_text(&mut self, name: &str, owner: NodeId, content: String) {
+        self.cache.store_text(name, owner, content);
+        self.save_texts();
+    }
+
+    fn text(&self, name: &str) -> Option<String> {
+        self.cache.text(name)
+    }
+
+    fn text_names(&self) -> Vec<String> {
+        self.cache.text_names()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn open(dir: &TempDir) -> FileStorage {
+        FileStorage::open(dir.path(), 2, 2).expect("store should open")
+    }
+
+    fn entry(message_id: u64, message: &str) -> HistoryEntry {
+        HistoryEntry { sender_id: 5, message_id, timestamp: message_id, message: message.to_string() }
+    }
+
+    fn texts(history: Option<VecDeque<HistoryEntry>>) -> Vec<String> {
+        history.unwrap_or_default().into_iter().map(|e| e.message).collect()
+    }
+
+    #[test]
+    fn test_history_keeps_only_the_newest_messages() {
+        let mut memory = MemoryStorage::new(3, 2);
+        for i in 0..5 {
+            memory.append_message((1, 2), entry(i, &format!("msg {}", i)));
+        }
+        assert_eq!(texts(memory.history((1, 2))), vec!["msg 2", "msg 3", "msg 4"]);
+
+        let mut unlimited = MemoryStorage::new(0, 2);
+        for i in 0..5 {
+            unlimited.append_message((1, 2), entry(i, "x"));
+        }
+        assert_eq!(unlimited.history((1, 2)).unwrap().len(), 5, "retention 0 keeps everything");
+    }
+
+    #[test]
+    fn test_file_storage_reloads_clients_and_histories() {
+        let dir = TempDir::new("storage_test");
+        {
+            let mut storage = open(&dir);
+            storage.register_client(4);
+            storage.register_client(5);
+            storage.unregister_client(4);
+            storage.open_conversation((4, 5));
+            for (i, message) in ["a", "b", "c"].into_iter().enumerate() {
+                storage.append_message((5, 6), entry(i as u64, message));
+            }
+        }
+        let reopened = open(&dir);
+        assert_eq!(reopened.registered_clients(), vec![5]);
+        assert_eq!(reopened.history((4, 5)), Some(VecDeque::new()), "opened conversations are kept even when empty");
+        assert_eq!(texts(reopened.history((5, 6))), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn test_media_names_cannot_escape_the_store() {
+        let dir = TempDir::new("storage_names_test");
+        let mut storage = open(&dir);
+        storage.store_media("cat.png", 5, vec![1, 2, 3]);
+        storage.store_media("../cat.png", 6, vec![4]);
+        assert_eq!(storage.media("cat.png"), Some((5, vec![1, 2, 3])));
+        assert_eq!(storage.media("../cat.png"), Some((6, vec![4])), "names with path separators do not clash");
+        assert!(!dir.path().parent().unwrap().join("cat.png").exists());
+    }
+
+    #[test]
+    fn test_storage_config_defaults() {
+        let config: StorageConfig = toml::from_str("kind = \"file\"\ndir = \"data\"\nretention = 10").unwrap();
+        assert_eq!(config, StorageConfig { kind: StorageKind::File, dir: Some("data".to_string()), retention: Some(10), offline_queue: None, media_quota: None });
+        assert_eq!(StorageConfig::default().retention(), DEFAULT_HISTORY_RETENTION);
+        assert_eq!(StorageConfig::default().offline_queue_cap(), DEFAULT_OFFLINE_QUEUE_CAP);
+    }
+
+    #[test]
+    fn test_offline_queue_holds_messages_for_known_clients_up_to_the_cap() {
+        let queued = |sender_id, message_id, message: &str| QueuedMessage { sender_id, message_id, message: message.to_string() };
+        let mut storage = MemoryStorage::new(50, 2);
+        assert!(!storage.is_known(5), "a client that never logged in is unknown");
+        storage.register_client(5);
+        storage.unregister_client(5);
+        assert!(storage.is_known(5) && !storage.is_registered(5), "a logged out client stays known");
+
+        assert!(storage.queue_message(5, queued(4, 1, "first")));
+        assert!(storage.queue_message(5, queued(6, 1, "second")));
+        assert!(!storage.queue_message(5, queued(4, 2, "third")), "the queue is capped per client");
+        assert_eq!(storage.take_queued(5), vec![queued(4, 1, "first"), queued(6, 1, "second")], "delivery keeps the order");
+        assert!(storage.take_queued(5).is_empty());
+        assert!(storage.queue_message(5, queued(4, 3, "again")), "draining frees the queue");
+    }
+
+    #[test]
+    fn test_file_storage_reloads_offline_queues() {
+        let dir = TempDir::new("offline_test");
+        let waiting = QueuedMessage { sender_id: 8, message_id: 1, message: "while you were away".to_string() };
+        {
+            let mut storage = open(&dir);
+            storage.register_client(7);
+            storage.unregister_client(7);
+            storage.queue_message(7, waiting.clone());
+        }
+        let mut reopened = open(&dir);
+        assert!(reopened.is_known(7));
+        assert_eq!(reopened.take_queued(7), vec![waiting]);
+    }
+
+    #[test]
+    fn test_rooms_track_members_and_keep_their_own_history() {
+        let mut storage = MemoryStorage::new(2, 2);
+        assert!(storage.create_room("lobby"));
+        assert!(!storage.create_room("lobby"), "room names are unique");
+        assert!(!storage.join_room("attic", 5), "joining needs an existing room");
+        assert!(storage.join_room("lobby", 9) && storage.join_room("lobby", 5) && storage.join_room("lobby", 5));
+        assert_eq!(storage.room("lobby").unwrap().members, vec![5, 9], "members are sorted and not repeated");
+        for entry in ["5:\n a", "9:\n b", "5:\n c"] {
+            storage.append_room_message("lobby", entry.to_string());
+        }
+        assert_eq!(storage.room("lobby").unwrap().history, vec!["9:\n b".to_string(), "5:\n c".to_string()], "rooms follow the retention limit");
+        assert!(storage.history((5, 9)).is_none(), "room messages stay out of the pairwise histories");
+        assert!(storage.leave_room("lobby", 9));
+        assert!(!storage.leave_room("lobby", 9), "leaving twice fails");
+        assert_eq!(storage.room_names(), vec!["lobby".to_string()]);
+    }
+
+    #[test]
+    fn test_file_storage_reloads_rooms() {
+        let dir = TempDir::new("rooms_test");
+        {
+            let mut storage = open(&dir);
+            storage.create_room("book club");
+            storage.join_room("book club", 3);
+            storage.append_room_message("book club", "3:\n chapter 2?".to_string());
+        }
+        let room = open(&dir).room("book club").expect("rooms survive a restart");
+        assert_eq!((room.members, room.history.len()), (vec![3], 1));
+    }
+
+    #[test]
+    fn test_replicated_histories_merge_by_message_id_and_converge() {
+        use crate::nodes::protocol::ConversationDigest;
+        let entry = |sender_id: NodeId, message_id: u64, timestamp: u64| HistoryEntry { sender_id, message_id, timestamp, message: format!("{}/{}", sender_id, message_id) };
+        let key = (101, 102);
+
+        // the same conversation goes on through two servers at once
+        let mut a = MemoryStorage::new(0, 2);
+        let mut b = MemoryStorage::new(0, 2);
+        a.append_message(key, entry(101, 1, 10));
+        a.append_message(key, entry(101, 2, 30));
+        b.append_message(key, entry(102, 1, 20));
+        b.append_message(key, entry(101, 2, 25)); // the same message, stamped differently
+
+        assert_ne!(ConversationDigest::of(key, &a.history(key).unwrap()), ConversationDigest::of(key, &b.history(key).unwrap()));
+        let added = a.merge_history(key, b.history(key).unwrap().into());
+        assert_eq!(added, vec![entry(102, 1, 20)], "only unseen messages are new");
+        b.merge_history(key, a.history(key).unwrap().into());
+        assert_eq!(a.history(key), b.history(key), "both servers end with the same history");
+        let ids: Vec<_> = a.history(key).unwrap().iter().map(|e| (e.id(), e.timestamp)).collect();
+        assert_eq!(ids, vec![((101, 1), 10), ((102, 1), 20), ((101, 2), 25)], "ordered by timestamp, duplicates keep the earliest");
+        assert!(!a.append_message(key, entry(101, 1, 99)), "a delta received twice is not stored twice");
+        assert_eq!(ConversationDigest::of(key, &a.history(key).unwrap()), ConversationDigest::of(key, &b.history(key).unwrap()));
+    }
+
+    #[test]
+    fn test_media_are_stored_once_per_content_and_names_keep_their_owner() {
+        let mut memory = MemoryStorage::new(50, 2);
+        assert!(memory.store_media("pic1.png", 101, vec![1, 2, 3]));
+        assert!(!memory.store_media("pic1.png", 102, vec![9]), "another client can't overwrite the media");
+        assert_eq!(memory.media("pic1.png"), Some((101, vec![1, 2, 3])));
+        assert!(memory.store_media("pic1.png", 101, vec![4, 5]), "the owner can replace its own media");
+        let info = memory.media_info("pic1.png").unwrap();
+        assert_eq!((info.size, info.owner, info.hash), (2, 101, checksum(&[4, 5])));
+        assert!(info.uploaded_at > 0);
+    }
+
+    #[test]
+    fn test_colliding_hashes_do_not_share_bytes() {
+        let mut memory = MemoryStorage::new(50, 2);
+        memory.store_media("private.png", 101, vec![1, 2, 3]);
+        // same hash, other bytes, as a crafted collision would give
+        let hash = checksum(&[1, 2, 3]);
+        let forged = MediaInfo { name: "mine.png".to_string(), size: 2, owner: 102, hash, uploaded_at: 1, visibility: MediaVisibility::default(), content_type: crate::nodes::media::OCTET_STREAM.to_string() };
+        assert!(memory.insert_media(forged, vec![6, 6]));
+        assert_eq!(memory.media("mine.png"), Some((102, vec![6, 6])));
+        assert_eq!(memory.media("private.png"), Some((101, vec![1, 2, 3])), "the first bytes are untouched");
+        assert_ne!(memory.media_info("mine.png").unwrap().hash, hash);
+        memory.delete_media("private.png");
+        assert_eq!(memory.media("mine.png"), Some((102, vec![6, 6])));
+    }
+
+    #[test]
+    fn test_file_storage_keeps_one_blob_per_content() {
+        let dir = TempDir::new("media_blobs");
+        let blobs = || fs::read_dir(dir.path().join("media")).unwrap().count();
+        {
+            let mut storage = open(&dir);
+            storage.store_media("a.png", 101, vec![7; 10]);
+            storage.store_media("b.png", 102, vec![7; 10]);
+            assert_eq!(blobs(), 1, "identical uploads are stored once");
+            storage.store_media("a.png", 101, vec![8; 10]);
+            assert_eq!(blobs(), 2);
+            storage.store_media("b.png", 102, vec![8; 10]);
+            assert_eq!(blobs(), 1, "bytes no name points at anymore are dropped");
+        }
+        let reopened = open(&dir);
+        let list = reopened.media_list();
+        assert_eq!(list.iter().map(|m| (m.name.as_str(), m.owner, m.size)).collect::<Vec<_>>(), vec![("a.png", 101, 10), ("b.png", 102, 10)]);
+        assert_eq!(list[0].hash, list[1].hash);
+        assert_eq!(reopened.media("b.png"), Some((102, vec![8; 10])));
+    }
+
+    #[test]
+    fn test_media_visibility_deletion_and_usage() {
+        let mut memory = MemoryStorage::new(50, 2);
+        memory.store_media("a.png", 101, vec![1; 4]);
+        memory.store_media("b.png", 101, vec![1; 4]);
+        memory.store_media("c.png", 102, vec![2; 6]);
+        assert!(memory.set_media_visibility("a.png", MediaVisibility::Clients(vec![102])));
+        assert!(!memory.set_media_visibility("missing.png", MediaVisibility::Owner));
+        let info = memory.media_info("a.png").unwrap();
+        assert!(info.visible_to(101) && info.visible_to(102) && !info.visible_to(103), "the owner and the listed clients only");
+        memory.store_media("a.png", 101, vec![3; 5]);
+        assert_eq!(memory.media_info("a.png").unwrap().visibility, MediaVisibility::Clients(vec![102]), "replacing a media keeps who sees it");
+        assert_eq!(memory.media_usage(101), 9, "every name counts, shared content or not");
+        assert!(memory.delete_media("a.png"));
+        assert!(!memory.delete_media("a.png"));
+        assert_eq!(memory.media("b.png"), Some((101, vec![1; 4])));
+        assert_eq!(memory.media_usage(101), 4);
+    }
+
+    #[test]
+    fn test_file_storage_reloads_media_visibility_and_deletions() {
+        let dir = TempDir::new("media_acl");
+        {
+            let mut storage = open(&dir);
+            storage.store_media("private.png", 101, vec![5; 3]);
+            storage.store_media("gone.png", 101, vec![6; 3]);
+            storage.set_media_visibility("private.png", MediaVisibility::Owner);
+            storage.delete_media("gone.png");
+            assert_eq!(fs::read_dir(dir.path().join("media")).unwrap().count(), 1, "deleted bytes leave the disk");
+        }
+        let reopened = open(&dir);
+        assert_eq!(reopened.media_names(), vec!["private.png"]);
+        assert_eq!(reopened.media_info("private.png").unwrap().visibility, MediaVisibility::Owner);
+    }
+
+    #[test]
+    fn test_media_quota_config() {
+        let config: StorageConfig = toml::from_str("media_quota = 1024").unwrap();
+        assert_eq!(config.media_quota(), 1024);
+        assert_eq!(StorageConfig::default().media_quota(), DEFAULT_MEDIA_QUOTA);
+    }
+
+    #[test]
+    fn test_media_info_takes_the_content_type_from_the_envelope() {
+        let mut storage = MemoryStorage::new(50, 2);
+        storage.store_media("paper", 101, MediaEnvelope::new("paper.pdf", b"%PDF".to_vec()).encode());
+        assert_eq!(storage.media_info("paper").unwrap().content_type, "application/pdf");
+    }
+}
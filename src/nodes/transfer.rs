@@ -0,0 +1,261 @@
+use std::time::{Duration, Instant};
+use wg_2024::network::NodeId;
+use crate::nodes::protocol::ChatProtocol;
+
+/// Bytes of media carried by one `MediaChunk` / `MediaUploadChunk` (64 fragments of 128 bytes).
+pub const MEDIA_CHUNK_SIZE: u32 = 8 * 1024;
+/// How long a transfer may wait for its next chunk (or chunk ack) before asking again from the last verified offset.
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(3);
+/// Timeouts in a row, without any progress, before a transfer is given up.
+pub const MAX_RESUMES: u8 = 5;
+
+/// FNV-1a over the bytes, used for both the per-chunk and the whole-file checksums.
+pub fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Chunk of `data` starting at `offset`, with its checksums, as a server sends it.
+pub fn media_chunk(name: &str, data: &[u8], offset: u64, length: u32) -> ChatProtocol {
+    let start = (offset as usize).min(data.len());
+    let end = start.saturating_add(length.min(MEDIA_CHUNK_SIZE) as usize).min(data.len());
+    let chunk = data[start..end].to_vec();
+    ChatProtocol::MediaChunk {
+        name: name.to_string(),
+        offset: start as u64,
+        total_size: data.len() as u64,
+        chunk_checksum: checksum(&chunk),
+        file_checksum: checksum(data),
+        data: chunk,
+    }
+}
+
+/// Media whose upload the server refused for good with this error reason
+/// (`media_checksum_mismatch::name`, `media_name_taken::name`, `media_quota_exceeded::name::used::quota`).
+pub fn refused_upload(reason: &str) -> Option<&str> {
+    ["media_checksum_mismatch::", "media_name_taken::", "media_quota_exceeded::"]
+        .iter()
+        .find_map(|prefix| reason.strip_prefix(prefix))
+        .and_then(|rest| rest.split("::").next())
+}
+
+/// What a download wants done with the chunk it was just given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadStep {
+    /// The chunk verified: send this request for the next one.
+    Request(ChatProtocol),
+    /// Duplicate, out of order or damaged chunk. Nothing is sent, the timeout asks for it again.
+    Ignored,
+    /// Every chunk arrived and the whole-file checksum matches.
+    Complete(Vec<u8>),
+    /// The assembled file does not match its checksum.
+    Corrupted,
+}
+
+/// What a transfer wants done when polled for timeouts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resume {
+    Wait,
+    /// No progress for `CHUNK_TIMEOUT`: send this again, from the last verified offset.
+    Resend(ChatProtocol),
+    /// `MAX_RESUMES` timeouts in a row.
+    GiveUp,
+}
+
+/// A media being fetched chunk by chunk; only verified chunks are kept, so it can resume after a route failure.
+#[derive(Debug, Clone)]
+pub struct Download {
+    pub server_id: NodeId,
+    pub name: String,
+    data: Vec<u8>,
+    total_size: Option<u64>,
+    file_checksum: Option<u64>,
+    last_activity: Instant,
+    resumes: u8,
+}
+
+impl Download {
+    pub fn new(server_id: NodeId, name: &str) -> Self {
+        Self { server_id, name: name.to_string(), data: Vec::new(), total_size: None, file_checksum: None, last_activity: Instant::now(), resumes: 0 }
+    }
+
+    /// Bytes received and verified so far.
+    pub fn verified(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    pub fn total_size(&self) -> Option<u64> {
+        self.total_size
+    }
+
+    /// Request for the chunk right after the verified part.
+    pub fn next_request(&mut self) -> ChatProtocol {
+        self.last_activity = Instant::now();
+        ChatProtocol::MediaChunkRequest { name: self.name.clone(), offset: self.verified(), length: MEDIA_CHUNK_SIZE }
+    }
+
+    pub fn poll(&mut self, now: Instant) -> Resume {
+        if now.duration_since(self.last_activity) < CHUNK_TIMEOUT {
+            Resume::Wait
+        } else if self.resumes >= MAX_RESUMES {
+            Resume::GiveUp
+        } else {
+            self.resumes += 1;
+            Resume::Resend(self.next_request())
+        }
+    }
+
+    pub fn receive(&mut self, offset: u64, total_size: u64, data: Vec<u8>, chunk_checksum: u64, file_checksum: u64) -> DownloadStep {
+        if self.file_checksum.is_some_and(|known| known != file_checksum) {
+            // the media changed on the server since the first chunk
+            self.data.clear();
+        }
+        self.total_size = Some(total_size);
+        self.file_checksum = Some(file_checksum);
+        if offset != self.verified() || checksum(&data) != chunk_checksum || self.verified() + data.len() as u64 > total_size {
+            return DownloadStep::Ignored;
+        }
+        self.data.extend_from_slice(&data);
+        self.resumes = 0;
+        if self.verified() < total_size {
+            return DownloadStep::Request(self.next_request());
+        }
+        if checksum(&self.data) == file_checksum {
+            DownloadStep::Complete(std::mem::take(&mut self.data))
+        } else {
+            self.data.clear();
+            DownloadStep::Corrupted
+        }
+    }
+}
+
+/// A media being sent chunk by chunk; the server acks the offset it expects next.
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub server_id: NodeId,
+    pub name: String,
+    data: Vec<u8>,
+    file_checksum: u64,
+    acked: u64,
+    last_activity: Instant,
+    resumes: u8,
+}
+
+impl Upload {
+    pub fn new(server_id: NodeId, name: &str, data: Vec<u8>) -> Self {
+        let file_checksum = checksum(&data);
+        Self { server_id, name: name.to_string(), data, file_checksum, acked: 0, last_activity: Instant::now(), resumes: 0 }
+    }
+
+    pub fn acked(&self) -> u64 {
+        self.acked
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// The chunk starting at the offset the server acked last.
+    pub fn next_chunk(&mut self) -> ChatProtocol {
+        self.last_activity = Instant::now();
+        let start = self.acked as usize;
+        let end = (start + MEDIA_CHUNK_SIZE as usize).min(self.data.len());
+        let chunk = self.data[start..end].to_vec();
+        ChatProtocol::MediaUploadChunk {
+            name: self.name.clone(),
+            offset: self.acked,
+            total_size: self.total_size(),
+            chunk_checksum: checksum(&chunk),
+            file_checksum: self.file_checksum,
+            data: chunk,
+        }
+    }
+
+    /// Records the server's ack and gives the chunk to send next; acks that do not move forward
+    /// (duplicates, or a chunk the server could not verify) are left to the timeout.
+    pub fn acknowledge(&mut self, next_offset: u64) -> Option<ChatProtocol> {
+        if next_offset <= self.acked || next_offset > self.total_size() {
+            return None;
+        }
+        self.acked = next_offset;
+        self.resumes = 0;
+        Some(self.next_chunk())
+    }
+
+    pub fn poll(&mut self, now: Instant) -> Resume {
+        if now.duration_since(self.last_activity) < CHUNK_TIMEOUT {
+            Resume::Wait
+        } else if self.resumes >= MAX_RESUMES {
+            Resume::GiveUp
+        } else {
+            self.resumes += 1;
+            Resume::Resend(self.next_chunk())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn media() -> Vec<u8> {
+        (0..MEDIA_CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// `(offset, total_size, data, chunk_checksum, file_checksum)` of the server chunk at `offset`.
+    fn chunk_at(media: &[u8], offset: u64) -> (u64, u64, Vec<u8>, u64, u64) {
+        match media_chunk("pic.png", media, offset, MEDIA_CHUNK_SIZE) {
+            ChatProtocol::MediaChunk { offset, total_size, chunk_checksum, file_checksum, data, .. } => (offset, total_size, data, chunk_checksum, file_checksum),
+            other => panic!("a server chunk was expected, got {}", other.tag()),
+        }
+    }
+
+    #[test]
+    fn test_media_chunks_stop_at_the_end_and_survive_the_text_form() {
+        let media = media();
+        let chunk = media_chunk("pic.png", &media, 0, MEDIA_CHUNK_SIZE);
+        assert_eq!(chunk.to_string().parse::<ChatProtocol>(), Ok(chunk.clone()), "chunks survive the text form");
+        assert_eq!(ChatProtocol::decode(&chunk.encode().unwrap()), Ok(chunk));
+        let (_, _, tail, _, _) = chunk_at(&media, MEDIA_CHUNK_SIZE as u64 * 2);
+        assert_eq!(tail.len(), 100, "the last chunk stops at the end of the media");
+    }
+
+    #[test]
+    fn test_download_keeps_only_verified_chunks_and_resumes_after_a_stall() {
+        let media = media();
+        let mut download = Download::new(41, "pic.png");
+        assert_eq!(download.next_request(), ChatProtocol::MediaChunkRequest { name: "pic.png".to_string(), offset: 0, length: MEDIA_CHUNK_SIZE });
+        let (offset, total, data, chunk_sum, file_sum) = chunk_at(&media, 0);
+        assert!(matches!(download.receive(offset, total, data, chunk_sum, file_sum), DownloadStep::Request(_)));
+        let (offset, total, mut damaged, chunk_sum, file_sum) = chunk_at(&media, MEDIA_CHUNK_SIZE as u64);
+        damaged[0] ^= 1;
+        assert_eq!(download.receive(offset, total, damaged, chunk_sum, file_sum), DownloadStep::Ignored, "a damaged chunk is not kept");
+        let (offset, total, data, chunk_sum, file_sum) = chunk_at(&media, 0);
+        assert_eq!(download.receive(offset, total, data, chunk_sum, file_sum), DownloadStep::Ignored, "nor a duplicate one");
+        assert_eq!(download.total_size(), Some(media.len() as u64));
+        assert_eq!(download.poll(Instant::now()), Resume::Wait);
+        assert_eq!(download.poll(Instant::now() + CHUNK_TIMEOUT), Resume::Resend(ChatProtocol::MediaChunkRequest {
+            name: "pic.png".to_string(), offset: MEDIA_CHUNK_SIZE as u64, length: MEDIA_CHUNK_SIZE,
+        }), "a stalled download asks again from its verified part");
+        let (offset, total, data, chunk_sum, file_sum) = chunk_at(&media, MEDIA_CHUNK_SIZE as u64);
+        assert!(matches!(download.receive(offset, total, data, chunk_sum, file_sum), DownloadStep::Request(_)));
+        let (offset, total, data, chunk_sum, file_sum) = chunk_at(&media, MEDIA_CHUNK_SIZE as u64 * 2);
+        assert_eq!(download.receive(offset, total, data, chunk_sum, file_sum), DownloadStep::Complete(media));
+    }
+
+    #[test]
+    fn test_upload_moves_on_only_when_the_server_acks_progress() {
+        let media = media();
+        let mut upload = Upload::new(41, "pic.png", media.clone());
+        assert!(matches!(upload.next_chunk(), ChatProtocol::MediaUploadChunk { offset: 0, file_checksum, .. } if file_checksum == checksum(&media)));
+        assert!(upload.acknowledge(0).is_none(), "an ack without progress waits for the timeout");
+        assert!(matches!(upload.acknowledge(MEDIA_CHUNK_SIZE as u64), Some(ChatProtocol::MediaUploadChunk { offset, .. }) if offset == MEDIA_CHUNK_SIZE as u64));
+        assert_eq!(upload.acked(), MEDIA_CHUNK_SIZE as u64);
+        assert!(matches!(upload.poll(Instant::now() + CHUNK_TIMEOUT), Resume::Resend(ChatProtocol::MediaUploadChunk { offset, .. }) if offset == MEDIA_CHUNK_SIZE as u64));
+    }
+
+    #[test]
+    fn test_refused_upload_names_the_media_over_quota() {
+        assert_eq!(refused_upload("media_quota_exceeded::big.png::4::100"), Some("big.png"));
+        assert_eq!(refused_upload("text_not_found::big.png"), None);
+    }
+}
@@ -1,6 +1,9 @@
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::fmt;
 use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use log::{info, warn};
 use rustastic_drone::RustasticDrone;
@@ -10,9 +13,14 @@ use wg_2024::drone::Drone;
 use wg_2024::network::NodeId;
 use crate::network::initializer::{NetworkInitializer, ParsedConfig};
 use crate::simulation_controller::network_designer::{Node, NodeType};
-use crate::simulation_controller::gui_input_queue::{broadcast_topology_change, SharedGuiInput};
+use crate::simulation_controller::gui_input_queue::{broadcast_topology_change, SharedGuiInput, SHUTDOWN_COMMAND};
 use crate::network::initializer::GroupImplFactory;
 use crate::network::initializer::DroneImplementation;
+use crate::simulation_controller::headless::ScriptCommand;
+use crate::simulation_controller::replay::{new_recorder, record_action, SharedRecorder};
+use crate::simulation_controller::stats::{new_network_stats, LinkStats, NodeStats, SharedNetworkStats};
+use crate::simulation_controller::packet_trace::{new_packet_trace, SharedPacketTrace, DEFAULT_TRACE_CAPACITY};
+use crate::simulation_controller::sim_log::{new_sim_log, packet_hop, packet_type_name, push_sim_event, SharedSimLog, SimEvent, SimEventKind};
 
 pub struct SimulationController {
     network_config: Arc<Mutex<ParsedConfig>>,
@@ -40,7 +48,13 @@ pub struct SimulationController {
 
     pub initializer: Arc<Mutex<NetworkInitializer>>,
     shared_senders:  Arc<Mutex<HashMap<(NodeId, NodeId), Sender<Packet>>>>,
-    inbox_senders:Arc<Mutex<HashMap<NodeId, Sender<Packet>>>>
+    inbox_senders:Arc<Mutex<HashMap<NodeId, Sender<Packet>>>>,
+    node_threads: Vec<(NodeId, JoinHandle<()>)>, // drones spawned at runtime
+    started_at: Instant,
+    simulation_log: SharedSimLog,
+    packet_trace: SharedPacketTrace,
+    recorder: SharedRecorder,
+    stats: SharedNetworkStats,
 }
 
 struct NodeState {
@@ -48,6 +62,33 @@ struct NodeState {
     active: bool,
 }
 
+/// How long `shutdown` waits for the node threads to stop.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// What `SimulationController::shutdown` managed to stop.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    pub run_time: Duration,
+    pub drones_stopped: usize,
+    pub clients_stopped: usize,
+    pub servers_stopped: usize,
+    /// Threads still running when the grace period ran out; they are left detached.
+    pub still_running: Vec<NodeId>,
+}
+
+impl fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "📊 Simulation summary after {:.1}s", self.run_time.as_secs_f32())?;
+        writeln!(f, "   🛸 drones stopped:  {}", self.drones_stopped)?;
+        writeln!(f, "   👤 clients stopped: {}", self.clients_stopped)?;
+        write!(f, "   🖥 servers stopped: {}", self.servers_stopped)?;
+        if !self.still_running.is_empty() {
+            write!(f, "\n   ⚠️ still running:   {:?}", self.still_running)?;
+        }
+        Ok(())
+    }
+}
+
 impl SimulationController {
     pub fn new(
         network_config: Arc<Mutex<ParsedConfig>>,
@@ -81,6 +122,12 @@ impl SimulationController {
             initializer,
             shared_senders,
             inbox_senders,
+            node_threads: Vec::new(),
+            started_at: Instant::now(),
+            simulation_log: new_sim_log(),
+            packet_trace: new_packet_trace(DEFAULT_TRACE_CAPACITY),
+            recorder: new_recorder(0, ""),
+            stats: new_network_stats(),
         };
 
         controller.initialize_network_graph();
@@ -97,6 +144,28 @@ impl SimulationController {
         });
     }
 
+    pub fn attach_log(&mut self, log: SharedSimLog) {
+        self.simulation_log = log;
+    }
+
+    /// Where the actions applied to the network are recorded, for `--record`.
+    pub fn attach_recorder(&mut self, recorder: SharedRecorder) {
+        self.recorder = recorder;
+    }
+
+    /// Every `DroneEvent` processed so far (see `packet_trace.rs`).
+    pub fn packet_trace(&self) -> SharedPacketTrace {
+        self.packet_trace.clone()
+    }
+
+    /// Records a drone event in the simulation log, under the node that sent (or dropped) the packet.
+    fn log_packet(&self, kind: SimEventKind, packet: &Packet) {
+        let Some((from, to)) = packet_hop(packet) else { return };
+        let to = to.map(|to| to.to_string()).unwrap_or_else(|| "?".to_string());
+        let event = SimEvent::new(Some(from), kind, format!("{} {} → {}", packet_type_name(packet), from, to));
+        push_sim_event(&self.simulation_log, event.with_session(packet.session_id));
+    }
+
     // Initialize network graph from config
     fn initialize_network_graph(&mut self) {
         let config = self.network_config.lock().unwrap();
@@ -134,48 +203,30 @@ impl SimulationController {
         }
     }
     pub(crate) fn process_event(&mut self, event: DroneEvent) {
+        if let Ok(mut trace) = self.packet_trace.lock() {
+            trace.record(&event);
+        } else {
+            println!("❌ Could not lock packet trace");
+        }
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.record(&event);
+        } else {
+            println!("❌ Could not lock network stats");
+        }
         match event {
             DroneEvent::PacketSent(packet) => {
-                let hops = &packet.routing_header.hops;
-                let hop_index = packet.routing_header.hop_index;
-
-                if hop_index > 0 && hop_index < hops.len() {
-                    let sender = hops[hop_index - 1];
-                    let receiver = hops[hop_index];
-                    println!("📨Packet sent from {} to {}", sender, receiver);
-                } else if hop_index == 0 && !hops.is_empty() {
-                    let sender = hops[0];
-                    let receiver = hops.get(1).copied();
-                    match receiver {
-                        Some(r) => println!("📨Packet sent from {} to {}", sender, r),
-                        None => println!("Packet sent from {} but no receiver (single-hop)", sender),
-                    }
-                }
+                self.log_packet(SimEventKind::PacketSent, &packet);
             },
             DroneEvent::PacketDropped(packet) => {
-                let hops = &packet.routing_header.hops;
-                let hop_index = packet.routing_header.hop_index;
-
-                if hop_index > 0 && hop_index < hops.len() {
-                    let sender = hops[hop_index - 1];
-                    let receiver = hops[hop_index];
-                    println!("🩸Packet dropped from {} to {}", sender, receiver);
-                } else if hop_index == 0 && !hops.is_empty() {
-                    let sender = hops[0];
-                    let receiver = hops.get(1).copied();
-                    match receiver {
-                        Some(r) => println!("🩸Packet dropped from {} to {}", sender, r),
-                        None => println!("Packet dropped from {} but no receiver (single-hop)", sender),
-                    }
-                }
+                self.log_packet(SimEventKind::PacketDropped, &packet);
             },
             DroneEvent::ControllerShortcut(packet) => {
                 if let Some(dest_id) = packet.routing_header.destination() {
+                    let event = SimEvent::new(Some(dest_id), SimEventKind::Shortcut, format!("{} delivered to {} by the controller", packet_type_name(&packet), dest_id));
+                    push_sim_event(&self.simulation_log, event.with_session(packet.session_id));
                     if let Some(sender) = self.host_senders.get(&dest_id) {
                         if let Err(e) = sender.send(packet.clone()) {
                             eprintln!("❌ Failed to send ControllerShortcut to node {}: {}", dest_id, e);
-                        } else {
-                            println!("✅ ControllerShortcut delivered to node {}", dest_id);
                         }
                     } else {
                         eprintln!("❌ No direct host_sender for destination {}", dest_id);
@@ -281,6 +332,7 @@ impl SimulationController {
 
 
         broadcast_topology_change(&self.gui_input,&self.network_config,&format!("[FloodRequired]::Crash::{}",drone_id));
+        record_action(&self.recorder, ScriptCommand::Crash(drone_id));
         Ok(())
     }
 
@@ -359,6 +411,7 @@ impl SimulationController {
             &format!("[FloodRequired]::RemoveSender::{}::{}", a, b),
         );
         println!("✅ Successfully removed link between {} and {}", a, b);
+        record_action(&self.recorder, ScriptCommand::RemoveLink(a, b));
         Ok(())
     }
 
@@ -495,6 +548,7 @@ impl SimulationController {
             &format!("[FloodRequired]::AddSender::{}::{}", a, b),
         );
 
+        record_action(&self.recorder, ScriptCommand::AddLink(a, b));
         Ok(())
     }
     pub fn spawn_drone(&mut self, id: NodeId, pdr: f32, connections: Vec<NodeId>, ) -> Result<(), Box<dyn Error>> {
@@ -593,9 +647,10 @@ impl SimulationController {
             .expect("rustastic_drone group implementation must exist");
 
         let mut drone = sky_factory(id, controller_send, controller_recv, packet_recv, packet_send_map, pdr);
-        std::thread::spawn(move || {
+        let handle = std::thread::spawn(move || {
             drone.run();
         });
+        self.node_threads.push((id, handle));
 
         // 10) ⚠️ CRITICAL: Send AddSender commands to peers
         for &peer in &connections {
@@ -648,6 +703,7 @@ impl SimulationController {
         // 13) Add small delay to ensure all commands are processed
         std::thread::sleep(std::time::Duration::from_millis(100));
 
+        record_action(&self.recorder, ScriptCommand::Spawn { id, pdr, connections });
         Ok(())
     }
     pub fn add_connection(&mut self, a: NodeId, b: NodeId) {
@@ -678,12 +734,14 @@ impl SimulationController {
 
     pub fn set_packet_drop_rate(&mut self, drone_id: NodeId, rate: f32) -> Result<(), Box<dyn Error>> {
         if let Some(sender) = self.command_senders.lock().unwrap().get(&drone_id) {
+            // what drop_ratio(..) compares the observed drops with
+            self.network_config.lock().unwrap().set_drone_pdr(drone_id, rate);
             broadcast_topology_change(&self.gui_input,&self.network_config,&"[FloodRequired]::newpdr".to_string());
 
             sender.send(DroneCommand::SetPacketDropRate(rate))
-                .map_err(|_| "Failed to send SetPacketDropRate command".into())
-
-
+                .map_err(|_| "Failed to send SetPacketDropRate command")?;
+            record_action(&self.recorder, ScriptCommand::SetPdr { drone: drone_id, rate });
+            Ok(())
 
         } else {
             Err("Drone not found".into())
@@ -691,6 +749,78 @@ impl SimulationController {
     }
 
 
+    /// Ends the simulation: every host gets `[Shutdown]` and leaves its loop, then every drone loses
+    /// its neighbors and gets `Crash`. Threads are joined as they stop, for at most `grace` overall.
+    pub fn shutdown(&mut self, grace: Duration) -> ShutdownSummary {
+        let deadline = Instant::now() + grace;
+        let mut threads = self.initializer.lock().unwrap().take_node_threads();
+        threads.append(&mut self.node_threads);
+        let (clients, servers) = {
+            let cfg = self.config.lock().unwrap();
+            (cfg.client.iter().map(|c| c.id).collect::<HashSet<_>>(), cfg.server.iter().map(|s| s.id).collect::<HashSet<_>>())
+        };
+        let (hosts, drones): (Vec<_>, Vec<_>) = threads.into_iter().partition(|(id, _)| clients.contains(id) || servers.contains(id));
+
+        // 1) hosts first, so nothing new enters the network while the drones go down
+        info!("🛑 Stopping {} hosts", hosts.len());
+        broadcast_topology_change(&self.gui_input, &self.network_config, SHUTDOWN_COMMAND);
+        let (hosts_stopped, mut still_running) = join_until(hosts, deadline);
+
+        // 2) drones: once no neighbor (and no map here) holds a sender to a crashed drone, its channel closes
+        info!("🛑 Stopping {} drones", drones.len());
+        {
+            let command_senders = self.command_senders.lock().unwrap();
+            for (drone_id, cmd_tx) in command_senders.iter() {
+                for neighbor_id in self.network_graph.get(drone_id).into_iter().flatten() {
+                    let _ = cmd_tx.send(DroneCommand::RemoveSender(*neighbor_id));
+                }
+                let _ = cmd_tx.send(DroneCommand::Crash);
+            }
+        }
+        self.packet_senders.lock().unwrap().clear();
+        self.shared_senders.lock().unwrap().clear();
+        self.inbox_senders.lock().unwrap().clear();
+        self.host_senders.clear();
+        let (drones_stopped, drones_running) = join_until(drones, deadline);
+        if let Err(e) = self.packet_trace.lock().map(|mut trace| trace.flush()).unwrap_or(Ok(())) {
+            warn!("❌ Could not flush the packet trace: {}", e);
+        }
+        still_running.extend(drones_running);
+        still_running.sort_unstable();
+
+        ShutdownSummary {
+            run_time: self.started_at.elapsed(),
+            drones_stopped: drones_stopped.len(),
+            clients_stopped: hosts_stopped.iter().filter(|id| clients.contains(id)).count(),
+            servers_stopped: hosts_stopped.iter().filter(|id| servers.contains(id)).count(),
+            still_running,
+        }
+    }
+
+    // 📊📊📊 Traffic statistics 📊📊📊
+    pub fn node_stats(&self, id: NodeId) -> NodeStats {
+        self.stats.lock().map(|stats| stats.node(id)).unwrap_or_default()
+    }
+
+    pub fn link_stats(&self, a: NodeId, b: NodeId) -> LinkStats {
+        self.stats.lock().map(|stats| stats.link(a, b)).unwrap_or_default()
+    }
+
+    /// Every link that carried traffic, busiest first.
+    pub fn link_traffic(&self) -> Vec<((NodeId, NodeId), LinkStats)> {
+        self.stats.lock().map(|stats| stats.links()).unwrap_or_default()
+    }
+
+    pub fn total_shortcuts(&self) -> u64 {
+        self.stats.lock().map(|stats| stats.total_shortcuts()).unwrap_or_default()
+    }
+
+    /// Drop ratio observed on `drone` so far, and the PDR it is configured with.
+    pub fn drop_ratio(&self, drone: NodeId) -> (Option<f32>, Option<f32>) {
+        let configured = self.network_config.lock().unwrap().drone.iter().find(|d| d.id == drone).map(|d| d.pdr);
+        (self.node_stats(drone).observed_drop_ratio(), configured)
+    }
+
     //✅✅✅controls before applying the DroneCommand✅✅✅
     fn is_crash_allowed(&self, test_graph: &HashMap<NodeId, HashSet<NodeId>>, crashing_node: NodeId) -> bool {
         for server_id in self.get_all_server_ids() {
@@ -1056,3 +1186,69 @@ impl SimulationController {
     }
 
 }
+
+/// Joins the threads that finish before `deadline`; gives the ids of the stopped ones and of the others.
+fn join_until(mut threads: Vec<(NodeId, JoinHandle<()>)>, deadline: Instant) -> (Vec<NodeId>, Vec<NodeId>) {
+    let mut stopped = Vec::new();
+    loop {
+        let (finished, running): (Vec<_>, Vec<_>) = threads.into_iter().partition(|(_, handle)| handle.is_finished());
+        for (id, handle) in finished {
+            if handle.join().is_err() {
+                warn!("⚠ Thread of node {} panicked", id);
+            }
+            stopped.push(id);
+        }
+        threads = running;
+        if threads.is_empty() || Instant::now() >= deadline {
+            return (stopped, threads.into_iter().map(|(id, _)| id).collect());
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::initializer::MyDrone;
+    use crate::network::TOML_parser;
+    use crate::simulation_controller::gui_input_queue::new_gui_input_queue;
+
+    /// Controller over the default topology, with no node started yet; the receiver keeps the events channel open.
+    fn setup_controller() -> (SimulationController, Receiver<DroneEvent>) {
+        let path = "topologies/default.toml";
+        let config = Arc::new(Mutex::new(TOML_parser::parse_config(path).unwrap()));
+        let shared_senders: Arc<Mutex<HashMap<(NodeId, NodeId), Sender<Packet>>>> = Default::default();
+        let initializer = Arc::new(Mutex::new(NetworkInitializer::new(path, vec![], new_sim_log(), shared_senders.clone()).unwrap()));
+        let (event_tx, event_rx) = unbounded();
+        let (command_tx, _) = unbounded();
+        let drone_factory = Arc::new(|id: NodeId, event_tx: Sender<DroneEvent>, command_rx: Receiver<DroneCommand>, packet_rx: Receiver<Packet>, packet_tx: HashMap<NodeId, Sender<Packet>>, pdr: f32| {
+            Box::new(MyDrone::new(id, event_tx, command_rx, packet_rx, packet_tx, pdr)) as Box<dyn Drone>
+        });
+        let mut controller = SimulationController::new(config, event_tx, command_tx, drone_factory, new_gui_input_queue(), initializer, Default::default(), Default::default(), Default::default(), HashMap::new(), shared_senders, Default::default());
+        let factory: GroupImplFactory = Box::new(|id, event_tx, command_rx, packet_rx, packet_tx, pdr| {
+            Box::new(<MyDrone as Drone>::new(id, event_tx, command_rx, packet_rx, packet_tx, pdr)) as Box<dyn DroneImplementation>
+        });
+        controller.group_implementations.insert("group_1".to_string(), factory);
+        (controller, event_rx)
+    }
+
+    #[test]
+    fn test_shutdown_stops_the_drones_spawned_at_runtime() {
+        let (mut controller, _event_rx) = setup_controller();
+        controller.spawn_drone(50, 0.0, vec![1, 2]).unwrap();
+
+        let summary = controller.shutdown(SHUTDOWN_GRACE);
+        assert_eq!(summary.drones_stopped, 1, "{}", summary);
+        assert!(summary.still_running.is_empty(), "{}", summary);
+    }
+
+    #[test]
+    fn test_shutdown_summary_lists_the_threads_left_running() {
+        let mut summary = ShutdownSummary { run_time: Duration::from_secs(3), drones_stopped: 4, clients_stopped: 2, servers_stopped: 1, still_running: vec![9] };
+        let printed = summary.to_string();
+        assert!(printed.starts_with("📊 Simulation summary after 3.0s"), "{}", printed);
+        assert!(printed.contains("drones stopped:  4") && printed.contains("still running:   [9]"), "{}", printed);
+        summary.still_running.clear();
+        assert!(!summary.to_string().contains("still running"), "only shown when some thread did not stop");
+    }
+}
@@ -1,20 +1,26 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use eframe::{egui, CreationContext};
 use eframe::egui::{Stroke, StrokeKind};
 use egui::{Color32, RichText, Vec2, Rect, Sense, Shape, Pos2};
-use crate::network::initializer::{NetworkInitializer, ParsedConfig};
+use crate::network::initializer::ParsedConfig;
 use crate::simulation_controller::network_designer::NetworkRenderer;
 use std::thread;
-use crossbeam_channel::{ Receiver, Sender};
+use std::time::{Duration, Instant};
+use crossbeam_channel::Sender;
 use petgraph::visit::Walker;
 use crate::simulation_controller::SC_backend::SimulationController;
-use wg_2024::controller::{DroneCommand, DroneEvent};
+use wg_2024::controller::DroneEvent;
 use wg_2024::drone::Drone;
 use wg_2024::network::NodeId;
 use wg_2024::packet::Packet;
 use crate::simulation_controller::chatUI::{ ChatUIState, ClientStatus};
 use crate::simulation_controller::gui_input_queue::{ SharedGuiInput};
+use crate::simulation_controller::gui_event_queue::SharedGuiEvents;
+use crate::simulation_controller::replay::SharedRecorder;
+use crate::simulation_controller::packet_animation::PACKET_COLORS;
+use crate::simulation_controller::sim_log::{export_jsonl, new_sim_log, now_millis, push_sim_event, SharedSimLog, SimEvent, SimEventKind, SimLogFilter};
 
 enum AppState {
     Welcome,
@@ -32,7 +38,9 @@ pub struct NetworkApp {
     network_renderer: Option<NetworkRenderer>,
     topology_selected: bool,
     selected_topology: Option<String>,
-    simulation_log: Arc<Mutex<Vec<String>>>,
+    simulation_log: SharedSimLog,
+    log_filter: SimLogFilter,
+    log_export_status: Option<String>,
     chat_messages: Vec<String>,
     chat_input: String,
     is_simulation_running: bool,
@@ -58,6 +66,7 @@ pub struct NetworkApp {
     show_shared_senders_popup:bool,
     shared_senders: Arc<Mutex<HashMap<(NodeId, NodeId), Sender<Packet>>>>,
     host_senders: HashMap<NodeId, Sender<Packet>>, // ✅ the sc-hosts hashmap
+    close_at: Option<Instant>, // end of the simulation duration given on the command line
 
 
 }
@@ -85,10 +94,48 @@ impl NetworkApp {
     }
 
     fn log(&self, message: impl ToString) {
+        push_sim_event(&self.simulation_log, SimEvent::new(None, SimEventKind::Control, message));
+    }
 
-        if let Ok(mut log) = self.simulation_log.lock() {
-            log.push(message.to_string());
+    /// Node and kind filters of the Simulation Log panel, plus the JSON Lines export.
+    fn render_log_filters(&mut self, ui: &mut egui::Ui) {
+        let mut nodes: Vec<NodeId> = self.simulation_log.lock().map(|log| log.iter().filter_map(|event| event.node).collect::<BTreeSet<_>>().into_iter().collect()).unwrap_or_default();
+        if let Some(node) = self.log_filter.node.filter(|node| !nodes.contains(node)) {
+            nodes.push(node);
         }
+        ui.horizontal_wrapped(|ui| {
+            egui::ComboBox::from_id_salt("log_node_filter")
+                .selected_text(self.log_filter.node.map(|node| format!("Node {}", node)).unwrap_or_else(|| "All nodes".to_string()))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.log_filter.node, None, "All nodes");
+                    for node in nodes {
+                        ui.selectable_value(&mut self.log_filter.node, Some(node), format!("Node {}", node));
+                    }
+                });
+            for kind in SimEventKind::ALL {
+                let mut shown = self.log_filter.kinds.contains(&kind);
+                if ui.toggle_value(&mut shown, kind.name()).changed() {
+                    if shown {
+                        self.log_filter.kinds.insert(kind);
+                    } else {
+                        self.log_filter.kinds.remove(&kind);
+                    }
+                }
+            }
+            if ui.button("Export JSONL").clicked() {
+                let path = format!("simulation_log_{}.jsonl", now_millis());
+                let result = self.simulation_log.lock().map_err(|_| "log lock poisoned".to_string()).and_then(|log| {
+                    export_jsonl(log.iter().filter(|event| self.log_filter.matches(event)), Path::new(&path)).map_err(|e| e.to_string())
+                });
+                self.log_export_status = Some(match result {
+                    Ok(count) => format!("Exported {} entries to {}", count, path),
+                    Err(e) => format!("Export failed: {}", e),
+                });
+            }
+            if let Some(status) = &self.log_export_status {
+                ui.label(status);
+            }
+        });
     }
 
 
@@ -262,11 +309,18 @@ impl NetworkApp {
                     .show(ui, |ui| {
                         ui.set_min_width(ui.available_width());
                         ui.heading("Simulation Log");
-                        egui::ScrollArea::vertical().show(ui, |ui| {
-                            let logs = self.simulation_log.lock().unwrap();
-                            for (idx, log) in logs.iter().enumerate() {
-                                let color = if idx % 2 == 0 { Color32::DARK_GRAY } else { Color32::DARK_BLUE };
-                                ui.colored_label(color, log);
+                        self.render_log_filters(ui);
+                        let logs = self.simulation_log.lock().unwrap();
+                        let shown: Vec<&SimEvent> = logs.iter().filter(|event| self.log_filter.matches(event)).collect();
+                        let row_height = ui.text_style_height(&egui::TextStyle::Body);
+                        egui::ScrollArea::vertical().stick_to_bottom(true).show_rows(ui, row_height, shown.len(), |ui, rows| {
+                            for idx in rows {
+                                let color = match shown[idx].kind {
+                                    SimEventKind::Error | SimEventKind::Delivery | SimEventKind::PacketDropped => Color32::DARK_RED,
+                                    _ if idx % 2 == 0 => Color32::DARK_GRAY,
+                                    _ => Color32::DARK_BLUE,
+                                };
+                                ui.colored_label(color, shown[idx].to_string());
                             }
                         });
                     });
@@ -320,6 +374,16 @@ impl NetworkApp {
                             self.show_shared_senders_popup = true;
                         }
                     });
+                    if let Some(renderer) = &mut self.network_renderer {
+                        ui.horizontal(|ui| {
+                            ui.add_space(10.0);
+                            ui.add(egui::Slider::new(&mut renderer.packet_animator.speed, 0.0..=4.0).text("Packet speed"));
+                            for (name, color) in PACKET_COLORS {
+                                ui.colored_label(color, format!("● {}", name));
+                            }
+                            ui.colored_label(Color32::RED, "✖ dropped");
+                        });
+                    }
 
                     let (response, painter) = ui.allocate_painter(ui.available_size(), Sense::drag());
 
@@ -545,21 +609,16 @@ impl NetworkApp {
 
     pub fn new_with_network(
         cc: &eframe::CreationContext<'_>,
+        controller: Arc<Mutex<SimulationController>>,
         event_sender: Sender<DroneEvent>,
-        command_sender: Sender<DroneCommand>,
-        command_receiver: Receiver<DroneCommand>,
         config: Arc<Mutex<ParsedConfig>>,
-        drone_factory: Arc<dyn Fn(NodeId, Sender<DroneEvent>, Receiver<DroneCommand>, Receiver<Packet>, HashMap<NodeId, Sender<Packet>>, f32) -> Box<dyn wg_2024::drone::Drone> + Send + Sync>,
         config_path: &str,
         gui_input: SharedGuiInput,
-        simulation_log: Arc<Mutex<Vec<String>>>,
-        packet_senders: Arc<Mutex<HashMap<NodeId, HashMap<NodeId, Sender<Packet>>>>>,
-        packet_receivers: Arc<Mutex<HashMap<NodeId, Receiver<Packet>>>>,
-        command_senders: Arc<Mutex<HashMap<NodeId, Sender<DroneCommand>>>>,
+        gui_events: SharedGuiEvents,
+        simulation_log: SharedSimLog,
         shared_senders: Arc<Mutex<HashMap<(NodeId, NodeId), Sender<Packet>>>>,
-        host_senders: HashMap<NodeId, Sender<Packet>>,
-        inbox_senders: Arc<Mutex<HashMap<NodeId, Sender<Packet>>>>,
-
+        recorder: SharedRecorder,
+        run_for: Option<Duration>,
 
     ) -> Self {
         cc.egui_ctx.set_visuals(egui::Visuals::light());
@@ -568,19 +627,7 @@ impl NetworkApp {
 
         app.controller_send = Some(event_sender.clone());
 
-        let initializer = Arc::new(Mutex::new(
-            NetworkInitializer::new(
-                config_path,
-                vec![],
-                simulation_log.clone(),
-                shared_senders.clone(),
-            )
-                .expect("Failed to create initializer"),
-        ));
-
-        let controller = SimulationController::new(config.clone(), event_sender.clone(), command_sender.clone(), drone_factory.clone(), gui_input.clone(), initializer.clone(), packet_senders.clone(), packet_receivers.clone(), command_senders.clone(), host_senders.clone(), shared_senders.clone(),inbox_senders.clone() );
-
-        let controller = Arc::new(Mutex::new(controller));
+        // the controller main built: drones spawned here are stopped by its shutdown after the window closes
         app.simulation_controller = Some(controller.clone());
 
         app.simulation_log = simulation_log.clone();
@@ -600,12 +647,14 @@ impl NetworkApp {
             app.log("Controller connected to network renderer".to_string());
         }
 
-        app.chat_ui = ChatUIState::new(gui_input.clone());
+        app.chat_ui = ChatUIState::new(gui_input.clone(), gui_events.clone());
+        app.chat_ui.attach_recorder(recorder);
         app.network_config = Some(config.clone());
         app.detect_and_log_topology(config_path, config.clone());
         app.topology_selected = true;
         app.state = AppState::Welcome;
         app.shared_senders=shared_senders.clone();
+        app.close_at = run_for.map(|duration| Instant::now() + duration);
         app
     }
 
@@ -635,7 +684,9 @@ impl Default for NetworkApp {
             network_renderer: None,
             topology_selected: false,
             selected_topology: None,
-            simulation_log: Arc::new(Mutex::new(Vec::new())),
+            simulation_log: new_sim_log(),
+            log_filter: SimLogFilter::default(),
+            log_export_status: None,
             chat_messages: Vec::new(),
             chat_input: String::new(),
             is_simulation_running: false,
@@ -653,18 +704,28 @@ impl Default for NetworkApp {
             new_drone_id: 0,
             new_drone_pdr: 0.0,
             new_drone_connections_str: String::new(),
-            chat_ui: ChatUIState::new(Arc::new(Default::default())),
+            chat_ui: ChatUIState::new(Arc::new(Default::default()), Arc::new(Default::default())),
             packet_senders: HashMap::new(),
             show_shared_senders_popup:false,
             shared_senders: Arc::new(Mutex::new(HashMap::new())),
             host_senders: HashMap::new(),
-
+            close_at: None,
         }
     }
 }
 
 impl eframe::App for NetworkApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        if let Some(close_at) = self.close_at {
+            let now = Instant::now();
+            if now >= close_at {
+                self.log("⏱ Simulation duration reached, closing");
+                self.close_at = None;
+                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+            } else {
+                ctx.request_repaint_after(close_at - now);
+            }
+        }
         match self.state {
             AppState::Welcome => self.render_welcome_screen(ctx),
             AppState::Simulation => self.render_simulation_tabs(ctx),
@@ -1,7 +1,12 @@
 use egui::{Color32, RichText, ScrollArea, TextEdit};
 use wg_2024::network::NodeId;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use crate::simulation_controller::gui_input_queue::{push_gui_message, new_gui_input_queue, SharedGuiInput};
+use crate::simulation_controller::gui_event_queue::{drain_gui_events, GuiEvent, SharedGuiEvents};
+use crate::simulation_controller::headless::ScriptCommand;
+use crate::simulation_controller::replay::{new_recorder, record_action, SharedRecorder};
+use crate::nodes::media;
+use crate::nodes::protocol::{self, ChatProtocol, DeliveryStatus, MediaInfo, MediaVisibility, ReceiptKind, ServerService};
 use std::fs;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -11,7 +16,9 @@ use log::info;
 #[derive(Clone)]
 pub struct ChatMessage {
     pub from: NodeId,
+    pub id: u64, // chosen by the GUI when sending, unique per sender
     pub content: String,
+    pub receipt: Option<ReceiptKind>, // best receipt the sender got back so far
 }
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -40,6 +47,8 @@ pub struct ChatUIState {
     pub pending_chat_termination: Option<(NodeId, NodeId)>,
     pub server_client_map: HashMap<NodeId, Vec<NodeId>>,
     pub gui_input: SharedGuiInput,
+    recorder: SharedRecorder, // every command sent, for `--record`
+    pub gui_events: SharedGuiEvents,
     pub chat_history: HashMap<(NodeId, NodeId), Vec<ChatMessage>>,
     pub chat_type_map: HashMap<(NodeId, NodeId), ChatType>,
     pub pending_chat_type: Option<ChatType>,
@@ -55,17 +64,42 @@ pub struct ChatUIState {
     pub show_upload_media_list: bool,
     pub download_media_name_input: String,
     pub download_result_message: Option<String>,
+    pub media_share_input: String, // client ids an owner shares a media with
 
     pub show_broadcast_list: bool,
 
     broadcast_result_message: Option <String>,
     pub broadcast_result_time: Option<Instant>,
     pub show_broadcast_media_list: bool,
+
+    // filled from what the clients report back, not from what the GUI sent
+    pub pending_logins: HashMap<NodeId, NodeId>, // client -> server it is logging into
+    pub client_lists: HashMap<NodeId, Vec<NodeId>>,
+    pub media_lists: HashMap<NodeId, Vec<MediaInfo>>,
+    pub history_results: HashMap<NodeId, String>,
+    pub client_notices: HashMap<NodeId, (String, Color32)>,
+    pub server_services: HashMap<NodeId, BTreeMap<NodeId, Vec<ServerService>>>, // client -> server -> what it answered
+    pub next_message_id: u64,
+    pub pending_reads: Vec<(NodeId, NodeId, u64)>, // (reader, sender, message id) received but not displayed yet
+
+    // rooms are seen through each client, since two servers can host rooms with the same name
+    pub room_lists: HashMap<NodeId, Vec<String>>,
+    pub joined_rooms: HashMap<NodeId, HashMap<String, Vec<NodeId>>>, // client -> room -> members
+    pub room_messages: HashMap<(NodeId, String), Vec<ChatMessage>>,
+    pub active_room: Option<(NodeId, String)>,
+    pub room_name_input: String,
+    pub room_input: String,
+
+    // documents from the text servers, fetched like pages in a browser
+    pub text_lists: HashMap<NodeId, BTreeMap<NodeId, Vec<String>>>, // client -> text server -> files
+    pub open_documents: HashMap<NodeId, (NodeId, String, String)>, // client -> (server, name, content)
+    pub document_name_input: String,
+    pub document_input: String,
 }
 
 impl ChatUIState {
 
-    pub fn new(gui_input: SharedGuiInput) -> Self {
+    pub fn new(gui_input: SharedGuiInput, gui_events: SharedGuiEvents) -> Self {
         ChatUIState {
             client_status: HashMap::new(),
             servers: vec![],
@@ -79,6 +113,8 @@ impl ChatUIState {
             pending_chat_termination: None,
             server_client_map: HashMap::new(),
             gui_input,
+            recorder: new_recorder(0, ""),
+            gui_events,
             chat_history: HashMap::new(),
             chat_type_map: HashMap::new(),
             pending_chat_type: None,
@@ -93,15 +129,326 @@ impl ChatUIState {
             show_upload_media_list: false,
             download_media_name_input: String::new(),
             download_result_message: None,
+            media_share_input: String::new(),
 
             show_broadcast_list: false,
 
             broadcast_result_message: None,
             broadcast_result_time: None,
             show_broadcast_media_list: false,
+
+            pending_logins: HashMap::new(),
+            client_lists: HashMap::new(),
+            media_lists: HashMap::new(),
+            history_results: HashMap::new(),
+            client_notices: HashMap::new(),
+            server_services: HashMap::new(),
+            next_message_id: 1,
+            pending_reads: Vec::new(),
+
+            room_lists: HashMap::new(),
+            joined_rooms: HashMap::new(),
+            room_messages: HashMap::new(),
+            active_room: None,
+            room_name_input: String::new(),
+            room_input: String::new(),
+
+            text_lists: HashMap::new(),
+            open_documents: HashMap::new(),
+            document_name_input: String::new(),
+            document_input: String::new(),
+        }
+    }
+
+    pub fn attach_recorder(&mut self, recorder: SharedRecorder) {
+        self.recorder = recorder;
+    }
+
+    fn apply_gui_events(&mut self) {
+        for (client_id, event) in drain_gui_events(&self.gui_events) {
+            self.apply_gui_event(client_id, event);
         }
     }
 
+    fn apply_gui_event(&mut self, client_id: NodeId, event: GuiEvent) {
+        match event {
+            GuiEvent::LoggedIn { server_id, .. } => {
+                let server_id = self.pending_logins.remove(&client_id).unwrap_or(server_id);
+                self.client_status.insert(client_id, ClientStatus::Connected);
+                let clients = self.server_client_map.entry(server_id).or_default();
+                if !clients.contains(&client_id) {
+                    clients.push(client_id);
+                }
+                let code = format!("{:06}", rand::random::<u32>() % 1_000_000);
+                self.client_server_codes.insert((client_id, server_id), code);
+            }
+            GuiEvent::ServerType { server_id, services } => {
+                self.server_services.entry(client_id).or_default().insert(server_id, services);
+            }
+            GuiEvent::ChatMessage { from, message_id, message } => {
+                // the sender's copy is already shown when it was sent from this window
+                let msg = ChatMessage { from, id: message_id, content: message, receipt: Some(ReceiptKind::Delivered) };
+                let key = (from.min(client_id), from.max(client_id));
+                let is_same = |m: &ChatMessage| m.from == from && m.id == message_id;
+                if self.chat_type_map.get(&key) == Some(&ChatType::Normal) {
+                    let history = self.chat_history.entry(key).or_default();
+                    if !history.iter().any(is_same) {
+                        history.push(msg.clone());
+                    }
+                }
+                if self.active_chat_pair.map(|(a, b)| (a.min(b), a.max(b))) == Some(key) && !self.chat_messages.iter().any(is_same) {
+                    self.chat_messages.push(msg);
+                }
+                self.pending_reads.push((client_id, from, message_id));
+            }
+            GuiEvent::Receipt { message_id, kind, .. } => {
+                for msg in self.chat_messages.iter_mut().chain(self.chat_history.values_mut().flatten()) {
+                    if msg.from == client_id && msg.id == message_id && msg.receipt < Some(kind) {
+                        msg.receipt = Some(kind);
+                    }
+                }
+            }
+            GuiEvent::Delivery { target_id, status } => {
+                let notice = match status {
+                    DeliveryStatus::Delivered => (format!("✔ Delivered to Client #{}", target_id), Color32::LIGHT_GREEN),
+                    DeliveryStatus::Queued => (format!("📥 Client #{} is offline, message queued", target_id), Color32::YELLOW),
+                    DeliveryStatus::Rejected => (format!("❌ Message to Client #{} rejected", target_id), Color32::RED),
+                };
+                self.client_notices.insert(client_id, notice);
+            }
+            GuiEvent::ChatRequested { from } => {
+                info!("Client {} was asked to chat by client {}", client_id, from);
+            }
+            GuiEvent::ChatStarted { success } => {
+                if !success {
+                    self.end_chat_of(client_id);
+                    self.client_notices.insert(client_id, ("❌ Chat request refused by the server".to_string(), Color32::RED));
+                }
+            }
+            GuiEvent::ChatFinished => self.end_chat_of(client_id),
+            GuiEvent::ClientList { clients } => {
+                self.client_lists.insert(client_id, clients);
+            }
+            GuiEvent::RoomList { rooms } => {
+                self.room_lists.insert(client_id, rooms);
+            }
+            GuiEvent::RoomUpdate { room, members } => {
+                let rooms = self.joined_rooms.entry(client_id).or_default();
+                if members.contains(&client_id) {
+                    rooms.insert(room, members);
+                } else {
+                    rooms.remove(&room);
+                    if self.active_room.as_ref() == Some(&(client_id, room.clone())) {
+                        self.active_room = None;
+                    }
+                    self.client_notices.insert(client_id, (format!("🚪 Left room \"{}\"", room), Color32::LIGHT_GREEN));
+                }
+            }
+            GuiEvent::RoomMessage { room, from, message_id, message } => {
+                let msg = ChatMessage { from, id: message_id, content: message, receipt: None };
+                self.room_messages.entry((client_id, room)).or_default().push(msg);
+            }
+            GuiEvent::History { history } => {
+                self.history_results.insert(client_id, history);
+            }
+            GuiEvent::MediaList { media } => {
+                self.media_lists.insert(client_id, media);
+            }
+            GuiEvent::MediaUploaded { name } => {
+                self.client_notices.insert(client_id, (format!("📤 Uploaded \"{}\"", name), Color32::LIGHT_GREEN));
+            }
+            GuiEvent::MediaDownloaded { name, file_name, content_type, size, path } => {
+                self.download_result_message = Some(format!("{} Received \"{}\" as {} ({}, {} bytes), saved to {}", media_icon(&content_type), name, file_name, content_type, size, path));
+            }
+            GuiEvent::MediaNotFound => {
+                self.download_result_message = Some("Media not found on the server".to_string());
+            }
+            GuiEvent::MediaBroadcasted { name } => {
+                self.client_notices.insert(client_id, (format!("📡 Broadcasted \"{}\"", name), Color32::LIGHT_GREEN));
+            }
+            GuiEvent::MediaVisibility { name, visibility } => {
+                self.client_notices.insert(client_id, (format!("🔐 \"{}\" is visible to {}", name, visibility), Color32::LIGHT_GREEN));
+                if let Some(info) = self.media_lists.get_mut(&client_id).and_then(|media| media.iter_mut().find(|info| info.name == name)) {
+                    info.visibility = visibility;
+                }
+            }
+            GuiEvent::MediaDeleted { name } => {
+                self.client_notices.insert(client_id, (format!("🗑 Deleted \"{}\"", name), Color32::LIGHT_GREEN));
+                if let Some(media) = self.media_lists.get_mut(&client_id) {
+                    media.retain(|info| info.name != name);
+                }
+            }
+            GuiEvent::MediaProgress { name, done, total, upload } => {
+                let arrow = if upload { "📤" } else { "📥" };
+                let percent = if total == 0 { 100 } else { done * 100 / total };
+                self.client_notices.insert(client_id, (format!("{} \"{}\" {}% ({}/{} bytes)", arrow, name, percent, done, total), Color32::LIGHT_BLUE));
+            }
+            GuiEvent::TextList { server_id, files } => {
+                self.text_lists.entry(client_id).or_default().insert(server_id, files);
+            }
+            GuiEvent::TextFile { server_id, name, content } => {
+                self.open_documents.insert(client_id, (server_id, name, content));
+            }
+            GuiEvent::TextUploaded { server_id, name } => {
+                self.client_notices.insert(client_id, (format!("📄 Published \"{}\" on Server #{}", name, server_id), Color32::LIGHT_GREEN));
+                let files = self.text_lists.entry(client_id).or_default().entry(server_id).or_default();
+                if !files.contains(&name) {
+                    files.push(name);
+                    files.sort();
+                }
+            }
+            GuiEvent::Error { reason } => {
+                if let Some(server_id) = self.pending_logins.remove(&client_id) {
+                    self.client_notices.insert(client_id, (format!("❌ Login to Server #{} failed: {}", server_id, reason), Color32::RED));
+                } else {
+                    self.client_notices.insert(client_id, (format!("❌ {}", reason), Color32::RED));
+                }
+            }
+        }
+    }
+
+    fn end_chat_of(&mut self, client_id: NodeId) {
+        if let Some((a, b)) = self.active_chat_pair {
+            if a == client_id || b == client_id {
+                let key = (a.min(b), a.max(b));
+                if self.chat_type_map.get(&key) == Some(&ChatType::Temporary) {
+                    self.chat_messages.clear();
+                }
+                self.chat_type_map.remove(&key);
+                self.client_status.insert(a, ClientStatus::Connected);
+                self.client_status.insert(b, ClientStatus::Connected);
+                self.active_chat_pair = None;
+            }
+        }
+    }
+
+    /// Text servers need no login: list their files, open one (its linked media is downloaded
+    /// from a media server by the client) or publish a new one.
+    fn render_documents(&mut self, ui: &mut egui::Ui, client_id: NodeId) {
+        ui.separator();
+        ui.label(RichText::new("Documents").strong());
+        ui.horizontal(|ui| {
+            if ui.button("List Documents").clicked() {
+                send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::TextListRequest.to_string());
+            }
+            ui.add(TextEdit::singleline(&mut self.document_name_input).hint_text("File name..."));
+            let name = self.document_name_input.trim().to_string();
+            if ui.button("Publish").clicked() && protocol::is_valid_file_name(&name) && !self.document_input.trim().is_empty() {
+                let content = std::mem::take(&mut self.document_input);
+                send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::TextUpload { name, content }.to_string());
+            }
+        });
+        ui.add(TextEdit::multiline(&mut self.document_input).hint_text("Text to publish, link media with ![alt](name.png)").desired_rows(2));
+
+        let mut requested: Option<String> = None;
+        if let Some(servers) = self.text_lists.get(&client_id) {
+            for (server_id, files) in servers {
+                ui.horizontal_wrapped(|ui| {
+                    ui.label(format!("Server #{}:", server_id));
+                    for name in files {
+                        if ui.link(name).clicked() {
+                            requested = Some(name.clone());
+                        }
+                    }
+                });
+            }
+        }
+        if let Some(name) = requested {
+            send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::TextRequest { name }.to_string());
+        }
+
+        let mut close = false;
+        if let Some((server_id, name, content)) = self.open_documents.get(&client_id) {
+            ui.horizontal(|ui| {
+                ui.label(RichText::new(format!("📄 {} (Server #{})", name, server_id)).color(Color32::LIGHT_BLUE));
+                close = ui.button("Close").clicked();
+            });
+            ScrollArea::vertical().id_salt(("document", client_id)).max_height(150.0).show(ui, |ui| {
+                ui.label(content.as_str());
+            });
+            let media = protocol::referenced_media(content);
+            if !media.is_empty() {
+                ui.label(RichText::new(format!("Linked media: {}", media.join(", "))).color(Color32::GRAY));
+            }
+        }
+        if close {
+            self.open_documents.remove(&client_id);
+        }
+    }
+
+    fn render_rooms(&mut self, ui: &mut egui::Ui, client_id: NodeId) {
+        ui.separator();
+        ui.label(RichText::new("Rooms").strong());
+        ui.horizontal(|ui| {
+            ui.add(TextEdit::singleline(&mut self.room_name_input).hint_text("Room name..."));
+            let room = self.room_name_input.trim().to_string();
+            if ui.button("Create").clicked() && protocol::is_valid_room_name(&room) {
+                send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::RoomCreate { room: room.clone() }.to_string());
+            }
+            if ui.button("Join").clicked() && protocol::is_valid_room_name(&room) {
+                send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::RoomJoin { room }.to_string());
+            }
+            if ui.button("List Rooms").clicked() {
+                send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::RoomListRequest.to_string());
+            }
+        });
+        if let Some(rooms) = self.room_lists.get(&client_id) {
+            ui.label(format!("Rooms on the server: {}", if rooms.is_empty() { "none".to_string() } else { rooms.join(", ") }));
+        }
+
+        let mut joined: Vec<(String, Vec<NodeId>)> = self
+            .joined_rooms
+            .get(&client_id)
+            .map(|rooms| rooms.iter().map(|(room, members)| (room.clone(), members.clone())).collect())
+            .unwrap_or_default();
+        joined.sort();
+        for (room, members) in &joined {
+            ui.horizontal(|ui| {
+                ui.label(format!("🏠 {} ({} members)", room, members.len()));
+                if ui.button("Open").clicked() {
+                    self.active_room = Some((client_id, room.clone()));
+                }
+                if ui.button("Leave").clicked() {
+                    send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::RoomLeave { room: room.clone() }.to_string());
+                }
+            });
+        }
+
+        let room = match &self.active_room {
+            Some((viewer, room)) if *viewer == client_id => room.clone(),
+            _ => return,
+        };
+        ui.group(|ui| {
+            let members = joined.iter().find(|(r, _)| *r == room).map(|(_, m)| m.clone()).unwrap_or_default();
+            ui.horizontal(|ui| {
+                ui.label(RichText::new(format!("Room \"{}\" - members: {:?}", room, members)).strong());
+                if ui.button("Room History").clicked() {
+                    send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::RoomHistoryRequest { room: room.clone() }.to_string());
+                }
+                if ui.button("Close").clicked() {
+                    self.active_room = None;
+                }
+            });
+            ScrollArea::vertical().id_salt("room_messages").max_height(150.0).show(ui, |ui| {
+                for msg in self.room_messages.get(&(client_id, room.clone())).into_iter().flatten() {
+                    ui.label(format!("From Client #{}: {}", msg.from, msg.content));
+                }
+            });
+            ui.horizontal(|ui| {
+                let lost_focus = ui.add(TextEdit::singleline(&mut self.room_input).hint_text("Message the room...")).lost_focus();
+                if (ui.button("Send").clicked() || (lost_focus && ui.input(|i| i.key_pressed(egui::Key::Enter)))) && !self.room_input.trim().is_empty() {
+                    // the server fans it out to the other members, so the sender's copy is added here
+                    let message_id = self.next_message_id;
+                    self.next_message_id += 1;
+                    let msg = ChatMessage { from: client_id, id: message_id, content: self.room_input.clone(), receipt: None };
+                    self.room_messages.entry((client_id, room.clone())).or_default().push(msg);
+                    send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::RoomMessageTo { room: room.clone(), message_id, message: self.room_input.clone() }.to_string());
+                    self.room_input.clear();
+                }
+            });
+        });
+    }
+
     fn render_server_info(&mut self, ui: &mut egui::Ui) {
         ui.group(|ui| {
             ui.label(RichText::new("Server Overview").strong());
@@ -198,6 +545,8 @@ impl ChatUIState {
     }
 
     pub fn render(&mut self, ui: &mut egui::Ui, on_send: &mut impl FnMut(NodeId, NodeId, String)) {
+        self.apply_gui_events();
+
         egui::SidePanel::right("server_status_panel").show_inside(ui, |ui| {
             self.render_server_info(ui);
         });
@@ -247,17 +596,34 @@ impl ChatUIState {
                                 }
                             });
 
-                        if ui.button("Login").clicked() {
+                        if let Some(server_id) = self.pending_logins.get(&client_id) {
+                            ui.label(format!("⏳ Waiting for Server #{}...", server_id));
+                        } else if ui.button("Login").clicked() {
                             if let Some(server_id) = self.selected_server {
-                                self.client_status.insert(client_id, ClientStatus::Connected);
-                                self.server_client_map.entry(server_id).or_default().push(client_id);
-                                push_gui_message(&self.gui_input, client_id, format!("[Login]::{}", server_id));
-
-                                let code = format!("{:06}", rand::random::<u32>() % 1_000_000);
-                                self.client_server_codes.insert((client_id, server_id), code);
+                                // the client becomes Connected once its LoginAck comes back
+                                self.pending_logins.insert(client_id, server_id);
+                                send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::login(server_id).to_string());
                             }
                         }
                     });
+
+                    ui.horizontal(|ui| {
+                        if ui.button("🔍 Discover Servers").clicked() {
+                            send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::ServerTypeRequest.to_string());
+                        }
+                        if !self.server_services.contains_key(&client_id) {
+                            ui.label(RichText::new("Ask the servers what they offer before logging in").color(Color32::GRAY));
+                        }
+                    });
+                    if let Some(known) = self.server_services.get(&client_id) {
+                        for (&server_id, services) in known {
+                            let names: Vec<&str> = services.iter().map(|service| service.name()).collect();
+                            let label = format!("Server #{}: {}", server_id, if names.is_empty() { "nothing".to_string() } else { names.join(", ") });
+                            if ui.selectable_label(self.selected_server == Some(server_id), label).clicked() {
+                                self.selected_server = Some(server_id);
+                            }
+                        }
+                    }
                 }
                 ClientStatus::Connected => {
                     let mut requested_chat_with: Option<NodeId> = None;
@@ -265,24 +631,18 @@ impl ChatUIState {
                     ui.horizontal(|ui| {
                         ui.label("Start Chat With:");
                         for (&other_id, &other_status) in self.client_status.iter() {
-                            if other_id != client_id && other_status == ClientStatus::Connected {
-                                let same_server = self.server_client_map.iter().any(|(_, list)| {
-                                    list.contains(&client_id) && list.contains(&other_id)
-                                });
-                                if same_server && ui.button(format!("Client #{other_id}")).clicked() {
-                                    requested_chat_with = Some(other_id);
-                                }
+                            // servers relay to clients logged in elsewhere, so any connected client will do
+                            if other_id != client_id && other_status == ClientStatus::Connected && ui.button(format!("Client #{other_id}")).clicked() {
+                                requested_chat_with = Some(other_id);
                             }
                         }
                     });
 
                     if let Some(peer_id) = requested_chat_with {
-                        // find the server they both share
+                        // the requester's own server carries the chat
                         if let Some((&server_id, _)) = self.server_client_map
                             .iter()
-                            .find(|(_sid, clients)| {
-                                clients.contains(&client_id) && clients.contains(&peer_id)
-                            })
+                            .find(|(_sid, clients)| clients.contains(&client_id))
                         {
                             self.pending_chat_request = Some((client_id, peer_id));
                             self.selected_client = Some(peer_id);
@@ -324,9 +684,12 @@ impl ChatUIState {
                                 .show(ui.ctx(), |ui| {
                                     if ui.button("Request Client List").clicked() {
                                         if let Some(client_id) = self.selected_client {
-                                            push_gui_message(&self.gui_input, client_id, "[ClientListRequest]".to_string());
+                                            send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::ClientListRequest.to_string());
                                         }
                                     }
+                                    if let Some(clients) = self.client_lists.get(&client_id) {
+                                        ui.label(format!("Clients: {:?}", clients));
+                                    }
 
                                     ui.separator();
                                     if ui.button("Upload Media").clicked() {
@@ -340,9 +703,8 @@ impl ChatUIState {
                                                 if let Some(client_id) = self.selected_client {
                                                     match std::fs::read(path) {
                                                         Ok(bytes) => {
-                                                            let base64_data = base64::encode(bytes);
-                                                            let msg = format!("[MediaUpload]::{}::{}", media_name, base64_data);
-                                                            push_gui_message(&self.gui_input, client_id, msg);
+                                                            let msg = ChatProtocol::MediaUpload { name: media_name.clone(), data: bytes }.to_string();
+                                                            send_gui_command(&self.gui_input, &self.recorder, client_id, msg);
                                                         }
                                                         Err(e) => {
                                                             eprintln!("Error reading image file '{}': {}", path, e);
@@ -365,15 +727,14 @@ impl ChatUIState {
                                                         Ok(bytes) => {
                                                             info!("🐛 GUI encoding started for: {}", media_name);
 
-                                                            let base64_data = base64::encode(bytes);
                                                             info!( "🐻🐻🐻🐻🐻🐻🐻
                                                                 → server: {} bytes, prefix = {:?}",
-                                                                base64_data.len(),
-                                                                &base64_data.as_bytes()[0..20]
+                                                                bytes.len(),
+                                                                &bytes[..bytes.len().min(20)]
                                                             );
 
-                                                            let msg = format!("[MediaBroadcast]::{}::{}", media_name, base64_data);
-                                                            push_gui_message(&self.gui_input, client_id, msg);
+                                                            let msg = ChatProtocol::MediaBroadcast { name: media_name.clone(), data: bytes }.to_string();
+                                                            send_gui_command(&self.gui_input, &self.recorder, client_id, msg);
                                                         }
                                                         Err(e) => {
                                                             eprintln!("Error reading image file '{}': {}", path, e);
@@ -387,7 +748,49 @@ impl ChatUIState {
                                     ui.separator();
                                     if ui.button("Request Media List").clicked() {
                                         if let Some(client_id) = self.selected_client {
-                                            push_gui_message(&self.gui_input, client_id, "[MediaListRequest]".to_string());
+                                            send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::MediaListRequest.to_string());
+                                        }
+                                    }
+                                    if let Some(media) = self.media_lists.get(&client_id) {
+                                        if media.is_empty() {
+                                            ui.label("No media on the server");
+                                        } else {
+                                            ui.label("Media (click to download):");
+                                            for info in media {
+                                                ui.horizontal(|ui| {
+                                                    let label = format!("{} {} — {}, {} bytes, owner #{}, {:016x}", media_icon(&info.content_type), info.name, info.content_type, info.size, info.owner, info.hash);
+                                                    if ui.selectable_label(self.download_media_name_input == info.name, label).clicked() {
+                                                        self.download_media_name_input = info.name.clone();
+                                                    }
+                                                    if info.owner != client_id {
+                                                        return;
+                                                    }
+                                                    // owner-only controls: who else sees the media, and its removal
+                                                    ui.label(format!("({})", info.visibility));
+                                                    let mut command = None;
+                                                    if ui.small_button("🔒").on_hover_text("Only me").clicked() {
+                                                        command = Some(ChatProtocol::MediaSetVisibility { name: info.name.clone(), visibility: MediaVisibility::Owner });
+                                                    }
+                                                    if ui.small_button("🌍").on_hover_text("Everyone").clicked() {
+                                                        command = Some(ChatProtocol::MediaSetVisibility { name: info.name.clone(), visibility: MediaVisibility::Everyone });
+                                                    }
+                                                    if ui.small_button("👥").on_hover_text("The client ids written below").clicked() {
+                                                        if let Ok(visibility) = self.media_share_input.parse::<MediaVisibility>() {
+                                                            command = Some(ChatProtocol::MediaSetVisibility { name: info.name.clone(), visibility });
+                                                        }
+                                                    }
+                                                    if ui.small_button("🗑").on_hover_text("Delete").clicked() {
+                                                        command = Some(ChatProtocol::MediaDelete { name: info.name.clone() });
+                                                    }
+                                                    if let Some(command) = command {
+                                                        send_gui_command(&self.gui_input, &self.recorder, client_id, command.to_string());
+                                                    }
+                                                });
+                                            }
+                                            ui.horizontal(|ui| {
+                                                ui.label("Share with client ids:");
+                                                ui.text_edit_singleline(&mut self.media_share_input);
+                                            });
                                         }
                                     }
                                     ui.separator();
@@ -400,8 +803,8 @@ impl ChatUIState {
                                             if let Some(client_id) = self.selected_client {
                                                 let trimmed = self.download_media_name_input.trim();
                                                 if !trimmed.is_empty() {
-                                                    let msg = format!("[MediaDownloadRequest]::{}", trimmed);
-                                                    push_gui_message(&self.gui_input, client_id, msg);
+                                                    let msg = ChatProtocol::MediaDownloadRequest { name: trimmed.to_string() }.to_string();
+                                                    send_gui_command(&self.gui_input, &self.recorder, client_id, msg);
                                                     self.download_result_message = Some(format!("Requested \"{}\"", trimmed));
                                                     self.download_media_name_input.clear();
                                                 }
@@ -450,7 +853,7 @@ impl ChatUIState {
                                             self.selected_server = None;
 
                                             // 6. Push logout message
-                                            push_gui_message(&self.gui_input, client_id, "[Logout]".to_string());
+                                            send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::Logout.to_string());
                                             self.show_server_popup = None;
                                             self.show_upload_media_list = false;
                                         }
@@ -476,8 +879,8 @@ impl ChatUIState {
                                 let initiator = a;
                                 let peer = b;
                                 if let Some(server_id) = self.selected_server {
-                                    push_gui_message(&self.gui_input, initiator, format!("[ChatFinish]::{peer}"));
-                                    push_gui_message(&self.gui_input, peer, format!("[ChatFinish]::{initiator}"));
+                                    send_gui_command(&self.gui_input, &self.recorder, initiator, ChatProtocol::ChatFinish { peer_id: peer }.to_string());
+                                    send_gui_command(&self.gui_input, &self.recorder, peer, ChatProtocol::ChatFinish { peer_id: initiator }.to_string());
                                 }
 
                                 let key = (initiator.min(peer), initiator.max(peer));
@@ -497,6 +900,11 @@ impl ChatUIState {
                 }
             }
 
+            if status != ClientStatus::Offline {
+                self.render_rooms(ui, client_id);
+            }
+            self.render_documents(ui, client_id);
+
             // Show Chat History button is now outside of any active chat condition
             ui.separator();
             ui.horizontal(|ui| {
@@ -507,6 +915,12 @@ impl ChatUIState {
 
                 }
             });
+            if let Some(history) = self.history_results.get(&client_id) {
+                ui.label(format!("📜 {}", history));
+            }
+            if let Some((notice, color)) = self.client_notices.get(&client_id) {
+                ui.label(RichText::new(notice).color(*color));
+            }
         }
         ui.separator();
         if let Some(server_id) = self.selected_server {
@@ -527,9 +941,8 @@ impl ChatUIState {
                                     if let Some(server_id) = self.selected_server {
                                         match std::fs::read(path) {
                                             Ok(bytes) => {
-                                                let base64_data = base64::encode(bytes);
-                                                let msg = format!("[MediaBroadcast]::{}::{}", media_name, base64_data);
-                                                push_gui_message(&self.gui_input, server_id, msg);
+                                                let msg = ChatProtocol::MediaBroadcast { name: media_name.clone(), data: bytes }.to_string();
+                                                send_gui_command(&self.gui_input, &self.recorder, server_id, msg);
                                                 self.broadcast_result_message =
                                                     Some(format!("📤 Sent '{}' to server {}", media_name, server_id));
                                             }
@@ -616,7 +1029,7 @@ impl ChatUIState {
                         } else {
                             self.chat_messages = self.chat_history.get(&key).cloned().unwrap_or_default();
                         }
-                        push_gui_message(&self.gui_input, requester, format!("[ChatRequest]::{target}"));
+                        send_gui_command(&self.gui_input, &self.recorder, requester, ChatProtocol::ChatRequest { peer_id: target }.to_string());
                         // push_gui_message(&self.gui_input, target, format!("[ChatRequest]::{requester}"));
 
 
@@ -648,15 +1061,16 @@ impl ChatUIState {
                     if let Some(from) = self.selected_sender {
                         let to = if from == a { b } else { a };
                         if !self.chat_input.trim().is_empty() {
-                            let msg = ChatMessage { from, content: self.chat_input.clone() };
-                            self.chat_messages.push(msg.clone());
-
-                            let key = (from.min(to), from.max(to));
+                            // shown right away, the ticks follow the receipts coming back
+                            let message_id = self.next_message_id;
+                            self.next_message_id += 1;
+                            let msg = ChatMessage { from, id: message_id, content: self.chat_input.clone(), receipt: None };
+                            let key = (a.min(b), a.max(b));
                             if self.chat_type_map.get(&key) == Some(&ChatType::Normal) {
                                 self.chat_history.entry(key).or_default().push(msg.clone());
                             }
-
-                            push_gui_message(&self.gui_input, from, format!("[MessageTo]::{to}::{}", msg.content));
+                            self.chat_messages.push(msg);
+                            send_gui_command(&self.gui_input, &self.recorder, from, ChatProtocol::MessageTo { target_id: to, message_id, message: self.chat_input.clone() }.to_string());
                             self.chat_input.clear();
                         }
                     }
@@ -665,9 +1079,25 @@ impl ChatUIState {
 
             ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
                 for msg in &self.chat_messages {
-                    ui.label(format!("From Client #{}: {}", msg.from, msg.content));
+                    let ticks = match msg.receipt {
+                        None => "⏳",
+                        Some(ReceiptKind::Delivered) => "✓",
+                        Some(ReceiptKind::Read) => "✓✓",
+                    };
+                    ui.horizontal(|ui| {
+                        ui.label(format!("From Client #{}: {}", msg.from, msg.content));
+                        ui.label(RichText::new(ticks).color(if msg.receipt == Some(ReceiptKind::Read) { Color32::LIGHT_BLUE } else { Color32::GRAY }));
+                    });
                 }
             });
+
+            // whatever reached this pair is on screen now: tell the senders it was read
+            let pair = (a.min(b), a.max(b));
+            let (shown, waiting): (Vec<_>, Vec<_>) = self.pending_reads.drain(..).partition(|&(reader, from, _)| (reader.min(from), reader.max(from)) == pair);
+            self.pending_reads = waiting;
+            for (reader, from, message_id) in shown {
+                send_gui_command(&self.gui_input, &self.recorder, reader, ChatProtocol::Receipt { peer_id: from, message_id, kind: ReceiptKind::Read }.to_string());
+            }
         }
 
         // History popup is now outside the active chat condition
@@ -686,7 +1116,7 @@ impl ChatUIState {
                             self.history_target_id_input.parse::<NodeId>(),
                             self.selected_server,
                         ) {
-                            push_gui_message(&self.gui_input, client_id, format!("[HistoryRequest]::{}::{}", client_id, target_id));
+                            send_gui_command(&self.gui_input, &self.recorder, client_id, ChatProtocol::HistoryRequest { client_id, target_id }.to_string());
                             self.show_history_popup = false;
                         }
                     }
@@ -703,37 +1133,43 @@ impl ChatUIState {
 }
 
 //🖼️🖼️🖼️loading media🖼️🖼️🖼️
+/// Icon for a media list entry, by the family of its content type.
+fn media_icon(content_type: &str) -> &'static str {
+    match content_type.split('/').next() {
+        Some("image") => "🖼",
+        Some("audio") => "🎵",
+        Some("video") => "🎬",
+        Some("text") => "📝",
+        _ => "📄",
+    }
+}
+
 fn load_media_files() -> Vec<(String, String)> {
-    let media_dir = "media"; // relative path
+    load_sendable_files("media") // relative path
+}
+fn load_broadcast_files() -> Vec<(String, String)> {
+    load_sendable_files("broadcast media") // relative path
+}
+
+/// Files of `media_dir` whose type is known, as (file name, path).
+fn load_sendable_files(media_dir: &str) -> Vec<(String, String)> {
     let mut files = Vec::new();
     if let Ok(entries) = fs::read_dir(media_dir) {
         for entry in entries.flatten() {
             let path: PathBuf = entry.path();
-            if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-                if ["jpg", "jpeg", "png"].contains(&ext.to_lowercase().as_str()) {
-                    if let Some(fname) = path.file_name().and_then(|f| f.to_str()) {
-                        files.push((fname.to_string(), path.to_string_lossy().to_string()));
-                    }
+            if let Some(fname) = path.file_name().and_then(|f| f.to_str()) {
+                if media::content_type_for(fname).is_some() {
+                    files.push((fname.to_string(), path.to_string_lossy().to_string()));
                 }
             }
         }
     }
+    files.sort();
     files
 }
-fn load_broadcast_files() -> Vec<(String, String)> {
-    let media_dir = "broadcast media"; // relative path
-    let mut files = Vec::new();
-    if let Ok(entries) = fs::read_dir(media_dir) {
-        for entry in entries.flatten() {
-            let path: PathBuf = entry.path();
-            if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-                if ["jpg", "jpeg", "png"].contains(&ext.to_lowercase().as_str()) {
-                    if let Some(fname) = path.file_name().and_then(|f| f.to_str()) {
-                        files.push((fname.to_string(), path.to_string_lossy().to_string()));
-                    }
-                }
-            }
-        }
-       }
-   files
+
+/// Pushes `msg` for `client` like `push_gui_message`, recording it for replays.
+fn send_gui_command(gui_input: &SharedGuiInput, recorder: &SharedRecorder, client: NodeId, msg: String) {
+    record_action(recorder, ScriptCommand::Send { client, command: msg.clone() });
+    push_gui_message(gui_input, client, msg);
 }
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use wg_2024::network::NodeId;
+use crate::nodes::protocol::{Capability, ChatProtocol, DeliveryStatus, MediaInfo, MediaVisibility, ReceiptKind, ServerService};
+
+/// What a client saw arrive over the drone network, reported back to the chat window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuiEvent {
+    LoggedIn { server_id: NodeId, capabilities: Vec<Capability> },
+    /// What `server_id` answered to a `ServerTypeRequest`.
+    ServerType { server_id: NodeId, services: Vec<ServerService> },
+    ChatMessage { from: NodeId, message_id: u64, message: String },
+    /// `peer_id` got (or read) the message `message_id` this client sent.
+    Receipt { peer_id: NodeId, message_id: u64, kind: ReceiptKind },
+    Delivery { target_id: NodeId, status: DeliveryStatus },
+    ChatRequested { from: NodeId },
+    ChatStarted { success: bool },
+    ChatFinished,
+    ClientList { clients: Vec<NodeId> },
+    RoomList { rooms: Vec<String> },
+    /// Membership of a room this client is (or just stopped being) in.
+    RoomUpdate { room: String, members: Vec<NodeId> },
+    RoomMessage { room: String, from: NodeId, message_id: u64, message: String },
+    History { history: String },
+    MediaList { media: Vec<MediaInfo> },
+    MediaUploaded { name: String },
+    /// A downloaded media, saved by the client in its downloads directory.
+    MediaDownloaded { name: String, file_name: String, content_type: String, size: usize, path: String },
+    MediaNotFound,
+    MediaBroadcasted { name: String },
+    MediaVisibility { name: String, visibility: MediaVisibility },
+    MediaDeleted { name: String },
+    /// A chunked transfer moved forward: `done` of `total` bytes verified.
+    MediaProgress { name: String, done: u64, total: u64, upload: bool },
+    TextList { server_id: NodeId, files: Vec<String> },
+    TextFile { server_id: NodeId, name: String, content: String },
+    TextUploaded { server_id: NodeId, name: String },
+    Error { reason: String },
+}
+
+impl GuiEvent {
+    /// Event for a message a client received from `source` (the server that sent it);
+    /// requests that only make sense server-side give `None`.
+    pub fn from_message(source: NodeId, message: &ChatProtocol) -> Option<Self> {
+        let event = match message {
+            ChatProtocol::LoginAck { capabilities, .. } => GuiEvent::LoggedIn { server_id: source, capabilities: capabilities.clone() },
+            ChatProtocol::ServerTypeResponse { services } => GuiEvent::ServerType { server_id: source, services: services.clone() },
+            ChatProtocol::MessageFrom { sender_id, message_id, message } => GuiEvent::ChatMessage { from: *sender_id, message_id: *message_id, message: message.clone() },
+            ChatProtocol::Receipt { peer_id, message_id, kind } => GuiEvent::Receipt { peer_id: *peer_id, message_id: *message_id, kind: *kind },
+            ChatProtocol::DeliveryStatus { target_id, status } => GuiEvent::Delivery { target_id: *target_id, status: *status },
+            ChatProtocol::ChatRequest { peer_id } => GuiEvent::ChatRequested { from: *peer_id },
+            ChatProtocol::ChatStart { success } => GuiEvent::ChatStarted { success: *success },
+            ChatProtocol::ChatFinish { .. } => GuiEvent::ChatFinished,
+            ChatProtocol::ClientListResponse { clients } => GuiEvent::ClientList { clients: clients.clone() },
+            ChatProtocol::RoomListResponse { rooms } => GuiEvent::RoomList { rooms: rooms.clone() },
+            ChatProtocol::RoomUpdate { room, members } => GuiEvent::RoomUpdate { room: room.clone(), members: members.clone() },
+            ChatProtocol::RoomMessageFrom { room, sender_id, message_id, message } => {
+                GuiEvent::RoomMessage { room: room.clone(), from: *sender_id, message_id: *message_id, message: message.clone() }
+            }
+            ChatProtocol::HistoryResponse { history } => GuiEvent::History { history: history.clone() },
+            ChatProtocol::MediaListResponse { media } => GuiEvent::MediaList { media: media.clone() },
+            ChatProtocol::MediaUploadAck { name } => GuiEvent::MediaUploaded { name: name.clone() },
+            ChatProtocol::MediaNotFound => GuiEvent::MediaNotFound,
+            ChatProtocol::MediaBroadcastAck { name } => GuiEvent::MediaBroadcasted { name: name.clone() },
+            ChatProtocol::MediaVisibilityAck { name, visibility } => GuiEvent::MediaVisibility { name: name.clone(), visibility: visibility.clone() },
+            ChatProtocol::MediaDeleteAck { name } => GuiEvent::MediaDeleted { name: name.clone() },
+            ChatProtocol::TextListResponse { files } => GuiEvent::TextList { server_id: source, files: files.clone() },
+            ChatProtocol::TextResponse { name, content } => GuiEvent::TextFile { server_id: source, name: name.clone(), content: content.clone() },
+            ChatProtocol::TextUploadAck { name } => GuiEvent::TextUploaded { server_id: source, name: name.clone() },
+            ChatProtocol::Error { reason } => GuiEvent::Error { reason: reason.clone() },
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+// Mirror of SharedGuiInput: events are keyed by the client that received them
+type GuiEventBuffer = HashMap<NodeId, Vec<GuiEvent>>;
+pub type SharedGuiEvents = Arc<Mutex<GuiEventBuffer>>;
+
+pub fn new_gui_event_queue() -> SharedGuiEvents {
+    Arc::new(Mutex::new(HashMap::new()))
+}
+
+pub fn push_gui_event(queue: &SharedGuiEvents, client: NodeId, event: GuiEvent) {
+    if let Ok(mut map) = queue.lock() {
+        map.entry(client).or_default().push(event);
+    } else {
+        println!("❌ Could not lock GUI event queue");
+    }
+}
+
+/// Takes every pending event, oldest first for each client.
+pub fn drain_gui_events(queue: &SharedGuiEvents) -> Vec<(NodeId, GuiEvent)> {
+    match queue.lock() {
+        Ok(mut map) => map
+            .drain()
+            .flat_map(|(client, events)| events.into_iter().map(move |event| (client, event)))
+            .collect(),
+        Err(_) => {
+            println!("❌ Could not lock GUI event queue");
+            Vec::new()
+        }
+    }
+}
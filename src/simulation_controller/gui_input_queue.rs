@@ -3,6 +3,9 @@ use std::sync::{Arc, Mutex};
 use wg_2024::network::NodeId;
 use crate::network::initializer::ParsedConfig;
 
+/// Pushed to every client and server when the controller ends the simulation: the host leaves its run loop.
+pub const SHUTDOWN_COMMAND: &str = "[Shutdown]";
+
 type GuiMessageBuffer = HashMap<NodeId, Vec<String>>;
 pub type SharedGuiInput = Arc<Mutex<GuiMessageBuffer>>;
 
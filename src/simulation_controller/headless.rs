@@ -0,0 +1,301 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use wg_2024::network::NodeId;
+use crate::simulation_controller::SC_backend::SimulationController;
+use crate::simulation_controller::gui_input_queue::{push_gui_message, SharedGuiInput};
+use crate::simulation_controller::gui_event_queue::{drain_gui_events, GuiEvent, SharedGuiEvents};
+
+/// Exit code of a script that ran to the end (or to `exit`).
+pub const EXIT_OK: i32 = 0;
+/// Exit code when an `expect` timed out or the controller refused an action.
+pub const EXIT_FAILED: i32 = 1;
+/// Exit code for a line that is not a command, or a script that can't be read.
+pub const EXIT_BAD_SCRIPT: i32 = 2;
+
+/// How long `expect` waits when the script did not set a `timeout`.
+const DEFAULT_EXPECT_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// One line of a headless script. Blank lines and lines starting with `#` are skipped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptCommand {
+    /// `send <client> <command>`: what the chat window would push for that client, e.g. `send 101 [Login]::30`.
+    Send { client: NodeId, command: String },
+    /// `wait <ms>`
+    Wait(Duration),
+    /// `timeout <ms>`: how long the following `expect`s wait.
+    Timeout(Duration),
+    /// `expect <client> <GuiEvent variant> [text]`: waits for an event of that kind whose fields contain `text`.
+    Expect { client: NodeId, kind: String, text: Option<String> },
+    Crash(NodeId),
+    /// `pdr <drone> <rate>`
+    SetPdr { drone: NodeId, rate: f32 },
+    /// `add_link <a> <b>`
+    AddLink(NodeId, NodeId),
+    /// `remove_link <a> <b>`
+    RemoveLink(NodeId, NodeId),
+    /// `spawn <id> <pdr> <neighbor,neighbor,...>`
+    Spawn { id: NodeId, pdr: f32, connections: Vec<NodeId> },
+    /// `exit [code]`
+    Exit(i32),
+    /// `seed <n>`: seed of the run, read by `main.rs` before the network starts (see `replay.rs`).
+    Seed(u64),
+    /// `topology <path>`: topology the script was recorded against, read by `main.rs` like `seed`.
+    Topology(String),
+}
+
+impl ScriptCommand {
+    /// The command on `line`, `Ok(None)` for blank lines and comments.
+    pub fn parse(line: &str) -> Result<Option<Self>, String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let (keyword, rest) = line.split_once(char::is_whitespace).map(|(k, r)| (k, r.trim())).unwrap_or((line, ""));
+        let args: Vec<&str> = rest.split_whitespace().collect();
+        let id = |i: usize| args.get(i).and_then(|a| a.parse::<NodeId>().ok()).ok_or_else(|| format!("'{}': argument {} should be a node id", keyword, i + 1));
+        let number = |i: usize| args.get(i).and_then(|a| a.parse::<u64>().ok()).ok_or_else(|| format!("'{}': argument {} should be a number", keyword, i + 1));
+        let rate = |i: usize| args.get(i).and_then(|a| a.parse::<f32>().ok()).filter(|r| (0.0..=1.0).contains(r)).ok_or_else(|| format!("'{}': argument {} should be a rate between 0 and 1", keyword, i + 1));
+
+        let command = match keyword {
+            "send" => {
+                let client = id(0)?;
+                let command = rest.split_once(char::is_whitespace).map(|(_, c)| c.trim()).unwrap_or("");
+                if command.is_empty() {
+                    return Err("'send' needs a client id and a command".to_string());
+                }
+                // multi-line commands (text uploads) are written with `\n`
+                ScriptCommand::Send { client, command: command.replace("\\n", "\n") }
+            }
+            "wait" => ScriptCommand::Wait(Duration::from_millis(number(0)?)),
+            "timeout" => ScriptCommand::Timeout(Duration::from_millis(number(0)?)),
+            "expect" => {
+                let client = id(0)?;
+                let kind = args.get(1).ok_or("'expect' needs a client id and an event kind")?.to_string();
+                let text = (args.len() > 2).then(|| args[2..].join(" "));
+                ScriptCommand::Expect { client, kind, text }
+            }
+            "crash" => ScriptCommand::Crash(id(0)?),
+            "pdr" => ScriptCommand::SetPdr { drone: id(0)?, rate: rate(1)? },
+            "add_link" => ScriptCommand::AddLink(id(0)?, id(1)?),
+            "remove_link" => ScriptCommand::RemoveLink(id(0)?, id(1)?),
+            "spawn" => {
+                let connections = args
+                    .get(2)
+                    .ok_or("'spawn' needs an id, a pdr and its neighbors")?
+                    .split(',')
+                    .map(|n| n.trim().parse::<NodeId>().map_err(|_| format!("'spawn': bad neighbor id '{}'", n)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                ScriptCommand::Spawn { id: id(0)?, pdr: rate(1)?, connections }
+            }
+            "seed" => ScriptCommand::Seed(number(0)?),
+            "topology" if !rest.is_empty() => ScriptCommand::Topology(rest.to_string()),
+            "exit" => ScriptCommand::Exit(args.first().map(|c| c.parse::<i32>().map_err(|_| format!("'exit': bad code '{}'", c))).transpose()?.unwrap_or(EXIT_OK)),
+            other => return Err(format!("unknown command '{}'", other)),
+        };
+        Ok(Some(command))
+    }
+}
+
+/// The line `parse` reads back as the same command.
+impl fmt::Display for ScriptCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ids = |ids: &[NodeId]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(",");
+        match self {
+            ScriptCommand::Send { client, command } => write!(f, "send {} {}", client, command.replace('\n', "\\n")),
+            ScriptCommand::Wait(duration) => write!(f, "wait {}", duration.as_millis()),
+            ScriptCommand::Timeout(duration) => write!(f, "timeout {}", duration.as_millis()),
+            ScriptCommand::Expect { client, kind, text: Some(text) } => write!(f, "expect {} {} {}", client, kind, text),
+            ScriptCommand::Expect { client, kind, text: None } => write!(f, "expect {} {}", client, kind),
+            ScriptCommand::Crash(drone) => write!(f, "crash {}", drone),
+            ScriptCommand::SetPdr { drone, rate } => write!(f, "pdr {} {}", drone, rate),
+            ScriptCommand::AddLink(a, b) => write!(f, "add_link {} {}", a, b),
+            ScriptCommand::RemoveLink(a, b) => write!(f, "remove_link {} {}", a, b),
+            ScriptCommand::Spawn { id, pdr, connections } => write!(f, "spawn {} {} {}", id, pdr, ids(connections)),
+            ScriptCommand::Exit(code) => write!(f, "exit {}", code),
+            ScriptCommand::Seed(seed) => write!(f, "seed {}", seed),
+            ScriptCommand::Topology(path) => write!(f, "topology {}", path),
+        }
+    }
+}
+
+/// Name of the `GuiEvent` variant, as written in `expect`.
+pub fn event_kind(event: &GuiEvent) -> String {
+    let debug = format!("{:?}", event);
+    debug.split([' ', '{', '(']).next().unwrap_or_default().to_string()
+}
+
+/// Whether `event` is what an `expect <kind> [text]` waits for.
+pub fn event_matches(event: &GuiEvent, kind: &str, text: Option<&str>) -> bool {
+    event_kind(event) == kind && text.is_none_or(|text| format!("{:?}", event).contains(text))
+}
+
+/// Drives the simulation without the egui window: GUI commands come from a script (or stdin)
+/// and what the clients report back is checked against its `expect` lines.
+pub struct HeadlessRunner {
+    controller: Arc<Mutex<SimulationController>>,
+    gui_input: SharedGuiInput,
+    gui_events: SharedGuiEvents,
+    received: Vec<(NodeId, GuiEvent)>, // drained but not yet matched by an expect
+    expect_timeout: Duration,
+}
+
+impl HeadlessRunner {
+    pub fn new(controller: Arc<Mutex<SimulationController>>, gui_input: SharedGuiInput, gui_events: SharedGuiEvents) -> Self {
+        Self { controller, gui_input, gui_events, received: Vec::new(), expect_timeout: DEFAULT_EXPECT_TIMEOUT }
+    }
+
+    /// Runs the script at `script`, or stdin when there is none, and gives the process exit code.
+    pub fn run_script(&mut self, script: Option<&str>) -> i32 {
+        match script {
+            Some(path) => match File::open(path) {
+                Ok(file) => self.run(BufReader::new(file)),
+                Err(e) => {
+                    println!("❌ Could not open script {}: {}", path, e);
+                    EXIT_BAD_SCRIPT
+                }
+            },
+            None => self.run(io::stdin().lock()),
+        }
+    }
+
+    /// Executes the lines in order and stops at the first one that fails.
+    pub fn run(&mut self, input: impl BufRead) -> i32 {
+        for (number, line) in input.lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    println!("❌ Could not read script line {}: {}", number + 1, e);
+                    return EXIT_BAD_SCRIPT;
+                }
+            };
+            let command = match ScriptCommand::parse(&line) {
+                Ok(Some(command)) => command,
+                Ok(None) => continue,
+                Err(e) => {
+                    println!("❌ Line {}: {}", number + 1, e);
+                    return EXIT_BAD_SCRIPT;
+                }
+            };
+            if let ScriptCommand::Exit(code) = command {
+                println!("🏁 exit {}", code);
+                return code;
+            }
+            if let Err(e) = self.execute(command) {
+                println!("❌ Line {} failed: {}", number + 1, e);
+                return EXIT_FAILED;
+            }
+        }
+        self.collect_events();
+        println!("🏁 Script finished");
+        EXIT_OK
+    }
+
+    fn execute(&mut self, command: ScriptCommand) -> Result<(), String> {
+        match command {
+            ScriptCommand::Send { client, command } => {
+                push_gui_message(&self.gui_input, client, command);
+            }
+            ScriptCommand::Wait(duration) => {
+                thread::sleep(duration);
+                self.collect_events();
+            }
+            ScriptCommand::Timeout(duration) => self.expect_timeout = duration,
+            ScriptCommand::Expect { client, kind, text } => {
+                let event = self.expect(client, &kind, text.as_deref())?;
+                println!("✅ Client {} got {:?}", client, event);
+            }
+            ScriptCommand::Crash(drone) => self.control(|ctrl| ctrl.crash_drone(drone))?,
+            ScriptCommand::SetPdr { drone, rate } => self.control(|ctrl| ctrl.set_packet_drop_rate(drone, rate))?,
+            ScriptCommand::AddLink(a, b) => self.control(|ctrl| ctrl.add_link(a, b))?,
+            ScriptCommand::RemoveLink(a, b) => self.control(|ctrl| ctrl.remove_link(a, b))?,
+            ScriptCommand::Spawn { id, pdr, connections } => self.control(|ctrl| ctrl.spawn_drone(id, pdr, connections))?,
+            // both only matter before the network starts
+            ScriptCommand::Exit(_) | ScriptCommand::Seed(_) | ScriptCommand::Topology(_) => {}
+        }
+        Ok(())
+    }
+
+    fn control(&self, action: impl FnOnce(&mut SimulationController) -> Result<(), Box<dyn std::error::Error>>) -> Result<(), String> {
+        let mut ctrl = self.controller.lock().map_err(|_| "simulation controller lock poisoned".to_string())?;
+        action(&mut ctrl).map_err(|e| format!("SC refused: {}", e))
+    }
+
+    /// Moves the reported events into `received`, printing them as they come.
+    fn collect_events(&mut self) {
+        for (client, event) in drain_gui_events(&self.gui_events) {
+            println!("📥 Client {}: {:?}", client, event);
+            self.received.push((client, event));
+        }
+    }
+
+    /// Waits for (and consumes) the oldest matching event of `client`.
+    fn expect(&mut self, client: NodeId, kind: &str, text: Option<&str>) -> Result<GuiEvent, String> {
+        let deadline = Instant::now() + self.expect_timeout;
+        loop {
+            self.collect_events();
+            if let Some(index) = self.received.iter().position(|(from, event)| *from == client && event_matches(event, kind, text)) {
+                return Ok(self.received.remove(index).1);
+            }
+            if Instant::now() >= deadline {
+                return Err(format!("client {} got no {}{} within {:?}", client, kind, text.map(|t| format!(" with '{}'", t)).unwrap_or_default(), self.expect_timeout));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_lines_parse() {
+        let script = "# log in and chat\n\nsend 101 [Login]::30\nwait 250\ntimeout 2000\nexpect 101 ChatMessage see you at 10::30\ncrash 3\npdr 4 0.25\nremove_link 1 2\nspawn 9 0.1 1,2\nexit 3";
+        let commands: Vec<ScriptCommand> = script.lines().filter_map(|line| ScriptCommand::parse(line).unwrap()).collect();
+        assert_eq!(commands, vec![
+            ScriptCommand::Send { client: 101, command: "[Login]::30".to_string() },
+            ScriptCommand::Wait(Duration::from_millis(250)),
+            ScriptCommand::Timeout(Duration::from_millis(2000)),
+            ScriptCommand::Expect { client: 101, kind: "ChatMessage".to_string(), text: Some("see you at 10::30".to_string()) },
+            ScriptCommand::Crash(3),
+            ScriptCommand::SetPdr { drone: 4, rate: 0.25 },
+            ScriptCommand::RemoveLink(1, 2),
+            ScriptCommand::Spawn { id: 9, pdr: 0.1, connections: vec![1, 2] },
+            ScriptCommand::Exit(3),
+        ]);
+        for bad in ["send 101", "wait soon", "pdr 4 1.5", "spawn 9 0.1 1,x", "teleport 3"] {
+            assert!(ScriptCommand::parse(bad).is_err(), "'{}' should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn test_expectations_match_gui_events_by_kind_and_text() {
+        let event = GuiEvent::ChatMessage { from: 102, message_id: 1, message: "see you at 10::30".to_string() };
+        assert_eq!(event_kind(&event), "ChatMessage");
+        assert_eq!(event_kind(&GuiEvent::MediaNotFound), "MediaNotFound");
+        assert!(event_matches(&event, "ChatMessage", None));
+        assert!(event_matches(&event, "ChatMessage", Some("10::30")));
+        assert!(!event_matches(&event, "ChatMessage", Some("goodbye")));
+        assert!(!event_matches(&event, "ChatStarted", None));
+    }
+
+    #[test]
+    fn test_script_commands_read_back_from_their_display() {
+        let upload = ScriptCommand::Send { client: 1, command: "[TextUpload]::notes.md::line one\nline two".to_string() };
+        assert_eq!(upload.to_string().lines().count(), 1, "a recorded command stays on one line");
+        for command in [
+            upload,
+            ScriptCommand::SetPdr { drone: 2, rate: 0.25 },
+            ScriptCommand::Spawn { id: 9, pdr: 0.1, connections: vec![2, 3] },
+            ScriptCommand::Seed(42),
+            ScriptCommand::Topology("topologies/default.toml".to_string()),
+        ] {
+            assert_eq!(ScriptCommand::parse(&command.to_string()), Ok(Some(command.clone())), "{} should read back", command);
+        }
+    }
+}
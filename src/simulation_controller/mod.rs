@@ -3,3 +3,10 @@ pub(crate) mod network_designer;
 pub(crate) mod SC_backend;
 mod chatUI;
 pub(crate) mod gui_input_queue;
+pub(crate) mod gui_event_queue;
+pub(crate) mod headless;
+pub(crate) mod sim_log;
+pub(crate) mod packet_trace;
+pub(crate) mod replay;
+pub(crate) mod stats;
+pub(crate) mod packet_animation;
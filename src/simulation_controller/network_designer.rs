@@ -6,6 +6,7 @@ use wg_2024::network::NodeId;
 use std::sync::{Arc, Mutex};
 use std::collections::{HashMap, HashSet};
 use crate::simulation_controller::SC_backend::SimulationController;
+use crate::simulation_controller::packet_animation::{packet_color, PacketAnimator};
 use crate::network::initializer::ParsedConfig;
 use crate::simulation_controller::gui_input_queue::{broadcast_topology_change, SharedGuiInput};
 
@@ -119,7 +120,8 @@ pub(crate) struct NetworkRenderer {
 
     pub gui_input: SharedGuiInput,
 
-
+    // packets moving on the canvas, from the controller's packet trace
+    pub(crate) packet_animator: PacketAnimator,
 }
 
 impl NetworkRenderer {
@@ -156,6 +158,7 @@ impl NetworkRenderer {
             manual_positions: HashMap::new(),
             last_spawned_position: None,
             gui_input,
+            packet_animator: PacketAnimator::default(),
         };
 
         match Topology::from_str(topology) {
@@ -1311,19 +1314,8 @@ impl NetworkRenderer {
                     };
 
                     if node_a.node_type == NodeType::Client || node_b.node_type == NodeType::Client {
-                        let control = Pos2::new((pos_a.x + pos_b.x) / 2.0, (pos_a.y + pos_b.y) / 2.0 - 40.0);
-
-                        let mut points = Vec::new();
                         let steps = 20;
-                        for i in 0..=steps {
-                            let t = i as f32 / steps as f32;
-                            let inv_t = 1.0 - t;
-
-                            let x = inv_t * inv_t * pos_a.x + 2.0 * inv_t * t * control.x + t * t * pos_b.x;
-                            let y = inv_t * inv_t * pos_a.y + 2.0 * inv_t * t * control.y + t * t * pos_b.y;
-
-                            points.push(Pos2::new(x, y));
-                        }
+                        let points: Vec<Pos2> = (0..=steps).map(|i| edge_point(pos_a, pos_b, true, i as f32 / steps as f32)).collect();
 
                         let curve = egui::epaint::PathShape::line(points, egui::Stroke::new(2.0, color));
                         painter.add(curve);
@@ -1388,6 +1380,53 @@ impl NetworkRenderer {
 
             }
         }
+
+        self.render_packets(ui, offset);
+    }
+
+    /// Packets the controller saw since the last frame, moving along their edge in the color of
+    /// their type; a dropped packet fades out with a ✖ at the drone that dropped it.
+    fn render_packets(&mut self, ui: &mut egui::Ui, offset: Vec2) {
+        if let Some(ctrl) = &self.simulation_controller {
+            let trace = ctrl.lock().unwrap().packet_trace();
+            if let Ok(trace) = trace.lock() {
+                self.packet_animator.ingest(trace.entries());
+            };
+        }
+        self.packet_animator.advance(ui.input(|i| i.stable_dt));
+
+        let position = |id: NodeId| {
+            let node = self.node_id_to_index.get(&id).and_then(|&idx| self.nodes.get(idx))?;
+            Some((Pos2::new(node.position.0 * self.scale + offset.x, node.position.1 * self.scale + offset.y), node.node_type))
+        };
+        let painter = ui.painter();
+        for animation in self.packet_animator.animations() {
+            let Some((from, from_type)) = position(animation.from) else { continue };
+            let color = packet_color(&animation.packet_type);
+            let t = animation.progress();
+            if animation.dropped {
+                let alpha = ((1.0 - t) * 255.0) as u8;
+                let faded = Color32::from_rgba_unmultiplied(color.r(), color.g(), color.b(), alpha);
+                painter.circle_stroke(from, 10.0 + 14.0 * t, egui::Stroke::new(2.0, faded));
+                painter.text(
+                    Pos2::new(from.x, from.y - 22.0),
+                    egui::Align2::CENTER_CENTER,
+                    "✖",
+                    egui::FontId::proportional(16.0),
+                    Color32::from_rgba_unmultiplied(220, 0, 0, alpha),
+                );
+            } else if let Some((to, to_type)) = animation.to.and_then(position) {
+                let curved = from_type == NodeType::Client || to_type == NodeType::Client;
+                painter.circle_filled(edge_point(from, to, curved, t), 5.0, color);
+            }
+        }
+
+        // keep polling the trace even when nothing moves on screen
+        if self.packet_animator.animations().is_empty() {
+            ui.ctx().request_repaint_after(std::time::Duration::from_millis(100));
+        } else {
+            ui.ctx().request_repaint();
+        }
     }
     pub fn render_node_details(&mut self, ctx: &egui::Context) {
         if let Some(idx) = self.selected_node {
@@ -1441,6 +1480,40 @@ impl NetworkRenderer {
                         ui.label(format!("Connected to: [{}]", list));
                     }
 
+                    // 📊 what the controller counted from the drone events
+                    if let Some(ctrl_arc) = &self.simulation_controller {
+                        let ctrl = ctrl_arc.lock().unwrap();
+                        let stats = ctrl.node_stats(node_id);
+                        let percent = |ratio: Option<f32>| ratio.map(|r| format!("{:.1}%", r * 100.0)).unwrap_or_else(|| "-".to_string());
+                        ui.separator();
+                        ui.strong("📊 Traffic");
+                        if let NodeType::Drone = node_type {
+                            ui.label(format!("Forwarded: {} ({} fragments)   Dropped: {}", stats.forwarded, stats.fragments_forwarded, stats.dropped));
+                            let (observed, configured) = ctrl.drop_ratio(node_id);
+                            ui.label(format!("Drop ratio: {} observed / {} configured PDR", percent(observed), percent(configured)));
+                        }
+                        ui.label(format!("Controller shortcuts to this node: {}", stats.shortcuts));
+
+                        let mut peers: Vec<NodeId> = ctrl.network_graph.get(&node_id).into_iter().flatten().copied().collect();
+                        peers.sort_unstable();
+                        if !peers.is_empty() {
+                            egui::Grid::new("link_stats_grid").striped(true).show(ui, |ui| {
+                                ui.label("Link");
+                                ui.label("Sent");
+                                ui.label("Dropped");
+                                ui.end_row();
+                                for peer in peers {
+                                    let link = ctrl.link_stats(node_id, peer);
+                                    ui.label(format!("{} ↔ {}", node_id, peer));
+                                    ui.label(link.sent.to_string());
+                                    ui.label(link.dropped.to_string());
+                                    ui.end_row();
+                                }
+                            });
+                        }
+                        ui.separator();
+                    }
+
 
                     if let NodeType::Drone = node_type {
                         if self.nodes[idx].active {
@@ -1654,6 +1727,20 @@ fn rand_offset() -> f32 {
     rng.gen_range(-30.0..30.0)
 }
 
+/// Point at `t` (0 at `a`, 1 at `b`) of an edge as `render` draws it: straight, or curved upwards
+/// for client links.
+fn edge_point(a: Pos2, b: Pos2, curved: bool, t: f32) -> Pos2 {
+    if !curved {
+        return a + (b - a) * t;
+    }
+    let control = Pos2::new((a.x + b.x) / 2.0, (a.y + b.y) / 2.0 - 40.0);
+    let inv_t = 1.0 - t;
+    Pos2::new(
+        inv_t * inv_t * a.x + 2.0 * inv_t * t * control.x + t * t * b.x,
+        inv_t * inv_t * a.y + 2.0 * inv_t * t * control.y + t * t * b.y,
+    )
+}
+
 fn load_texture(ctx: &egui::Context, path: &str) -> egui::TextureHandle {
     let image = image::open(path).unwrap_or_else(|e| {
         panic!("❌ Failed to load image at '{}': {}", path, e);
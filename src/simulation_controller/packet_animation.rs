@@ -0,0 +1,149 @@
+use egui::Color32;
+use wg_2024::network::NodeId;
+use crate::simulation_controller::packet_trace::{PacketTrace, TraceEventKind};
+
+/// Seconds a packet takes to cross an edge at speed 1.
+const HOP_SECONDS: f32 = 0.6;
+/// Seconds a drop stays on screen at speed 1.
+const DROP_SECONDS: f32 = 0.9;
+/// Packets animated at once; under heavy traffic the oldest are skipped.
+const MAX_ANIMATIONS: usize = 400;
+
+/// Color of each packet type on the canvas, as named by `packet_type_name`.
+pub const PACKET_COLORS: [(&str, Color32); 5] = [
+    ("fragment", Color32::from_rgb(30, 120, 230)),
+    ("ack", Color32::from_rgb(40, 170, 60)),
+    ("nack", Color32::from_rgb(240, 140, 0)),
+    ("flood_request", Color32::from_rgb(150, 60, 200)),
+    ("flood_response", Color32::from_rgb(0, 170, 170)),
+];
+
+pub fn packet_color(packet_type: &str) -> Color32 {
+    PACKET_COLORS.iter().find(|(name, _)| *name == packet_type).map(|(_, color)| *color).unwrap_or(Color32::GRAY)
+}
+
+/// One packet on the canvas: moving along `from → to`, or, when `dropped`, fading out at `from`
+/// (the drone that dropped it: drones report drops after moving to the next hop).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketAnimation {
+    pub from: NodeId,
+    pub to: Option<NodeId>,
+    pub packet_type: String,
+    pub dropped: bool,
+    age: f32, // seconds at speed 1
+}
+
+impl PacketAnimation {
+    /// How far along it is, from 0 (just sent/dropped) to 1 (done).
+    pub fn progress(&self) -> f32 {
+        let duration = if self.dropped { DROP_SECONDS } else { HOP_SECONDS };
+        (self.age / duration).min(1.0)
+    }
+}
+
+/// Turns the controller's packet trace into animations for `NetworkRenderer::render`.
+#[derive(Debug, Clone)]
+pub struct PacketAnimator {
+    pub speed: f32, // 0 pauses, 1 is one hop in `HOP_SECONDS`
+    last_seq: Option<u64>,
+    animations: Vec<PacketAnimation>,
+}
+
+impl Default for PacketAnimator {
+    fn default() -> Self {
+        Self { speed: 1.0, last_seq: None, animations: Vec::new() }
+    }
+}
+
+impl PacketAnimator {
+    /// Starts an animation for each trace entry not seen yet; `traces` is the trace oldest first.
+    pub fn ingest<'a>(&mut self, traces: impl DoubleEndedIterator<Item = &'a PacketTrace>) {
+        let last_seq = self.last_seq;
+        let mut new: Vec<&PacketTrace> = traces.rev().take_while(|trace| last_seq.is_none_or(|last| trace.seq > last)).take(MAX_ANIMATIONS).collect();
+        new.reverse();
+        if let Some(newest) = new.last() {
+            self.last_seq = Some(newest.seq);
+        }
+        for trace in new {
+            // shortcuts are delivered by the controller, not over an edge
+            let Some(from) = trace.from.filter(|_| trace.kind != TraceEventKind::Shortcut) else { continue };
+            let dropped = trace.kind == TraceEventKind::Dropped;
+            self.animations.push(PacketAnimation { from, to: trace.to, packet_type: trace.packet_type.clone(), dropped, age: 0.0 });
+        }
+        let excess = self.animations.len().saturating_sub(MAX_ANIMATIONS);
+        self.animations.drain(..excess);
+    }
+
+    /// Moves every animation forward by `dt` seconds of wall time, removing the finished ones.
+    pub fn advance(&mut self, dt: f32) {
+        for animation in &mut self.animations {
+            animation.age += dt * self.speed;
+        }
+        self.animations.retain(|animation| animation.progress() < 1.0);
+    }
+
+    pub fn animations(&self) -> &[PacketAnimation] {
+        &self.animations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use wg_2024::controller::DroneEvent;
+    use crate::simulation_controller::packet_trace::PacketTracer;
+    use crate::test_support::{ack, fragment};
+
+    fn sent_and_dropped() -> PacketAnimator {
+        let mut tracer = PacketTracer::new(16);
+        tracer.record(&DroneEvent::PacketSent(fragment(2, 0)));
+        tracer.record(&DroneEvent::PacketDropped(fragment(2, 0)));
+        let mut animator = PacketAnimator::default();
+        animator.ingest(tracer.entries());
+        animator
+    }
+
+    #[test]
+    fn test_trace_entries_are_animated_once_and_shortcuts_are_not_drawn() {
+        let mut tracer = PacketTracer::new(16);
+        tracer.record(&DroneEvent::PacketSent(fragment(2, 0)));
+        tracer.record(&DroneEvent::PacketDropped(fragment(2, 0)));
+        tracer.record(&DroneEvent::ControllerShortcut(ack(2)));
+
+        let mut animator = PacketAnimator::default();
+        animator.ingest(tracer.entries());
+        let shown: Vec<(NodeId, Option<NodeId>, &str, bool)> = animator.animations().iter().map(|a| (a.from, a.to, a.packet_type.as_str(), a.dropped)).collect();
+        assert_eq!(shown, vec![(2, Some(3), "fragment", false), (2, Some(3), "fragment", true)]);
+
+        tracer.record(&DroneEvent::PacketSent(ack(2)));
+        animator.ingest(tracer.entries());
+        assert_eq!(animator.animations().iter().map(|a| (a.from, a.to)).collect::<Vec<_>>(), vec![(2, Some(3)), (2, Some(3)), (3, Some(2))], "only the new entry starts");
+    }
+
+    #[test]
+    fn test_speed_zero_pauses_the_animations() {
+        let mut animator = sent_and_dropped();
+        animator.speed = 0.0;
+        animator.advance(10.0);
+        assert!(animator.animations().iter().all(|a| a.progress() == 0.0));
+    }
+
+    #[test]
+    fn test_drops_fade_out_after_the_hop_is_done() {
+        let mut animator = sent_and_dropped();
+        animator.advance(0.3);
+        assert!(animator.animations().iter().all(|a| a.progress() > 0.0 && a.progress() < 1.0));
+        animator.advance(0.4);
+        assert_eq!(animator.animations().iter().map(|a| a.dropped).collect::<Vec<_>>(), vec![true]);
+        animator.advance(1.0);
+        assert!(animator.animations().is_empty());
+    }
+
+    #[test]
+    fn test_every_packet_type_has_its_own_color() {
+        let colors: HashSet<_> = PACKET_COLORS.iter().map(|(name, _)| packet_color(name)).collect();
+        assert_eq!(colors.len(), PACKET_COLORS.len());
+        assert_eq!(packet_color("unknown"), Color32::GRAY);
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use serde::{Deserialize, Serialize};
+use wg_2024::controller::DroneEvent;
+use wg_2024::network::NodeId;
+use wg_2024::packet::{Packet, PacketType};
+use crate::simulation_controller::sim_log::{packet_hop, packet_type_name};
+
+/// Trace entries kept in memory; past this the oldest go (the trace file, if any, keeps everything).
+pub const DEFAULT_TRACE_CAPACITY: usize = 50_000;
+
+/// Which `DroneEvent` a trace entry comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceEventKind {
+    Sent,
+    Dropped,
+    Shortcut,
+}
+
+impl TraceEventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TraceEventKind::Sent => "sent",
+            TraceEventKind::Dropped => "dropped",
+            TraceEventKind::Shortcut => "shortcut",
+        }
+    }
+}
+
+/// One `DroneEvent` as the controller received it. `elapsed_us` is monotonic (µs since the
+/// recorder started) and `seq` orders entries that share a timestamp.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PacketTrace {
+    pub seq: u64,
+    pub elapsed_us: u64,
+    pub kind: TraceEventKind,
+    pub packet_type: String,
+    pub session_id: u64,
+    /// For fragments, acks and nacks.
+    pub fragment_index: Option<u64>,
+    /// For fragments only.
+    pub total_fragments: Option<u64>,
+    /// For flood requests and responses.
+    pub flood_id: Option<u64>,
+    pub hops: Vec<NodeId>,
+    pub hop_index: usize,
+    /// Node that sent (or dropped) the packet on this hop, and the one it was going to.
+    pub from: Option<NodeId>,
+    pub to: Option<NodeId>,
+}
+
+impl PacketTrace {
+    fn new(seq: u64, elapsed_us: u64, kind: TraceEventKind, packet: &Packet) -> Self {
+        let (fragment_index, total_fragments, flood_id) = match &packet.pack_type {
+            PacketType::MsgFragment(fragment) => (Some(fragment.fragment_index), Some(fragment.total_n_fragments), None),
+            PacketType::Ack(ack) => (Some(ack.fragment_index), None, None),
+            PacketType::Nack(nack) => (Some(nack.fragment_index), None, None),
+            PacketType::FloodRequest(request) => (None, None, Some(request.flood_id)),
+            PacketType::FloodResponse(response) => (None, None, Some(response.flood_id)),
+        };
+        let (from, to) = packet_hop(packet).map(|(from, to)| (Some(from), to)).unwrap_or((None, None));
+        Self {
+            seq,
+            elapsed_us,
+            kind,
+            packet_type: packet_type_name(packet).to_string(),
+            session_id: packet.session_id,
+            fragment_index,
+            total_fragments,
+            flood_id,
+            hops: packet.routing_header.hops.clone(),
+            hop_index: packet.routing_header.hop_index,
+            from,
+            to,
+        }
+    }
+}
+
+impl fmt::Display for PacketTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>10}µs #{} {} {} session {}", self.elapsed_us, self.seq, self.kind.name(), self.packet_type, self.session_id)?;
+        if let Some(index) = self.fragment_index {
+            write!(f, " fragment {}", index)?;
+        }
+        match (self.from, self.to) {
+            (Some(from), Some(to)) => write!(f, " {} → {}", from, to)?,
+            (Some(from), None) => write!(f, " {} → ?", from)?,
+            _ => {}
+        }
+        write!(f, " route {:?} @{}", self.hops, self.hop_index)
+    }
+}
+
+/// Bounded recorder of every `DroneEvent` the controller processes, optionally mirrored to a
+/// JSON Lines trace file (one `PacketTrace` per line).
+pub struct PacketTracer {
+    started_at: Instant,
+    next_seq: u64,
+    capacity: usize,
+    entries: VecDeque<PacketTrace>,
+    file: Option<BufWriter<File>>,
+}
+
+pub type SharedPacketTrace = Arc<Mutex<PacketTracer>>;
+
+pub fn new_packet_trace(capacity: usize) -> SharedPacketTrace {
+    Arc::new(Mutex::new(PacketTracer::new(capacity)))
+}
+
+impl PacketTracer {
+    pub fn new(capacity: usize) -> Self {
+        Self { started_at: Instant::now(), next_seq: 0, capacity: capacity.max(1), entries: VecDeque::new(), file: None }
+    }
+
+    /// From now on every entry is also appended to `path` (created, or truncated).
+    pub fn open_file(&mut self, path: &Path) -> io::Result<()> {
+        self.file = Some(BufWriter::new(File::create(path)?));
+        Ok(())
+    }
+
+    pub fn record(&mut self, event: &DroneEvent) {
+        let (kind, packet) = match event {
+            DroneEvent::PacketSent(packet) => (TraceEventKind::Sent, packet),
+            DroneEvent::PacketDropped(packet) => (TraceEventKind::Dropped, packet),
+            DroneEvent::ControllerShortcut(packet) => (TraceEventKind::Shortcut, packet),
+        };
+        let trace = PacketTrace::new(self.next_seq, self.started_at.elapsed().as_micros() as u64, kind, packet);
+        self.next_seq += 1;
+
+        if let Some(file) = &mut self.file {
+            let written = serde_json::to_writer(&mut *file, &trace).map_err(io::Error::from).and_then(|_| file.write_all(b"\n"));
+            if let Err(e) = written {
+                println!("❌ Could not write packet trace, closing the trace file: {}", e);
+                self.file = None;
+            }
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(trace);
+    }
+
+    /// Entries still in memory, oldest first.
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &PacketTrace> {
+        self.entries.iter()
+    }
+
+    /// Every event of one session, in the order they happened.
+    pub fn session(&self, session_id: u64) -> Vec<&PacketTrace> {
+        self.entries.iter().filter(|trace| trace.session_id == session_id).collect()
+    }
+
+    /// Path and fate of one fragment: its hops (resends included), plus the acks and nacks about it.
+    pub fn fragment(&self, session_id: u64, fragment_index: u64) -> Vec<&PacketTrace> {
+        self.entries
+            .iter()
+            .filter(|trace| trace.session_id == session_id && trace.fragment_index == Some(fragment_index))
+            .collect()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{ack, fragment};
+
+    #[test]
+    fn test_packet_trace_reconstructs_the_path_and_fate_of_a_fragment() {
+        let mut tracer = PacketTracer::new(4);
+        tracer.record(&DroneEvent::PacketSent(fragment(2, 0)));
+        tracer.record(&DroneEvent::PacketSent(fragment(2, 1)));
+        tracer.record(&DroneEvent::PacketDropped(fragment(2, 1)));
+        tracer.record(&DroneEvent::PacketSent(ack(2)));
+        tracer.record(&DroneEvent::ControllerShortcut(ack(2)));
+
+        let kept: Vec<u64> = tracer.entries().map(|trace| trace.seq).collect();
+        assert_eq!(kept, vec![1, 2, 3, 4], "the buffer keeps the newest entries");
+        assert!(tracer.entries().zip(tracer.entries().skip(1)).all(|(a, b)| a.elapsed_us <= b.elapsed_us), "timestamps are monotonic");
+
+        let lost: Vec<(TraceEventKind, Option<NodeId>, Option<NodeId>)> = tracer.fragment(7, 1).iter().map(|trace| (trace.kind, trace.from, trace.to)).collect();
+        assert_eq!(lost, vec![(TraceEventKind::Sent, Some(2), Some(3)), (TraceEventKind::Dropped, Some(2), Some(3))]);
+        let acked = tracer.fragment(7, 0);
+        assert_eq!(acked.iter().map(|trace| trace.packet_type.as_str()).collect::<Vec<_>>(), vec!["ack", "ack"]);
+        assert_eq!((acked[1].kind, &acked[1].hops, acked[1].hop_index), (TraceEventKind::Shortcut, &vec![4, 3, 2, 1], 2));
+        assert_eq!(tracer.session(7).len(), 4);
+    }
+
+    #[test]
+    fn test_trace_entries_round_trip_as_json() {
+        let trace = PacketTrace::new(0, 12, TraceEventKind::Sent, &fragment(2, 0));
+        assert_eq!((trace.fragment_index, trace.total_fragments), (Some(0), Some(2)));
+        let line = serde_json::to_string(&trace).unwrap();
+        assert!(line.contains("\"kind\":\"sent\""), "{}", line);
+        assert_eq!(serde_json::from_str::<PacketTrace>(&line).unwrap(), trace);
+    }
+}
@@ -0,0 +1,168 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use wg_2024::network::NodeId;
+use crate::simulation_controller::headless::ScriptCommand;
+
+/// Seed of one node's RNG, derived from the simulation seed so that no two nodes draw the same sequence.
+pub fn node_seed(seed: u64, node: NodeId) -> u64 {
+    // splitmix64 finalizer
+    let mut z = seed ^ (node as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Records what the user did during a run (GUI commands and controller actions, with the time
+/// they happened) so that `save` can write it as a headless script replaying them in order.
+pub struct ActionRecorder {
+    started_at: Instant,
+    seed: u64,
+    topology: String,
+    actions: Vec<(Duration, ScriptCommand)>, // time since start, action
+}
+
+pub type SharedRecorder = Arc<Mutex<ActionRecorder>>;
+
+pub fn new_recorder(seed: u64, topology: &str) -> SharedRecorder {
+    Arc::new(Mutex::new(ActionRecorder::new(seed, topology)))
+}
+
+/// Adds `action` to the recording, if it is one a replay should repeat.
+pub fn record_action(recorder: &SharedRecorder, action: ScriptCommand) {
+    if let Ok(mut recorder) = recorder.lock() {
+        recorder.record(action);
+    } else {
+        println!("❌ Could not lock action recorder");
+    }
+}
+
+impl ActionRecorder {
+    pub fn new(seed: u64, topology: &str) -> Self {
+        Self { started_at: Instant::now(), seed, topology: topology.to_string(), actions: Vec::new() }
+    }
+
+    pub fn record(&mut self, action: ScriptCommand) {
+        let at = self.started_at.elapsed();
+        match action {
+            ScriptCommand::Send { .. }
+            | ScriptCommand::Crash(_)
+            | ScriptCommand::SetPdr { .. }
+            | ScriptCommand::AddLink(..)
+            | ScriptCommand::RemoveLink(..)
+            | ScriptCommand::Spawn { .. } => self.actions.push((at, action)),
+            _ => {}
+        }
+    }
+
+    pub fn actions(&self) -> &[(Duration, ScriptCommand)] {
+        &self.actions
+    }
+
+    /// The recording as a headless script: seed and topology first, then every action preceded by
+    /// a `wait` for the time that passed since the previous one, up to `until`.
+    pub fn to_script(&self, until: Duration) -> String {
+        let mut lines = vec![
+            format!("# recorded run, {} actions; replay with --replay <this file>", self.actions.len()),
+            ScriptCommand::Seed(self.seed).to_string(),
+            ScriptCommand::Topology(self.topology.clone()).to_string(),
+        ];
+        let mut last = Duration::ZERO;
+        for (at, action) in &self.actions {
+            if *at > last {
+                lines.push(ScriptCommand::Wait(*at - last).to_string());
+                last = *at;
+            }
+            lines.push(action.to_string());
+        }
+        if until > last {
+            lines.push(ScriptCommand::Wait(until - last).to_string());
+        }
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    /// Writes the recording, up to now, to `path`.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_script(self.started_at.elapsed()))
+    }
+}
+
+/// `seed` and `topology` of a recorded script, which must be known before the network is built.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayHeader {
+    pub seed: Option<u64>,
+    pub topology: Option<String>,
+}
+
+impl ReplayHeader {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Fails on a `seed` line that does not parse: replaying with another seed would not reproduce the run.
+    pub fn from_reader(reader: impl BufRead) -> io::Result<Self> {
+        let mut header = ReplayHeader::default();
+        for line in reader.lines() {
+            let line = line?;
+            match ScriptCommand::parse(&line) {
+                Ok(Some(ScriptCommand::Seed(seed))) => header.seed = Some(seed),
+                Ok(Some(ScriptCommand::Topology(topology))) => header.topology = Some(topology),
+                Err(e) if line.split_whitespace().next() == Some("seed") => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+                _ => {}
+            }
+        }
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload() -> ScriptCommand {
+        ScriptCommand::Send { client: 1, command: "[TextUpload]::notes.md::line one\nline two".to_string() }
+    }
+
+    #[test]
+    fn test_node_seeds_depend_on_the_seed_and_the_node() {
+        assert_eq!(node_seed(42, 3), node_seed(42, 3), "the same seed gives the same node seed");
+        assert_ne!(node_seed(42, 3), node_seed(42, 4), "nodes do not share a sequence");
+        assert_ne!(node_seed(42, 3), node_seed(43, 3));
+    }
+
+    #[test]
+    fn test_recorder_keeps_only_actions_in_order() {
+        let mut recorder = ActionRecorder::new(42, "topologies/default.toml");
+        recorder.record(ScriptCommand::Crash(5));
+        recorder.record(ScriptCommand::Wait(Duration::from_millis(10)));
+        recorder.record(upload());
+        assert_eq!(recorder.actions().iter().map(|(_, action)| action.clone()).collect::<Vec<_>>(), vec![ScriptCommand::Crash(5), upload()]);
+        assert!(recorder.actions()[0].0 <= recorder.actions()[1].0);
+    }
+
+    #[test]
+    fn test_recorded_script_replays_the_actions_for_as_long_as_the_run() {
+        let mut recorder = ActionRecorder::new(42, "topologies/default.toml");
+        recorder.record(ScriptCommand::Crash(5));
+        recorder.record(upload());
+        let script = recorder.to_script(Duration::from_secs(60));
+
+        let commands: Vec<ScriptCommand> = script.lines().filter_map(|line| ScriptCommand::parse(line).unwrap()).collect();
+        assert_eq!(&commands[..2], &[ScriptCommand::Seed(42), ScriptCommand::Topology("topologies/default.toml".to_string())]);
+        let replayed: Vec<&ScriptCommand> = commands.iter().filter(|c| !matches!(c, ScriptCommand::Wait(_) | ScriptCommand::Seed(_) | ScriptCommand::Topology(_))).collect();
+        assert_eq!(replayed, vec![&ScriptCommand::Crash(5), &upload()]);
+        let waited: Duration = commands.iter().filter_map(|c| if let ScriptCommand::Wait(d) = c { Some(*d) } else { None }).sum();
+        assert!(waited > Duration::from_secs(59) && waited <= Duration::from_secs(60), "the replay lasts as long as the run");
+    }
+
+    #[test]
+    fn test_replay_header_reads_seed_and_topology() {
+        let header = ReplayHeader::from_reader("# recorded run\nseed 42\ntopology topologies/default.toml\ncrash 5\n".as_bytes()).unwrap();
+        assert_eq!((header.seed, header.topology.as_deref()), (Some(42), Some("topologies/default.toml")));
+        assert!(ReplayHeader::from_reader("seed forty-two\n".as_bytes()).is_err(), "a bad seed is not replaced by a random one");
+        assert_eq!(ReplayHeader::from_reader("crash 5\n".as_bytes()).unwrap(), ReplayHeader::default());
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use wg_2024::network::NodeId;
+use wg_2024::packet::{Packet, PacketType};
+
+/// Entries kept in memory; past this the oldest go (export regularly for long runs).
+pub const MAX_SIM_EVENTS: usize = 20_000;
+
+/// What a log entry is about, for filtering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SimEventKind {
+    /// A host starting or stopping.
+    Lifecycle,
+    /// Floods, crashes, links added or removed.
+    Topology,
+    /// Login, logout and client lists.
+    Login,
+    Chat,
+    Room,
+    Media,
+    Text,
+    /// A message that could not be delivered.
+    Delivery,
+    Error,
+    /// Actions taken from the GUI on the network (crash, PDR, spawn...).
+    Control,
+    PacketSent,
+    PacketDropped,
+    Shortcut,
+}
+
+impl SimEventKind {
+    pub const ALL: [SimEventKind; 13] = [
+        SimEventKind::Lifecycle,
+        SimEventKind::Topology,
+        SimEventKind::Login,
+        SimEventKind::Chat,
+        SimEventKind::Room,
+        SimEventKind::Media,
+        SimEventKind::Text,
+        SimEventKind::Delivery,
+        SimEventKind::Error,
+        SimEventKind::Control,
+        SimEventKind::PacketSent,
+        SimEventKind::PacketDropped,
+        SimEventKind::Shortcut,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SimEventKind::Lifecycle => "lifecycle",
+            SimEventKind::Topology => "topology",
+            SimEventKind::Login => "login",
+            SimEventKind::Chat => "chat",
+            SimEventKind::Room => "room",
+            SimEventKind::Media => "media",
+            SimEventKind::Text => "text",
+            SimEventKind::Delivery => "delivery",
+            SimEventKind::Error => "error",
+            SimEventKind::Control => "control",
+            SimEventKind::PacketSent => "packet_sent",
+            SimEventKind::PacketDropped => "packet_dropped",
+            SimEventKind::Shortcut => "shortcut",
+        }
+    }
+}
+
+/// One entry of the simulation log. `timestamp` is in ms since the Unix epoch, `node` is the host
+/// (or drone) it comes from, `None` for the GUI itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimEvent {
+    pub timestamp: u64,
+    pub node: Option<NodeId>,
+    pub kind: SimEventKind,
+    pub session: Option<u64>,
+    pub payload: String,
+}
+
+/// Milliseconds since the Unix epoch, as stored in `SimEvent::timestamp`.
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+impl SimEvent {
+    pub fn new(node: Option<NodeId>, kind: SimEventKind, payload: impl ToString) -> Self {
+        Self { timestamp: now_millis(), node, kind, session: None, payload: payload.to_string() }
+    }
+
+    pub fn with_session(mut self, session: u64) -> Self {
+        self.session = Some(session);
+        self
+    }
+}
+
+impl fmt::Display for SimEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.node {
+            Some(node) => write!(f, "[{}] #{}: {}", self.kind.name(), node, self.payload)?,
+            None => write!(f, "[{}] {}", self.kind.name(), self.payload)?,
+        }
+        if let Some(session) = self.session {
+            write!(f, " (session {})", session)?;
+        }
+        Ok(())
+    }
+}
+
+pub type SharedSimLog = Arc<Mutex<VecDeque<SimEvent>>>;
+
+pub fn new_sim_log() -> SharedSimLog {
+    Arc::new(Mutex::new(VecDeque::new()))
+}
+
+pub fn push_sim_event(log: &SharedSimLog, event: SimEvent) {
+    if let Ok(mut log) = log.lock() {
+        if log.len() >= MAX_SIM_EVENTS {
+            log.pop_front();
+        }
+        log.push_back(event);
+    } else {
+        println!("❌ Could not lock simulation log");
+    }
+}
+
+/// Short name of the packet's type, as shown in the log.
+pub fn packet_type_name(packet: &Packet) -> &'static str {
+    match packet.pack_type {
+        PacketType::MsgFragment(_) => "fragment",
+        PacketType::Ack(_) => "ack",
+        PacketType::Nack(_) => "nack",
+        PacketType::FloodRequest(_) => "flood_request",
+        PacketType::FloodResponse(_) => "flood_response",
+    }
+}
+
+/// Drone (or host) that sent the packet on its current hop, and the node it was sent to.
+pub fn packet_hop(packet: &Packet) -> Option<(NodeId, Option<NodeId>)> {
+    let hops = &packet.routing_header.hops;
+    let hop_index = packet.routing_header.hop_index;
+    if hop_index == 0 {
+        hops.first().map(|&from| (from, hops.get(1).copied()))
+    } else {
+        hops.get(hop_index - 1).map(|&from| (from, hops.get(hop_index).copied()))
+    }
+}
+
+/// Which entries the Simulation Log panel shows: one node (or all), and the kinds checked (none checked = all).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimLogFilter {
+    pub node: Option<NodeId>,
+    pub kinds: BTreeSet<SimEventKind>,
+}
+
+impl SimLogFilter {
+    pub fn matches(&self, event: &SimEvent) -> bool {
+        self.node.is_none_or(|node| event.node == Some(node)) && (self.kinds.is_empty() || self.kinds.contains(&event.kind))
+    }
+}
+
+/// Writes `events` to `path` as JSON Lines (one `SimEvent` object per line) and gives how many were written.
+pub fn export_jsonl<'a>(events: impl IntoIterator<Item = &'a SimEvent>, path: &Path) -> io::Result<usize> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let written = write_jsonl(events, &mut out)?;
+    out.flush()?;
+    Ok(written)
+}
+
+fn write_jsonl<'a>(events: impl IntoIterator<Item = &'a SimEvent>, mut out: impl Write) -> io::Result<usize> {
+    let mut written = 0;
+    for event in events {
+        serde_json::to_writer(&mut out, event)?;
+        out.write_all(b"\n")?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_events() -> Vec<SimEvent> {
+        vec![
+            SimEvent::new(Some(1), SimEventKind::Chat, "hello from 1"),
+            SimEvent::new(Some(3), SimEventKind::PacketDropped, "fragment 3 → 4").with_session(9),
+        ]
+    }
+
+    #[test]
+    fn test_sim_events_display_and_filter() {
+        let events = sample_events();
+        assert_eq!(events[1].to_string(), "[packet_dropped] #3: fragment 3 → 4 (session 9)");
+
+        let mut filter = SimLogFilter::default();
+        assert!(events.iter().all(|event| filter.matches(event)), "an empty filter shows everything");
+        filter.node = Some(3);
+        assert!(!filter.matches(&events[0]) && filter.matches(&events[1]));
+        filter.node = None;
+        filter.kinds.insert(SimEventKind::Chat);
+        assert!(filter.matches(&events[0]) && !filter.matches(&events[1]));
+    }
+
+    #[test]
+    fn test_sim_events_export_as_json_lines() {
+        let events = sample_events();
+        let mut out = Vec::new();
+        assert_eq!(write_jsonl(&events, &mut out).unwrap(), 2);
+        let read_back: Vec<SimEvent> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("every line is one event"))
+            .collect();
+        assert_eq!(read_back, events);
+    }
+
+    #[test]
+    fn test_sim_log_is_capped_dropping_the_oldest_events() {
+        let log = new_sim_log();
+        for i in 0..=MAX_SIM_EVENTS {
+            push_sim_event(&log, SimEvent::new(None, SimEventKind::Control, i));
+        }
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), MAX_SIM_EVENTS, "the log is capped");
+        assert_eq!(log.front().unwrap().payload, "1", "the oldest entries are dropped first");
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use wg_2024::controller::DroneEvent;
+use wg_2024::network::NodeId;
+use wg_2024::packet::PacketType;
+use crate::simulation_controller::sim_log::packet_hop;
+
+/// What the controller saw a node do. Packets are counted for the node that sent (or dropped)
+/// them on their hop; shortcuts for the node the controller delivered them to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NodeStats {
+    pub forwarded: u64,
+    pub fragments_forwarded: u64, // the part of `forwarded` the PDR applies to
+    pub dropped: u64,
+    pub shortcuts: u64,
+}
+
+impl NodeStats {
+    /// Share of the fragments that reached this node and were dropped, `None` before any fragment.
+    /// Only fragments can be dropped, so acks, nacks and floods are left out to compare it with the PDR.
+    pub fn observed_drop_ratio(&self) -> Option<f32> {
+        let total = self.fragments_forwarded + self.dropped;
+        (total > 0).then(|| self.dropped as f32 / total as f32)
+    }
+}
+
+/// Traffic over one link, both directions together.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    pub sent: u64,
+    pub dropped: u64,
+}
+
+/// Counters the controller keeps from the `DroneEvent`s it processes.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkStats {
+    nodes: HashMap<NodeId, NodeStats>,
+    links: HashMap<(NodeId, NodeId), LinkStats>, // (smaller id, larger id)
+}
+
+pub type SharedNetworkStats = Arc<Mutex<NetworkStats>>;
+
+pub fn new_network_stats() -> SharedNetworkStats {
+    Arc::new(Mutex::new(NetworkStats::default()))
+}
+
+fn link_key(a: NodeId, b: NodeId) -> (NodeId, NodeId) {
+    (a.min(b), a.max(b))
+}
+
+impl NetworkStats {
+    pub fn record(&mut self, event: &DroneEvent) {
+        match event {
+            DroneEvent::PacketSent(packet) => {
+                if let Some((from, to)) = packet_hop(packet) {
+                    let node = self.nodes.entry(from).or_default();
+                    node.forwarded += 1;
+                    if matches!(packet.pack_type, PacketType::MsgFragment(_)) {
+                        node.fragments_forwarded += 1;
+                    }
+                    if let Some(to) = to {
+                        self.links.entry(link_key(from, to)).or_default().sent += 1;
+                    }
+                }
+            }
+            DroneEvent::PacketDropped(packet) => {
+                if let Some((from, to)) = packet_hop(packet) {
+                    self.nodes.entry(from).or_default().dropped += 1;
+                    if let Some(to) = to {
+                        self.links.entry(link_key(from, to)).or_default().dropped += 1;
+                    }
+                }
+            }
+            DroneEvent::ControllerShortcut(packet) => {
+                if let Some(dest) = packet.routing_header.destination() {
+                    self.nodes.entry(dest).or_default().shortcuts += 1;
+                }
+            }
+        }
+    }
+
+    pub fn node(&self, id: NodeId) -> NodeStats {
+        self.nodes.get(&id).copied().unwrap_or_default()
+    }
+
+    pub fn link(&self, a: NodeId, b: NodeId) -> LinkStats {
+        self.links.get(&link_key(a, b)).copied().unwrap_or_default()
+    }
+
+    /// Every link that carried traffic, busiest first.
+    pub fn links(&self) -> Vec<((NodeId, NodeId), LinkStats)> {
+        let mut links: Vec<_> = self.links.iter().map(|(&key, &stats)| (key, stats)).collect();
+        links.sort_by(|(ka, a), (kb, b)| (b.sent + b.dropped).cmp(&(a.sent + a.dropped)).then(ka.cmp(kb)));
+        links
+    }
+
+    pub fn total_shortcuts(&self) -> u64 {
+        self.nodes.values().map(|node| node.shortcuts).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{ack, fragment};
+
+    #[test]
+    fn test_nodes_count_forwarded_dropped_and_shortcut_packets() {
+        let mut stats = NetworkStats::default();
+        for _ in 0..3 {
+            stats.record(&DroneEvent::PacketSent(fragment(2, 0)));
+        }
+        stats.record(&DroneEvent::PacketDropped(fragment(2, 0)));
+        stats.record(&DroneEvent::ControllerShortcut(ack(2)));
+
+        assert_eq!(stats.node(2), NodeStats { forwarded: 3, fragments_forwarded: 3, dropped: 1, shortcuts: 0 });
+        assert_eq!(stats.node(2).observed_drop_ratio(), Some(0.25));
+        assert_eq!(stats.node(7).observed_drop_ratio(), None, "no traffic, no ratio");
+        assert_eq!(stats.node(1).shortcuts, 1, "shortcuts count for the node they were delivered to");
+        assert_eq!(stats.total_shortcuts(), 1);
+    }
+
+    #[test]
+    fn test_drop_ratio_only_counts_fragments() {
+        let mut stats = NetworkStats::default();
+        stats.record(&DroneEvent::PacketSent(fragment(3, 0)));
+        stats.record(&DroneEvent::PacketDropped(fragment(3, 1)));
+        for _ in 0..6 {
+            stats.record(&DroneEvent::PacketSent(ack(2)));
+        }
+        assert_eq!(stats.node(3).forwarded, 7);
+        assert_eq!(stats.node(3).observed_drop_ratio(), Some(0.5), "acks do not dilute the ratio");
+        assert_eq!(stats.node(4).observed_drop_ratio(), None, "no fragment, no ratio");
+    }
+
+    #[test]
+    fn test_links_add_up_both_directions() {
+        let mut stats = NetworkStats::default();
+        stats.record(&DroneEvent::PacketSent(fragment(2, 0)));
+        stats.record(&DroneEvent::PacketDropped(fragment(2, 0)));
+        stats.record(&DroneEvent::PacketSent(ack(2)));
+        stats.record(&DroneEvent::PacketSent(ack(1)));
+
+        assert_eq!(stats.link(2, 3), LinkStats { sent: 2, dropped: 1 });
+        assert_eq!(stats.link(3, 2), stats.link(2, 3));
+        assert_eq!(stats.link(1, 2), LinkStats::default());
+        assert_eq!(stats.links().first().map(|(key, _)| *key), Some((2, 3)), "busiest link first");
+    }
+}
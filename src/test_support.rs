@@ -0,0 +1,37 @@
+//! Helpers shared by the unit tests of several modules.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use wg_2024::network::SourceRoutingHeader;
+use wg_2024::packet::{Fragment, Packet};
+
+/// Directory under the system temp dir, removed when dropped, so a failing test leaves nothing behind.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("krusty_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Self(dir)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Fragment `index` of 2 in session 7, on the route 1 → 2 → 3 → 4 and about to leave `hops[hop_index - 1]`.
+pub(crate) fn fragment(hop_index: usize, index: u64) -> Packet {
+    Packet::new_fragment(SourceRoutingHeader::new(vec![1, 2, 3, 4], hop_index), 7, Fragment::from_string(index, 2, "hi".to_string()))
+}
+
+/// Ack of fragment 0 in session 7, on the way back 4 → 3 → 2 → 1.
+pub(crate) fn ack(hop_index: usize) -> Packet {
+    Packet::new_ack(SourceRoutingHeader::new(vec![4, 3, 2, 1], hop_index), 7, 0)
+}